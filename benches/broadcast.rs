@@ -0,0 +1,107 @@
+//! ルーム内のブロードキャストで、受信者ごとにエンコードする場合と一度だけエンコードして共有する場合を比べる。
+//! `cargo bench --bench broadcast`で実行する。
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use mini_realtime_server::actor::{Delivery, OutputMessage, OutputMessageEvent};
+use mini_realtime_server::config;
+use mini_realtime_server::network_protocol::codec::Codec;
+use mini_realtime_server::network_protocol::compression::Compressor;
+use mini_realtime_server::protobuf::app;
+
+const ROOM_SIZES: [usize; 3] = [10, 100, 1000];
+const BODY_SIZES: [usize; 2] = [64, 4096];
+
+fn new_event(body_size: usize) -> Arc<OutputMessageEvent> {
+    Arc::new(OutputMessageEvent::new(
+        "room".to_string(),
+        "sender".to_string(),
+        Bytes::from(vec![1; body_size]),
+        Delivery::Reliable,
+    ))
+}
+
+fn compressors(room_size: usize) -> Vec<Compressor> {
+    let config = config::Compression {
+        enable: false,
+        threshold: 1024,
+    };
+    (0..room_size)
+        .map(|_| Compressor::new(&config, usize::MAX))
+        .collect()
+}
+
+// 以前の実装と同じく、受信者ごとに通知を作ってエンコードする。
+fn encode_per_recipient(event: &OutputMessageEvent, compressors: &mut [Compressor], codec: Codec) {
+    for compressor in compressors.iter_mut() {
+        let message = app::ServerMessage {
+            data: Some(app::server_message::Data::MessageNotification(
+                app::MessageNotification {
+                    sender_id: event.sender_player_id.clone(),
+                    room_id: event.room_id.clone(),
+                    body: event.body.to_vec(),
+                    delivery: app::Delivery::Reliable as i32,
+                },
+            )),
+        };
+        black_box(
+            compressor
+                .encode(OutputMessage::Owned(message), codec)
+                .unwrap(),
+        );
+    }
+}
+
+// 通知を一度だけエンコードして、全ての受信者で共有する。
+fn encode_once(event: &OutputMessageEvent, compressors: &mut [Compressor], codec: Codec) {
+    for compressor in compressors.iter_mut() {
+        let message = OutputMessage::Shared(event.notification(app::Delivery::Reliable));
+        black_box(compressor.encode(message, codec).unwrap());
+    }
+}
+
+/// 1回のブロードキャストで、ルーム内の全員に送るフレームを作る時間。
+fn broadcast(c: &mut Criterion) {
+    for codec in [Codec::Protobuf, Codec::Json] {
+        let mut group = c.benchmark_group(format!("broadcast/{}", codec.name()));
+        for room_size in ROOM_SIZES {
+            for body_size in BODY_SIZES {
+                let mut compressors = compressors(room_size);
+                let parameter = format!("{}/{}", room_size, body_size);
+                group.throughput(Throughput::Elements(room_size as u64));
+                for (name, f) in [
+                    (
+                        "per_recipient",
+                        encode_per_recipient as fn(&OutputMessageEvent, &mut [Compressor], Codec),
+                    ),
+                    ("encode_once", encode_once),
+                ] {
+                    group.bench_with_input(
+                        BenchmarkId::new(name, &parameter),
+                        &body_size,
+                        |b, &body_size| {
+                            b.iter(|| {
+                                // ブロードキャストごとに新しいイベントが作られる。
+                                let event = new_event(body_size);
+                                f(&event, &mut compressors, codec);
+                            })
+                        },
+                    );
+                }
+            }
+        }
+        group.finish();
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(2));
+    targets = broadcast
+}
+criterion_main!(benches);
@@ -0,0 +1,160 @@
+//! 各トランスポートが1メッセージごとに行うエンコードとデコードにかかる時間を計測する。
+//! `cargo bench --bench codec`で実行する。
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use prost::Message as _;
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+use mini_realtime_server::actor::{Delivery, OutputMessage, OutputMessageEvent};
+use mini_realtime_server::config;
+use mini_realtime_server::network_protocol::codec::Codec;
+use mini_realtime_server::network_protocol::compression::Compressor;
+use mini_realtime_server::network_protocol::udp::{Lane, Lanes, Packet};
+use mini_realtime_server::protobuf::app;
+
+const BODY_SIZES: [usize; 3] = [64, 4096, 65536];
+
+// トランスポートと、そのトランスポートで選べるコーデックの組み合わせ。
+// gRPCはtonicがprotobufでエンコードするので、protobufと同じ処理になる。
+const TRANSPORTS: [(&str, Codec, bool); 6] = [
+    ("tcp/protobuf", Codec::Protobuf, true),
+    ("tcp/msgpack", Codec::MessagePack, true),
+    ("websocket/protobuf", Codec::Protobuf, false),
+    ("websocket/json", Codec::Json, false),
+    ("websocket/msgpack", Codec::MessagePack, false),
+    ("grpc", Codec::Protobuf, false),
+];
+
+fn compressor() -> Compressor {
+    Compressor::new(
+        &config::Compression {
+            enable: false,
+            threshold: 1024,
+        },
+        usize::MAX,
+    )
+}
+
+fn client_message(body_size: usize) -> app::ClientMessage {
+    app::ClientMessage {
+        data: Some(app::client_message::Data::SendMessage(app::SendMessage {
+            target_ids: Vec::new(),
+            room_id: "room".to_string(),
+            body: vec![1; body_size],
+            delivery: app::Delivery::Reliable as i32,
+        })),
+    }
+}
+
+fn new_event(body_size: usize) -> Arc<OutputMessageEvent> {
+    Arc::new(OutputMessageEvent::new(
+        "room".to_string(),
+        "sender".to_string(),
+        Bytes::from(vec![1; body_size]),
+        Delivery::Reliable,
+    ))
+}
+
+/// Roomからの通知を送信するフレームにする。TCPの場合は長さのプレフィックスも付ける。
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec/encode");
+    for (transport, codec, length_delimited) in TRANSPORTS {
+        for body_size in BODY_SIZES {
+            let mut compressor = compressor();
+            let mut framer = LengthDelimitedCodec::new();
+            let mut buf = BytesMut::new();
+            group.throughput(Throughput::Bytes(body_size as u64));
+            group.bench_with_input(
+                BenchmarkId::new(transport, body_size),
+                &body_size,
+                |b, &body_size| {
+                    b.iter(|| {
+                        // 通知はイベントごとにキャッシュされるので、毎回新しいイベントを作る。
+                        let event = new_event(body_size);
+                        let message =
+                            OutputMessage::Shared(event.notification(app::Delivery::Reliable));
+                        let frame = compressor.encode(message, codec).unwrap();
+                        if length_delimited {
+                            framer.encode(frame, &mut buf).unwrap();
+                            buf.clear();
+                        }
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+/// 受信したフレームからクライアントのメッセージをデコードする。
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec/decode");
+    for (transport, codec, length_delimited) in TRANSPORTS {
+        for body_size in BODY_SIZES {
+            let data = codec.encode(&client_message(body_size)).unwrap();
+            let mut framed = BytesMut::new();
+            LengthDelimitedCodec::new()
+                .encode(Bytes::from(data.clone()), &mut framed)
+                .unwrap();
+            let mut framer = LengthDelimitedCodec::new();
+            group.throughput(Throughput::Bytes(body_size as u64));
+            group.bench_with_input(
+                BenchmarkId::new(transport, body_size),
+                &body_size,
+                |b, _| {
+                    b.iter(|| {
+                        if length_delimited {
+                            let frame = framer.decode(&mut framed.clone()).unwrap().unwrap();
+                            codec.decode::<app::ClientMessage>(&frame).unwrap()
+                        } else {
+                            codec.decode::<app::ClientMessage>(&data).unwrap()
+                        }
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+/// UDPのReliableレーンで、送信からAckを受け取るまでのパケットの処理。
+fn udp(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec/udp");
+    // UDPのデータグラムに収まる大きさだけを計測する。
+    for body_size in [64, 1024] {
+        let mut sender = Lanes::new(1, usize::MAX);
+        let mut receiver = Lanes::new(1, usize::MAX);
+        group.throughput(Throughput::Bytes(body_size as u64));
+        group.bench_with_input(
+            BenchmarkId::new("reliable", body_size),
+            &body_size,
+            |b, &body_size| {
+                b.iter(|| {
+                    let payload = client_message(body_size).encode_to_vec().into();
+                    let packet = sender
+                        .send(Lane::Reliable, payload, Instant::now())
+                        .unwrap();
+                    let packet = Packet::decode(packet.encode()).unwrap();
+                    let received = receiver.receive(packet.body);
+                    for payload in received.payloads {
+                        app::ClientMessage::decode(payload).unwrap();
+                    }
+                    let ack = Packet::decode(received.reply.unwrap().encode()).unwrap();
+                    sender.receive(ack.body);
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(2));
+    targets = encode, decode, udp
+}
+criterion_main!(benches);
@@ -0,0 +1,104 @@
+//! `entity::Room`のブロードキャストと、1人への送信にかかる時間を計測する。
+//! `cargo bench --bench room`で実行する。
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::sync::mpsc;
+
+use mini_realtime_server::actor::{Delivery, OutputEvent, OutputMessageEvent};
+use mini_realtime_server::entity;
+
+const ROOM_SIZES: [usize; 3] = [10, 100, 1000];
+const BODY_SIZES: [usize; 2] = [64, 4096];
+
+struct Fixture {
+    room: entity::Room<OutputEvent>,
+    receivers: Vec<mpsc::Receiver<OutputEvent>>,
+}
+
+impl Fixture {
+    fn new(room_size: usize) -> Self {
+        let config = entity::RoomConfig {
+            max_players: room_size as u32,
+        };
+        let mut room = entity::Room::new("room".to_string(), config.clone());
+        let receivers = (0..room_size)
+            .map(|i| {
+                let (tx, rx) = mpsc::channel(1);
+                room.add_player(entity::Player::new(format!("player-{}", i), tx), &config)
+                    .unwrap();
+                rx
+            })
+            .collect();
+        Self { room, receivers }
+    }
+
+    // 次の送信でキューが溢れないように、受信者のキューを空にしておく。
+    fn drain(&mut self) {
+        for rx in self.receivers.iter_mut() {
+            while rx.try_recv().is_ok() {}
+        }
+    }
+}
+
+fn new_event(body_size: usize) -> OutputEvent {
+    OutputEvent::Message(Arc::new(OutputMessageEvent::new(
+        "room".to_string(),
+        "player-0".to_string(),
+        Bytes::from(vec![1; body_size]),
+        Delivery::Reliable,
+    )))
+}
+
+fn broadcast(c: &mut Criterion) {
+    let mut group = c.benchmark_group("room/broadcast");
+    for room_size in ROOM_SIZES {
+        for body_size in BODY_SIZES {
+            let mut fixture = Fixture::new(room_size);
+            group.throughput(Throughput::Elements(room_size as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("room_size={}", room_size), body_size),
+                &body_size,
+                |b, &body_size| {
+                    b.iter(|| {
+                        fixture.room.broadcast(new_event(body_size));
+                        fixture.drain();
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+fn send(c: &mut Criterion) {
+    let mut group = c.benchmark_group("room/send");
+    for room_size in ROOM_SIZES {
+        for body_size in BODY_SIZES {
+            let mut fixture = Fixture::new(room_size);
+            let target_id = format!("player-{}", room_size - 1);
+            group.throughput(Throughput::Elements(1));
+            group.bench_with_input(
+                BenchmarkId::new(format!("room_size={}", room_size), body_size),
+                &body_size,
+                |b, &body_size| {
+                    b.iter(|| {
+                        fixture.room.send(&target_id, new_event(body_size));
+                        fixture.receivers[room_size - 1].try_recv().unwrap();
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(2));
+    targets = broadcast, send
+}
+criterion_main!(benches);
@@ -0,0 +1,114 @@
+//! `actor::Room`のイベントループで、メッセージを受け取ってから全員に届くまでの時間を計測する。
+//! `cargo bench --bench room_actor`で実行する。
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+use mini_realtime_server::actor::{
+    Delivery, InputEvent, InputMessageEvent, OutputEvent, Registries, Room,
+};
+use mini_realtime_server::entity;
+
+const ROOM_SIZES: [usize; 3] = [10, 100, 1000];
+const BODY_SIZES: [usize; 2] = [64, 4096];
+
+/// 全員が参加済みのRoom actorを起動して、入力キューと各プレイヤーの受信キューを返す。
+fn spawn_room(
+    runtime: &Runtime,
+    room_size: usize,
+) -> (mpsc::Sender<InputEvent>, Vec<mpsc::Receiver<OutputEvent>>) {
+    let config = entity::RoomConfig {
+        max_players: room_size as u32,
+    };
+    let mut room = entity::Room::new("room".to_string(), config.clone());
+    let receivers = (0..room_size)
+        .map(|i| {
+            let (tx, rx) = mpsc::channel(1);
+            room.add_player(entity::Player::new(format!("player-{}", i), tx), &config)
+                .unwrap();
+            rx
+        })
+        .collect();
+
+    let (room_tx, room_rx) = mpsc::channel(1);
+    let registries = Arc::new(Registries::new());
+    runtime.spawn(async move { Room::new(room, room_rx, registries).run().await });
+    (room_tx, receivers)
+}
+
+fn new_event(body_size: usize, target_ids: Vec<entity::PlayerId>) -> InputEvent {
+    InputEvent::Message(Box::new(InputMessageEvent {
+        sender_player_id: "player-0".to_string(),
+        target_ids,
+        body: Bytes::from(vec![1; body_size]),
+        delivery: Delivery::Reliable,
+        sent_at: Instant::now(),
+    }))
+}
+
+fn broadcast(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("room_actor/broadcast");
+    for room_size in ROOM_SIZES {
+        for body_size in BODY_SIZES {
+            let (room_tx, mut receivers) = spawn_room(&runtime, room_size);
+            group.throughput(Throughput::Elements(room_size as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("room_size={}", room_size), body_size),
+                &body_size,
+                |b, &body_size| {
+                    b.iter(|| {
+                        runtime.block_on(async {
+                            room_tx
+                                .send(new_event(body_size, Vec::new()))
+                                .await
+                                .unwrap();
+                            for rx in receivers.iter_mut() {
+                                rx.recv().await.unwrap();
+                            }
+                        })
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+fn send(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("room_actor/send");
+    for room_size in ROOM_SIZES {
+        for body_size in BODY_SIZES {
+            let (room_tx, mut receivers) = spawn_room(&runtime, room_size);
+            let target_ids = vec![format!("player-{}", room_size - 1)];
+            group.throughput(Throughput::Elements(1));
+            group.bench_with_input(
+                BenchmarkId::new(format!("room_size={}", room_size), body_size),
+                &body_size,
+                |b, &body_size| {
+                    b.iter(|| {
+                        runtime.block_on(async {
+                            let event = new_event(body_size, target_ids.clone());
+                            room_tx.send(event).await.unwrap();
+                            receivers[room_size - 1].recv().await.unwrap();
+                        })
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(2));
+    targets = broadcast, send
+}
+criterion_main!(benches);
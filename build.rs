@@ -1,4 +1,19 @@
+use std::env;
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("protobuf/app.proto")?;
+    let descriptor_path = PathBuf::from(env::var("OUT_DIR")?).join("app_descriptor.bin");
+    tonic_build::configure()
+        .file_descriptor_set_path(&descriptor_path)
+        .compile(
+            &["protobuf/app.proto", "protobuf/admin.proto"],
+            &["protobuf"],
+        )?;
+
+    // WebSocketのテキストフレームで使う、protobufのJSONマッピングのシリアライズ実装を生成する。
+    let descriptor_set = std::fs::read(descriptor_path)?;
+    pbjson_build::Builder::new()
+        .register_descriptors(&descriptor_set)?
+        .build(&[".app"])?;
     Ok(())
 }
@@ -1,10 +1,21 @@
 //! 各プロトコルから利用される共通処理。
 //! actorを通じでクライアントからのメッセージと内部ロジックのやり取りを行う。
 
+mod channel;
 mod event;
+mod outbound;
+mod party;
 mod player;
+mod presence;
+mod registry;
 mod room;
+mod watch;
 
+pub use channel::*;
 pub use event::*;
+pub use outbound::*;
+pub use party::*;
 pub use player::*;
+pub use registry::*;
 pub use room::*;
+pub use watch::*;
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tracing::debug;
+
+use super::event::*;
+use super::registry::*;
+use crate::entity;
+
+/// `global`や`region-eu`、`guild:123`のような名前付きのチャネル。
+/// Roomとは異なり人数の上限は無く、購読しているプレイヤー全員にメッセージを配信するだけ。
+pub type ChannelName = String;
+
+pub(super) type Subscribers = HashMap<entity::PlayerId, entity::Player<OutputEvent>>;
+
+impl Registries {
+    pub fn subscribe_channel(&self, name: &ChannelName, player: entity::Player<OutputEvent>) {
+        self.channels
+            .get_or_insert_with(name.clone(), Subscribers::new, |subscribers| {
+                subscribers.insert(player.id.clone(), player);
+            });
+    }
+
+    pub fn unsubscribe_channel(&self, name: &ChannelName, player_id: &entity::PlayerId) -> bool {
+        let ok = self
+            .channels
+            .update(name, |subscribers| subscribers.remove(player_id).is_some())
+            .unwrap_or(false);
+        self.channels
+            .remove_if(name, |subscribers| subscribers.is_empty());
+        ok
+    }
+
+    pub fn publish_channel_message(
+        &self,
+        name: &ChannelName,
+        sender_player_id: &entity::PlayerId,
+        body: Bytes,
+    ) {
+        // 送信中にロックを保持し続けないように、購読者をcloneしてから送信する。
+        let subscribers: Vec<entity::Player<OutputEvent>> = match self
+            .channels
+            .get_with(name, |subscribers| subscribers.values().cloned().collect())
+        {
+            Some(subscribers) => subscribers,
+            None => return,
+        };
+
+        let event = OutputEvent::ChannelMessage(Arc::new(OutputChannelMessageEvent::new(
+            name.clone(),
+            sender_player_id.clone(),
+            body,
+        )));
+        send_to_players(subscribers, event);
+    }
+
+    /// ログイン中の全てのプレイヤーにサーバからのお知らせを送信する。
+    /// メンテナンスの告知など、管理用の経路から呼び出される想定。
+    pub fn publish_server_announcement(&self, message: String) {
+        let players = self.get_all_players();
+        let event =
+            OutputEvent::ServerAnnouncement(Arc::new(OutputServerAnnouncementEvent { message }));
+        send_to_players(players, event);
+    }
+}
+
+fn send_to_players(players: Vec<entity::Player<OutputEvent>>, event: OutputEvent) {
+    players.into_iter().for_each(|mut player| {
+        if player.send(event.clone()).is_err() {
+            // 切断処理中のプレイヤー。切断時に購読は解除されるので、ここではログだけ出しておく。
+            debug!("Player disconnected. player_id={}", player.id);
+        }
+    });
+}
@@ -1,10 +1,19 @@
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    fmt::Debug,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
+use once_cell::sync::OnceCell;
+use tokio::sync::mpsc;
 
+use super::outbound::SharedMessage;
 use crate::entity;
+use crate::protobuf;
 
 type Result<T> = std::result::Result<T, entity::RoomError>;
+type PartyResult<T> = std::result::Result<T, entity::PartyError>;
 
 #[derive(Clone, Debug)]
 pub struct InputJoinEvent {
@@ -17,11 +26,48 @@ pub struct InputLeaveEvent {
     pub player_id: entity::PlayerId,
 }
 
+/// Roomでのメッセージの配送方法。
+/// 信頼性の無いレーンを持つプロトコル(UDP)でのみ意味を持つ。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    Reliable,
+    Unreliable,
+}
+
 #[derive(Clone, Debug)]
 pub struct InputMessageEvent {
     pub sender_player_id: entity::PlayerId,
     pub target_ids: Vec<entity::PlayerId>,
     pub body: Bytes,
+    pub delivery: Delivery,
+    // プレイヤーがメッセージを受け取った時刻。Roomでの配送までの遅延の計測に使う。
+    pub sent_at: Instant,
+}
+
+#[derive(Clone, Debug)]
+pub struct InputReserveEvent {
+    // 予約した主体。予約されるプレイヤー自身である必要はない。
+    pub requester_id: entity::RequesterId,
+    // 予約結果の送り先。受信側のキューが一杯の場合は結果を捨てる。
+    pub reply_tx: mpsc::Sender<OutputEvent>,
+    pub player_ids: Vec<entity::PlayerId>,
+    pub room_config: entity::RoomConfig,
+    pub timeout: Duration,
+    // 予約した主体がこのRoomに持てる予約の数。
+    pub max_reservations: u32,
+}
+
+/// 予約した主体が、使われなくなった予約を期限前に取り消す。
+#[derive(Clone, Debug)]
+pub struct InputCancelReservationEvent {
+    pub requester_id: entity::RequesterId,
+    pub player_ids: Vec<entity::PlayerId>,
+}
+
+/// 管理者がRoomを閉じる。参加中のプレイヤーは全員抜け、以降のJoinや予約は失敗する。
+#[derive(Clone, Debug)]
+pub struct InputCloseEvent {
+    pub reason: String,
 }
 
 #[derive(Clone, Debug)]
@@ -29,6 +75,58 @@ pub enum InputEvent {
     Join(Box<InputJoinEvent>),
     Leave(Box<InputLeaveEvent>),
     Message(Box<InputMessageEvent>),
+    Reserve(Box<InputReserveEvent>),
+    CancelReservation(Box<InputCancelReservationEvent>),
+    Close(Box<InputCloseEvent>),
+}
+
+#[derive(Clone, Debug)]
+pub struct InputPartyInviteEvent {
+    pub inviter_id: entity::PlayerId,
+    pub player_id: entity::PlayerId,
+}
+
+#[derive(Clone, Debug)]
+pub struct InputPartyJoinEvent {
+    pub player: entity::Player<OutputEvent>,
+}
+
+#[derive(Clone, Debug)]
+pub struct InputPartyLeaveEvent {
+    pub player_id: entity::PlayerId,
+}
+
+#[derive(Clone, Debug)]
+pub struct InputPartyMessageEvent {
+    pub sender_player_id: entity::PlayerId,
+    pub body: Bytes,
+}
+
+#[derive(Clone, Debug)]
+pub struct InputPartyJoinRoomEvent {
+    pub requester_id: entity::PlayerId,
+    // Noneの場合は条件に一致するRoomを探す。
+    pub room_id: Option<entity::RoomId>,
+    pub room_config: entity::RoomConfig,
+    pub room_properties: entity::RoomProperties,
+}
+
+/// パーティで移動中のRoomにメンバーがJoinした結果。
+#[derive(Clone, Debug)]
+pub struct InputPartyJoinRoomResultEvent {
+    pub player_id: entity::PlayerId,
+    pub room_id: entity::RoomId,
+    pub result: PartyResult<()>,
+}
+
+#[derive(Clone, Debug)]
+pub enum PartyInputEvent {
+    Invite(Box<InputPartyInviteEvent>),
+    Join(Box<InputPartyJoinEvent>),
+    Leave(Box<InputPartyLeaveEvent>),
+    Message(Box<InputPartyMessageEvent>),
+    JoinRoom(Box<InputPartyJoinRoomEvent>),
+    JoinRoomResult(Box<InputPartyJoinRoomResultEvent>),
 }
 
 #[derive(Clone, Debug)]
@@ -37,6 +135,7 @@ pub struct OutputJoinEvent {
     pub player_id: entity::PlayerId,
     pub room_player_ids: Vec<entity::PlayerId>,
     pub room_config: entity::RoomConfig,
+    pub room_properties: entity::RoomProperties,
 }
 
 #[derive(Clone, Debug)]
@@ -50,6 +149,165 @@ pub struct OutputMessageEvent {
     pub room_id: entity::RoomId,
     pub sender_player_id: entity::PlayerId,
     pub body: Bytes,
+    pub delivery: Delivery,
+    // ルーム内の全員で共有する通知。クライアントに届ける配送方法ごとに一度だけ作る。
+    notifications: [OnceCell<Arc<SharedMessage>>; 2],
+}
+
+impl OutputMessageEvent {
+    pub fn new(
+        room_id: entity::RoomId,
+        sender_player_id: entity::PlayerId,
+        body: Bytes,
+        delivery: Delivery,
+    ) -> Self {
+        Self {
+            room_id,
+            sender_player_id,
+            body,
+            delivery,
+            notifications: Default::default(),
+        }
+    }
+
+    /// クライアントに送る通知。信頼性の無い配送に対応していないクライアントには`Reliable`として送る。
+    pub fn notification(&self, delivery: protobuf::app::Delivery) -> Arc<SharedMessage> {
+        let i = match delivery {
+            protobuf::app::Delivery::Reliable => 0,
+            protobuf::app::Delivery::Unreliable => 1,
+        };
+        self.notifications[i]
+            .get_or_init(|| {
+                Arc::new(SharedMessage::new(protobuf::app::ServerMessage {
+                    data: Some(protobuf::app::server_message::Data::MessageNotification(
+                        protobuf::app::MessageNotification {
+                            sender_id: self.sender_player_id.clone(),
+                            room_id: self.room_id.clone(),
+                            body: self.body.to_vec(),
+                            delivery: delivery as i32,
+                        },
+                    )),
+                }))
+            })
+            .clone()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputReserveEvent {
+    pub room_id: entity::RoomId,
+    pub player_ids: Vec<entity::PlayerId>,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputReservationExpiredEvent {
+    pub room_id: entity::RoomId,
+    pub player_ids: Vec<entity::PlayerId>,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputPartyInviteEvent {
+    pub party_id: entity::PartyId,
+    pub inviter_id: entity::PlayerId,
+    pub player_id: entity::PlayerId,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputPartyJoinEvent {
+    pub party_id: entity::PartyId,
+    pub player_id: entity::PlayerId,
+    pub leader_id: entity::PlayerId,
+    pub member_ids: Vec<entity::PlayerId>,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputPartyLeaveEvent {
+    pub party_id: entity::PartyId,
+    pub player_id: entity::PlayerId,
+    pub leader_id: entity::PlayerId,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputPartyMessageEvent {
+    pub party_id: entity::PartyId,
+    pub sender_player_id: entity::PlayerId,
+    pub body: Bytes,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputPartyJoinRoomEvent {
+    pub party_id: entity::PartyId,
+    pub requester_id: entity::PlayerId,
+    pub room_id: entity::RoomId,
+    pub room_config: entity::RoomConfig,
+}
+
+/// パーティの移動を確定したメンバーは前のRoomから、取り消したメンバーは移動先のRoomから抜ける。
+#[derive(Clone, Debug)]
+pub struct OutputPartyLeaveRoomEvent {
+    pub party_id: entity::PartyId,
+    pub room_id: entity::RoomId,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputDirectMessageEvent {
+    pub sender_player_id: entity::PlayerId,
+    pub body: Bytes,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputChannelMessageEvent {
+    pub channel: String,
+    pub sender_player_id: entity::PlayerId,
+    pub body: Bytes,
+    // 購読者全員で共有する通知。
+    notification: OnceCell<Arc<SharedMessage>>,
+}
+
+impl OutputChannelMessageEvent {
+    pub fn new(channel: String, sender_player_id: entity::PlayerId, body: Bytes) -> Self {
+        Self {
+            channel,
+            sender_player_id,
+            body,
+            notification: OnceCell::new(),
+        }
+    }
+
+    pub fn notification(&self) -> Arc<SharedMessage> {
+        self.notification
+            .get_or_init(|| {
+                Arc::new(SharedMessage::new(protobuf::app::ServerMessage {
+                    data: Some(
+                        protobuf::app::server_message::Data::ChannelMessageNotification(
+                            protobuf::app::ChannelMessageNotification {
+                                channel: self.channel.clone(),
+                                sender_id: self.sender_player_id.clone(),
+                                body: self.body.to_vec(),
+                            },
+                        ),
+                    ),
+                }))
+            })
+            .clone()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputServerAnnouncementEvent {
+    pub message: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputRoomClosedEvent {
+    pub room_id: entity::RoomId,
+    pub reason: String,
+}
+
+/// 管理者による切断。Player actorは理由を通知してから終了する。
+#[derive(Clone, Debug)]
+pub struct OutputKickEvent {
+    pub reason: String,
 }
 
 #[derive(Clone, Debug)]
@@ -57,4 +315,21 @@ pub enum OutputEvent {
     Join(Result<Arc<OutputJoinEvent>>),
     Leave(Result<Arc<OutputLeaveEvent>>),
     Message(Arc<OutputMessageEvent>),
+    Reserve(Result<Arc<OutputReserveEvent>>),
+    ReservationExpired(Arc<OutputReservationExpiredEvent>),
+    PartyInvite(PartyResult<Arc<OutputPartyInviteEvent>>),
+    PartyJoin(PartyResult<Arc<OutputPartyJoinEvent>>),
+    PartyLeave(Arc<OutputPartyLeaveEvent>),
+    PartyMessage(Arc<OutputPartyMessageEvent>),
+    // 移動の結果。移動を指示したリーダーにだけ届く。
+    PartyJoinRoom(PartyResult<Arc<OutputPartyJoinRoomEvent>>),
+    // 予約済みの移動先のRoomへJoinする。前のRoomからは移動が確定するまで抜けない。
+    PartyMoveRoom(Arc<OutputPartyJoinRoomEvent>),
+    PartyLeaveRoom(Arc<OutputPartyLeaveRoomEvent>),
+    DirectMessage(Arc<OutputDirectMessageEvent>),
+    Presence(Arc<entity::Presence>),
+    ChannelMessage(Arc<OutputChannelMessageEvent>),
+    ServerAnnouncement(Arc<OutputServerAnnouncementEvent>),
+    RoomClosed(Arc<OutputRoomClosedEvent>),
+    Kick(Arc<OutputKickEvent>),
 }
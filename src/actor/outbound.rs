@@ -0,0 +1,404 @@
+//! Player actorからトランスポートへの送信キュー。
+//! クライアントが受信しきれない場合にメモリを使い切らないよう、溜められるメッセージの数に上限を設ける。
+//! 上限に達した場合は`config::OverflowPolicy`に従って、切断するかメッセージを捨てるか置き換える。
+//! ルーム内のブロードキャストのように同じ内容を複数のプレイヤーに送る場合は、
+//! トランスポートがエンコードしたフレームを`SharedMessage`に置いて共有し、受信者ごとのエンコードを省く。
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use once_cell::sync::OnceCell;
+use prost::Message as _;
+use thiserror::Error;
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::config::{self, OverflowPolicy};
+use crate::metrics::METRICS;
+use crate::protobuf::app::{server_message, Delivery, ServerMessage};
+
+/// 共有するメッセージに置いておけるエンコード済みのフレームの数。
+/// どの形式のフレームをどこに置くかはトランスポート側で決める。
+pub const NUM_FRAME_SLOTS: usize = 9;
+
+/// 複数のプレイヤーに同じ内容を送るメッセージ。
+/// トランスポートは形式ごとに一度だけエンコードしたフレームをここに置いて、全ての受信者で同じバッファを共有する。
+#[derive(Debug)]
+pub struct SharedMessage {
+    message: ServerMessage,
+    encoded_len: usize,
+    frames: [OnceCell<Bytes>; NUM_FRAME_SLOTS],
+}
+
+impl SharedMessage {
+    pub fn new(message: ServerMessage) -> Self {
+        Self {
+            encoded_len: message.encoded_len(),
+            message,
+            frames: Default::default(),
+        }
+    }
+
+    pub fn message(&self) -> &ServerMessage {
+        &self.message
+    }
+
+    /// protobufでエンコードした場合の大きさ。圧縮するかどうかの判断に使う。
+    pub fn encoded_len(&self) -> usize {
+        self.encoded_len
+    }
+
+    /// `slot`に置いたフレームを返す。まだ無い場合は`encode`で作って置く。
+    pub fn frame<E>(
+        &self,
+        slot: usize,
+        encode: impl FnOnce(&ServerMessage) -> Result<Bytes, E>,
+    ) -> Result<Bytes, E> {
+        self.frames[slot]
+            .get_or_try_init(|| encode(&self.message))
+            .cloned()
+    }
+}
+
+impl PartialEq for SharedMessage {
+    fn eq(&self, other: &Self) -> bool {
+        self.message == other.message
+    }
+}
+
+/// 送信キューに積むメッセージ。
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutputMessage {
+    // このプレイヤーだけに送るメッセージ。
+    Owned(ServerMessage),
+    // 他のプレイヤーと共有するエンコード済みのメッセージ。
+    Shared(Arc<SharedMessage>),
+}
+
+impl OutputMessage {
+    pub fn message(&self) -> &ServerMessage {
+        match self {
+            OutputMessage::Owned(message) => message,
+            OutputMessage::Shared(shared) => shared.message(),
+        }
+    }
+
+    /// 共有されている場合は複製する。エンコード済みのフレームを使えないトランスポート向け。
+    pub fn into_message(self) -> ServerMessage {
+        match self {
+            OutputMessage::Owned(message) => message,
+            OutputMessage::Shared(shared) => shared.message().clone(),
+        }
+    }
+}
+
+impl From<ServerMessage> for OutputMessage {
+    fn from(message: ServerMessage) -> Self {
+        OutputMessage::Owned(message)
+    }
+}
+
+impl From<Arc<SharedMessage>> for OutputMessage {
+    fn from(shared: Arc<SharedMessage>) -> Self {
+        OutputMessage::Shared(shared)
+    }
+}
+
+/// 受信側がDropしたか、キューが溢れて切断したために送れなかった。
+#[derive(Error, Debug)]
+#[error("output queue is closed")]
+pub struct OutputClosed;
+
+#[derive(Debug)]
+struct State {
+    queue: VecDeque<OutputMessage>,
+    // 受信側がDropしたか、キューが溢れて切断した。
+    closed: bool,
+    sender_dropped: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    overflow_policy: OverflowPolicy,
+    // 受信側に新しいメッセージや送信側のDropを知らせる。
+    message_notify: Notify,
+    // 送信側に切断を知らせる。
+    closed_notify: Notify,
+}
+
+pub fn output_channel(config: &config::Outbound) -> (OutputSender, OutputReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            closed: false,
+            sender_dropped: false,
+        }),
+        capacity: config.queue_size.max(1),
+        overflow_policy: config.overflow_policy,
+        message_notify: Notify::new(),
+        closed_notify: Notify::new(),
+    });
+    (
+        OutputSender {
+            shared: shared.clone(),
+        },
+        OutputReceiver { shared },
+    )
+}
+
+// 溢れた時に捨てても良いメッセージ。
+fn is_unreliable(message: &OutputMessage) -> bool {
+    matches!(
+        &message.message().data,
+        Some(server_message::Data::MessageNotification(notification))
+            if notification.delivery == Delivery::Unreliable as i32
+    )
+}
+
+#[derive(PartialEq, Eq)]
+enum CoalesceKey<'a> {
+    Message {
+        room_id: &'a str,
+        sender_id: &'a str,
+    },
+    Presence {
+        player_id: &'a str,
+    },
+}
+
+// 同じキーのメッセージは、古いものを捨てて新しいものだけを送れば良い。
+fn coalesce_key(message: &OutputMessage) -> Option<CoalesceKey<'_>> {
+    match &message.message().data {
+        Some(server_message::Data::MessageNotification(notification))
+            if notification.delivery == Delivery::Unreliable as i32 =>
+        {
+            Some(CoalesceKey::Message {
+                room_id: &notification.room_id,
+                sender_id: &notification.sender_id,
+            })
+        }
+        Some(server_message::Data::PresenceNotification(notification)) => {
+            Some(CoalesceKey::Presence {
+                player_id: &notification.presence.as_ref()?.player_id,
+            })
+        }
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub struct OutputSender {
+    shared: Arc<Shared>,
+}
+
+impl OutputSender {
+    /// キューにメッセージを積む。受信側がDropしているか、キューが溢れて切断した場合はエラーを返す。
+    pub fn send(&self, message: impl Into<OutputMessage>) -> Result<(), OutputClosed> {
+        let message = message.into();
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(OutputClosed);
+        }
+
+        if state.queue.len() >= self.shared.capacity {
+            let accepted = match self.shared.overflow_policy {
+                OverflowPolicy::Disconnect => false,
+                OverflowPolicy::DropOldestUnreliable => {
+                    if let Some(i) = state.queue.iter().position(is_unreliable) {
+                        state.queue.remove(i);
+                        METRICS.inc_dropped_messages();
+                        true
+                    } else if is_unreliable(&message) {
+                        METRICS.inc_dropped_messages();
+                        return Ok(());
+                    } else {
+                        false
+                    }
+                }
+                OverflowPolicy::Coalesce => {
+                    let i = coalesce_key(&message).and_then(|key| {
+                        state
+                            .queue
+                            .iter()
+                            .position(|queued| coalesce_key(queued).as_ref() == Some(&key))
+                    });
+                    if let Some(i) = i {
+                        // 他のメッセージとの順序が入れ替わらないように、末尾に積み直す。
+                        state.queue.remove(i);
+                        METRICS.inc_coalesced_messages();
+                        true
+                    } else {
+                        false
+                    }
+                }
+            };
+            if !accepted {
+                warn!(
+                    "Output queue overflowed. Disconnect slow consumer. capacity={}",
+                    self.shared.capacity
+                );
+                METRICS.inc_slow_consumer_disconnects();
+                state.closed = true;
+                state.queue.clear();
+                drop(state);
+                self.shared.message_notify.notify_one();
+                self.shared.closed_notify.notify_one();
+                return Err(OutputClosed);
+            }
+        }
+
+        state.queue.push_back(message);
+        drop(state);
+        self.shared.message_notify.notify_one();
+        Ok(())
+    }
+
+    /// 受信側がDropするか、キューが溢れて切断するまで待つ。
+    pub async fn closed(&self) {
+        loop {
+            if self.shared.state.lock().unwrap().closed {
+                return;
+            }
+            self.shared.closed_notify.notified().await;
+        }
+    }
+}
+
+impl Drop for OutputSender {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().sender_dropped = true;
+        self.shared.message_notify.notify_one();
+    }
+}
+
+#[derive(Debug)]
+pub struct OutputReceiver {
+    shared: Arc<Shared>,
+}
+
+impl OutputReceiver {
+    /// 送信側がDropした場合は、積まれているメッセージを全て受け取った後にNoneを返す。
+    /// キューが溢れて切断した場合は、積まれていたメッセージは捨てられてすぐにNoneを返す。
+    pub async fn recv(&mut self) -> Option<OutputMessage> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(message) = state.queue.pop_front() {
+                    return Some(message);
+                }
+                if state.closed || state.sender_dropped {
+                    return None;
+                }
+            }
+            self.shared.message_notify.notified().await;
+        }
+    }
+}
+
+impl Drop for OutputReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        state.queue.clear();
+        drop(state);
+        self.shared.closed_notify.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::app;
+
+    fn message_notification(sender_id: &str, delivery: Delivery) -> ServerMessage {
+        ServerMessage {
+            data: Some(server_message::Data::MessageNotification(
+                app::MessageNotification {
+                    sender_id: sender_id.to_string(),
+                    room_id: "room".to_string(),
+                    body: Vec::new(),
+                    delivery: delivery as i32,
+                },
+            )),
+        }
+    }
+
+    fn channel(overflow_policy: OverflowPolicy) -> (OutputSender, OutputReceiver) {
+        output_channel(&config::Outbound {
+            queue_size: 2,
+            overflow_policy,
+        })
+    }
+
+    #[tokio::test]
+    async fn disconnect_slow_consumer() {
+        let (tx, mut rx) = channel(OverflowPolicy::Disconnect);
+        let before = METRICS.slow_consumer_disconnects();
+        tx.send(message_notification("p1", Delivery::Reliable))
+            .unwrap();
+        tx.send(message_notification("p1", Delivery::Unreliable))
+            .unwrap();
+        assert!(tx
+            .send(message_notification("p1", Delivery::Reliable))
+            .is_err());
+        tx.closed().await;
+        assert!(rx.recv().await.is_none());
+        assert!(METRICS.slow_consumer_disconnects() > before);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_unreliable_message() {
+        let (tx, mut rx) = channel(OverflowPolicy::DropOldestUnreliable);
+        tx.send(message_notification("p1", Delivery::Unreliable))
+            .unwrap();
+        tx.send(message_notification("p2", Delivery::Reliable))
+            .unwrap();
+        tx.send(message_notification("p3", Delivery::Reliable))
+            .unwrap();
+        assert_eq!(
+            Some(message_notification("p2", Delivery::Reliable).into()),
+            rx.recv().await
+        );
+        assert_eq!(
+            Some(message_notification("p3", Delivery::Reliable).into()),
+            rx.recv().await
+        );
+
+        // 捨てられる信頼性の無いメッセージが無い場合は切断する。
+        tx.send(message_notification("p4", Delivery::Reliable))
+            .unwrap();
+        tx.send(message_notification("p5", Delivery::Reliable))
+            .unwrap();
+        tx.send(message_notification("p6", Delivery::Unreliable))
+            .unwrap();
+        assert!(tx
+            .send(message_notification("p7", Delivery::Reliable))
+            .is_err());
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn coalesce_messages_from_same_sender() {
+        let (tx, mut rx) = channel(OverflowPolicy::Coalesce);
+        tx.send(message_notification("p1", Delivery::Unreliable))
+            .unwrap();
+        tx.send(message_notification("p2", Delivery::Reliable))
+            .unwrap();
+        let mut latest = message_notification("p1", Delivery::Unreliable);
+        if let Some(server_message::Data::MessageNotification(notification)) = &mut latest.data {
+            notification.body = vec![1];
+        }
+        tx.send(latest.clone()).unwrap();
+        assert_eq!(
+            Some(message_notification("p2", Delivery::Reliable).into()),
+            rx.recv().await
+        );
+        assert_eq!(Some(latest.into()), rx.recv().await);
+
+        drop(tx);
+        assert!(rx.recv().await.is_none());
+    }
+}
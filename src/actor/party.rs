@@ -0,0 +1,581 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use tracing::{debug, error, info_span, warn, Instrument};
+use uuid::Uuid;
+
+use super::event::*;
+use super::registry::*;
+use super::room::*;
+use crate::entity;
+
+type PartyResult<T> = std::result::Result<T, entity::PartyError>;
+
+// Party actorへの入力キューの大きさ。一杯の場合は送信側が空くまで待つ。
+const PARTY_CHANNEL_CAPACITY: usize = 256;
+
+impl Registries {
+    pub fn get_party_channel(&self, id: &entity::PartyId) -> Option<mpsc::Sender<PartyInputEvent>> {
+        self.parties.get_with(id, |tx| tx.clone())
+    }
+
+    /// `leader`をリーダーとするパーティを作成する。
+    pub fn create_party(
+        self: &Arc<Self>,
+        leader: entity::Player<OutputEvent>,
+    ) -> (entity::PartyId, mpsc::Sender<PartyInputEvent>) {
+        let id = Uuid::new_v4().to_string();
+        let (tx, rx) = mpsc::channel(PARTY_CHANNEL_CAPACITY);
+        {
+            // moveされるのでここでcloneしておく。
+            let id = id.clone();
+            let registries = self.clone();
+            // パーティはリーダーが抜けても残るので、接続のスパンとは切り離す。
+            let span = info_span!(parent: None, "party", party_id = %id);
+            tokio::spawn(
+                async move {
+                    let mut party_runner =
+                        Party::new(entity::Party::new(id, leader), rx, registries);
+                    party_runner.run().await
+                }
+                .instrument(span),
+            );
+        }
+        self.parties.insert(id.clone(), tx.clone());
+        (id, tx)
+    }
+
+    fn remove_party_from_channels(&self, id: &entity::PartyId) {
+        self.parties.remove(id);
+    }
+}
+
+// パーティでのRoomの移動。メンバー全員のJoinの結果が揃うまで、誰も前のRoomからは抜けない。
+struct RoomMove {
+    event: Arc<OutputPartyJoinRoomEvent>,
+    room_tx: mpsc::Sender<InputEvent>,
+    previous_room_id: Option<entity::RoomId>,
+    // 席を予約したメンバー。
+    reserved_ids: Vec<entity::PlayerId>,
+    // Joinの結果をまだ受け取っていないメンバー。
+    waiting_ids: HashSet<entity::PlayerId>,
+    joined_ids: Vec<entity::PlayerId>,
+    // 最初に失敗したメンバーのエラー。
+    error: Option<entity::PartyError>,
+}
+
+pub struct Party {
+    party: entity::Party<OutputEvent>,
+    party_rx: mpsc::Receiver<PartyInputEvent>,
+    registries: Arc<Registries>,
+    room_move: Option<RoomMove>,
+}
+
+impl Party {
+    pub fn new(
+        party: entity::Party<OutputEvent>,
+        party_rx: mpsc::Receiver<PartyInputEvent>,
+        registries: Arc<Registries>,
+    ) -> Self {
+        Self {
+            party,
+            party_rx,
+            registries,
+            room_move: None,
+        }
+    }
+
+    pub async fn run(&mut self) {
+        debug!("Start Party");
+        while let Some(event) = self.party_rx.recv().await {
+            match event {
+                PartyInputEvent::Invite(event) => {
+                    debug!(player_id = %event.inviter_id, "Receive InputPartyInviteEvent");
+                    self.handle_invite_event(*event);
+                }
+                PartyInputEvent::Join(event) => {
+                    debug!(player_id = %event.player.id, "Receive InputPartyJoinEvent");
+                    self.handle_join_event(*event);
+                }
+                PartyInputEvent::Leave(event) => {
+                    debug!(player_id = %event.player_id, "Receive InputPartyLeaveEvent");
+                    self.handle_leave_event(*event);
+                    self.finish_room_move().await;
+                }
+                PartyInputEvent::Message(event) => {
+                    debug!(player_id = %event.sender_player_id, "Receive InputPartyMessageEvent");
+                    self.handle_message_event(*event);
+                }
+                PartyInputEvent::JoinRoom(event) => {
+                    debug!(player_id = %event.requester_id, "Receive InputPartyJoinRoomEvent");
+                    self.handle_join_room_event(*event).await;
+                }
+                PartyInputEvent::JoinRoomResult(event) => {
+                    debug!(player_id = %event.player_id, "Receive InputPartyJoinRoomResultEvent");
+                    self.handle_join_room_result_event(*event).await;
+                }
+            }
+
+            if self.party.num_members() == 0 {
+                debug!("Stop Party");
+                self.registries.remove_party_from_channels(&self.party.id);
+                self.close().await;
+                break;
+            }
+        }
+    }
+
+    /// 削除前に届いていたJoinイベントにはエラーを返してから終了する。
+    async fn close(&mut self) {
+        self.party_rx.close();
+        while let Some(event) = self.party_rx.recv().await {
+            if let PartyInputEvent::Join(mut event) = event {
+                let err = entity::PartyError::PartyNotFound(
+                    self.party.id.clone(),
+                    event.player.id.clone(),
+                );
+                if event.player.send(OutputEvent::PartyJoin(Err(err))).is_err() {
+                    warn!("Join party failed and player disconnected");
+                }
+            }
+        }
+    }
+
+    fn handle_invite_event(&mut self, event: InputPartyInviteEvent) {
+        let result = match self.registries.get_player(&event.player_id) {
+            Some(player) => self
+                .party
+                .invite(&event.inviter_id, event.player_id.clone())
+                .map(|_| player),
+            None => Err(entity::PartyError::PlayerNotFound(
+                self.party.id.clone(),
+                event.player_id.clone(),
+            )),
+        };
+
+        match result {
+            Ok(mut player) => {
+                let output_event = OutputEvent::PartyInvite(Ok(Arc::new(OutputPartyInviteEvent {
+                    party_id: self.party.id.clone(),
+                    inviter_id: event.inviter_id.clone(),
+                    player_id: event.player_id,
+                })));
+                if player.send(output_event.clone()).is_err() {
+                    // 招待自体は有効なので、ここではログだけ出しておく。
+                    warn!("Invited player disconnected");
+                }
+                self.send(&event.inviter_id, output_event);
+            }
+            Err(err) => {
+                self.send(&event.inviter_id, OutputEvent::PartyInvite(Err(err)));
+            }
+        }
+    }
+
+    fn handle_join_event(&mut self, mut event: InputPartyJoinEvent) {
+        match self.party.add_member(event.player.clone()) {
+            Ok(_) => {
+                let output_event = OutputEvent::PartyJoin(Ok(Arc::new(OutputPartyJoinEvent {
+                    party_id: self.party.id.clone(),
+                    player_id: event.player.id.clone(),
+                    leader_id: self.party.leader_id.clone(),
+                    member_ids: self.party.member_ids(),
+                })));
+                self.party.broadcast(output_event);
+            }
+            Err(err) => {
+                if event.player.send(OutputEvent::PartyJoin(Err(err))).is_err() {
+                    // Joinに失敗かつプレイヤーが切断した場合なので特にハンドリングは不要。
+                    warn!("Join party failed and player disconnected");
+                }
+            }
+        }
+    }
+
+    fn handle_leave_event(&mut self, event: InputPartyLeaveEvent) {
+        if !self.party.is_member(&event.player_id) {
+            // 二重Leaveかパーティに所属していなかった。
+            // このように呼び出されない想定なのでここでは何もしない。
+            warn!("Double leave or not joining the party");
+            return;
+        }
+
+        // 抜けるプレイヤーにもレスポンスとして届くように、削除前に送信する。
+        let leaver = self
+            .party
+            .members
+            .iter()
+            .find(|member| member.id == event.player_id)
+            .cloned();
+        let ok = self.party.remove_member(&event.player_id);
+        debug_assert!(ok);
+        // 抜けたメンバーのJoinの結果は届かないので待たない。
+        if let Some(room_move) = &mut self.room_move {
+            room_move.waiting_ids.remove(&event.player_id);
+        }
+        let output_event = OutputEvent::PartyLeave(Arc::new(OutputPartyLeaveEvent {
+            party_id: self.party.id.clone(),
+            player_id: event.player_id,
+            leader_id: self.party.leader_id.clone(),
+        }));
+        self.party.broadcast(output_event.clone());
+        if let Some(mut leaver) = leaver {
+            if leaver.send(output_event).is_err() {
+                debug!("Player disconnected before leave party processing");
+            }
+        }
+    }
+
+    fn handle_message_event(&mut self, event: InputPartyMessageEvent) {
+        let output_event = OutputEvent::PartyMessage(Arc::new(OutputPartyMessageEvent {
+            party_id: self.party.id.clone(),
+            sender_player_id: event.sender_player_id,
+            body: event.body,
+        }));
+        self.party.broadcast(output_event);
+    }
+
+    async fn handle_join_room_event(&mut self, event: InputPartyJoinRoomEvent) {
+        if self.room_move.is_some() {
+            let err =
+                entity::PartyError::MovingRoom(self.party.id.clone(), event.requester_id.clone());
+            self.send(&event.requester_id, OutputEvent::PartyJoinRoom(Err(err)));
+            return;
+        }
+
+        match self.reserve_room(&event).await {
+            Ok((output_event, room_tx)) => {
+                let mut room_move = RoomMove {
+                    event: output_event.clone(),
+                    room_tx,
+                    previous_room_id: self.party.room_id.clone(),
+                    reserved_ids: self.party.member_ids(),
+                    waiting_ids: HashSet::new(),
+                    joined_ids: Vec::new(),
+                    error: None,
+                };
+                let output_event = OutputEvent::PartyMoveRoom(output_event);
+                // 移動を通知できなかったメンバーはJoinしないので、移動全体を取り消す。
+                for member in self.party.members.iter_mut() {
+                    match member.send(output_event.clone()) {
+                        Ok(_) => {
+                            room_move.waiting_ids.insert(member.id.clone());
+                        }
+                        Err(err) => {
+                            warn!(
+                                "failed to send message. player_id={}, error={}",
+                                member.id, err
+                            );
+                            room_move.error.get_or_insert_with(|| {
+                                entity::PartyError::PlayerNotFound(
+                                    self.party.id.clone(),
+                                    member.id.clone(),
+                                )
+                            });
+                        }
+                    }
+                }
+                self.room_move = Some(room_move);
+                self.finish_room_move().await;
+            }
+            Err(err) => {
+                self.send(&event.requester_id, OutputEvent::PartyJoinRoom(Err(err)));
+            }
+        }
+    }
+
+    async fn handle_join_room_result_event(&mut self, event: InputPartyJoinRoomResultEvent) {
+        let room_move = match &mut self.room_move {
+            Some(room_move) if room_move.event.room_id == event.room_id => room_move,
+            _ => {
+                // 取り消した移動の結果が遅れて届いた。
+                debug!("Room move has already finished. room_id={}", event.room_id);
+                return;
+            }
+        };
+        if !room_move.waiting_ids.remove(&event.player_id) {
+            debug!("Unexpected join room result. player_id={}", event.player_id);
+            return;
+        }
+        match event.result {
+            Ok(()) => room_move.joined_ids.push(event.player_id),
+            Err(err) => {
+                warn!(
+                    "Party member failed to join room. player_id={}, error={}",
+                    event.player_id, err
+                );
+                room_move.error.get_or_insert(err);
+            }
+        }
+        self.finish_room_move().await;
+    }
+
+    /// 全員のJoinの結果が揃ったら移動を確定する。
+    /// 1人でも失敗していれば、Joinできたメンバーも移動先から抜けて、全員が前のRoomに残る。
+    async fn finish_room_move(&mut self) {
+        match &self.room_move {
+            Some(room_move) if room_move.waiting_ids.is_empty() => {}
+            _ => return,
+        }
+        let room_move = match self.room_move.take() {
+            Some(room_move) => room_move,
+            None => return,
+        };
+
+        let requester_id = room_move.event.requester_id.clone();
+        match room_move.error {
+            None => {
+                self.party.room_id = Some(room_move.event.room_id.clone());
+                if let Some(previous_room_id) = room_move.previous_room_id {
+                    self.party.broadcast(OutputEvent::PartyLeaveRoom(Arc::new(
+                        OutputPartyLeaveRoomEvent {
+                            party_id: self.party.id.clone(),
+                            room_id: previous_room_id,
+                        },
+                    )));
+                }
+                self.send(
+                    &requester_id,
+                    OutputEvent::PartyJoinRoom(Ok(room_move.event)),
+                );
+            }
+            Some(err) => {
+                let unused: Vec<entity::PlayerId> = room_move
+                    .reserved_ids
+                    .into_iter()
+                    .filter(|player_id| !room_move.joined_ids.contains(player_id))
+                    .collect();
+                if !unused.is_empty() {
+                    self.cancel_reservations(&room_move.room_tx, unused).await;
+                }
+                let output_event =
+                    OutputEvent::PartyLeaveRoom(Arc::new(OutputPartyLeaveRoomEvent {
+                        party_id: self.party.id.clone(),
+                        room_id: room_move.event.room_id.clone(),
+                    }));
+                for player_id in &room_move.joined_ids {
+                    self.send(player_id, output_event.clone());
+                }
+                self.send(&requester_id, OutputEvent::PartyJoinRoom(Err(err)));
+            }
+        }
+    }
+
+    /// メンバー全員分の席をまとめて予約する。
+    /// 予約はRoom actor内で全員分成功するか全員分失敗するかのどちらかなので、
+    /// 一部のメンバーだけが別のRoomに移動することはない。
+    async fn reserve_room(
+        &mut self,
+        event: &InputPartyJoinRoomEvent,
+    ) -> PartyResult<(Arc<OutputPartyJoinRoomEvent>, mpsc::Sender<InputEvent>)> {
+        self.party.check_leader(&event.requester_id)?;
+
+        let member_ids = self.party.member_ids();
+        let num_seats = member_ids.len() as u32;
+        let (room_id, room_tx) = match &event.room_id {
+            Some(room_id) => {
+                let room_tx = self.registries.claim_room_seats(
+                    room_id,
+                    event.room_config.clone(),
+                    event.room_properties.clone(),
+                    num_seats,
+                );
+                (room_id.clone(), room_tx)
+            }
+            None => self.registries.claim_random_room_seats(
+                event.room_config.clone(),
+                event.room_properties.clone(),
+                num_seats,
+            ),
+        };
+
+        // 予約の結果はこのパーティ専用のチャネルで受け取る。
+        let (reply_tx, mut reply_rx) = mpsc::channel(1);
+        let result = room_tx
+            .send(InputEvent::Reserve(Box::new(InputReserveEvent {
+                requester_id: self.requester_id(),
+                reply_tx,
+                max_reservations: num_seats,
+                player_ids: member_ids,
+                room_config: event.room_config.clone(),
+                timeout: DEFAULT_RESERVATION_TIMEOUT,
+            })))
+            .await;
+        if result.is_err() {
+            // 席を確保済みなのでRoomはDropしないはず。
+            error!("Room was removed during Reserve processing");
+            return Err(entity::PartyError::RoomNotFound(room_id));
+        }
+
+        match reply_rx.recv().await {
+            Some(OutputEvent::Reserve(Ok(_))) => Ok((
+                Arc::new(OutputPartyJoinRoomEvent {
+                    party_id: self.party.id.clone(),
+                    requester_id: event.requester_id.clone(),
+                    room_id,
+                    room_config: event.room_config.clone(),
+                }),
+                room_tx,
+            )),
+            Some(OutputEvent::Reserve(Err(err))) => Err(err.into()),
+            _ => Err(entity::PartyError::RoomNotFound(room_id)),
+        }
+    }
+
+    async fn cancel_reservations(
+        &self,
+        room_tx: &mpsc::Sender<InputEvent>,
+        player_ids: Vec<entity::PlayerId>,
+    ) {
+        let result = room_tx
+            .send(InputEvent::CancelReservation(Box::new(
+                InputCancelReservationEvent {
+                    requester_id: self.requester_id(),
+                    player_ids,
+                },
+            )))
+            .await;
+        if result.is_err() {
+            // Roomが閉じられた場合は予約も残っていない。
+            debug!("Room has been dropped before canceling reservations");
+        }
+    }
+
+    fn requester_id(&self) -> entity::RequesterId {
+        entity::RequesterId::Party(self.party.id.clone())
+    }
+
+    fn send(&mut self, player_id: &entity::PlayerId, event: OutputEvent) {
+        if let Some(member) = self
+            .party
+            .members
+            .iter_mut()
+            .find(|member| &member.id == player_id)
+        {
+            if member.send(event).is_err() {
+                // 切断したプレイヤーはPlayer actorからLeaveが届くので、ここではログだけ出しておく。
+                warn!("failed to send message. player_id={}", player_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn join_room_result(
+        player_id: &str,
+        room_id: &str,
+        result: PartyResult<()>,
+    ) -> PartyInputEvent {
+        PartyInputEvent::JoinRoomResult(Box::new(InputPartyJoinRoomResultEvent {
+            player_id: player_id.to_string(),
+            room_id: room_id.to_string(),
+            result,
+        }))
+    }
+
+    async fn recv_move(rx: &mut mpsc::Receiver<OutputEvent>) -> Arc<OutputPartyJoinRoomEvent> {
+        match rx.recv().await {
+            Some(OutputEvent::PartyMoveRoom(event)) => event,
+            event => panic!("Unexpected event. {:?}", event),
+        }
+    }
+
+    async fn recv_leave_room(rx: &mut mpsc::Receiver<OutputEvent>) -> entity::RoomId {
+        match rx.recv().await {
+            Some(OutputEvent::PartyLeaveRoom(event)) => event.room_id.clone(),
+            event => panic!("Unexpected event. {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn move_room_together_or_roll_back() {
+        let registries = Arc::new(Registries::new());
+        let (leader_tx, mut leader_rx) = mpsc::channel(16);
+        let (member_tx, mut member_rx) = mpsc::channel(16);
+        let mut party = entity::Party::new(
+            "party".to_string(),
+            entity::Player::new("leader".to_string(), leader_tx),
+        );
+        party
+            .members
+            .push(entity::Player::new("member".to_string(), member_tx));
+        let (party_tx, party_rx) = mpsc::channel(16);
+        tokio::spawn(async move { Party::new(party, party_rx, registries).run().await });
+        let join_room = |room_id: &str| {
+            PartyInputEvent::JoinRoom(Box::new(InputPartyJoinRoomEvent {
+                requester_id: "leader".to_string(),
+                room_id: Some(room_id.to_string()),
+                room_config: entity::RoomConfig::default(),
+                room_properties: entity::RoomProperties::new(),
+            }))
+        };
+
+        // 全員がJoinできれば移動を確定する。前のRoomが無いので抜けるRoomも無い。
+        party_tx.send(join_room("r1")).await.unwrap();
+        assert_eq!("r1", recv_move(&mut leader_rx).await.room_id);
+        assert_eq!("r1", recv_move(&mut member_rx).await.room_id);
+        for player_id in ["leader", "member"] {
+            party_tx
+                .send(join_room_result(player_id, "r1", Ok(())))
+                .await
+                .unwrap();
+        }
+        match leader_rx.recv().await {
+            Some(OutputEvent::PartyJoinRoom(Ok(event))) => assert_eq!("r1", event.room_id),
+            event => panic!("Unexpected event. {:?}", event),
+        }
+
+        // 結果が揃うまでは次の移動を受け付けない。
+        party_tx.send(join_room("r2")).await.unwrap();
+        recv_move(&mut leader_rx).await;
+        recv_move(&mut member_rx).await;
+        party_tx.send(join_room("r3")).await.unwrap();
+        match leader_rx.recv().await {
+            Some(OutputEvent::PartyJoinRoom(Err(entity::PartyError::MovingRoom(..)))) => {}
+            event => panic!("Unexpected event. {:?}", event),
+        }
+
+        // 1人でも失敗すると、Joinできたメンバーは移動先から抜けて全員が前のRoomに残る。
+        party_tx
+            .send(join_room_result("leader", "r2", Ok(())))
+            .await
+            .unwrap();
+        party_tx
+            .send(join_room_result(
+                "member",
+                "r2",
+                Err(entity::PartyError::RoomNotFound("r2".to_string())),
+            ))
+            .await
+            .unwrap();
+        assert_eq!("r2", recv_leave_room(&mut leader_rx).await);
+        match leader_rx.recv().await {
+            Some(OutputEvent::PartyJoinRoom(Err(entity::PartyError::RoomNotFound(room_id)))) => {
+                assert_eq!("r2", room_id)
+            }
+            event => panic!("Unexpected event. {:?}", event),
+        }
+        assert!(member_rx.try_recv().is_err());
+
+        // 確定した移動では、全員が前のRoomを抜ける。
+        party_tx.send(join_room("r3")).await.unwrap();
+        recv_move(&mut leader_rx).await;
+        recv_move(&mut member_rx).await;
+        for player_id in ["leader", "member"] {
+            party_tx
+                .send(join_room_result(player_id, "r3", Ok(())))
+                .await
+                .unwrap();
+        }
+        assert_eq!("r1", recv_leave_room(&mut leader_rx).await);
+        assert_eq!("r1", recv_leave_room(&mut member_rx).await);
+        match leader_rx.recv().await {
+            Some(OutputEvent::PartyJoinRoom(Ok(event))) => assert_eq!("r3", event.room_id),
+            event => panic!("Unexpected event. {:?}", event),
+        }
+    }
+}
@@ -1,87 +1,320 @@
 use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use log::{debug, error};
-use once_cell::sync::Lazy;
-
-use tokio::sync::{mpsc, RwLock};
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tracing::{debug, error, warn, Instrument, Span};
 
+use super::channel::*;
 use super::event::*;
+use super::outbound::*;
+use super::registry::*;
 use super::room::*;
 use crate::config;
 use crate::entity;
+use crate::logging;
+use crate::metrics::METRICS;
+use crate::network_protocol::server::Protocol;
 use crate::protobuf;
 
-static PLAYERS: Lazy<RwLock<HashSet<entity::PlayerId>>> = Lazy::new(|| RwLock::new(HashSet::new()));
+/// 管理者によるプレイヤーの切断に失敗した理由。
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum KickError {
+    #[error("player not found")]
+    NotFound,
+    // 送信キューが溢れたプレイヤーは、Player actorが次のイベントを処理する時に切断される。
+    #[error("player event queue is full")]
+    QueueFull,
+}
+
+pub(super) struct PlayerEntry {
+    player: entity::Player<OutputEvent>,
+    room_ids: Vec<entity::RoomId>,
+    connection: ConnectionInfo,
+    logged_in_at: SystemTime,
+}
+
+impl PlayerEntry {
+    pub(super) fn queue_depth(&self) -> usize {
+        let sender = &self.player.sender;
+        sender.max_capacity() - sender.capacity()
+    }
+}
+
+impl Registries {
+    fn register_player(
+        &self,
+        player: entity::Player<OutputEvent>,
+        connection: &ConnectionInfo,
+    ) -> bool {
+        self.players.insert_if_absent(
+            player.id.clone(),
+            PlayerEntry {
+                player,
+                room_ids: Vec::new(),
+                connection: connection.clone(),
+                logged_in_at: SystemTime::now(),
+            },
+        )
+    }
+
+    /// 参加中のRoomの一覧は、Player actorがJoinやLeaveを処理した時点のもの。
+    pub fn get_player_info(&self, id: &entity::PlayerId) -> Option<PlayerInfo> {
+        self.players.get_with(id, PlayerInfo::new)
+    }
+
+    /// ログイン中の全てのプレイヤーの情報。IDの順に並べる。
+    pub fn list_players(&self) -> Vec<PlayerInfo> {
+        let mut players = self.players.fold(Vec::new(), |mut players, _, entry| {
+            players.push(PlayerInfo::new(entry));
+            players
+        });
+        players.sort_by(|a, b| a.id.cmp(&b.id));
+        players
+    }
+
+    /// Roomごとの参加中のプレイヤー。各プレイヤーのIDの順に並ぶ。
+    pub(super) fn player_ids_by_room(&self) -> HashMap<entity::RoomId, Vec<entity::PlayerId>> {
+        let mut rooms = self.players.fold(HashMap::new(), |mut rooms, id, entry| {
+            for room_id in &entry.room_ids {
+                rooms
+                    .entry(room_id.clone())
+                    .or_insert_with(Vec::new)
+                    .push(id.clone());
+            }
+            rooms
+        });
+        for player_ids in rooms.values_mut() {
+            player_ids.sort();
+        }
+        rooms
+    }
+
+    /// 管理者がプレイヤーを切断する。
+    /// 管理APIの呼び出しを止めないよう、Player actorのキューが一杯の場合は待たずにエラーを返す。
+    pub fn kick_player(&self, id: &entity::PlayerId, reason: String) -> Result<(), KickError> {
+        let mut player = self.get_player(id).ok_or(KickError::NotFound)?;
+        let event = OutputEvent::Kick(Arc::new(OutputKickEvent { reason }));
+        player.send(event).map_err(|err| match err {
+            mpsc::error::TrySendError::Full(_) => KickError::QueueFull,
+            mpsc::error::TrySendError::Closed(_) => KickError::NotFound,
+        })
+    }
+
+    fn set_player_room_ids(&self, id: &entity::PlayerId, room_ids: Vec<entity::RoomId>) {
+        self.players.update(id, |entry| entry.room_ids = room_ids);
+    }
+
+    /// ログイン中のプレイヤーを取得する。
+    /// 返り値のPlayerを通じて、Roomを介さずにPlayer actorへイベントを送信できる。
+    pub fn get_player(&self, id: &entity::PlayerId) -> Option<entity::Player<OutputEvent>> {
+        self.players.get_with(id, |entry| entry.player.clone())
+    }
+
+    /// ログイン中の全てのプレイヤーを取得する。
+    pub fn get_all_players(&self) -> Vec<entity::Player<OutputEvent>> {
+        self.players.fold(Vec::new(), |mut players, _, entry| {
+            players.push(entry.player.clone());
+            players
+        })
+    }
+
+    pub fn get_presence(&self, id: &entity::PlayerId) -> entity::Presence {
+        self.players
+            .get_with(id, |entry| {
+                entity::Presence::online(id.clone(), entry.room_ids.clone())
+            })
+            .unwrap_or_else(|| entity::Presence::offline(id.clone()))
+    }
+
+    /// `player_tx`で登録したプレイヤーの場合だけ削除する。
+    /// 二重ログインで拒否した接続が、先にログインしている同じIDのプレイヤーを削除しないようにするため。
+    fn unregister_player(&self, id: &entity::PlayerId, player_tx: &mpsc::Sender<OutputEvent>) {
+        self.players
+            .remove_if(id, |entry| entry.player.sender.same_channel(player_tx));
+    }
+}
+
+// 参加中のパーティ。
+struct PartyMembership {
+    id: entity::PartyId,
+    tx: mpsc::Sender<PartyInputEvent>,
+    // パーティで移動中のRoom。Joinの結果をパーティに報告する。
+    moving_room_id: Option<entity::RoomId>,
+}
+
+// ログイン後のPlayer actorの状態。
+struct PlayerState {
+    player: entity::Player<OutputEvent>,
+    // 参加中のRoomのチャネル。Roomへのメッセージはレジストリを引かずにここから送る。
+    joined_rooms: HashMap<entity::RoomId, mpsc::Sender<InputEvent>>,
+    party: Option<PartyMembership>,
+    presence_subscriptions: HashSet<entity::PlayerId>,
+    subscribed_channels: HashSet<ChannelName>,
+}
+
+impl PlayerState {
+    fn new(player: entity::Player<OutputEvent>) -> Self {
+        Self {
+            player,
+            joined_rooms: HashMap::new(),
+            party: None,
+            presence_subscriptions: HashSet::new(),
+            subscribed_channels: HashSet::new(),
+        }
+    }
+}
+
+// クライアントからの入力キューの大きさ。一杯の場合はトランスポートが受信を待つ。
+const INPUT_CHANNEL_CAPACITY: usize = 64;
+// ルームやパーティからのイベントのキューの大きさ。Player actorはすぐに取り出すので、通常は溢れない。
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// Unix domain socketで接続したクライアントのプロセスの情報。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+/// クライアントの接続元。管理用のAPIで表示し、`peer_credentials`はログイン時の認証にも使う。
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub protocol: Option<Protocol>,
+    pub remote_addr: Option<String>,
+    pub peer_credentials: Option<PeerCredentials>,
+}
+
+impl ConnectionInfo {
+    pub fn new(protocol: Protocol, remote_addr: Option<SocketAddr>) -> Self {
+        Self {
+            protocol: Some(protocol),
+            remote_addr: remote_addr.map(|addr| addr.to_string()),
+            peer_credentials: None,
+        }
+    }
+}
 
-async fn register_player(id: entity::PlayerId) -> bool {
-    let mut players = PLAYERS.write().await;
-    players.insert(id)
+/// 管理用のAPIで返すログイン中のプレイヤーの情報。
+#[derive(Clone, Debug)]
+pub struct PlayerInfo {
+    pub id: entity::PlayerId,
+    pub room_ids: Vec<entity::RoomId>,
+    pub connection: ConnectionInfo,
+    pub logged_in_at: SystemTime,
 }
 
-async fn unregister_player(id: &entity::PlayerId) {
-    let mut players = PLAYERS.write().await;
-    players.remove(id);
+impl PlayerInfo {
+    fn new(entry: &PlayerEntry) -> Self {
+        Self {
+            id: entry.player.id.clone(),
+            room_ids: entry.room_ids.clone(),
+            connection: entry.connection.clone(),
+            logged_in_at: entry.logged_in_at,
+        }
+    }
+}
+
+/// リクエストに対応しないエラーを通知するメッセージ。送信後は切断する。
+pub fn error_notification(error: protobuf::app::Error) -> protobuf::app::ServerMessage {
+    protobuf::app::ServerMessage {
+        data: Some(protobuf::app::server_message::Data::ErrorNotification(
+            protobuf::app::ErrorNotification { error: Some(error) },
+        )),
+    }
 }
 
 pub struct Player {
-    input_tx: mpsc::UnboundedSender<protobuf::app::ClientMessage>,
-    output_rx: mpsc::UnboundedReceiver<protobuf::app::ServerMessage>,
+    input_tx: mpsc::Sender<protobuf::app::ClientMessage>,
+    output_rx: OutputReceiver,
 }
 
 impl Player {
-    pub fn new(config: Arc<config::Config>) -> Self {
-        let (input_tx, mut input_rx) = mpsc::unbounded_channel::<protobuf::app::ClientMessage>();
-        let (output_tx, output_rx) = mpsc::unbounded_channel();
+    /// プレイヤーやRoomは`registries`に登録され、同じ`registries`を使うプレイヤー同士でだけ遊べる。
+    pub fn new(config: Arc<config::Config>, registries: Arc<Registries>) -> Self {
+        Self::with_connection(config, registries, ConnectionInfo::default())
+    }
+
+    /// 接続元のプロセスが分かる場合は、ログイン時の認証に使う。
+    pub fn with_connection(
+        config: Arc<config::Config>,
+        registries: Arc<Registries>,
+        connection: ConnectionInfo,
+    ) -> Self {
+        let (input_tx, mut input_rx) =
+            mpsc::channel::<protobuf::app::ClientMessage>(INPUT_CHANNEL_CAPACITY);
+        let (output_tx, output_rx) = output_channel(&config.outbound);
 
         tokio::spawn(async move {
             debug!("Start player actor task");
-            let (player_tx, mut player_rx) = mpsc::unbounded_channel();
-            let player_id = match Self::wait_login(&mut input_rx, &output_tx, &config).await {
-                Some(player_id) => player_id,
-                None => {
-                    drop(output_tx);
-                    return;
-                }
-            };
+            let (player_tx, mut player_rx) = mpsc::channel(EVENT_CHANNEL_CAPACITY);
+            let (player, capabilities) =
+                match Self::wait_login(
+                    &mut input_rx,
+                    &output_tx,
+                    &player_tx,
+                    &config,
+                    &registries,
+                    &connection,
+                )
+                .await
+                {
+                    Some(login) => login,
+                    None => {
+                        drop(output_tx);
+                        return;
+                    }
+                };
+            // 接続のスパンで動いているので、以降の接続のログにもプレイヤーのIDが付く。
+            Span::current().record("player_id", player.id.as_str());
 
-            let player = entity::Player::new(player_id, player_tx);
-            let mut joined_rooms: HashMap<entity::RoomId, mpsc::UnboundedSender<InputEvent>> =
-                HashMap::new();
+            registries.publish_presence(entity::Presence::online(player.id.clone(), Vec::new()));
+            let mut state = PlayerState::new(player);
             loop {
                 tokio::select! {
                     // クライアントからサーバに対してのメッセージ
                     message = input_rx.recv() => {
-                        Self::on_client_message(message, &output_tx, &player).await;
+                        let validated = message
+                            .as_ref()
+                            .map_or(Ok(()), |message| Self::validate_client_message(message, &config));
+                        if let Err(error) = validated {
+                            // 不正なメッセージを送ってきたクライアントには、エラーを通知してから切断する。
+                            Self::try_to_send_output_message(&output_tx, error_notification(error));
+                            Self::on_disconnect(&state, &registries).await;
+                            return;
+                        }
+                        Self::on_client_message(message, &output_tx, &mut state, &config, &registries).await;
                     }
-                    // ルーム内からプレイヤーへのイベント通知
+                    // ルーム内やパーティからプレイヤーへのイベント通知
                     event = player_rx.recv() => {
-                        Self::on_output_event(event, &output_tx, &player.id, &mut joined_rooms).await;
+                        if state.player.is_overflowed() {
+                            // ルームやパーティがイベントを捨てているので、状態がずれる前に切断する。
+                            warn!("Event queue overflowed. Disconnect slow consumer. capacity={}", EVENT_CHANNEL_CAPACITY);
+                            METRICS.inc_slow_consumer_disconnects();
+                            Self::on_disconnect(&state, &registries).await;
+                            return;
+                        }
+                        if let Some(OutputEvent::Kick(event)) = &event {
+                            // 理由を通知してから切断する。
+                            let error = Self::new_error(protobuf::app::ErrorCode::Kicked, &event.reason);
+                            Self::try_to_send_output_message(&output_tx, error_notification(error));
+                            Self::on_disconnect(&state, &registries).await;
+                            return;
+                        }
+                        Self::on_output_event(event, &output_tx, &mut state, &capabilities, &registries).await;
                     }
                     _ = output_tx.closed() => {
                         // 切断した場合。
-                        // JoinしているルームにLeaveイベントを投げる。
                         // output_tx/rxがcloseしているのでレスポンスだけ返るということも無い。
-                        for (_, room_tx) in joined_rooms.iter() {
-                            let result = room_tx.send(InputEvent::Leave(
-                                Box::new(InputLeaveEvent {
-                                    player_id: player.id.clone(),
-                                }),
-                            ));
-
-                            // RoomがDropしている場合(プレイヤー数が0)。ここではハンドリングしない。
-                            if result.is_err() {
-                                debug!("Room has been dropped");
-                            }
-                        }
-
-                        unregister_player(&player.id).await;
-                        debug!("Finish player actor task");
+                        Self::on_disconnect(&state, &registries).await;
                         return;
                     }
                 };
             }
-        });
+        }.instrument(Span::current()));
 
         Self {
             input_tx,
@@ -89,27 +322,95 @@ impl Player {
         }
     }
 
-    pub fn send(
+    /// JoinしているルームやパーティにLeaveイベントを投げて、プレイヤーの登録を削除する。
+    async fn on_disconnect(state: &PlayerState, registries: &Registries) {
+        let player = &state.player;
+        for (_, room_tx) in state.joined_rooms.iter() {
+            let result = room_tx
+                .send(InputEvent::Leave(Box::new(InputLeaveEvent {
+                    player_id: player.id.clone(),
+                })))
+                .await;
+
+            // RoomがDropしている場合(プレイヤー数が0)。ここではハンドリングしない。
+            if result.is_err() {
+                debug!("Room has been dropped");
+            }
+        }
+
+        if let Some(party) = &state.party {
+            let result = party
+                .tx
+                .send(PartyInputEvent::Leave(Box::new(InputPartyLeaveEvent {
+                    player_id: player.id.clone(),
+                })))
+                .await;
+            if result.is_err() {
+                debug!("Party has been dropped");
+            }
+        }
+
+        registries.unsubscribe_presence(&player.id, &state.presence_subscriptions);
+        for channel in state.subscribed_channels.iter() {
+            registries.unsubscribe_channel(channel, &player.id);
+        }
+        registries.unregister_player(&player.id, &player.sender);
+        registries.publish_presence(entity::Presence::offline(player.id.clone()));
+        debug!("Finish player actor task");
+    }
+
+    /// メッセージの本文の大きさが上限を超えていないか確認する。
+    fn validate_client_message(
+        message: &protobuf::app::ClientMessage,
+        config: &config::Config,
+    ) -> Result<(), protobuf::app::Error> {
+        let body = match &message.data {
+            Some(protobuf::app::client_message::Data::SendMessage(req)) => &req.body,
+            Some(protobuf::app::client_message::Data::PartyMessageRequest(req)) => &req.body,
+            Some(protobuf::app::client_message::Data::DirectMessageRequest(req)) => &req.body,
+            Some(protobuf::app::client_message::Data::ChannelPublishRequest(req)) => &req.body,
+            _ => return Ok(()),
+        };
+        if body.len() > config.limits.max_body_size {
+            return Err(Self::new_error(
+                protobuf::app::ErrorCode::MessageTooLarge,
+                &format!(
+                    "Message body is too large. size={}, max_size={}",
+                    body.len(),
+                    config.limits.max_body_size
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// 入力キューが一杯の場合は空くまで待つ。
+    pub async fn send(
         &self,
         message: protobuf::app::ClientMessage,
     ) -> Result<(), mpsc::error::SendError<protobuf::app::ClientMessage>> {
-        self.input_tx.send(message)
+        self.input_tx.send(message).await
     }
 
-    pub async fn recv(&mut self) -> Option<protobuf::app::ServerMessage> {
+    /// 他のプレイヤーと共有するメッセージはエンコード済みのフレームを使って送れる。
+    pub async fn recv(&mut self) -> Option<OutputMessage> {
         self.output_rx.recv().await
     }
 
     async fn wait_login(
-        input_rx: &mut mpsc::UnboundedReceiver<protobuf::app::ClientMessage>,
-        output_tx: &mpsc::UnboundedSender<protobuf::app::ServerMessage>,
+        input_rx: &mut mpsc::Receiver<protobuf::app::ClientMessage>,
+        output_tx: &OutputSender,
+        player_tx: &mpsc::Sender<OutputEvent>,
         config: &config::Config,
-    ) -> Option<entity::PlayerId> {
+        registries: &Registries,
+        connection: &ConnectionInfo,
+    ) -> Option<(entity::Player<OutputEvent>, HashSet<protobuf::app::Capability>)> {
         while let Some(message) = input_rx.recv().await {
             if let Some(data) = message.data {
                 match data {
                     protobuf::app::client_message::Data::LoginRequest(req) => {
-                        let ok = register_player(req.player_id.clone()).await;
+                        let player = entity::Player::new(req.player_id.clone(), player_tx.clone());
+                        let ok = registries.register_player(player.clone(), connection);
                         if !ok {
                             // すでにログインしていた場合。
                             Self::send_login_error(
@@ -117,40 +418,52 @@ impl Player {
                                 protobuf::app::ErrorCode::AlreadyLoggedIn,
                                 "Already logged in".to_string(),
                                 output_tx,
-                            )
-                            .await;
+                                player_tx,
+                                registries,
+                            );
                             return None;
                         }
 
-                        match req.auth_config {
-                            Some(auth_config) => match auth_config {
-                                protobuf::app::login_request::AuthConfig::Bearer(bearer) => {
-                                    if bearer.token != config.auth.bearer {
-                                        Self::send_login_error(
-                                            &req.player_id,
-                                            protobuf::app::ErrorCode::Unauthorized,
-                                            "Unauthorized".to_string(),
-                                            output_tx,
-                                        )
-                                        .await;
-                                        return None;
-                                    }
+                        if req.protocol_version < config.compatibility.min_protocol_version {
+                            Self::send_login_error(
+                                &req.player_id,
+                                protobuf::app::ErrorCode::ClientTooOld,
+                                format!(
+                                    "Client protocol version is too old. version={}, min_version={}",
+                                    req.protocol_version, config.compatibility.min_protocol_version
+                                ),
+                                output_tx,
+                                player_tx,
+                                registries,
+                            );
+                            return None;
+                        }
 
-                                    Self::send_login_ok(output_tx);
-                                    return Some(req.player_id);
-                                }
-                            },
-                            None => {
-                                Self::send_login_error(
-                                    &req.player_id,
-                                    protobuf::app::ErrorCode::Unauthorized,
-                                    "Unauthorized".to_string(),
-                                    output_tx,
-                                )
-                                .await;
-                                return None;
+                        let authorized = match &req.auth_config {
+                            Some(protobuf::app::login_request::AuthConfig::Bearer(bearer)) => {
+                                bearer.token == config.auth.bearer
                             }
+                            None => false,
+                        } || Self::is_trusted_peer(connection.peer_credentials.as_ref(), config);
+                        if !authorized {
+                            Self::send_login_error(
+                                &req.player_id,
+                                protobuf::app::ErrorCode::Unauthorized,
+                                "Unauthorized".to_string(),
+                                output_tx,
+                                player_tx,
+                                registries,
+                            );
+                            return None;
                         }
+
+                        let capabilities = Self::to_capabilities(&req.capabilities);
+                        Self::send_login_ok(
+                            output_tx,
+                            Self::to_compression(req.compression, config),
+                            &capabilities,
+                        );
+                        return Some((player, capabilities));
                     }
                     // ひとまずLogin以外がきたら切断にしてしまう。
                     _ => return None,
@@ -161,7 +474,41 @@ impl Player {
         None
     }
 
-    fn send_login_ok(tx: &mpsc::UnboundedSender<protobuf::app::ServerMessage>) {
+    /// 信頼するユーザーのプロセスからの接続はトークン無しでログインできる。
+    fn is_trusted_peer(peer_credentials: Option<&PeerCredentials>, config: &config::Config) -> bool {
+        match peer_credentials {
+            Some(peer_credentials) => {
+                let trusted = config.auth.trusted_unix_uids.contains(&peer_credentials.uid);
+                debug!("Peer credentials: {:?}, trusted={}", peer_credentials, trusted);
+                trusted
+            }
+            None => false,
+        }
+    }
+
+    /// クライアントが希望した圧縮方式のうち、サーバで受け入れるものを返す。
+    fn to_compression(compression: i32, config: &config::Config) -> protobuf::app::Compression {
+        if !config.compression.enable {
+            return protobuf::app::Compression::Uncompressed;
+        }
+        protobuf::app::Compression::try_from(compression)
+            .unwrap_or(protobuf::app::Compression::Uncompressed)
+    }
+
+    /// クライアントが提示した機能のうち、サーバが対応しているものを返す。
+    fn to_capabilities(capabilities: &[i32]) -> HashSet<protobuf::app::Capability> {
+        capabilities
+            .iter()
+            .filter_map(|capability| protobuf::app::Capability::try_from(*capability).ok())
+            .filter(|capability| *capability != protobuf::app::Capability::UnknownCapability)
+            .collect()
+    }
+
+    fn send_login_ok(
+        tx: &OutputSender,
+        compression: protobuf::app::Compression,
+        capabilities: &HashSet<protobuf::app::Capability>,
+    ) {
         let result = tx.send(protobuf::app::ServerMessage {
             data: Some(protobuf::app::server_message::Data::LoginResponse(
                 protobuf::app::LoginResponse {
@@ -169,6 +516,12 @@ impl Player {
                         code: protobuf::app::ErrorCode::None as i32,
                         message: String::new(),
                     }),
+                    compression: compression as i32,
+                    protocol_version: protobuf::PROTOCOL_VERSION,
+                    capabilities: capabilities
+                        .iter()
+                        .map(|capability| *capability as i32)
+                        .collect(),
                 },
             )),
         });
@@ -180,12 +533,15 @@ impl Player {
         }
     }
 
-    async fn send_login_error(
+    fn send_login_error(
         player_id: &entity::PlayerId,
         code: protobuf::app::ErrorCode,
         message: String,
-        tx: &mpsc::UnboundedSender<protobuf::app::ServerMessage>,
+        tx: &OutputSender,
+        player_tx: &mpsc::Sender<OutputEvent>,
+        registries: &Registries,
     ) {
+        METRICS.inc_login_failures(code);
         let result = tx.send(protobuf::app::ServerMessage {
             data: Some(protobuf::app::server_message::Data::LoginResponse(
                 protobuf::app::LoginResponse {
@@ -193,6 +549,9 @@ impl Player {
                         code: code as i32,
                         message,
                     }),
+                    compression: protobuf::app::Compression::Uncompressed as i32,
+                    protocol_version: protobuf::PROTOCOL_VERSION,
+                    capabilities: Vec::new(),
                 },
             )),
         });
@@ -203,20 +562,28 @@ impl Player {
             debug!("Player disconnected before sending response");
         }
 
-        unregister_player(player_id).await;
+        registries.unregister_player(player_id, player_tx);
     }
 
     async fn on_client_message(
         message: Option<protobuf::app::ClientMessage>,
-        output_tx: &mpsc::UnboundedSender<protobuf::app::ServerMessage>,
-        player: &entity::Player<OutputEvent>,
+        output_tx: &OutputSender,
+        state: &mut PlayerState,
+        config: &config::Config,
+        registries: &Arc<Registries>,
     ) {
+        let player = &state.player;
         if let Some(client_message) = message {
-            debug!("ClientMessage: {:?}", client_message);
+            debug!(
+                kind = logging::client_message_kind(&client_message),
+                payload = ?logging::payload(&client_message),
+                "Receive ClientMessage"
+            );
             // output_tx.sendのエラー(output_rxがDrop or closeされている状態)は呼び出し元の次回ループでハンドリングされるので、この関数内ではハンドリングしない。
             if let Some(data) = client_message.data {
                 match data {
                     protobuf::app::client_message::Data::LoginRequest(_) => {
+                        METRICS.inc_login_failures(protobuf::app::ErrorCode::AlreadyLoggedIn);
                         Self::try_to_send_output_message(
                             output_tx,
                             protobuf::app::ServerMessage {
@@ -226,57 +593,142 @@ impl Player {
                                             code: protobuf::app::ErrorCode::AlreadyLoggedIn as i32,
                                             message: "Already logged in".to_string(),
                                         }),
+                                        compression: protobuf::app::Compression::Uncompressed
+                                            as i32,
+                                        protocol_version: protobuf::PROTOCOL_VERSION,
+                                        capabilities: Vec::new(),
                                     },
                                 )),
                             },
                         );
                     }
                     protobuf::app::client_message::Data::JoinRequest(req) => {
-                        let room_config = match req.room_config {
-                            Some(req_room_config) => entity::RoomConfig {
-                                max_players: req_room_config.max_players,
-                            },
-                            None => entity::RoomConfig::default(),
-                        };
-
-                        let room_tx =
-                            get_or_create_room_channel(&req.room_id, room_config.clone()).await;
-                        // room_tx.sendが失敗するのはRoomがDropした場合。
-                        // タイミング次第でこのJoin前に別プレイヤーがLeaveしてRoomの人数が0になればあり得なくもない。
-                        // ひとまひとまずこの場合はエラーを返す。
-                        let result = room_tx.send(InputEvent::Join(Box::new(InputJoinEvent {
-                            player: player.clone(),
-                            room_config,
-                        })));
-
-                        if result.is_err() {
+                        let room_config = Self::to_room_config(req.room_config);
+                        let room_tx = registries.claim_room_seat(
+                            &req.room_id,
+                            room_config.clone(),
+                            req.room_properties,
+                        );
+                        Self::send_join_event(output_tx, player, req.room_id, room_tx, room_config)
+                            .await;
+                    }
+                    protobuf::app::client_message::Data::JoinRandomRoomRequest(req) => {
+                        let room_config = Self::to_room_config(req.room_config);
+                        let (room_id, room_tx) = registries
+                            .claim_random_room_seat(room_config.clone(), req.room_properties);
+                        Self::send_join_event(output_tx, player, room_id, room_tx, room_config)
+                            .await;
+                    }
+                    protobuf::app::client_message::Data::ReserveSeatsRequest(req) => {
+                        if req.player_ids.is_empty() {
                             Self::try_to_send_output_message(
                                 output_tx,
                                 protobuf::app::ServerMessage {
-                                    data: Some(protobuf::app::server_message::Data::JoinResponse(
-                                        protobuf::app::JoinResponse {
+                                    data: Some(protobuf::app::server_message::Data::ReserveSeatsResponse(
+                                        protobuf::app::ReserveSeatsResponse {
                                             room_id: req.room_id,
-                                            current_players: Vec::new(),
-                                            room_config: None,
+                                            player_ids: Vec::new(),
                                             error: Some(protobuf::app::Error {
-                                                code: protobuf::app::ErrorCode::RoomNotFound as i32,
-                                                message: "Room was removed during Join processing"
-                                                    .to_string(),
+                                                code: protobuf::app::ErrorCode::FailedPrecondition as i32,
+                                                message: "player_ids is empty".to_string(),
                                             }),
                                         },
                                     )),
                                 },
                             );
+                            return;
                         }
+
+                        let room_config = Self::to_room_config(req.room_config);
+                        let timeout = match req.timeout_ms {
+                            0 => DEFAULT_RESERVATION_TIMEOUT,
+                            timeout_ms => Duration::from_millis(timeout_ms as u64),
+                        }
+                        .min(config.limits.max_reservation_timeout);
+                        let room_tx = registries.claim_room_seats(
+                            &req.room_id,
+                            room_config.clone(),
+                            req.room_properties,
+                            req.player_ids.len() as u32,
+                        );
+                        // 席を確保済みなのでRoomはDropしないはず。
+                        let result = room_tx
+                            .send(InputEvent::Reserve(Box::new(InputReserveEvent {
+                                requester_id: entity::RequesterId::Player(player.id.clone()),
+                                reply_tx: player.sender.clone(),
+                                player_ids: req.player_ids,
+                                room_config,
+                                timeout,
+                                max_reservations: config.limits.max_reservations_per_requester,
+                            })))
+                            .await;
+                        if result.is_err() {
+                            error!("Room was removed during Reserve processing");
+                        }
+                    }
+                    data @ (protobuf::app::client_message::Data::CreatePartyRequest(_)
+                    | protobuf::app::client_message::Data::InvitePartyRequest(_)
+                    | protobuf::app::client_message::Data::JoinPartyRequest(_)
+                    | protobuf::app::client_message::Data::LeavePartyRequest(_)
+                    | protobuf::app::client_message::Data::PartyJoinRoomRequest(_)
+                    | protobuf::app::client_message::Data::PartyMessageRequest(_)) => {
+                        Self::on_party_message(
+                            data,
+                            output_tx,
+                            player,
+                            &mut state.party,
+                            registries,
+                        )
+                        .await;
+                    }
+                    protobuf::app::client_message::Data::DirectMessageRequest(req) => {
+                        Self::on_direct_message(req, output_tx, player, registries);
+                    }
+                    protobuf::app::client_message::Data::PresenceSubscribeRequest(req) => {
+                        let presences = registries.subscribe_presence(&player.id, &req.player_ids);
+                        state.presence_subscriptions.extend(req.player_ids);
+                        Self::try_to_send_output_message(
+                            output_tx,
+                            protobuf::app::ServerMessage {
+                                data: Some(protobuf::app::server_message::Data::PresenceSubscribeResponse(
+                                    protobuf::app::PresenceSubscribeResponse {
+                                        presences: presences.iter().map(Self::to_presence).collect(),
+                                        error: Some(Self::new_error(protobuf::app::ErrorCode::None, "")),
+                                    },
+                                )),
+                            },
+                        );
+                    }
+                    protobuf::app::client_message::Data::PresenceUnsubscribeRequest(req) => {
+                        registries.unsubscribe_presence(&player.id, &req.player_ids);
+                        req.player_ids.iter().for_each(|player_id| {
+                            state.presence_subscriptions.remove(player_id);
+                        });
+                    }
+                    data @ (protobuf::app::client_message::Data::ChannelSubscribeRequest(_)
+                    | protobuf::app::client_message::Data::ChannelUnsubscribeRequest(_)
+                    | protobuf::app::client_message::Data::ChannelPublishRequest(_)) => {
+                        Self::on_channel_message(
+                            data,
+                            output_tx,
+                            player,
+                            &mut state.subscribed_channels,
+                            registries,
+                        );
+                    }
+                    protobuf::app::client_message::Data::CompressedMessage(_) => {
+                        // 展開はトランスポート側で行うので、ここには届かない想定。
+                        error!("Received compressed message that was not decompressed");
                     }
                     protobuf::app::client_message::Data::LeaveRequest(req) => {
-                        let room_tx = get_room_channel(&req.room_id).await;
+                        let room_tx = registries.get_room_channel(&req.room_id);
                         match room_tx {
                             Some(room_tx) => {
                                 let result =
                                     room_tx.send(InputEvent::Leave(Box::new(InputLeaveEvent {
                                         player_id: player.id.clone(),
-                                    })));
+                                    })))
+                                    .await;
 
                                 // RoomがDropしていた場合。
                                 // プレイヤー切断タイミング次第ではエラーになることはあり得なくもなさそう。
@@ -305,16 +757,19 @@ impl Player {
                         };
                     }
                     protobuf::app::client_message::Data::SendMessage(send_message) => {
-                        debug!("SendMessage: {:?}", send_message);
                         let event = Box::new(InputMessageEvent {
                             sender_player_id: player.id.clone(),
                             target_ids: send_message.target_ids,
                             body: send_message.body.into(),
+                            delivery: match protobuf::app::Delivery::try_from(send_message.delivery) {
+                                Ok(protobuf::app::Delivery::Unreliable) => Delivery::Unreliable,
+                                _ => Delivery::Reliable,
+                            },
+                            sent_at: Instant::now(),
                         });
-                        let room_tx = get_room_channel(&send_message.room_id).await;
-                        match room_tx {
+                        match state.joined_rooms.get(&send_message.room_id) {
                             Some(room_tx) => {
-                                let result = room_tx.send(InputEvent::Message(event));
+                                let result = room_tx.send(InputEvent::Message(event)).await;
                                 // RoomがDropしていた場合。
                                 // プレイヤー切断タイミング次第ではエラーになることはあり得なくもなさそう。
                                 if result.is_err() {
@@ -322,9 +777,21 @@ impl Player {
                                 }
                             }
                             None => {
-                                // 存在しないRoomにMessageを送ったケース。
-                                // 現状SendMessageにはレスポンスを返さずベストエフォートな想定なので無視する。
-                                error!("Attempted to send a message to a room that does not exist or is not joined");
+                                // 参加していないRoomにMessageを送ったケース。成功時と違い、エラーを通知する。
+                                Self::try_to_send_output_message(
+                                    output_tx,
+                                    protobuf::app::ServerMessage {
+                                        data: Some(protobuf::app::server_message::Data::SendMessageErrorNotification(
+                                            protobuf::app::SendMessageErrorNotification {
+                                                room_id: send_message.room_id,
+                                                error: Some(Self::new_error(
+                                                    protobuf::app::ErrorCode::NotJoinedTheRoom,
+                                                    "You have not joined the room",
+                                                )),
+                                            },
+                                        )),
+                                    },
+                                );
                             }
                         };
                     }
@@ -333,23 +800,79 @@ impl Player {
         }
     }
 
+    fn to_room_config(room_config: Option<protobuf::app::RoomConfig>) -> entity::RoomConfig {
+        match room_config {
+            Some(room_config) => entity::RoomConfig {
+                max_players: room_config.max_players,
+            },
+            None => entity::RoomConfig::default(),
+        }
+    }
+
+    async fn send_join_event(
+        output_tx: &OutputSender,
+        player: &entity::Player<OutputEvent>,
+        room_id: entity::RoomId,
+        room_tx: mpsc::Sender<InputEvent>,
+        room_config: entity::RoomConfig,
+    ) -> bool {
+        // 席を確保済みなのでRoomはDropしないはずだが、念のためエラーを返しておく。
+        let result = room_tx
+            .send(InputEvent::Join(Box::new(InputJoinEvent {
+                player: player.clone(),
+                room_config,
+            })))
+            .await;
+
+        if result.is_err() {
+            Self::try_to_send_output_message(
+                output_tx,
+                protobuf::app::ServerMessage {
+                    data: Some(protobuf::app::server_message::Data::JoinResponse(
+                        protobuf::app::JoinResponse {
+                            room_id,
+                            current_players: Vec::new(),
+                            room_config: None,
+                            error: Some(protobuf::app::Error {
+                                code: protobuf::app::ErrorCode::RoomNotFound as i32,
+                                message: "Room was removed during Join processing".to_string(),
+                            }),
+                            room_properties: HashMap::new(),
+                        },
+                    )),
+                },
+            );
+        }
+        result.is_ok()
+    }
+
     async fn on_output_event(
         event: Option<OutputEvent>,
-        output_tx: &mpsc::UnboundedSender<protobuf::app::ServerMessage>,
-        player_id: &entity::PlayerId,
-        joined_rooms: &mut HashMap<entity::RoomId, mpsc::UnboundedSender<InputEvent>>,
+        output_tx: &OutputSender,
+        state: &mut PlayerState,
+        capabilities: &HashSet<protobuf::app::Capability>,
+        registries: &Arc<Registries>,
     ) {
+        let PlayerState {
+            player,
+            joined_rooms,
+            party,
+            ..
+        } = state;
+        let player_id = &player.id;
         if let Some(event) = event {
-            debug!("OutputEvent: {:?}", event);
+            debug!(event = ?logging::payload(&event), "Receive OutputEvent");
             match event {
                 OutputEvent::Join(event) => {
                     match event {
                         Ok(ev) => {
                             if &ev.player_id == player_id {
-                                let room_tx = get_room_channel(&ev.room_id).await;
+                                let room_tx = registries.get_room_channel(&ev.room_id);
                                 match room_tx {
                                     Some(room_tx) => {
                                         joined_rooms.insert(ev.room_id.clone(), room_tx);
+                                        Self::update_presence(player_id, joined_rooms, registries);
+                                        Self::report_party_move(party, player_id, &ev.room_id, Ok(())).await;
                                         Self::try_to_send_output_message(
                                             output_tx,
                                             protobuf::app::ServerMessage {
@@ -366,6 +889,7 @@ impl Player {
                                                                     as i32,
                                                                 message: String::new(),
                                                             }),
+                                                            room_properties: ev.room_properties.clone(),
                                                         },
                                                     ),
                                                 ),
@@ -375,6 +899,13 @@ impl Player {
                                     None => {
                                         // Joinできた直後にRoomがDropした。
                                         // 切断タイミング次第ではあり得るか。
+                                        Self::report_party_move(
+                                            party,
+                                            player_id,
+                                            &ev.room_id,
+                                            Err(entity::PartyError::RoomNotFound(ev.room_id.clone())),
+                                        )
+                                        .await;
                                         Self::try_to_send_output_message(
                                             output_tx,
                                             protobuf::app::ServerMessage {
@@ -389,6 +920,7 @@ impl Player {
                                                                     as i32,
                                                                 message: "Room was deleted during Join processing".to_string(),
                                                             }),
+                                                            room_properties: HashMap::new(),
                                                         },
                                                     ),
                                                 ),
@@ -412,57 +944,31 @@ impl Player {
                                 );
                             }
                         }
-                        Err(err) => match err {
-                            entity::RoomError::AlreadyJoinedRoom(room_id, _player_id) => {
-                                Self::try_to_send_output_message(
-                                    output_tx,
-                                    protobuf::app::ServerMessage {
-                                        data: Some(protobuf::app::server_message::Data::JoinResponse(
-                                            protobuf::app::JoinResponse {
-                                                room_id,
-                                                current_players: Vec::new(),
-                                                room_config: None,
-                                                error: Some(protobuf::app::Error {
-                                                    code: protobuf::app::ErrorCode::AlreadyJoinedTheRoom
-                                                        as i32,
-                                                    message: "Already joined the room".to_string(),
-                                                }),
-                                            },
-                                        )),
-                                    },
-                                );
-                            }
-                            entity::RoomError::RoomConfigDoesNotMatch(room_id, _player_id) => {
-                                Self::try_to_send_output_message(
-                                    output_tx,
-                                    protobuf::app::ServerMessage {
-                                        data: Some(
-                                            protobuf::app::server_message::Data::JoinResponse(
-                                                protobuf::app::JoinResponse {
-                                                    room_id,
-                                                    current_players: Vec::new(),
-                                                    room_config: None,
-                                                    error: Some(protobuf::app::Error {
-                                                        code: protobuf::app::ErrorCode::RoomConfigDoesNotMatch
-                                                            as i32,
-                                                        message: "Room config does not match".to_string(),
-                                                    }),
-                                                },
-                                            ),
-                                        ),
-                                    },
-                                );
-                            }
-                            _ => {
-                                unreachable!("invalid error type for OutputEvent::Join");
-                            }
-                        },
+                        Err(err) => {
+                            Self::report_party_move(party, player_id, err.room_id(), Err(err.clone().into()))
+                                .await;
+                            Self::try_to_send_output_message(
+                                output_tx,
+                                protobuf::app::ServerMessage {
+                                    data: Some(protobuf::app::server_message::Data::JoinResponse(
+                                        protobuf::app::JoinResponse {
+                                            room_id: err.room_id().clone(),
+                                            current_players: Vec::new(),
+                                            room_config: None,
+                                            error: Some(Self::to_error(&err)),
+                                            room_properties: HashMap::new(),
+                                        },
+                                    )),
+                                },
+                            );
+                        }
                     }
                 }
                 OutputEvent::Leave(event) => {
                     if let Ok(ev) = event {
                         if &ev.player_id == player_id {
                             joined_rooms.remove(&ev.room_id);
+                            Self::update_presence(player_id, joined_rooms, registries);
 
                             Self::try_to_send_output_message(
                                 output_tx,
@@ -497,31 +1003,720 @@ impl Player {
                         unreachable!("invalid error type for OutputEvent::Leave");
                     }
                 }
-                OutputEvent::Message(event) => {
+                OutputEvent::Reserve(event) => {
+                    let response = match event {
+                        Ok(ev) => protobuf::app::ReserveSeatsResponse {
+                            room_id: ev.room_id.clone(),
+                            player_ids: ev.player_ids.clone(),
+                            error: Some(protobuf::app::Error {
+                                code: protobuf::app::ErrorCode::None as i32,
+                                message: String::new(),
+                            }),
+                        },
+                        Err(err) => protobuf::app::ReserveSeatsResponse {
+                            room_id: err.room_id().clone(),
+                            player_ids: Vec::new(),
+                            error: Some(Self::to_error(&err)),
+                        },
+                    };
+                    Self::try_to_send_output_message(
+                        output_tx,
+                        protobuf::app::ServerMessage {
+                            data: Some(protobuf::app::server_message::Data::ReserveSeatsResponse(
+                                response,
+                            )),
+                        },
+                    );
+                }
+                OutputEvent::ReservationExpired(event) => {
+                    Self::try_to_send_output_message(
+                        output_tx,
+                        protobuf::app::ServerMessage {
+                            data: Some(
+                                protobuf::app::server_message::Data::ReservationExpiredNotification(
+                                    protobuf::app::ReservationExpiredNotification {
+                                        room_id: event.room_id.clone(),
+                                        player_ids: event.player_ids.clone(),
+                                    },
+                                ),
+                            ),
+                        },
+                    );
+                }
+                event @ (OutputEvent::PartyInvite(_)
+                | OutputEvent::PartyJoin(_)
+                | OutputEvent::PartyLeave(_)
+                | OutputEvent::PartyMessage(_)
+                | OutputEvent::PartyJoinRoom(_)
+                | OutputEvent::PartyMoveRoom(_)
+                | OutputEvent::PartyLeaveRoom(_)) => {
+                    Self::on_party_event(event, output_tx, player, joined_rooms, party, registries)
+                        .await;
+                }
+                OutputEvent::DirectMessage(event) => {
                     Self::try_to_send_output_message(
                         output_tx,
                         protobuf::app::ServerMessage {
-                            data: Some(protobuf::app::server_message::Data::MessageNotification(
-                                protobuf::app::MessageNotification {
+                            data: Some(protobuf::app::server_message::Data::DirectMessageNotification(
+                                protobuf::app::DirectMessageNotification {
                                     sender_id: event.sender_player_id.clone(),
-                                    room_id: event.room_id.clone(),
                                     body: event.body.clone().into(),
                                 },
                             )),
                         },
                     );
                 }
-            }
-        }
-    }
-
-    fn try_to_send_output_message(
-        output_tx: &mpsc::UnboundedSender<protobuf::app::ServerMessage>,
-        message: protobuf::app::ServerMessage,
-    ) -> bool {
-        // output_tx.sendのエラー(output_rxがDrop or closeされている状態)は呼び出し元の次回ループでハンドリングされるので、Resultのハンドリングはしない。
-        output_tx.send(message).is_ok()
-    }
+                OutputEvent::Presence(presence) => {
+                    Self::try_to_send_output_message(
+                        output_tx,
+                        protobuf::app::ServerMessage {
+                            data: Some(protobuf::app::server_message::Data::PresenceNotification(
+                                protobuf::app::PresenceNotification {
+                                    presence: Some(Self::to_presence(&presence)),
+                                },
+                            )),
+                        },
+                    );
+                }
+                OutputEvent::ChannelMessage(event) => {
+                    Self::try_to_send_output_message(output_tx, event.notification());
+                }
+                OutputEvent::RoomClosed(event) => {
+                    joined_rooms.remove(&event.room_id);
+                    Self::update_presence(player_id, joined_rooms, registries);
+                    Self::try_to_send_output_message(
+                        output_tx,
+                        protobuf::app::ServerMessage {
+                            data: Some(protobuf::app::server_message::Data::RoomClosedNotification(
+                                protobuf::app::RoomClosedNotification {
+                                    room_id: event.room_id.clone(),
+                                    reason: event.reason.clone(),
+                                },
+                            )),
+                        },
+                    );
+                }
+                // Player actorのループで処理するので、ここには来ない。
+                OutputEvent::Kick(_) => {}
+                // メンテナンスの告知などを見逃さないように、機能の有無に関わらず送る。
+                OutputEvent::ServerAnnouncement(event) => {
+                    Self::try_to_send_output_message(
+                        output_tx,
+                        protobuf::app::ServerMessage {
+                            data: Some(protobuf::app::server_message::Data::ServerAnnouncementNotification(
+                                protobuf::app::ServerAnnouncementNotification {
+                                    message: event.message.clone(),
+                                },
+                            )),
+                        },
+                    );
+                }
+                OutputEvent::Message(event) => {
+                    // 対応していないクライアントにはReliableとして送る。
+                    let delivery = match event.delivery {
+                        Delivery::Unreliable
+                            if capabilities
+                                .contains(&protobuf::app::Capability::UnreliableDelivery) =>
+                        {
+                            protobuf::app::Delivery::Unreliable
+                        }
+                        _ => protobuf::app::Delivery::Reliable,
+                    };
+                    // エンコード済みの通知を他のプレイヤーと共有する。
+                    Self::try_to_send_output_message(output_tx, event.notification(delivery));
+                }
+            }
+        }
+    }
+
+    async fn on_party_message(
+        data: protobuf::app::client_message::Data,
+        output_tx: &OutputSender,
+        player: &entity::Player<OutputEvent>,
+        party: &mut Option<PartyMembership>,
+        registries: &Arc<Registries>,
+    ) {
+        match data {
+            protobuf::app::client_message::Data::CreatePartyRequest(_) => {
+                let response = match party {
+                    Some(party) => protobuf::app::CreatePartyResponse {
+                        party_id: party.id.clone(),
+                        error: Some(Self::new_error(
+                            protobuf::app::ErrorCode::AlreadyJoinedTheParty,
+                            "Already joined the party",
+                        )),
+                    },
+                    None => {
+                        let (party_id, party_tx) = registries.create_party(player.clone());
+                        *party = Some(PartyMembership {
+                            id: party_id.clone(),
+                            tx: party_tx,
+                            moving_room_id: None,
+                        });
+                        protobuf::app::CreatePartyResponse {
+                            party_id,
+                            error: Some(Self::new_error(protobuf::app::ErrorCode::None, "")),
+                        }
+                    }
+                };
+                Self::try_to_send_output_message(
+                    output_tx,
+                    protobuf::app::ServerMessage {
+                        data: Some(protobuf::app::server_message::Data::CreatePartyResponse(
+                            response,
+                        )),
+                    },
+                );
+            }
+            protobuf::app::client_message::Data::InvitePartyRequest(req) => match party {
+                Some(party) => {
+                    Self::send_party_event(
+                        party,
+                        PartyInputEvent::Invite(Box::new(InputPartyInviteEvent {
+                            inviter_id: player.id.clone(),
+                            player_id: req.player_id,
+                        })),
+                    )
+                    .await;
+                }
+                None => {
+                    Self::try_to_send_output_message(
+                        output_tx,
+                        protobuf::app::ServerMessage {
+                            data: Some(protobuf::app::server_message::Data::InvitePartyResponse(
+                                protobuf::app::InvitePartyResponse {
+                                    party_id: String::new(),
+                                    player_id: req.player_id,
+                                    error: Some(Self::new_error(
+                                        protobuf::app::ErrorCode::NotJoinedTheParty,
+                                        "You have not joined a party",
+                                    )),
+                                },
+                            )),
+                        },
+                    );
+                }
+            },
+            protobuf::app::client_message::Data::JoinPartyRequest(req) => {
+                let party_tx = match party {
+                    Some(_) => Err(Self::new_error(
+                        protobuf::app::ErrorCode::AlreadyJoinedTheParty,
+                        "Already joined a party",
+                    )),
+                    None => registries.get_party_channel(&req.party_id).ok_or_else(|| {
+                        Self::new_error(
+                            protobuf::app::ErrorCode::PartyNotFound,
+                            "The party does not exist",
+                        )
+                    }),
+                };
+                let result = match party_tx {
+                    Ok(party_tx) => party_tx
+                        .send(PartyInputEvent::Join(Box::new(InputPartyJoinEvent {
+                            player: player.clone(),
+                        })))
+                        .await
+                        .map_err(|_| {
+                            // 取得した直後にパーティが削除された。
+                            Self::new_error(
+                                protobuf::app::ErrorCode::PartyNotFound,
+                                "Party was removed during Join processing",
+                            )
+                        }),
+                    Err(err) => Err(err),
+                };
+                if let Err(error) = result {
+                    Self::try_to_send_output_message(
+                        output_tx,
+                        protobuf::app::ServerMessage {
+                            data: Some(protobuf::app::server_message::Data::JoinPartyResponse(
+                                protobuf::app::JoinPartyResponse {
+                                    party_id: req.party_id,
+                                    leader_id: String::new(),
+                                    member_ids: Vec::new(),
+                                    error: Some(error),
+                                },
+                            )),
+                        },
+                    );
+                }
+            }
+            protobuf::app::client_message::Data::LeavePartyRequest(_) => match party {
+                Some(party) => {
+                    Self::send_party_event(
+                        party,
+                        PartyInputEvent::Leave(Box::new(InputPartyLeaveEvent {
+                            player_id: player.id.clone(),
+                        })),
+                    )
+                    .await;
+                }
+                None => {
+                    Self::try_to_send_output_message(
+                        output_tx,
+                        protobuf::app::ServerMessage {
+                            data: Some(protobuf::app::server_message::Data::LeavePartyResponse(
+                                protobuf::app::LeavePartyResponse {
+                                    party_id: String::new(),
+                                    error: Some(Self::new_error(
+                                        protobuf::app::ErrorCode::NotJoinedTheParty,
+                                        "You have not joined a party",
+                                    )),
+                                },
+                            )),
+                        },
+                    );
+                }
+            },
+            protobuf::app::client_message::Data::PartyJoinRoomRequest(req) => match party {
+                Some(party) => {
+                    Self::send_party_event(
+                        party,
+                        PartyInputEvent::JoinRoom(Box::new(InputPartyJoinRoomEvent {
+                            requester_id: player.id.clone(),
+                            room_id: Some(req.room_id).filter(|room_id| !room_id.is_empty()),
+                            room_config: Self::to_room_config(req.room_config),
+                            room_properties: req.room_properties,
+                        })),
+                    )
+                    .await;
+                }
+                None => {
+                    Self::try_to_send_output_message(
+                        output_tx,
+                        protobuf::app::ServerMessage {
+                            data: Some(protobuf::app::server_message::Data::PartyJoinRoomResponse(
+                                protobuf::app::PartyJoinRoomResponse {
+                                    party_id: String::new(),
+                                    room_id: req.room_id,
+                                    error: Some(Self::new_error(
+                                        protobuf::app::ErrorCode::NotJoinedTheParty,
+                                        "You have not joined a party",
+                                    )),
+                                },
+                            )),
+                        },
+                    );
+                }
+            },
+            protobuf::app::client_message::Data::PartyMessageRequest(req) => match party {
+                Some(party) => {
+                    Self::send_party_event(
+                        party,
+                        PartyInputEvent::Message(Box::new(InputPartyMessageEvent {
+                            sender_player_id: player.id.clone(),
+                            body: req.body.into(),
+                        })),
+                    )
+                    .await;
+                }
+                None => {
+                    // SendMessageと同様にレスポンスを返さずベストエフォートな想定なので無視する。
+                    error!("Attempted to send a message to a party that is not joined");
+                }
+            },
+            _ => unreachable!("invalid message type for on_party_message"),
+        }
+    }
+
+    fn on_direct_message(
+        req: protobuf::app::DirectMessageRequest,
+        output_tx: &OutputSender,
+        player: &entity::Player<OutputEvent>,
+        registries: &Registries,
+    ) {
+        let event = OutputEvent::DirectMessage(Arc::new(OutputDirectMessageEvent {
+            sender_player_id: player.id.clone(),
+            body: req.body.into(),
+        }));
+        let ok = match registries.get_player(&req.target_id) {
+            // 送信に失敗するのは相手が切断処理中の場合。
+            Some(mut target) => target.send(event).is_ok(),
+            None => false,
+        };
+        let error = if ok {
+            Self::new_error(protobuf::app::ErrorCode::None, "")
+        } else {
+            Self::new_error(
+                protobuf::app::ErrorCode::PlayerNotFound,
+                "The player is not logged in",
+            )
+        };
+        Self::try_to_send_output_message(
+            output_tx,
+            protobuf::app::ServerMessage {
+                data: Some(protobuf::app::server_message::Data::DirectMessageResponse(
+                    protobuf::app::DirectMessageResponse {
+                        target_id: req.target_id,
+                        error: Some(error),
+                    },
+                )),
+            },
+        );
+    }
+
+    fn on_channel_message(
+        data: protobuf::app::client_message::Data,
+        output_tx: &OutputSender,
+        player: &entity::Player<OutputEvent>,
+        subscribed_channels: &mut HashSet<ChannelName>,
+        registries: &Registries,
+    ) {
+        match data {
+            protobuf::app::client_message::Data::ChannelSubscribeRequest(req) => {
+                let error = if req.channel.is_empty() {
+                    Self::new_error(
+                        protobuf::app::ErrorCode::FailedPrecondition,
+                        "channel is empty",
+                    )
+                } else {
+                    registries.subscribe_channel(&req.channel, player.clone());
+                    subscribed_channels.insert(req.channel.clone());
+                    Self::new_error(protobuf::app::ErrorCode::None, "")
+                };
+                Self::try_to_send_output_message(
+                    output_tx,
+                    protobuf::app::ServerMessage {
+                        data: Some(protobuf::app::server_message::Data::ChannelSubscribeResponse(
+                            protobuf::app::ChannelSubscribeResponse {
+                                channel: req.channel,
+                                error: Some(error),
+                            },
+                        )),
+                    },
+                );
+            }
+            protobuf::app::client_message::Data::ChannelUnsubscribeRequest(req) => {
+                let error = if subscribed_channels.remove(&req.channel) {
+                    registries.unsubscribe_channel(&req.channel, &player.id);
+                    Self::new_error(protobuf::app::ErrorCode::None, "")
+                } else {
+                    Self::new_error(
+                        protobuf::app::ErrorCode::NotSubscribedToTheChannel,
+                        "You have not subscribed to the channel",
+                    )
+                };
+                Self::try_to_send_output_message(
+                    output_tx,
+                    protobuf::app::ServerMessage {
+                        data: Some(protobuf::app::server_message::Data::ChannelUnsubscribeResponse(
+                            protobuf::app::ChannelUnsubscribeResponse {
+                                channel: req.channel,
+                                error: Some(error),
+                            },
+                        )),
+                    },
+                );
+            }
+            protobuf::app::client_message::Data::ChannelPublishRequest(req) => {
+                if !subscribed_channels.contains(&req.channel) {
+                    // SendMessageと同様にレスポンスを返さずベストエフォートな想定なので無視する。
+                    error!("Attempted to publish a message to a channel that is not subscribed");
+                    return;
+                }
+                registries.publish_channel_message(&req.channel, &player.id, req.body.into());
+            }
+            _ => unreachable!("invalid message type for on_channel_message"),
+        }
+    }
+
+    /// Join/Leaveしたことを、状態を購読しているプレイヤー達に通知する。
+    fn update_presence(
+        player_id: &entity::PlayerId,
+        joined_rooms: &HashMap<entity::RoomId, mpsc::Sender<InputEvent>>,
+        registries: &Registries,
+    ) {
+        let room_ids: Vec<entity::RoomId> = joined_rooms.keys().cloned().collect();
+        registries.set_player_room_ids(player_id, room_ids.clone());
+        registries.publish_presence(entity::Presence::online(player_id.clone(), room_ids));
+    }
+
+    fn to_presence(presence: &entity::Presence) -> protobuf::app::Presence {
+        let status = match presence.status {
+            entity::PresenceStatus::Offline => protobuf::app::PresenceStatus::Offline,
+            entity::PresenceStatus::Online => protobuf::app::PresenceStatus::Online,
+            entity::PresenceStatus::InRoom => protobuf::app::PresenceStatus::InRoom,
+        };
+        protobuf::app::Presence {
+            player_id: presence.player_id.clone(),
+            status: status as i32,
+            room_ids: presence.room_ids.clone(),
+        }
+    }
+
+    async fn send_party_event(party: &PartyMembership, event: PartyInputEvent) {
+        // パーティはメンバーがいる限りDropしないので、失敗するのはこのプレイヤーが既に抜けている場合。
+        if party.tx.send(event).await.is_err() {
+            debug!("Party has been dropped");
+        }
+    }
+
+    /// パーティで移動中のRoomへのJoinの結果をパーティに報告する。それ以外のRoomへのJoinでは何もしない。
+    async fn report_party_move(
+        party: &mut Option<PartyMembership>,
+        player_id: &entity::PlayerId,
+        room_id: &entity::RoomId,
+        result: Result<(), entity::PartyError>,
+    ) {
+        let party = match party {
+            Some(party) if party.moving_room_id.as_ref() == Some(room_id) => party,
+            _ => return,
+        };
+        party.moving_room_id = None;
+        Self::send_party_event(
+            party,
+            PartyInputEvent::JoinRoomResult(Box::new(InputPartyJoinRoomResultEvent {
+                player_id: player_id.clone(),
+                room_id: room_id.clone(),
+                result,
+            })),
+        )
+        .await;
+    }
+
+    async fn on_party_event(
+        event: OutputEvent,
+        output_tx: &OutputSender,
+        player: &entity::Player<OutputEvent>,
+        joined_rooms: &HashMap<entity::RoomId, mpsc::Sender<InputEvent>>,
+        party: &mut Option<PartyMembership>,
+        registries: &Arc<Registries>,
+    ) {
+        let party_id = party
+            .as_ref()
+            .map(|party| party.id.clone())
+            .unwrap_or_default();
+        let data = match event {
+            OutputEvent::PartyInvite(Ok(ev)) => {
+                if ev.player_id == player.id {
+                    protobuf::app::server_message::Data::PartyInviteNotification(
+                        protobuf::app::PartyInviteNotification {
+                            party_id: ev.party_id.clone(),
+                            inviter_id: ev.inviter_id.clone(),
+                        },
+                    )
+                } else {
+                    protobuf::app::server_message::Data::InvitePartyResponse(
+                        protobuf::app::InvitePartyResponse {
+                            party_id: ev.party_id.clone(),
+                            player_id: ev.player_id.clone(),
+                            error: Some(Self::new_error(protobuf::app::ErrorCode::None, "")),
+                        },
+                    )
+                }
+            }
+            OutputEvent::PartyInvite(Err(err)) => {
+                protobuf::app::server_message::Data::InvitePartyResponse(
+                    protobuf::app::InvitePartyResponse {
+                        party_id,
+                        player_id: String::new(),
+                        error: Some(Self::to_party_error(&err)),
+                    },
+                )
+            }
+            OutputEvent::PartyJoin(Ok(ev)) => {
+                if ev.player_id == player.id {
+                    match registries.get_party_channel(&ev.party_id) {
+                        Some(party_tx) => {
+                            *party = Some(PartyMembership {
+                                id: ev.party_id.clone(),
+                                tx: party_tx,
+                                moving_room_id: None,
+                            });
+                            protobuf::app::server_message::Data::JoinPartyResponse(
+                                protobuf::app::JoinPartyResponse {
+                                    party_id: ev.party_id.clone(),
+                                    leader_id: ev.leader_id.clone(),
+                                    member_ids: ev.member_ids.clone(),
+                                    error: Some(Self::new_error(
+                                        protobuf::app::ErrorCode::None,
+                                        "",
+                                    )),
+                                },
+                            )
+                        }
+                        None => {
+                            // Joinできた直後にパーティが削除された。
+                            protobuf::app::server_message::Data::JoinPartyResponse(
+                                protobuf::app::JoinPartyResponse {
+                                    party_id: ev.party_id.clone(),
+                                    leader_id: String::new(),
+                                    member_ids: Vec::new(),
+                                    error: Some(Self::new_error(
+                                        protobuf::app::ErrorCode::PartyNotFound,
+                                        "Party was deleted during Join processing",
+                                    )),
+                                },
+                            )
+                        }
+                    }
+                } else {
+                    protobuf::app::server_message::Data::PartyJoinNotification(
+                        protobuf::app::PartyJoinNotification {
+                            party_id: ev.party_id.clone(),
+                            player_id: ev.player_id.clone(),
+                        },
+                    )
+                }
+            }
+            OutputEvent::PartyJoin(Err(err)) => {
+                protobuf::app::server_message::Data::JoinPartyResponse(
+                    protobuf::app::JoinPartyResponse {
+                        party_id: err.party_id().cloned().unwrap_or_default(),
+                        leader_id: String::new(),
+                        member_ids: Vec::new(),
+                        error: Some(Self::to_party_error(&err)),
+                    },
+                )
+            }
+            OutputEvent::PartyLeave(ev) => {
+                if ev.player_id == player.id {
+                    *party = None;
+                    protobuf::app::server_message::Data::LeavePartyResponse(
+                        protobuf::app::LeavePartyResponse {
+                            party_id: ev.party_id.clone(),
+                            error: Some(Self::new_error(protobuf::app::ErrorCode::None, "")),
+                        },
+                    )
+                } else {
+                    protobuf::app::server_message::Data::PartyLeaveNotification(
+                        protobuf::app::PartyLeaveNotification {
+                            party_id: ev.party_id.clone(),
+                            player_id: ev.player_id.clone(),
+                            leader_id: ev.leader_id.clone(),
+                        },
+                    )
+                }
+            }
+            OutputEvent::PartyMessage(ev) => {
+                protobuf::app::server_message::Data::PartyMessageNotification(
+                    protobuf::app::PartyMessageNotification {
+                        party_id: ev.party_id.clone(),
+                        sender_id: ev.sender_player_id.clone(),
+                        body: ev.body.clone().into(),
+                    },
+                )
+            }
+            OutputEvent::PartyMoveRoom(ev) => {
+                // 移動が確定するまでは前のRoomに残る。Joinの結果はRoomから応答が届いた時にパーティへ報告する。
+                if let Some(party) = party.as_mut() {
+                    party.moving_room_id = Some(ev.room_id.clone());
+                }
+                // 予約した後にRoomが削除された場合、予約の無い新しいRoomを作ってしまわないように作成はしない。
+                let sent = match registries.claim_existing_room_seat(&ev.room_id) {
+                    Some(room_tx) => {
+                        Self::send_join_event(
+                            output_tx,
+                            player,
+                            ev.room_id.clone(),
+                            room_tx,
+                            ev.room_config.clone(),
+                        )
+                        .await
+                    }
+                    None => false,
+                };
+                if !sent {
+                    Self::report_party_move(
+                        party,
+                        &player.id,
+                        &ev.room_id,
+                        Err(entity::PartyError::RoomNotFound(ev.room_id.clone())),
+                    )
+                    .await;
+                }
+                return;
+            }
+            OutputEvent::PartyLeaveRoom(ev) => {
+                if let Some(room_tx) = joined_rooms.get(&ev.room_id) {
+                    let result = room_tx
+                        .send(InputEvent::Leave(Box::new(InputLeaveEvent {
+                            player_id: player.id.clone(),
+                        })))
+                        .await;
+                    if result.is_err() {
+                        debug!("Room has been dropped");
+                    }
+                }
+                return;
+            }
+            OutputEvent::PartyJoinRoom(Ok(ev)) => {
+                protobuf::app::server_message::Data::PartyJoinRoomResponse(
+                    protobuf::app::PartyJoinRoomResponse {
+                        party_id: ev.party_id.clone(),
+                        room_id: ev.room_id.clone(),
+                        error: Some(Self::new_error(protobuf::app::ErrorCode::None, "")),
+                    },
+                )
+            }
+            OutputEvent::PartyJoinRoom(Err(err)) => {
+                protobuf::app::server_message::Data::PartyJoinRoomResponse(
+                    protobuf::app::PartyJoinRoomResponse {
+                        party_id,
+                        room_id: String::new(),
+                        error: Some(Self::to_party_error(&err)),
+                    },
+                )
+            }
+            _ => unreachable!("invalid event type for on_party_event"),
+        };
+        Self::try_to_send_output_message(
+            output_tx,
+            protobuf::app::ServerMessage { data: Some(data) },
+        );
+    }
+
+    fn new_error(code: protobuf::app::ErrorCode, message: &str) -> protobuf::app::Error {
+        protobuf::app::Error {
+            code: code as i32,
+            message: message.to_string(),
+        }
+    }
+
+    fn to_party_error(err: &entity::PartyError) -> protobuf::app::Error {
+        let code = match err {
+            entity::PartyError::NotPartyLeader(..) => protobuf::app::ErrorCode::NotPartyLeader,
+            entity::PartyError::NotInvited(..) => protobuf::app::ErrorCode::NotInvitedToTheParty,
+            entity::PartyError::AlreadyJoinedParty(..) => {
+                protobuf::app::ErrorCode::AlreadyJoinedTheParty
+            }
+            entity::PartyError::PlayerNotFound(..) => protobuf::app::ErrorCode::PlayerNotFound,
+            entity::PartyError::PartyNotFound(..) => protobuf::app::ErrorCode::PartyNotFound,
+            entity::PartyError::MovingRoom(..) => protobuf::app::ErrorCode::FailedPrecondition,
+            entity::PartyError::RoomNotFound(..) => protobuf::app::ErrorCode::RoomNotFound,
+            entity::PartyError::Room(err) => return Self::to_error(err),
+        };
+        protobuf::app::Error {
+            code: code as i32,
+            message: err.to_string(),
+        }
+    }
+
+    fn to_error(err: &entity::RoomError) -> protobuf::app::Error {
+        let code = match err {
+            entity::RoomError::AlreadyJoinedRoom(..) => protobuf::app::ErrorCode::AlreadyJoinedTheRoom,
+            entity::RoomError::RoomConfigDoesNotMatch(..) => {
+                protobuf::app::ErrorCode::RoomConfigDoesNotMatch
+            }
+            entity::RoomError::RoomIsFull(..) => protobuf::app::ErrorCode::RoomIsFull,
+            entity::RoomError::RoomClosed(..) => protobuf::app::ErrorCode::RoomClosed,
+            entity::RoomError::TooManyReservations(..) => {
+                protobuf::app::ErrorCode::TooManyReservations
+            }
+        };
+        protobuf::app::Error {
+            code: code as i32,
+            message: err.to_string(),
+        }
+    }
+
+    fn try_to_send_output_message(
+        output_tx: &OutputSender,
+        message: impl Into<OutputMessage>,
+    ) -> bool {
+        // output_tx.sendのエラー(output_rxがDrop or closeされている状態)は呼び出し元の次回ループでハンドリングされるので、Resultのハンドリングはしない。
+        output_tx.send(message).is_ok()
+    }
 }
 
 #[cfg(test)]
@@ -535,12 +1730,34 @@ mod tests {
             auth: config::Auth {
                 enable_bearer: true,
                 bearer: "bearer".to_string(),
+                trusted_unix_uids: Vec::new(),
             },
             tls: config::Tls {
                 enable: false,
                 cert_file_path: "".to_string(),
                 key_file_path: "".to_string(),
             },
+            compression: config::Compression {
+                enable: true,
+                threshold: 1024,
+            },
+            compatibility: config::Compatibility {
+                min_protocol_version: 0,
+            },
+            limits: config::Limits {
+                max_frame_size: 1024 * 1024,
+                max_body_size: 64 * 1024,
+                max_reservation_timeout: crate::actor::DEFAULT_RESERVATION_TIMEOUT,
+                max_reservations_per_requester: 8,
+                max_udp_sessions: 1024,
+                max_http_sessions: 1024,
+                max_http_sessions_per_addr: 1024,
+            },
+            outbound: config::Outbound {
+                queue_size: 1024,
+                overflow_policy: config::OverflowPolicy::Disconnect,
+            },
+            admin: config::Admin::default(),
         })
     }
 
@@ -551,12 +1768,16 @@ mod tests {
 
         let p1_id = "p1".to_string();
         let p2_id = "p2".to_string();
-        let mut p1 = Player::new(config.clone());
-        let mut p2 = Player::new(config);
+        let registries = Arc::new(Registries::new());
+        let mut p1 = Player::new(config.clone(), registries.clone());
+        let mut p2 = Player::new(config, registries.clone());
 
         p1.send(app::ClientMessage {
             data: Some(app::client_message::Data::LoginRequest(app::LoginRequest {
                 player_id: p1_id.clone(),
+                compression: app::Compression::Uncompressed as i32,
+                protocol_version: crate::protobuf::PROTOCOL_VERSION,
+                capabilities: Vec::new(),
                 auth_config: Some(app::login_request::AuthConfig::Bearer(
                     app::AuthConfigBearer {
                         token: "bearer".to_string(),
@@ -564,9 +1785,10 @@ mod tests {
                 )),
             })),
         })
+        .await
         .unwrap();
 
-        let data = p1.recv().await.unwrap().data.unwrap();
+        let data = p1.recv().await.unwrap().into_message().data.unwrap();
         if let app::server_message::Data::LoginResponse(res) = data {
             assert_eq!(app::ErrorCode::None as i32, res.error.unwrap().code);
         } else {
@@ -577,11 +1799,13 @@ mod tests {
             data: Some(app::client_message::Data::JoinRequest(app::JoinRequest {
                 room_id: room_id.clone(),
                 room_config: Some(app::RoomConfig { max_players: 2 }),
+                room_properties: HashMap::new(),
             })),
         })
+        .await
         .unwrap();
 
-        let data = p1.recv().await.unwrap().data.unwrap();
+        let data = p1.recv().await.unwrap().into_message().data.unwrap();
         if let app::server_message::Data::JoinResponse(res) = data {
             assert_eq!(room_id, res.room_id);
             assert_eq!(app::ErrorCode::None as i32, res.error.unwrap().code);
@@ -592,6 +1816,9 @@ mod tests {
         p2.send(app::ClientMessage {
             data: Some(app::client_message::Data::LoginRequest(app::LoginRequest {
                 player_id: p2_id.clone(),
+                compression: app::Compression::Uncompressed as i32,
+                protocol_version: crate::protobuf::PROTOCOL_VERSION,
+                capabilities: Vec::new(),
                 auth_config: Some(app::login_request::AuthConfig::Bearer(
                     app::AuthConfigBearer {
                         token: "bearer".to_string(),
@@ -599,9 +1826,10 @@ mod tests {
                 )),
             })),
         })
+        .await
         .unwrap();
 
-        let data = p2.recv().await.unwrap().data.unwrap();
+        let data = p2.recv().await.unwrap().into_message().data.unwrap();
         if let app::server_message::Data::LoginResponse(res) = data {
             assert_eq!(app::ErrorCode::None as i32, res.error.unwrap().code);
         } else {
@@ -612,11 +1840,13 @@ mod tests {
             data: Some(app::client_message::Data::JoinRequest(app::JoinRequest {
                 room_id: room_id.clone(),
                 room_config: Some(app::RoomConfig { max_players: 2 }),
+                room_properties: HashMap::new(),
             })),
         })
+        .await
         .unwrap();
 
-        let data = p2.recv().await.unwrap().data.unwrap();
+        let data = p2.recv().await.unwrap().into_message().data.unwrap();
         if let app::server_message::Data::JoinResponse(res) = data {
             assert_eq!(room_id, res.room_id);
             assert_eq!(app::ErrorCode::None as i32, res.error.unwrap().code);
@@ -624,7 +1854,7 @@ mod tests {
             panic!("Unexpected message. {:?}", data);
         }
 
-        let data = p1.recv().await.unwrap().data.unwrap();
+        let data = p1.recv().await.unwrap().into_message().data.unwrap();
         if let app::server_message::Data::JoinNotification(notification) = data {
             assert_eq!(room_id, notification.room_id);
             assert_eq!(p2_id, notification.player_id);
@@ -637,9 +1867,10 @@ mod tests {
                 room_id: room_id.clone(),
             })),
         })
+        .await
         .unwrap();
 
-        let data = p2.recv().await.unwrap().data.unwrap();
+        let data = p2.recv().await.unwrap().into_message().data.unwrap();
         if let app::server_message::Data::LeaveResponse(res) = data {
             assert_eq!(room_id, res.room_id);
             assert_eq!(app::ErrorCode::None as i32, res.error.unwrap().code);
@@ -647,7 +1878,7 @@ mod tests {
             panic!("Unexpected message. {:?}", data);
         }
 
-        let data = p1.recv().await.unwrap().data.unwrap();
+        let data = p1.recv().await.unwrap().into_message().data.unwrap();
         if let app::server_message::Data::LeaveNotification(notification) = data {
             assert_eq!(room_id, notification.room_id);
             assert_eq!(p2_id, notification.player_id);
@@ -660,9 +1891,10 @@ mod tests {
                 room_id: room_id.clone(),
             })),
         })
+        .await
         .unwrap();
 
-        let data = p1.recv().await.unwrap().data.unwrap();
+        let data = p1.recv().await.unwrap().into_message().data.unwrap();
         if let app::server_message::Data::LeaveResponse(res) = data {
             assert_eq!(room_id, res.room_id);
             assert_eq!(app::ErrorCode::None as i32, res.error.unwrap().code);
@@ -671,7 +1903,484 @@ mod tests {
         }
 
         // Roomが掃除されていることを確認しておく。
-        let room_tx = get_room_channel(&room_id).await;
+        let room_tx = registries.get_room_channel(&room_id);
         assert!(room_tx.is_none());
     }
+
+    #[tokio::test]
+    async fn reject_login_from_too_old_client() {
+        let mut config = (*default_config()).clone();
+        config.compatibility.min_protocol_version = 2;
+        let config = Arc::new(config);
+        let registries = Arc::new(Registries::new());
+        let player_id = "too_old".to_string();
+
+        for (protocol_version, code) in [
+            (1, app::ErrorCode::ClientTooOld),
+            (2, app::ErrorCode::None),
+        ] {
+            let mut p = Player::new(config.clone(), registries.clone());
+            p.send(app::ClientMessage {
+                data: Some(app::client_message::Data::LoginRequest(app::LoginRequest {
+                    player_id: player_id.clone(),
+                    compression: app::Compression::Uncompressed as i32,
+                    protocol_version,
+                    capabilities: vec![
+                        app::Capability::ServerAnnouncement as i32,
+                        app::Capability::UnknownCapability as i32,
+                        // 未知の機能は無視される。
+                        100,
+                    ],
+                    auth_config: Some(app::login_request::AuthConfig::Bearer(
+                        app::AuthConfigBearer {
+                            token: "bearer".to_string(),
+                        },
+                    )),
+                })),
+            })
+            .await
+            .unwrap();
+
+            let data = p.recv().await.unwrap().into_message().data.unwrap();
+            if let app::server_message::Data::LoginResponse(res) = data {
+                assert_eq!(code as i32, res.error.unwrap().code);
+                assert_eq!(crate::protobuf::PROTOCOL_VERSION, res.protocol_version);
+                if code == app::ErrorCode::None {
+                    assert_eq!(
+                        vec![app::Capability::ServerAnnouncement as i32],
+                        res.capabilities
+                    );
+                } else {
+                    assert!(res.capabilities.is_empty());
+                    // 切断される。
+                    assert!(p.recv().await.is_none());
+                }
+            } else {
+                panic!("Unexpected message. {:?}", data);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn login_with_same_id_to_independent_registries() {
+        let config = default_config();
+        let registries = Arc::new(Registries::new());
+        let other_registries = Arc::new(Registries::new());
+
+        // 別のレジストリには同じIDでログインできるが、同じレジストリでは二重ログインになる。
+        let mut players = Vec::new();
+        for (registries, code) in [
+            (&registries, app::ErrorCode::None),
+            (&registries, app::ErrorCode::AlreadyLoggedIn),
+            (&other_registries, app::ErrorCode::None),
+        ] {
+            let mut p = Player::new(config.clone(), registries.clone());
+            p.send(app::ClientMessage {
+                data: Some(app::client_message::Data::LoginRequest(app::LoginRequest {
+                    player_id: "same_id".to_string(),
+                    compression: app::Compression::Uncompressed as i32,
+                    protocol_version: crate::protobuf::PROTOCOL_VERSION,
+                    capabilities: Vec::new(),
+                    auth_config: Some(app::login_request::AuthConfig::Bearer(
+                        app::AuthConfigBearer {
+                            token: "bearer".to_string(),
+                        },
+                    )),
+                })),
+            })
+            .await
+            .unwrap();
+
+            let data = p.recv().await.unwrap().into_message().data.unwrap();
+            if let app::server_message::Data::LoginResponse(res) = data {
+                assert_eq!(code as i32, res.error.unwrap().code);
+            } else {
+                panic!("Unexpected message. {:?}", data);
+            }
+            players.push(p);
+        }
+        let player = other_registries.get_player(&"same_id".to_string());
+        assert!(player.is_some());
+
+        // 二重ログインを拒否しても、先にログインしたプレイヤーは登録されたまま。
+        let player = registries.get_player(&"same_id".to_string());
+        assert!(player.is_some());
+        let mut sender = Player::new(config.clone(), registries.clone());
+        sender
+            .send(app::ClientMessage {
+                data: Some(app::client_message::Data::LoginRequest(app::LoginRequest {
+                    player_id: "sender".to_string(),
+                    compression: app::Compression::Uncompressed as i32,
+                    protocol_version: crate::protobuf::PROTOCOL_VERSION,
+                    capabilities: Vec::new(),
+                    auth_config: Some(app::login_request::AuthConfig::Bearer(
+                        app::AuthConfigBearer {
+                            token: "bearer".to_string(),
+                        },
+                    )),
+                })),
+            })
+            .await
+            .unwrap();
+        sender.recv().await.unwrap();
+        sender
+            .send(app::ClientMessage {
+                data: Some(app::client_message::Data::DirectMessageRequest(
+                    app::DirectMessageRequest {
+                        target_id: "same_id".to_string(),
+                        body: b"hello".to_vec(),
+                    },
+                )),
+            })
+            .await
+            .unwrap();
+
+        let data = players[0].recv().await.unwrap().into_message().data.unwrap();
+        if let app::server_message::Data::DirectMessageNotification(notification) = data {
+            assert_eq!("sender", notification.sender_id);
+            assert_eq!(b"hello".to_vec(), notification.body);
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_message_to_room_not_joined() {
+        let config = default_config();
+        let room_id = "not_joined".to_string();
+        let mut p = Player::new(config, Arc::new(Registries::new()));
+        p.send(app::ClientMessage {
+            data: Some(app::client_message::Data::LoginRequest(app::LoginRequest {
+                player_id: "p1".to_string(),
+                compression: app::Compression::Uncompressed as i32,
+                protocol_version: crate::protobuf::PROTOCOL_VERSION,
+                capabilities: Vec::new(),
+                auth_config: Some(app::login_request::AuthConfig::Bearer(
+                    app::AuthConfigBearer {
+                        token: "bearer".to_string(),
+                    },
+                )),
+            })),
+        })
+        .await
+        .unwrap();
+
+        let data = p.recv().await.unwrap().into_message().data.unwrap();
+        if let app::server_message::Data::LoginResponse(res) = data {
+            assert_eq!(app::ErrorCode::None as i32, res.error.unwrap().code);
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+
+        let send_message = app::ClientMessage {
+            data: Some(app::client_message::Data::SendMessage(app::SendMessage {
+                target_ids: Vec::new(),
+                room_id: room_id.clone(),
+                body: b"hello".to_vec(),
+                delivery: app::Delivery::Reliable as i32,
+            })),
+        };
+        p.send(send_message.clone()).await.unwrap();
+
+        let data = p.recv().await.unwrap().into_message().data.unwrap();
+        if let app::server_message::Data::SendMessageErrorNotification(notification) = data {
+            assert_eq!(room_id, notification.room_id);
+            assert_eq!(
+                app::ErrorCode::NotJoinedTheRoom as i32,
+                notification.error.unwrap().code
+            );
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+
+        // 参加した後は送信できる。
+        p.send(app::ClientMessage {
+            data: Some(app::client_message::Data::JoinRequest(app::JoinRequest {
+                room_id: room_id.clone(),
+                room_config: None,
+                room_properties: HashMap::new(),
+            })),
+        })
+        .await
+        .unwrap();
+
+        let data = p.recv().await.unwrap().into_message().data.unwrap();
+        if let app::server_message::Data::JoinResponse(res) = data {
+            assert_eq!(app::ErrorCode::None as i32, res.error.unwrap().code);
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+
+        p.send(send_message).await.unwrap();
+
+        let data = p.recv().await.unwrap().into_message().data.unwrap();
+        if let app::server_message::Data::MessageNotification(notification) = data {
+            assert_eq!(room_id, notification.room_id);
+            assert_eq!(b"hello".to_vec(), notification.body);
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnect_after_too_large_message() {
+        let config = default_config();
+        let mut p = Player::new(config.clone(), Arc::new(Registries::new()));
+        p.send(app::ClientMessage {
+            data: Some(app::client_message::Data::LoginRequest(app::LoginRequest {
+                player_id: "too_large".to_string(),
+                compression: app::Compression::Uncompressed as i32,
+                protocol_version: crate::protobuf::PROTOCOL_VERSION,
+                capabilities: Vec::new(),
+                auth_config: Some(app::login_request::AuthConfig::Bearer(
+                    app::AuthConfigBearer {
+                        token: "bearer".to_string(),
+                    },
+                )),
+            })),
+        })
+        .await
+        .unwrap();
+
+        let data = p.recv().await.unwrap().into_message().data.unwrap();
+        if let app::server_message::Data::LoginResponse(res) = data {
+            assert_eq!(app::ErrorCode::None as i32, res.error.unwrap().code);
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+
+        p.send(app::ClientMessage {
+            data: Some(app::client_message::Data::SendMessage(app::SendMessage {
+                target_ids: Vec::new(),
+                room_id: "too_large".to_string(),
+                body: vec![0; config.limits.max_body_size + 1],
+                delivery: app::Delivery::Reliable as i32,
+            })),
+        })
+        .await
+        .unwrap();
+
+        let data = p.recv().await.unwrap().into_message().data.unwrap();
+        if let app::server_message::Data::ErrorNotification(notification) = data {
+            assert_eq!(
+                app::ErrorCode::MessageTooLarge as i32,
+                notification.error.unwrap().code
+            );
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+        // 切断される。
+        assert!(p.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn disconnect_when_event_queue_overflowed() {
+        let registries = Arc::new(Registries::new());
+        let mut p = Player::new(default_config(), registries.clone());
+        p.send(app::ClientMessage {
+            data: Some(app::client_message::Data::LoginRequest(app::LoginRequest {
+                player_id: "slow".to_string(),
+                compression: app::Compression::Uncompressed as i32,
+                protocol_version: crate::protobuf::PROTOCOL_VERSION,
+                capabilities: Vec::new(),
+                auth_config: Some(app::login_request::AuthConfig::Bearer(
+                    app::AuthConfigBearer {
+                        token: "bearer".to_string(),
+                    },
+                )),
+            })),
+        })
+        .await
+        .unwrap();
+        p.recv().await.unwrap();
+
+        // Player actorが取り出す前にイベントのキューを溢れさせる。
+        let before = METRICS.slow_consumer_disconnects();
+        let mut player = registries.get_player(&"slow".to_string()).unwrap();
+        let event = OutputEvent::ServerAnnouncement(Arc::new(OutputServerAnnouncementEvent {
+            message: "announcement".to_string(),
+        }));
+        for _ in 0..EVENT_CHANNEL_CAPACITY {
+            player.send(event.clone()).unwrap();
+        }
+        assert!(matches!(
+            player.send(event),
+            Err(mpsc::error::TrySendError::Full(_))
+        ));
+        assert!(player.is_overflowed());
+
+        // イベントを捨てているので、届けずに切断する。
+        assert!(p.recv().await.is_none());
+        assert!(METRICS.slow_consumer_disconnects() > before);
+        assert!(registries.get_player(&"slow".to_string()).is_none());
+    }
+
+    #[tokio::test]
+    async fn login_without_token_from_trusted_peer() {
+        let mut config = (*default_config()).clone();
+        config.auth.trusted_unix_uids = vec![1000];
+        let config = Arc::new(config);
+        let registries = Arc::new(Registries::new());
+
+        for (uid, code) in [
+            (1001, app::ErrorCode::Unauthorized),
+            (1000, app::ErrorCode::None),
+        ] {
+            let mut p = Player::with_connection(
+                config.clone(),
+                registries.clone(),
+                ConnectionInfo {
+                    peer_credentials: Some(PeerCredentials {
+                        uid,
+                        gid: uid,
+                        pid: None,
+                    }),
+                    ..Default::default()
+                },
+            );
+            p.send(app::ClientMessage {
+                data: Some(app::client_message::Data::LoginRequest(app::LoginRequest {
+                    player_id: "trusted_peer".to_string(),
+                    compression: app::Compression::Uncompressed as i32,
+                    protocol_version: crate::protobuf::PROTOCOL_VERSION,
+                    capabilities: Vec::new(),
+                    auth_config: None,
+                })),
+            })
+            .await
+            .unwrap();
+
+            let data = p.recv().await.unwrap().into_message().data.unwrap();
+            if let app::server_message::Data::LoginResponse(res) = data {
+                assert_eq!(code as i32, res.error.unwrap().code);
+            } else {
+                panic!("Unexpected message. {:?}", data);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn close_room_and_kick_player_by_admin() {
+        let config = default_config();
+        let room_id = "closed".to_string();
+        let player_id = "p1".to_string();
+        let registries = Arc::new(Registries::new());
+        let mut p = Player::new(config, registries.clone());
+        p.send(app::ClientMessage {
+            data: Some(app::client_message::Data::LoginRequest(app::LoginRequest {
+                player_id: player_id.clone(),
+                compression: app::Compression::Uncompressed as i32,
+                protocol_version: crate::protobuf::PROTOCOL_VERSION,
+                capabilities: Vec::new(),
+                auth_config: Some(app::login_request::AuthConfig::Bearer(
+                    app::AuthConfigBearer {
+                        token: "bearer".to_string(),
+                    },
+                )),
+            })),
+        })
+        .await
+        .unwrap();
+        p.recv().await.unwrap();
+
+        p.send(app::ClientMessage {
+            data: Some(app::client_message::Data::JoinRequest(app::JoinRequest {
+                room_id: room_id.clone(),
+                room_config: None,
+                room_properties: HashMap::new(),
+            })),
+        })
+        .await
+        .unwrap();
+        p.recv().await.unwrap();
+
+        let room = registries.get_room_info(&room_id).unwrap();
+        assert_eq!(vec![player_id.clone()], room.player_ids);
+        let player = registries.get_player_info(&player_id).unwrap();
+        assert_eq!(vec![room_id.clone()], player.room_ids);
+
+        assert!(registries.close_room(&room_id, "maintenance".to_string()).await);
+        let data = p.recv().await.unwrap().into_message().data.unwrap();
+        if let app::server_message::Data::RoomClosedNotification(notification) = data {
+            assert_eq!(room_id, notification.room_id);
+            assert_eq!("maintenance", notification.reason);
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+        assert!(registries.get_room_info(&room_id).is_none());
+        assert!(registries.list_players()[0].room_ids.is_empty());
+
+        assert_eq!(Ok(()), registries.kick_player(&player_id, "cheating".to_string()));
+        let data = p.recv().await.unwrap().into_message().data.unwrap();
+        if let app::server_message::Data::ErrorNotification(notification) = data {
+            let error = notification.error.unwrap();
+            assert_eq!(app::ErrorCode::Kicked as i32, error.code);
+            assert_eq!("cheating", error.message);
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+        assert!(p.recv().await.is_none());
+        assert!(registries.get_player_info(&player_id).is_none());
+        assert_eq!(
+            Err(KickError::NotFound),
+            registries.kick_player(&player_id, "cheating".to_string())
+        );
+    }
+
+    #[test]
+    fn kick_player_without_waiting_for_full_queue() {
+        let registries = Registries::new();
+        let (tx, _rx) = mpsc::channel(1);
+        let player = entity::Player::new("kicked".to_string(), tx);
+        assert!(registries.register_player(player.clone(), &ConnectionInfo::default()));
+
+        assert_eq!(Ok(()), registries.kick_player(&player.id, "cheating".to_string()));
+        assert_eq!(
+            Err(KickError::QueueFull),
+            registries.kick_player(&player.id, "cheating".to_string())
+        );
+        assert!(player.is_overflowed());
+    }
+
+    #[tokio::test]
+    async fn server_announcement_to_all_logged_in_players() {
+        let config = default_config();
+        let registries = Arc::new(Registries::new());
+        let mut players = Vec::new();
+        // 機能を提示していないクライアントにも届く。
+        for (player_id, capabilities) in [
+            ("p1", vec![app::Capability::ServerAnnouncement as i32]),
+            ("p2", Vec::new()),
+            ("p3", Vec::new()),
+        ] {
+            let mut p = Player::new(config.clone(), registries.clone());
+            p.send(app::ClientMessage {
+                data: Some(app::client_message::Data::LoginRequest(app::LoginRequest {
+                    player_id: player_id.to_string(),
+                    compression: app::Compression::Uncompressed as i32,
+                    protocol_version: crate::protobuf::PROTOCOL_VERSION,
+                    capabilities,
+                    auth_config: Some(app::login_request::AuthConfig::Bearer(
+                        app::AuthConfigBearer {
+                            token: "bearer".to_string(),
+                        },
+                    )),
+                })),
+            })
+            .await
+            .unwrap();
+            p.recv().await.unwrap();
+            players.push(p);
+        }
+
+        registries.publish_server_announcement("maintenance at 12:00".to_string());
+        for p in players.iter_mut() {
+            let data = p.recv().await.unwrap().into_message().data.unwrap();
+            if let app::server_message::Data::ServerAnnouncementNotification(notification) = data {
+                assert_eq!("maintenance at 12:00", notification.message);
+            } else {
+                panic!("Unexpected message. {:?}", data);
+            }
+        }
+    }
 }
@@ -0,0 +1,69 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use tracing::debug;
+
+use super::event::*;
+use super::registry::*;
+use crate::entity;
+
+impl Registries {
+    /// `player_ids`の状態の変化を`subscriber_id`に通知するように登録し、現在の状態を返す。
+    pub fn subscribe_presence(
+        &self,
+        subscriber_id: &entity::PlayerId,
+        player_ids: &[entity::PlayerId],
+    ) -> Vec<entity::Presence> {
+        player_ids.iter().for_each(|player_id| {
+            self.presence_subscribers.get_or_insert_with(
+                player_id.clone(),
+                HashSet::new,
+                |subscriber_ids| {
+                    subscriber_ids.insert(subscriber_id.clone());
+                },
+            );
+        });
+
+        player_ids
+            .iter()
+            .map(|player_id| self.get_presence(player_id))
+            .collect()
+    }
+
+    pub fn unsubscribe_presence<'a>(
+        &self,
+        subscriber_id: &entity::PlayerId,
+        player_ids: impl IntoIterator<Item = &'a entity::PlayerId>,
+    ) {
+        player_ids.into_iter().for_each(|player_id| {
+            self.presence_subscribers
+                .update(player_id, |subscriber_ids| {
+                    subscriber_ids.remove(subscriber_id);
+                });
+            self.presence_subscribers
+                .remove_if(player_id, |subscriber_ids| subscriber_ids.is_empty());
+        });
+    }
+
+    /// 状態が変化したプレイヤーを購読しているプレイヤー達に通知する。
+    pub fn publish_presence(&self, presence: entity::Presence) {
+        let subscriber_ids: Vec<entity::PlayerId> = match self
+            .presence_subscribers
+            .get_with(&presence.player_id, |subscriber_ids| {
+                subscriber_ids.iter().cloned().collect()
+            }) {
+            Some(subscriber_ids) => subscriber_ids,
+            None => return,
+        };
+
+        let event = OutputEvent::Presence(Arc::new(presence));
+        for subscriber_id in subscriber_ids {
+            // 購読しているプレイヤーがオフラインの場合は通知しない。
+            if let Some(mut subscriber) = self.get_player(&subscriber_id) {
+                if subscriber.send(event.clone()).is_err() {
+                    debug!("Subscriber disconnected. player_id={}", subscriber_id);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! プレイヤーやRoomなどのactorをIDから引くためのレジストリ。
+//! キーのハッシュ値でシャードに分けてロックするので、異なるシャードのキーへの操作は互いに待たない。
+//! ロックを保持したままawaitしないように、操作は全て同期的なクロージャで行う。
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use tokio::sync::mpsc;
+
+use super::channel::*;
+use super::event::*;
+use super::player::*;
+use super::room::*;
+use super::watch::*;
+use crate::entity;
+
+const DEFAULT_NUM_SHARDS: usize = 32;
+
+type Shard<K, V> = RwLock<HashMap<K, V>>;
+
+pub struct Registry<K, V> {
+    shards: Box<[Shard<K, V>]>,
+    hasher: RandomState,
+}
+
+impl<K: Eq + Hash, V> Registry<K, V> {
+    pub fn new() -> Self {
+        Self::with_shards(DEFAULT_NUM_SHARDS)
+    }
+
+    pub fn with_shards(num_shards: usize) -> Self {
+        assert!(num_shards > 0, "num_shards must be greater than 0");
+        Self {
+            shards: (0..num_shards)
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Shard<K, V> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    // ロック中にクロージャがpanicしても、マップ自体は壊れていないのでそのまま使い続ける。
+    fn read(shard: &Shard<K, V>) -> RwLockReadGuard<'_, HashMap<K, V>> {
+        shard.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(shard: &Shard<K, V>) -> RwLockWriteGuard<'_, HashMap<K, V>> {
+        shard.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        Self::read(self.shard(key)).contains_key(key)
+    }
+
+    pub fn get_with<R>(&self, key: &K, f: impl FnOnce(&V) -> R) -> Option<R> {
+        Self::read(self.shard(key)).get(key).map(f)
+    }
+
+    /// 既に同じキーが登録されている場合は何もせずにfalseを返す。
+    pub fn insert_if_absent(&self, key: K, value: V) -> bool {
+        let mut shard = Self::write(self.shard(&key));
+        if shard.contains_key(&key) {
+            return false;
+        }
+        shard.insert(key, value);
+        true
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        Self::write(self.shard(&key)).insert(key, value)
+    }
+
+    /// キーが無ければ`init`の値を登録してから、`f`で更新する。
+    pub fn get_or_insert_with<R>(
+        &self,
+        key: K,
+        init: impl FnOnce() -> V,
+        f: impl FnOnce(&mut V) -> R,
+    ) -> R {
+        let mut shard = Self::write(self.shard(&key));
+        f(shard.entry(key).or_insert_with(init))
+    }
+
+    pub fn update<R>(&self, key: &K, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        Self::write(self.shard(key)).get_mut(key).map(f)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        Self::write(self.shard(key)).remove(key)
+    }
+
+    /// `f`がtrueを返した場合だけ削除する。判定と削除の間に他から更新されることは無い。
+    pub fn remove_if(&self, key: &K, f: impl FnOnce(&V) -> bool) -> Option<V> {
+        let mut shard = Self::write(self.shard(key));
+        if f(shard.get(key)?) {
+            shard.remove(key)
+        } else {
+            None
+        }
+    }
+
+    /// 全ての要素を畳み込む。シャードごとに順番にロックするので、全体の一貫したスナップショットではない。
+    pub fn fold<B>(&self, init: B, mut f: impl FnMut(B, &K, &V) -> B) -> B {
+        self.shards.iter().fold(init, |acc, shard| {
+            Self::read(shard)
+                .iter()
+                .fold(acc, |acc, (key, value)| f(acc, key, value))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| Self::read(shard).len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| Self::read(shard).is_empty())
+    }
+}
+
+impl<K: Eq + Hash, V> Default for Registry<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 1つのサーバが使うレジストリ一式。
+/// 異なるインスタンスを使うサーバ同士ではプレイヤーやRoomが共有されないので、同じプロセスで独立したサーバを動かせる。
+#[derive(Default)]
+pub struct Registries {
+    pub(super) players: Registry<entity::PlayerId, PlayerEntry>,
+    pub(super) rooms: Registry<entity::RoomId, RoomEntry>,
+    pub(super) parties: Registry<entity::PartyId, mpsc::Sender<PartyInputEvent>>,
+    pub(super) channels: Registry<ChannelName, Subscribers>,
+    // 状態を購読されているプレイヤーのIDと、購読しているプレイヤーのID。
+    pub(super) presence_subscribers: Registry<entity::PlayerId, HashSet<entity::PlayerId>>,
+    pub(super) watch_events: WatchEvents,
+    // 条件に一致するRoomが無い場合のRoomの作成を1つずつ行うためのロック。
+    pub(super) random_room_creation: Mutex<()>,
+}
+
+impl Registries {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 各actorの入力キューに溜まっているイベントの数を集計する。
+    pub fn queue_depths(&self) -> QueueDepths {
+        QueueDepths {
+            player: self.players.fold(QueueDepth::default(), |depth, _, entry| {
+                depth.add(entry.queue_depth())
+            }),
+            room: self.rooms.fold(QueueDepth::default(), |depth, _, entry| {
+                depth.add(entry.queue_depth())
+            }),
+            party: self.parties.fold(QueueDepth::default(), |depth, _, tx| {
+                depth.add(tx.max_capacity() - tx.capacity())
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueDepth {
+    pub total: usize,
+    // 最も溜まっているキューの長さ。
+    pub max: usize,
+}
+
+impl QueueDepth {
+    fn add(self, depth: usize) -> Self {
+        Self {
+            total: self.total + depth,
+            max: self.max.max(depth),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueDepths {
+    pub player: QueueDepth,
+    pub room: QueueDepth,
+    pub party: QueueDepth,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+
+    use super::*;
+
+    #[test]
+    fn update_and_remove_across_shards() {
+        let registry = Registry::with_shards(4);
+        for i in 0..100 {
+            assert!(registry.insert_if_absent(i, i));
+        }
+        assert!(!registry.insert_if_absent(0, 1));
+        assert_eq!(100, registry.len());
+        assert_eq!(Some(99), registry.get_with(&99, |value| *value));
+
+        registry.update(&1, |value| *value = 0);
+        assert_eq!(None, registry.remove_if(&2, |value| *value == 0));
+        assert_eq!(Some(0), registry.remove_if(&1, |value| *value == 0));
+        assert_eq!(None, registry.update(&1, |value| *value = 0));
+
+        let sum = registry.fold(0, |sum, _, value| sum + value);
+        assert_eq!((0..100).sum::<i32>() - 1, sum);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_random_claims_share_new_room() {
+        const NUM_PLAYERS: usize = 8;
+        // タイミングに依存するので、何度か繰り返す。
+        for _ in 0..20 {
+            let registries = Arc::new(Registries::new());
+            let barrier = Arc::new(Barrier::new(NUM_PLAYERS));
+            let handles: Vec<_> = (0..NUM_PLAYERS)
+                .map(|_| {
+                    let registries = registries.clone();
+                    let barrier = barrier.clone();
+                    tokio::task::spawn_blocking(move || {
+                        barrier.wait();
+                        let config = entity::RoomConfig {
+                            max_players: NUM_PLAYERS as u32,
+                        };
+                        registries.claim_random_room_seat(config, HashMap::new()).0
+                    })
+                })
+                .collect();
+
+            let mut room_ids = HashSet::new();
+            for handle in handles {
+                room_ids.insert(handle.await.unwrap());
+            }
+            // Roomが無い状態で同時に探しても、1つのRoomにまとまる。
+            assert_eq!(1, room_ids.len());
+            assert_eq!(1, registries.rooms.len());
+        }
+    }
+}
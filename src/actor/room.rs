@@ -1,93 +1,427 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::{Arc, PoisonError}, time::Duration};
 
-use log::{debug, warn};
-use once_cell::sync::Lazy;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::{debug, info_span, warn, Instrument};
+use uuid::Uuid;
 
 use super::event::*;
+use super::registry::*;
+use super::watch::*;
 use crate::entity;
+use crate::metrics::METRICS;
 
-static ROOM_CHANNELS: Lazy<RwLock<HashMap<entity::RoomId, mpsc::UnboundedSender<InputEvent>>>> =
-    Lazy::new(|| RwLock::new(HashMap::new()));
+pub const DEFAULT_RESERVATION_TIMEOUT: Duration = Duration::from_secs(30);
 
-pub async fn get_room_channel(id: &entity::RoomId) -> Option<mpsc::UnboundedSender<InputEvent>> {
-    let room_channels = ROOM_CHANNELS.read().await;
-    Some(room_channels.get(id)?.clone())
-}
+// Room actorへの入力キューの大きさ。一杯の場合は送信側が空くまで待つ。
+const ROOM_CHANNEL_CAPACITY: usize = 1024;
 
-pub async fn get_or_create_room_channel(
-    id: &entity::RoomId,
+pub(super) struct RoomEntry {
+    tx: mpsc::Sender<InputEvent>,
     config: entity::RoomConfig,
-) -> mpsc::UnboundedSender<InputEvent> {
-    let mut room_channels = ROOM_CHANNELS.write().await;
-    let tx = room_channels.get(id);
-    match tx {
-        Some(tx) => tx.clone(),
-        None => {
-            let (tx, rx) = mpsc::unbounded_channel();
-            let room_id = id.clone();
-            {
-                // moveされるのでここでcloneしておく。
-                let room_id = id.clone();
-                tokio::spawn(async move {
-                    let mut room_runner = Room::new(entity::Room::new(room_id, config), rx);
-                    room_runner.run().await
-                });
-            }
-            room_channels.insert(room_id, tx.clone());
-            tx
+    properties: entity::RoomProperties,
+    // Join処理中のプレイヤーも含めた確保済みの席数。
+    // Room actorの処理を待たずに空き状況を判断できるようにして、同じ席を複数のプレイヤーに割り当てないようにする。
+    seats: u32,
+}
+
+impl RoomEntry {
+    pub(super) fn queue_depth(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    fn to_info(
+        &self,
+        id: &entity::RoomId,
+        player_ids: &HashMap<entity::RoomId, Vec<entity::PlayerId>>,
+    ) -> RoomInfo {
+        RoomInfo {
+            id: id.clone(),
+            config: self.config.clone(),
+            properties: self.properties.clone(),
+            seats: self.seats,
+            player_ids: player_ids.get(id).cloned().unwrap_or_default(),
         }
     }
+
+    fn has_vacancy(&self, num_seats: u32) -> bool {
+        self.seats + num_seats <= self.config.max_players
+    }
+
+    fn matches(
+        &self,
+        config: &entity::RoomConfig,
+        properties: &entity::RoomProperties,
+        num_seats: u32,
+    ) -> bool {
+        &self.config == config
+            && entity::match_room_properties(&self.properties, properties)
+            && self.has_vacancy(num_seats)
+    }
+}
+
+/// 管理用のAPIで返すRoomの情報。
+#[derive(Clone, Debug)]
+pub struct RoomInfo {
+    pub id: entity::RoomId,
+    pub config: entity::RoomConfig,
+    pub properties: entity::RoomProperties,
+    // Join処理中や予約済みの席も含む。
+    pub seats: u32,
+    pub player_ids: Vec<entity::PlayerId>,
 }
 
-async fn remove_room_from_channels(id: &entity::RoomId) {
-    let mut room_channels = ROOM_CHANNELS.write().await;
-    room_channels.remove(id);
+impl Registries {
+    pub fn get_room_channel(&self, id: &entity::RoomId) -> Option<mpsc::Sender<InputEvent>> {
+        self.rooms.get_with(id, |entry| entry.tx.clone())
+    }
+
+    /// 指定したRoomの席を1つ確保してチャネルを返す。Roomが存在しなければ作成する。
+    /// 確保した席はJoinの失敗時かLeave時にRoom actorが解放する。
+    pub fn claim_room_seat(
+        self: &Arc<Self>,
+        id: &entity::RoomId,
+        config: entity::RoomConfig,
+        properties: entity::RoomProperties,
+    ) -> mpsc::Sender<InputEvent> {
+        self.claim_room_seats(id, config, properties, 1)
+    }
+
+    /// 指定したRoomの席を`num_seats`だけ確保してチャネルを返す。Roomが存在しなければ作成する。
+    /// 予約のように複数の席をまとめて確保する場合に使う。
+    pub fn claim_room_seats(
+        self: &Arc<Self>,
+        id: &entity::RoomId,
+        config: entity::RoomConfig,
+        properties: entity::RoomProperties,
+        num_seats: u32,
+    ) -> mpsc::Sender<InputEvent> {
+        self.rooms.get_or_insert_with(
+            id.clone(),
+            || self.spawn_room(id.clone(), config, properties),
+            |entry| {
+                entry.seats += num_seats;
+                entry.tx.clone()
+            },
+        )
+    }
+
+    /// 既存のRoomの席を1つ確保してチャネルを返す。Roomが存在しなければ作成せずにNoneを返す。
+    /// パーティの移動のように、予約済みのRoomにだけJoinする場合に使う。
+    pub fn claim_existing_room_seat(
+        &self,
+        id: &entity::RoomId,
+    ) -> Option<mpsc::Sender<InputEvent>> {
+        self.rooms.update(id, |entry| {
+            entry.seats += 1;
+            entry.tx.clone()
+        })
+    }
+
+    /// 条件に一致する空きのあるRoomの席を1つ確保する。
+    /// 該当するRoomが無ければ新しくRoomを作成する。
+    pub fn claim_random_room_seat(
+        self: &Arc<Self>,
+        config: entity::RoomConfig,
+        properties: entity::RoomProperties,
+    ) -> (entity::RoomId, mpsc::Sender<InputEvent>) {
+        self.claim_random_room_seats(config, properties, 1)
+    }
+
+    /// 条件に一致し`num_seats`以上の空きがあるRoomの席をまとめて確保する。
+    /// 該当するRoomが無ければ新しくRoomを作成する。
+    pub fn claim_random_room_seats(
+        self: &Arc<Self>,
+        config: entity::RoomConfig,
+        properties: entity::RoomProperties,
+        num_seats: u32,
+    ) -> (entity::RoomId, mpsc::Sender<InputEvent>) {
+        if let Some(claimed) = self.claim_matching_room_seats(&config, &properties, num_seats) {
+            return claimed;
+        }
+
+        // 同時に作成すると、同じ条件のプレイヤーが別々のRoomに分かれてしまう。
+        // 作成は1つずつ行い、先に作成されたRoomに空きが無いか確認し直してから作成する。
+        let _creation = self.random_room_creation.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(claimed) = self.claim_matching_room_seats(&config, &properties, num_seats) {
+            return claimed;
+        }
+        let id = Uuid::new_v4().to_string();
+        let mut entry = self.spawn_room(id.clone(), config, properties);
+        entry.seats += num_seats;
+        let tx = entry.tx.clone();
+        self.rooms.insert(id.clone(), entry);
+        (id, tx)
+    }
+
+    // 条件に一致するRoomの席を確保する。該当するRoomが無ければNoneを返す。
+    fn claim_matching_room_seats(
+        &self,
+        config: &entity::RoomConfig,
+        properties: &entity::RoomProperties,
+        num_seats: u32,
+    ) -> Option<(entity::RoomId, mpsc::Sender<InputEvent>)> {
+        loop {
+            // なるべく人数の多いRoomから埋めていく。
+            let candidate = self.rooms.fold(None, |candidate, id, entry| {
+                if !entry.matches(config, properties, num_seats) {
+                    return candidate;
+                }
+                match candidate {
+                    Some((_, seats)) if seats >= entry.seats => candidate,
+                    _ => Some((id.clone(), entry.seats)),
+                }
+            });
+            let (id, _) = candidate?;
+
+            // 探してから確保するまでの間に他のプレイヤーが席を埋めた場合は探し直す。
+            let tx = self.rooms.update(&id, |entry| {
+                if entry.matches(config, properties, num_seats) {
+                    entry.seats += num_seats;
+                    Some(entry.tx.clone())
+                } else {
+                    None
+                }
+            });
+            if let Some(Some(tx)) = tx {
+                return Some((id, tx));
+            }
+        }
+    }
+
+    fn spawn_room(
+        self: &Arc<Self>,
+        id: entity::RoomId,
+        config: entity::RoomConfig,
+        properties: entity::RoomProperties,
+    ) -> RoomEntry {
+        let (tx, rx) = mpsc::channel(ROOM_CHANNEL_CAPACITY);
+        let entry = RoomEntry {
+            tx,
+            config: config.clone(),
+            properties: properties.clone(),
+            seats: 0,
+        };
+        let registries = self.clone();
+        // Roomは作成したプレイヤーより長く残るので、接続のスパンとは切り離す。
+        let span = info_span!(parent: None, "room", room_id = %id);
+        tokio::spawn(async move {
+            let mut room_runner = Room::new(
+                entity::Room::with_properties(id, config, properties),
+                rx,
+                registries,
+            );
+            room_runner.run().await
+        }.instrument(span));
+        entry
+    }
+
+    fn release_room_seats(&self, id: &entity::RoomId, num_seats: u32) {
+        if num_seats == 0 {
+            return;
+        }
+
+        self.rooms.update(id, |entry| {
+            entry.seats = entry.seats.saturating_sub(num_seats);
+        });
+    }
+
+    /// 管理者がRoomを閉じる。Roomが存在しなければfalseを返す。
+    pub async fn close_room(&self, id: &entity::RoomId, reason: String) -> bool {
+        let tx = match self.get_room_channel(id) {
+            Some(tx) => tx,
+            None => return false,
+        };
+        let event = InputEvent::Close(Box::new(InputCloseEvent { reason }));
+        tx.send(event).await.is_ok()
+    }
+
+    pub fn get_room_info(&self, id: &entity::RoomId) -> Option<RoomInfo> {
+        let player_ids = self.player_ids_by_room();
+        self.rooms.get_with(id, |entry| entry.to_info(id, &player_ids))
+    }
+
+    /// 存在する全てのRoomの情報。IDの順に並べる。
+    pub fn list_rooms(&self) -> Vec<RoomInfo> {
+        let player_ids = self.player_ids_by_room();
+        let mut rooms = self.rooms.fold(Vec::new(), |mut rooms, id, entry| {
+            rooms.push(entry.to_info(id, &player_ids));
+            rooms
+        });
+        rooms.sort_by(|a, b| a.id.cmp(&b.id));
+        rooms
+    }
+
+    fn remove_room(&self, id: &entity::RoomId) {
+        self.rooms.remove(id);
+    }
+
+    /// 確保済みの席が無い場合のみRoomを削除する。
+    /// 席が残っている場合はJoinイベントが届く途中なので、Roomを継続させる。
+    fn remove_room_from_channels(&self, id: &entity::RoomId) -> bool {
+        self.rooms.remove_if(id, |entry| entry.seats == 0).is_some()
+    }
 }
 
 pub struct Room {
     room: entity::Room<OutputEvent>,
-    room_rx: mpsc::UnboundedReceiver<InputEvent>,
+    room_rx: mpsc::Receiver<InputEvent>,
+    registries: Arc<Registries>,
+    // 管理者に閉じられた後、キューに残っているイベントを処理している状態。
+    closed: bool,
 }
 
 impl Room {
     pub fn new(
         room: entity::Room<OutputEvent>,
-        room_rx: mpsc::UnboundedReceiver<InputEvent>,
+        room_rx: mpsc::Receiver<InputEvent>,
+        registries: Arc<Registries>,
     ) -> Self {
-        Self { room, room_rx }
+        Self {
+            room,
+            room_rx,
+            registries,
+            closed: false,
+        }
     }
 
     pub async fn run(&mut self) {
-        debug!("Start Room. room_id={}", self.room.id);
-        while let Some(event) = self.room_rx.recv().await {
-            match event {
-                InputEvent::Join(event) => {
-                    debug!("Receive InputJoinEvent");
-                    self.handle_join_event(event);
-                }
-                InputEvent::Leave(event) => {
-                    debug!("Receive InputLeaveEvent");
-                    self.handle_leave_event(event);
+        debug!("Start Room");
+        METRICS.inc_active_rooms();
+        self.registries.publish_watch_event(|| WatchEvent::RoomCreated {
+            room_id: self.room.id.clone(),
+        });
+        loop {
+            let next_deadline = self.room.next_reservation_deadline();
+            let reservation_timer = time::sleep_until(time::Instant::from_std(
+                next_deadline.unwrap_or_else(std::time::Instant::now),
+            ));
+            tokio::select! {
+                event = self.room_rx.recv() => {
+                    let event = match event {
+                        Some(event) => event,
+                        None => break,
+                    };
+                    let num_players = self.room.num_players();
+                    self.handle_event(event);
+                    METRICS.resize_room(num_players, self.room.num_players());
                 }
-                InputEvent::Message(event) => {
-                    debug!("Receive InputMessageEvent");
-                    self.handle_message_event(event);
+                _ = reservation_timer, if next_deadline.is_some() => {
+                    self.handle_reservation_expiry();
                 }
             }
 
-            if self.room.num_players() == 0 {
-                debug!("Stop Room. room_id={}", self.room.id);
-                // TODO: rx側のcloseを基本として、Player actor側でroom_tx.sendの結果をエラーハンドリングするという手もある。
-                // どちらからのcloseを基本とするかは一考の余地があるが、 Roomが空になるかは基本的にはLeave次第(Player側に主導権があるもの)なので、
-                // tx側からのcloseの方がgracefulかも。
-                remove_room_from_channels(&self.room.id).await;
+            // TODO: rx側のcloseを基本として、Player actor側でroom_tx.sendの結果をエラーハンドリングするという手もある。
+            // どちらからのcloseを基本とするかは一考の余地があるが、 Roomが空になるかは基本的にはLeave次第(Player側に主導権があるもの)なので、
+            // tx側からのcloseの方がgracefulかも。
+            if !self.closed
+                && self.room.num_players() == 0
+                && self.registries.remove_room_from_channels(&self.room.id)
+            {
+                debug!("Stop Room");
                 break;
             }
         }
+        METRICS.resize_room(self.room.num_players(), 0);
+        METRICS.dec_active_rooms();
+        self.registries.publish_watch_event(|| WatchEvent::RoomRemoved {
+            room_id: self.room.id.clone(),
+        });
+    }
+
+    fn handle_event(&mut self, event: InputEvent) {
+        if self.closed {
+            self.reject_event(event);
+            return;
+        }
+
+        match event {
+            InputEvent::Join(event) => {
+                debug!(player_id = %event.player.id, "Receive InputJoinEvent");
+                // 予約済みの席にJoinした場合、Join時に確保した席が予約の席と重複するので解放する。
+                let reserved = self.room.is_reserved(&event.player.id);
+                if !self.handle_join_event(*event) || reserved {
+                    self.registries.release_room_seats(&self.room.id, 1);
+                }
+            }
+            InputEvent::Leave(event) => {
+                debug!(player_id = %event.player_id, "Receive InputLeaveEvent");
+                if self.handle_leave_event(*event) {
+                    self.registries.release_room_seats(&self.room.id, 1);
+                }
+            }
+            InputEvent::Message(event) => {
+                debug!(player_id = %event.sender_player_id, "Receive InputMessageEvent");
+                self.handle_message_event(*event);
+            }
+            InputEvent::Reserve(event) => {
+                debug!(requester_id = %event.requester_id, "Receive InputReserveEvent");
+                // 予約の更新や重複したIDの分も席を確保しているので、新しく予約した分以外は解放する。
+                let claimed_seats = event.player_ids.len() as u32;
+                let reserved_seats = self.handle_reserve_event(*event);
+                self.registries
+                    .release_room_seats(&self.room.id, claimed_seats - reserved_seats);
+            }
+            InputEvent::CancelReservation(event) => {
+                debug!(requester_id = %event.requester_id, "Receive InputCancelReservationEvent");
+                let canceled = self
+                    .room
+                    .cancel_reservations(&event.requester_id, &event.player_ids);
+                self.registries
+                    .release_room_seats(&self.room.id, canceled.len() as u32);
+            }
+            InputEvent::Close(event) => {
+                debug!("Receive InputCloseEvent");
+                self.handle_close_event(*event);
+            }
+        }
+    }
+
+    /// 参加中のプレイヤーにRoomが閉じられたことを通知して、Roomを空にする。
+    /// レジストリからはすぐに削除するので、同じIDのRoomは新しく作られる。
+    fn handle_close_event(&mut self, event: InputCloseEvent) {
+        // 通知を受け取ったプレイヤーが入り直せないように、先にレジストリから削除する。
+        self.registries.remove_room(&self.room.id);
+        self.registries.publish_watch_event(|| WatchEvent::RoomClosed {
+            room_id: self.room.id.clone(),
+            reason: event.reason.clone(),
+        });
+        let output_event = OutputEvent::RoomClosed(Arc::new(OutputRoomClosedEvent {
+            room_id: self.room.id.clone(),
+            reason: event.reason,
+        }));
+        self.room.broadcast(output_event);
+        self.room.clear();
+        self.closed = true;
+        // 既にキューに入っているイベントを処理し終えたら終了する。
+        self.room_rx.close();
+    }
+
+    /// 閉じた後に届いたイベント。席はレジストリから削除済みなので解放しない。
+    fn reject_event(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::Join(mut event) => {
+                let err = entity::RoomError::RoomClosed(self.room.id.clone(), event.player.id.clone());
+                if event.player.send(OutputEvent::Join(Err(err))).is_err() {
+                    warn!("Join failed and player disconnected");
+                }
+            }
+            InputEvent::Reserve(event) => {
+                let player_id = event.player_ids.first().cloned().unwrap_or_default();
+                let err = entity::RoomError::RoomClosed(self.room.id.clone(), player_id);
+                if event.reply_tx.try_send(OutputEvent::Reserve(Err(err))).is_err() {
+                    warn!("Requester disconnected before receiving the reservation result");
+                }
+            }
+            // 参加しているプレイヤーも予約もないので、それ以外は何もしない。
+            InputEvent::Leave(_)
+            | InputEvent::Message(_)
+            | InputEvent::CancelReservation(_)
+            | InputEvent::Close(_) => {}
+        }
     }
 
-    fn handle_join_event(&mut self, mut event: Box<InputJoinEvent>) {
+    fn handle_join_event(&mut self, mut event: InputJoinEvent) -> bool {
         match self
             .room
             .add_player(event.player.clone(), &event.room_config)
@@ -98,20 +432,27 @@ impl Room {
                     player_id: event.player.id.clone(),
                     room_player_ids: self.room.players.keys().cloned().collect(),
                     room_config: self.room.config.clone(),
+                    room_properties: self.room.properties.clone(),
                 })));
 
                 self.room.broadcast(output_event);
+                self.registries.publish_watch_event(|| WatchEvent::PlayerJoined {
+                    room_id: self.room.id.clone(),
+                    player_id: event.player.id.clone(),
+                });
+                true
             }
             Err(err) => {
                 if event.player.send(OutputEvent::Join(Err(err))).is_err() {
                     // Joinに失敗かつプレイヤーが切断した場合なので特にハンドリングは不要。
                     warn!("Join failed and player disconnected");
                 }
+                false
             }
         }
     }
 
-    fn handle_leave_event(&mut self, event: Box<InputLeaveEvent>) {
+    fn handle_leave_event(&mut self, event: InputLeaveEvent) -> bool {
         if self.room.is_joined(&event.player_id) {
             let output_event = OutputEvent::Leave(Ok(Arc::new(OutputLeaveEvent {
                 room_id: self.room.id.clone(),
@@ -120,19 +461,75 @@ impl Room {
             self.room.broadcast(output_event);
             let ok = self.room.remove_player(&event.player_id);
             debug_assert!(ok);
+            self.registries.publish_watch_event(|| WatchEvent::PlayerLeft {
+                room_id: self.room.id.clone(),
+                player_id: event.player_id,
+            });
+            true
         } else {
             // 二重LeaveかRoomに所属していなかった。
             // このように呼び出されない想定なのでここでは何もしない。
             warn!("Double leave or not joining the room");
+            false
         }
     }
 
-    fn handle_message_event(&mut self, event: Box<InputMessageEvent>) {
-        let output_event = OutputEvent::Message(Arc::new(OutputMessageEvent {
+    fn handle_reserve_event(&mut self, event: InputReserveEvent) -> u32 {
+        let num_reservations = self.room.num_reservations();
+        let deadline = std::time::Instant::now() + event.timeout;
+        let result = self
+            .room
+            .reserve(
+                &event.requester_id,
+                &event.player_ids,
+                &event.room_config,
+                deadline,
+                event.max_reservations,
+            )
+            .map(|_| {
+                Arc::new(OutputReserveEvent {
+                    room_id: self.room.id.clone(),
+                    player_ids: event.player_ids,
+                })
+            });
+        if event.reply_tx.try_send(OutputEvent::Reserve(result)).is_err() {
+            // 予約自体は有効なので、予約したプレイヤーのJoinか期限切れを待つ。
+            warn!("Requester disconnected before receiving the reservation result");
+        }
+
+        self.room.num_reservations() - num_reservations
+    }
+
+    fn handle_reservation_expiry(&mut self) {
+        let expired = self
+            .room
+            .release_expired_reservations(std::time::Instant::now());
+        if expired.is_empty() {
+            return;
+        }
+
+        debug!(
+            "Reservations expired. room_id={}, player_ids={:?}",
+            self.room.id, expired
+        );
+        self.registries
+            .release_room_seats(&self.room.id, expired.len() as u32);
+        let output_event = OutputEvent::ReservationExpired(Arc::new(OutputReservationExpiredEvent {
             room_id: self.room.id.clone(),
-            body: event.body,
-            sender_player_id: event.sender_player_id,
+            player_ids: expired,
         }));
+        self.room.broadcast(output_event);
+    }
+
+    fn handle_message_event(&mut self, event: InputMessageEvent) {
+        let sent_at = event.sent_at;
+        // 通知は最初に届けるプレイヤーの処理で一度だけエンコードされ、他のプレイヤーと共有される。
+        let output_event = OutputEvent::Message(Arc::new(OutputMessageEvent::new(
+            self.room.id.clone(),
+            event.sender_player_id,
+            event.body,
+            event.delivery,
+        )));
 
         if event.target_ids.is_empty() {
             self.room.broadcast(output_event);
@@ -147,5 +544,6 @@ impl Room {
                 }
             });
         }
+        METRICS.observe_room_message_latency(sent_at.elapsed());
     }
 }
@@ -0,0 +1,77 @@
+use tokio::sync::broadcast;
+
+use super::registry::*;
+use crate::entity;
+
+// 購読者ごとに溜められるイベントの数。溢れた購読者は古いイベントを読み飛ばす。
+const WATCH_CHANNEL_CAPACITY: usize = 1024;
+
+/// 管理用のAPIで購読できる、RoomへのJoinやLeaveとRoomの作成や削除のイベント。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchEvent {
+    RoomCreated {
+        room_id: entity::RoomId,
+    },
+    // 管理者に閉じられた。この後にRoomRemovedが続く。
+    RoomClosed {
+        room_id: entity::RoomId,
+        reason: String,
+    },
+    RoomRemoved {
+        room_id: entity::RoomId,
+    },
+    PlayerJoined {
+        room_id: entity::RoomId,
+        player_id: entity::PlayerId,
+    },
+    PlayerLeft {
+        room_id: entity::RoomId,
+        player_id: entity::PlayerId,
+    },
+}
+
+pub(super) struct WatchEvents {
+    tx: broadcast::Sender<WatchEvent>,
+}
+
+impl Default for WatchEvents {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(WATCH_CHANNEL_CAPACITY);
+        Self { tx }
+    }
+}
+
+impl Registries {
+    /// これ以降に発生したイベントを購読する。
+    pub fn watch_events(&self) -> broadcast::Receiver<WatchEvent> {
+        self.watch_events.tx.subscribe()
+    }
+
+    /// 購読者がいない場合はイベントを作らない。
+    pub(super) fn publish_watch_event(&self, event: impl FnOnce() -> WatchEvent) {
+        if self.watch_events.tx.receiver_count() == 0 {
+            return;
+        }
+        // 送信に失敗するのは購読者が直前にいなくなった場合だけなので無視する。
+        let _ = self.watch_events.tx.send(event());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publish_only_to_current_watchers() {
+        let registries = Registries::new();
+        registries.publish_watch_event(|| unreachable!());
+
+        let mut rx = registries.watch_events();
+        let event = WatchEvent::RoomCreated {
+            room_id: "r1".to_string(),
+        };
+        registries.publish_watch_event(|| event.clone());
+        assert_eq!(event, rx.try_recv().unwrap());
+        assert!(rx.try_recv().is_err());
+    }
+}
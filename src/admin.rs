@@ -0,0 +1,511 @@
+//! 運用向けのHTTPサーバ。プレイヤーが接続するポートとは別のアドレスで待ち受ける。
+//! `/metrics`は認証なしで公開し、`/api`以下はBearerトークンで認証する。
+//! トークンを指定していない場合、`/api`以下は全て401を返す。
+//! 同じ操作をgRPCのAdminサービスとしても提供する。
+
+pub mod grpc;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::actor;
+use crate::entity;
+use crate::logging;
+use crate::metrics::METRICS;
+use crate::network_protocol::server;
+
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+// リクエストボディの上限。理由やお知らせの本文しか受け取らない。
+const MAX_BODY_SIZE: u64 = 16 * 1024;
+
+pub async fn run<F>(
+    addr: SocketAddr,
+    registries: Arc<actor::Registries>,
+    admin_token: Option<String>,
+    shutdown: F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    info!(
+        "Start admin server. addr={}, api_enabled={}",
+        addr,
+        admin_token.is_some()
+    );
+    let (_, server) = warp::serve(routes(registries, admin_token))
+        .try_bind_with_graceful_shutdown(addr, shutdown)?;
+    server.await;
+    Ok(())
+}
+
+pub fn routes(
+    registries: Arc<actor::Registries>,
+    admin_token: Option<String>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let metrics_registries = registries.clone();
+    let metrics = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || {
+            warp::reply::with_header(
+                METRICS.encode(&metrics_registries),
+                "content-type",
+                METRICS_CONTENT_TYPE,
+            )
+        });
+
+    let api = warp::path("api")
+        .and(authorize(admin_token))
+        .and(api_routes(registries));
+
+    metrics.or(api).recover(handle_rejection)
+}
+
+fn api_routes(
+    registries: Arc<actor::Registries>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    let with_registries = warp::any().map(move || registries.clone());
+
+    let list_rooms = warp::path!("rooms")
+        .and(warp::get())
+        .and(warp::query::<RoomQuery>())
+        .and(with_registries.clone())
+        .map(|query: RoomQuery, registries: Arc<actor::Registries>| {
+            let rooms: Vec<_> = registries
+                .list_rooms()
+                .iter()
+                .filter(|room| query.matches(room))
+                .map(RoomResponse::from)
+                .collect();
+            warp::reply::json(&rooms).into_response()
+        });
+
+    let get_room = warp::path!("rooms" / String)
+        .and(warp::get())
+        .and(with_registries.clone())
+        .map(|id: entity::RoomId, registries: Arc<actor::Registries>| {
+            match registries.get_room_info(&id) {
+                Some(room) => warp::reply::json(&RoomResponse::from(&room)).into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        });
+
+    let close_room = warp::path!("rooms" / String / "close")
+        .and(warp::post())
+        .and(json_body::<ReasonRequest>())
+        .and(with_registries.clone())
+        .and_then(close_room);
+
+    let list_players = warp::path!("players")
+        .and(warp::get())
+        .and(warp::query::<PlayerQuery>())
+        .and(with_registries.clone())
+        .map(|query: PlayerQuery, registries: Arc<actor::Registries>| {
+            let players: Vec<_> = registries
+                .list_players()
+                .iter()
+                .filter(|player| query.matches(player))
+                .map(PlayerResponse::from)
+                .collect();
+            warp::reply::json(&players).into_response()
+        });
+
+    let get_player = warp::path!("players" / String)
+        .and(warp::get())
+        .and(with_registries.clone())
+        .map(|id: entity::PlayerId, registries: Arc<actor::Registries>| {
+            match registries.get_player_info(&id) {
+                Some(player) => warp::reply::json(&PlayerResponse::from(&player)).into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        });
+
+    let kick_player = warp::path!("players" / String / "kick")
+        .and(warp::post())
+        .and(json_body::<ReasonRequest>())
+        .and(with_registries.clone())
+        .and_then(kick_player);
+
+    let announce = warp::path!("announcements")
+        .and(warp::post())
+        .and(json_body::<AnnouncementRequest>())
+        .and(with_registries)
+        .map(
+            |req: AnnouncementRequest, registries: Arc<actor::Registries>| {
+                if req.message.is_empty() {
+                    return StatusCode::BAD_REQUEST.into_response();
+                }
+                info!(
+                    "Publish server announcement by admin. message={:?}",
+                    logging::payload(&req.message)
+                );
+                registries.publish_server_announcement(req.message);
+                StatusCode::ACCEPTED.into_response()
+            },
+        );
+
+    list_rooms
+        .or(get_room)
+        .unify()
+        .or(close_room)
+        .unify()
+        .or(list_players)
+        .unify()
+        .or(get_player)
+        .unify()
+        .or(kick_player)
+        .unify()
+        .or(announce)
+        .unify()
+}
+
+async fn close_room(
+    id: entity::RoomId,
+    req: ReasonRequest,
+    registries: Arc<actor::Registries>,
+) -> Result<Response, Rejection> {
+    info!(
+        "Close room by admin. room_id={}, reason={:?}",
+        id, req.reason
+    );
+    let status = if registries.close_room(&id, req.reason).await {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::NOT_FOUND
+    };
+    Ok(status.into_response())
+}
+
+async fn kick_player(
+    id: entity::PlayerId,
+    req: ReasonRequest,
+    registries: Arc<actor::Registries>,
+) -> Result<Response, Rejection> {
+    info!(
+        "Kick player by admin. player_id={}, reason={:?}",
+        id, req.reason
+    );
+    let status = match registries.kick_player(&id, req.reason) {
+        Ok(()) => StatusCode::ACCEPTED,
+        Err(actor::KickError::NotFound) => StatusCode::NOT_FOUND,
+        Err(err @ actor::KickError::QueueFull) => {
+            warn!("Failed to kick player. player_id={}, error={}", id, err);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    };
+    Ok(status.into_response())
+}
+
+fn json_body<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: serde::de::DeserializeOwned + Send,
+{
+    warp::body::content_length_limit(MAX_BODY_SIZE).and(warp::body::json())
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// `Authorization: Bearer <token>`を検証する。トークンが未設定の場合は常に拒否する。
+fn authorize(admin_token: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let admin_token = Arc::new(admin_token);
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let admin_token = admin_token.clone();
+            async move {
+                if is_authorized(admin_token.as_deref(), header.as_deref()) {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// `authorization`の値が`Bearer <token>`の形式で、トークンが一致すればtrue。
+fn is_authorized(admin_token: Option<&str>, authorization: Option<&str>) -> bool {
+    let token = authorization.and_then(|value| value.strip_prefix("Bearer "));
+    match (admin_token, token) {
+        (Some(expected), Some(token)) => constant_time_eq(expected.as_bytes(), token.as_bytes()),
+        _ => false,
+    }
+}
+
+// 一致するまでの時間からトークンを推測されないように、長さが同じなら全てのバイトを比較する。
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn handle_rejection(err: Rejection) -> Result<Response, Rejection> {
+    if err.find::<Unauthorized>().is_some() {
+        return Ok(warp::reply::with_header(
+            StatusCode::UNAUTHORIZED,
+            "www-authenticate",
+            "Bearer",
+        )
+        .into_response());
+    }
+    Err(err)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RoomQuery {
+    // RoomのIDかプロパティのキーと値の部分一致。
+    q: Option<String>,
+    player_id: Option<entity::PlayerId>,
+}
+
+impl RoomQuery {
+    fn matches(&self, room: &actor::RoomInfo) -> bool {
+        let matches_q = self.q.as_deref().is_none_or(|q| {
+            room.id.contains(q)
+                || room
+                    .properties
+                    .iter()
+                    .any(|(key, value)| key.contains(q) || value.contains(q))
+        });
+        let matches_player = self
+            .player_id
+            .as_ref()
+            .is_none_or(|player_id| room.player_ids.contains(player_id));
+        matches_q && matches_player
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayerQuery {
+    // プレイヤーのIDか接続元のアドレスの部分一致。
+    q: Option<String>,
+    room_id: Option<entity::RoomId>,
+}
+
+impl PlayerQuery {
+    fn matches(&self, player: &actor::PlayerInfo) -> bool {
+        let matches_q = self.q.as_deref().is_none_or(|q| {
+            player.id.contains(q)
+                || player
+                    .connection
+                    .remote_addr
+                    .as_deref()
+                    .is_some_and(|addr| addr.contains(q))
+        });
+        let matches_room = self
+            .room_id
+            .as_ref()
+            .is_none_or(|room_id| player.room_ids.contains(room_id));
+        matches_q && matches_room
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ReasonRequest {
+    #[serde(default)]
+    reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnnouncementRequest {
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RoomResponse {
+    id: entity::RoomId,
+    max_players: u32,
+    properties: HashMap<String, String>,
+    seats: u32,
+    player_ids: Vec<entity::PlayerId>,
+}
+
+impl From<&actor::RoomInfo> for RoomResponse {
+    fn from(room: &actor::RoomInfo) -> Self {
+        Self {
+            id: room.id.clone(),
+            max_players: room.config.max_players,
+            properties: room.properties.clone(),
+            seats: room.seats,
+            player_ids: room.player_ids.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PlayerResponse {
+    id: entity::PlayerId,
+    room_ids: Vec<entity::RoomId>,
+    protocol: Option<String>,
+    remote_addr: Option<String>,
+    peer_credentials: Option<PeerCredentialsResponse>,
+    // UNIX時間のミリ秒。
+    logged_in_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct PeerCredentialsResponse {
+    uid: u32,
+    gid: u32,
+    pid: Option<i32>,
+}
+
+impl From<&actor::PlayerInfo> for PlayerResponse {
+    fn from(player: &actor::PlayerInfo) -> Self {
+        let connection = &player.connection;
+        Self {
+            id: player.id.clone(),
+            room_ids: player.room_ids.clone(),
+            protocol: connection.protocol.map(protocol_name),
+            remote_addr: connection.remote_addr.clone(),
+            peer_credentials: connection.peer_credentials.as_ref().map(|cred| {
+                PeerCredentialsResponse {
+                    uid: cred.uid,
+                    gid: cred.gid,
+                    pid: cred.pid,
+                }
+            }),
+            logged_in_at: unix_millis(player.logged_in_at),
+        }
+    }
+}
+
+fn protocol_name(protocol: server::Protocol) -> String {
+    protocol
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_string())
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "secret";
+
+    #[tokio::test]
+    async fn serve_metrics_in_prometheus_text_format() {
+        let routes = routes(Arc::new(actor::Registries::new()), None);
+        let response = warp::test::request()
+            .method("GET")
+            .path("/metrics")
+            .reply(&routes)
+            .await;
+        assert_eq!(200, response.status());
+        assert_eq!(METRICS_CONTENT_TYPE, response.headers()["content-type"]);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.contains("# TYPE mini_realtime_connected_players gauge"));
+        assert!(body.contains("mini_realtime_connected_players{protocol=\"websocket\"}"));
+        assert!(body.contains("mini_realtime_queue_depth{queue=\"room\"} 0"));
+        assert!(body.contains("mini_realtime_room_message_latency_seconds_bucket{le=\"+Inf\"}"));
+    }
+
+    #[tokio::test]
+    async fn reject_api_without_valid_token() {
+        let registries = Arc::new(actor::Registries::new());
+        let cases = [
+            (Some(TOKEN), None),
+            (Some(TOKEN), Some("Bearer wrong")),
+            (Some(TOKEN), Some("secret")),
+            // トークンを設定していなければAPIは使えない。
+            (None, Some("Bearer secret")),
+        ];
+        for (admin_token, header) in cases {
+            let routes = routes(registries.clone(), admin_token.map(String::from));
+            let mut request = warp::test::request().method("GET").path("/api/rooms");
+            if let Some(header) = header {
+                request = request.header("authorization", header);
+            }
+            let response = request.reply(&routes).await;
+            assert_eq!(401, response.status(), "header={:?}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn list_and_close_room() {
+        let registries = Arc::new(actor::Registries::new());
+        let properties = HashMap::from([("mode".to_string(), "ranked".to_string())]);
+        registries.claim_room_seat(&"r1".to_string(), Default::default(), properties);
+        registries.claim_room_seat(&"r2".to_string(), Default::default(), HashMap::new());
+        let routes = routes(registries.clone(), Some(TOKEN.to_string()));
+
+        let response = api_request("GET", "/api/rooms?q=rank").reply(&routes).await;
+        assert_eq!(200, response.status());
+        let rooms: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            serde_json::json!([{
+                "id": "r1",
+                "maxPlayers": 2,
+                "properties": { "mode": "ranked" },
+                "seats": 1,
+                "playerIds": [],
+            }]),
+            rooms
+        );
+
+        let response = api_request("POST", "/api/rooms/r1/close")
+            .json(&serde_json::json!({ "reason": "maintenance" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(202, response.status());
+
+        // Room actorが処理するとレジストリから削除される。
+        for _ in 0..100 {
+            if registries.get_room_info(&"r1".to_string()).is_none() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let response = api_request("GET", "/api/rooms/r1").reply(&routes).await;
+        assert_eq!(404, response.status());
+        let response = api_request("GET", "/api/rooms/r2").reply(&routes).await;
+        assert_eq!(200, response.status());
+    }
+
+    #[tokio::test]
+    async fn not_found_unknown_player() {
+        let routes = routes(Arc::new(actor::Registries::new()), Some(TOKEN.to_string()));
+
+        let response = api_request("GET", "/api/players").reply(&routes).await;
+        assert_eq!(200, response.status());
+        assert_eq!(b"[]", &response.body()[..]);
+
+        let response = api_request("POST", "/api/players/p1/kick")
+            .json(&serde_json::json!({ "reason": "cheating" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(404, response.status());
+
+        let response = api_request("POST", "/api/announcements")
+            .json(&serde_json::json!({ "message": "hello" }))
+            .reply(&routes)
+            .await;
+        assert_eq!(202, response.status());
+    }
+
+    fn api_request(method: &str, path: &str) -> warp::test::RequestBuilder {
+        warp::test::request()
+            .method(method)
+            .path(path)
+            .header("authorization", format!("Bearer {}", TOKEN))
+    }
+}
@@ -0,0 +1,364 @@
+//! gRPCのAdminサービス。`--enable-admin-grpc`を指定すると、gRPCのサーバでAppサービスと一緒に提供する。
+//! 認証にはRESTのAPIと同じトークンを使う。
+
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::Stream;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+use super::{is_authorized, protocol_name, unix_millis, PlayerQuery, RoomQuery};
+use crate::actor;
+use crate::config;
+use crate::protobuf::admin::admin_server::{Admin, AdminServer};
+use crate::protobuf::admin::{self, event::Data};
+
+// WatchEventsの送信キューの大きさ。クライアントが受信しきれない間は、Registries側のキューに溜まる。
+const WATCH_STREAM_CAPACITY: usize = 128;
+
+/// `network_protocol::grpc`のサーバに追加するAdminサービス。
+pub fn service(
+    config: &config::Config,
+    registries: Arc<actor::Registries>,
+) -> InterceptedService<AdminServer<AdminService>, Authenticator> {
+    let authenticator = Authenticator {
+        admin_token: config.admin.token.clone().map(Arc::from),
+    };
+    AdminServer::with_interceptor(AdminService { registries }, authenticator)
+}
+
+/// メタデータの`authorization`を検証する。トークンが未設定の場合は常に拒否する。
+#[derive(Clone)]
+pub struct Authenticator {
+    admin_token: Option<Arc<str>>,
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, req: Request<()>) -> Result<Request<()>, Status> {
+        let authorization = req
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+        if is_authorized(self.admin_token.as_deref(), authorization) {
+            Ok(req)
+        } else {
+            Err(Status::unauthenticated("invalid admin token"))
+        }
+    }
+}
+
+pub struct AdminService {
+    registries: Arc<actor::Registries>,
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    type WatchEventsStream = Pin<Box<dyn Stream<Item = Result<admin::Event, Status>> + Send>>;
+
+    async fn list_rooms(
+        &self,
+        req: Request<admin::ListRoomsRequest>,
+    ) -> Result<Response<admin::ListRoomsResponse>, Status> {
+        let req = req.into_inner();
+        let query = RoomQuery {
+            q: non_empty(req.query),
+            player_id: non_empty(req.player_id),
+        };
+        let rooms = self
+            .registries
+            .list_rooms()
+            .iter()
+            .filter(|room| query.matches(room))
+            .map(to_room)
+            .collect();
+        Ok(Response::new(admin::ListRoomsResponse { rooms }))
+    }
+
+    async fn get_room(
+        &self,
+        req: Request<admin::GetRoomRequest>,
+    ) -> Result<Response<admin::Room>, Status> {
+        let room_id = req.into_inner().room_id;
+        match self.registries.get_room_info(&room_id) {
+            Some(room) => Ok(Response::new(to_room(&room))),
+            None => Err(Status::not_found(format!(
+                "room not found. roomId={}",
+                room_id
+            ))),
+        }
+    }
+
+    async fn list_players(
+        &self,
+        req: Request<admin::ListPlayersRequest>,
+    ) -> Result<Response<admin::ListPlayersResponse>, Status> {
+        let req = req.into_inner();
+        let query = PlayerQuery {
+            q: non_empty(req.query),
+            room_id: non_empty(req.room_id),
+        };
+        let players = self
+            .registries
+            .list_players()
+            .iter()
+            .filter(|player| query.matches(player))
+            .map(to_player)
+            .collect();
+        Ok(Response::new(admin::ListPlayersResponse { players }))
+    }
+
+    async fn kick_player(
+        &self,
+        req: Request<admin::KickPlayerRequest>,
+    ) -> Result<Response<admin::KickPlayerResponse>, Status> {
+        let req = req.into_inner();
+        info!(
+            "Kick player by admin. player_id={}, reason={:?}",
+            req.player_id, req.reason
+        );
+        match self.registries.kick_player(&req.player_id, req.reason) {
+            Ok(()) => Ok(Response::new(admin::KickPlayerResponse {})),
+            Err(actor::KickError::NotFound) => Err(Status::not_found(format!(
+                "player not found. playerId={}",
+                req.player_id
+            ))),
+            Err(err @ actor::KickError::QueueFull) => {
+                warn!(
+                    "Failed to kick player. player_id={}, error={}",
+                    req.player_id, err
+                );
+                Err(Status::unavailable(format!(
+                    "{}. playerId={}",
+                    err, req.player_id
+                )))
+            }
+        }
+    }
+
+    async fn close_room(
+        &self,
+        req: Request<admin::CloseRoomRequest>,
+    ) -> Result<Response<admin::CloseRoomResponse>, Status> {
+        let req = req.into_inner();
+        info!(
+            "Close room by admin. room_id={}, reason={:?}",
+            req.room_id, req.reason
+        );
+        if self.registries.close_room(&req.room_id, req.reason).await {
+            Ok(Response::new(admin::CloseRoomResponse {}))
+        } else {
+            Err(Status::not_found(format!(
+                "room not found. roomId={}",
+                req.room_id
+            )))
+        }
+    }
+
+    async fn watch_events(
+        &self,
+        _req: Request<admin::WatchEventsRequest>,
+    ) -> Result<Response<Self::WatchEventsStream>, Status> {
+        let mut events = self.registries.watch_events();
+        let (tx, rx) = mpsc::channel(WATCH_STREAM_CAPACITY);
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // 取りこぼしたまま続けると状態がずれるので、ストリームを終えて購読し直してもらう。
+                        warn!("Admin watcher lagged behind. skipped={}", skipped);
+                        let status = Status::data_loss(format!(
+                            "watcher lagged behind and skipped {} events. watch again",
+                            skipped
+                        ));
+                        let _ = tx.send(Err(status)).await;
+                        return;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                if tx.send(Ok(to_event(event))).await.is_err() {
+                    // クライアントが切断した。
+                    return;
+                }
+            }
+        });
+
+        let out_stream = ReceiverStream::new(rx);
+        Ok(Response::new(
+            Box::pin(out_stream) as Self::WatchEventsStream
+        ))
+    }
+}
+
+// proto3では未指定と空文字列を区別できないので、空文字列は絞り込まないことにする。
+fn non_empty(value: String) -> Option<String> {
+    Some(value).filter(|value| !value.is_empty())
+}
+
+fn to_room(room: &actor::RoomInfo) -> admin::Room {
+    admin::Room {
+        room_id: room.id.clone(),
+        max_players: room.config.max_players,
+        properties: room.properties.clone(),
+        seats: room.seats,
+        player_ids: room.player_ids.clone(),
+    }
+}
+
+fn to_player(player: &actor::PlayerInfo) -> admin::Player {
+    let connection = &player.connection;
+    admin::Player {
+        player_id: player.id.clone(),
+        room_ids: player.room_ids.clone(),
+        protocol: connection.protocol.map(protocol_name).unwrap_or_default(),
+        remote_addr: connection.remote_addr.clone().unwrap_or_default(),
+        peer_credentials: connection
+            .peer_credentials
+            .as_ref()
+            .map(|cred| admin::PeerCredentials {
+                uid: cred.uid,
+                gid: cred.gid,
+                pid: cred.pid.unwrap_or_default(),
+            }),
+        logged_in_at: unix_millis(player.logged_in_at),
+    }
+}
+
+fn to_event(event: actor::WatchEvent) -> admin::Event {
+    let data = match event {
+        actor::WatchEvent::RoomCreated { room_id } => {
+            Data::RoomCreated(admin::RoomCreated { room_id })
+        }
+        actor::WatchEvent::RoomClosed { room_id, reason } => {
+            Data::RoomClosed(admin::RoomClosed { room_id, reason })
+        }
+        actor::WatchEvent::RoomRemoved { room_id } => {
+            Data::RoomRemoved(admin::RoomRemoved { room_id })
+        }
+        actor::WatchEvent::PlayerJoined { room_id, player_id } => {
+            Data::PlayerJoined(admin::PlayerJoined { room_id, player_id })
+        }
+        actor::WatchEvent::PlayerLeft { room_id, player_id } => {
+            Data::PlayerLeft(admin::PlayerLeft { room_id, player_id })
+        }
+    };
+    admin::Event { data: Some(data) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    #[test]
+    fn authenticate_with_admin_token() {
+        let mut authenticator = Authenticator {
+            admin_token: Some(Arc::from("secret")),
+        };
+        for (header, ok) in [
+            (None, false),
+            (Some("Bearer wrong"), false),
+            (Some("Bearer secret"), true),
+        ] {
+            let mut req = Request::new(());
+            if let Some(header) = header {
+                req.metadata_mut()
+                    .insert("authorization", header.parse().unwrap());
+            }
+            assert_eq!(ok, authenticator.call(req).is_ok(), "header={:?}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn watch_room_lifecycle_and_close_room() {
+        let registries = Arc::new(actor::Registries::new());
+        let service = AdminService {
+            registries: registries.clone(),
+        };
+        let mut events = service
+            .watch_events(Request::new(admin::WatchEventsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let room_id = "r1".to_string();
+        registries.claim_room_seat(&room_id, Default::default(), HashMap::new());
+        let rooms = service
+            .list_rooms(Request::new(admin::ListRoomsRequest::default()))
+            .await
+            .unwrap()
+            .into_inner()
+            .rooms;
+        assert_eq!(
+            vec![room_id.clone()],
+            rooms
+                .into_iter()
+                .map(|room| room.room_id)
+                .collect::<Vec<_>>()
+        );
+
+        service
+            .close_room(Request::new(admin::CloseRoomRequest {
+                room_id: room_id.clone(),
+                reason: "maintenance".to_string(),
+            }))
+            .await
+            .unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(events.next().await.unwrap().unwrap().data.unwrap());
+        }
+        assert_eq!(
+            vec![
+                Data::RoomCreated(admin::RoomCreated {
+                    room_id: room_id.clone(),
+                }),
+                Data::RoomClosed(admin::RoomClosed {
+                    room_id: room_id.clone(),
+                    reason: "maintenance".to_string(),
+                }),
+                Data::RoomRemoved(admin::RoomRemoved {
+                    room_id: room_id.clone(),
+                }),
+            ],
+            received
+        );
+
+        let status = service
+            .get_room(Request::new(admin::GetRoomRequest { room_id }))
+            .await
+            .unwrap_err();
+        assert_eq!(tonic::Code::NotFound, status.code());
+    }
+
+    #[tokio::test]
+    async fn end_watch_stream_when_lagged() {
+        let registries = Arc::new(actor::Registries::new());
+        let service = AdminService {
+            registries: registries.clone(),
+        };
+        let mut events = service
+            .watch_events(Request::new(admin::WatchEventsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // 購読しているタスクが動く前に、購読者のキューに入りきらない数のイベントを起こす。
+        for i in 0..2000 {
+            registries.claim_room_seat(&format!("r{}", i), Default::default(), HashMap::new());
+        }
+
+        let status = events.next().await.unwrap().unwrap_err();
+        assert_eq!(tonic::Code::DataLoss, status.code());
+        assert!(events.next().await.is_none());
+    }
+}
@@ -0,0 +1,468 @@
+//! 複数のbotでサーバに負荷をかけ、スループットと配送の遅延を計測する。
+//! botはログインしてRoomに参加した後、一定の間隔でRoomにメッセージをブロードキャストする。
+//! メッセージの先頭には送信時刻を入れておき、受信したbotが遅延を計算する。結果はJSONで標準出力に書く。
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use serde::Serialize;
+use tokio::sync::Barrier;
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::info;
+use uuid::Uuid;
+
+use mini_realtime_server::client::{self, Client};
+use mini_realtime_server::logging;
+use mini_realtime_server::network_protocol::codec::Codec;
+use mini_realtime_server::network_protocol::server::ListenAddr;
+use mini_realtime_server::network_protocol::websocket::SUBPROTOCOL_PROTOBUF;
+use mini_realtime_server::protobuf::{self, app};
+
+/// 送信時刻として本文の先頭に入れるバイト数。
+const TIMESTAMP_SIZE: usize = 8;
+
+/// 送信を止めた後、送信済みのメッセージが届くのを待つ時間。
+const DRAIN_TIME: Duration = Duration::from_secs(1);
+
+/// botは`client`モジュールのクライアントで接続するので、計測できるのはwebsocket, grpc, tcpだけ。
+/// udp, quic, http-fallbackはハンドシェイクと再送、証明書の検証、ロングポーリングといった
+/// トランスポート固有のクライアントが無いため、別のトランスポートで計測してしまわないようにエラーにする。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+enum Protocol {
+    Websocket,
+    Grpc,
+    Tcp,
+    Udp,
+    Quic,
+    HttpFallback,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    if env::var("RUST_LOG").is_err() {
+        env::set_var("RUST_LOG", "warn");
+    }
+
+    logging::init(logging::LogFormat::Pretty, false);
+
+    let args = Arc::new(Args::parse());
+    if matches!(
+        args.protocol,
+        Protocol::Udp | Protocol::Quic | Protocol::HttpFallback
+    ) {
+        return Err(anyhow!(
+            "udp, quic and http-fallback have no bench client. use websocket, grpc or tcp"
+        ));
+    }
+    if matches!(args.address, ListenAddr::Unix(_)) && args.protocol != Protocol::Tcp {
+        return Err(anyhow!("unix domain socket is only supported by tcp"));
+    }
+    if args.size < TIMESTAMP_SIZE {
+        return Err(anyhow!("size must be at least {} bytes", TIMESTAMP_SIZE));
+    }
+    if args.room_size == 0 || args.rate <= 0.0 {
+        return Err(anyhow!("room-size and rate must be greater than 0"));
+    }
+
+    // 実行ごとにRoomのIDを変えて、前回の実行で残ったRoomに参加しないようにする。
+    let room_prefix = format!("bench-{}", Uuid::new_v4());
+    let epoch = Instant::now();
+    // 全てのbotの準備ができてから、同時に送信を始める。
+    let barrier = Arc::new(Barrier::new(args.clients + 1));
+    let bots: Vec<_> = (0..args.clients)
+        .map(|index| {
+            let bot = Bot {
+                args: args.clone(),
+                index,
+                room_id: format!("{}-{}", room_prefix, index / args.room_size),
+                epoch,
+            };
+            tokio::spawn(bot.run(barrier.clone()))
+        })
+        .collect();
+
+    barrier.wait().await;
+    info!("Start sending. clients={}", args.clients);
+    let mut stats = Stats::default();
+    for bot in bots {
+        stats.merge(bot.await?);
+    }
+
+    let report = Report::new(&args, stats);
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+struct Bot {
+    args: Arc<Args>,
+    index: usize,
+    room_id: String,
+    epoch: Instant,
+}
+
+impl Bot {
+    async fn run(self, barrier: Arc<Barrier>) -> Stats {
+        let mut stats = Stats::default();
+        let setup = tokio::time::timeout(self.args.setup_timeout.into(), self.setup()).await;
+        let client = match setup {
+            Ok(Ok(client)) => Some(client),
+            Ok(Err(kind)) => {
+                stats.error(kind);
+                None
+            }
+            Err(_) => {
+                stats.error("setup_timeout");
+                None
+            }
+        };
+        // 準備に失敗したbotも待ち合わせには参加しないと、他のbotが送信を始められない。
+        barrier.wait().await;
+        if let Some(client) = client {
+            stats.connected = 1;
+            self.send_and_receive(client, &mut stats).await;
+        }
+        stats
+    }
+
+    /// 接続してログインし、Roomに参加する。失敗した場合はエラーの種類を返す。
+    async fn setup(&self) -> Result<Client, &'static str> {
+        let mut client = self.connect();
+        let login = app::client_message::Data::LoginRequest(app::LoginRequest {
+            player_id: Uuid::new_v4().to_string(),
+            compression: app::Compression::Uncompressed as i32,
+            protocol_version: protobuf::PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+            auth_config: Some(app::login_request::AuthConfig::Bearer(
+                app::AuthConfigBearer {
+                    token: self.args.auth_bearer.clone(),
+                },
+            )),
+        });
+        send(&client, login).map_err(|_| "disconnected")?;
+        let error = recv_until(&mut client, |data| match data {
+            app::server_message::Data::LoginResponse(res) => Some(res.error),
+            _ => None,
+        })
+        .await
+        .ok_or("disconnected")?;
+        if !is_ok(error) {
+            return Err("login");
+        }
+
+        let join = app::client_message::Data::JoinRequest(app::JoinRequest {
+            room_id: self.room_id.clone(),
+            room_config: Some(app::RoomConfig {
+                max_players: self.args.room_size as u32,
+            }),
+            room_properties: HashMap::new(),
+        });
+        send(&client, join).map_err(|_| "disconnected")?;
+        let error = recv_until(&mut client, |data| match data {
+            app::server_message::Data::JoinResponse(res) => Some(res.error),
+            _ => None,
+        })
+        .await
+        .ok_or("disconnected")?;
+        if !is_ok(error) {
+            return Err("join");
+        }
+        Ok(client)
+    }
+
+    fn connect(&self) -> Client {
+        match (self.args.protocol, &self.args.address) {
+            (Protocol::Tcp, ListenAddr::Inet(addr)) => client::tcp::connect(*addr, Codec::Protobuf),
+            (Protocol::Tcp, ListenAddr::Unix(path)) => {
+                let path = path.clone();
+                client::tcp::spawn(
+                    async move { Ok(tokio::net::UnixStream::connect(path).await?) },
+                    Codec::Protobuf,
+                )
+            }
+            (Protocol::Websocket, addr) => {
+                client::websocket::connect(format!("ws://{}/app", addr), SUBPROTOCOL_PROTOBUF)
+            }
+            (Protocol::Grpc, addr) => client::grpc::connect(format!("http://{}", addr)),
+            (Protocol::Udp | Protocol::Quic | Protocol::HttpFallback, _) => {
+                unreachable!("unsupported protocols are rejected before connecting")
+            }
+        }
+    }
+
+    async fn send_and_receive(&self, mut client: Client, stats: &mut Stats) {
+        let start = Instant::now();
+        let send_until = start + self.args.duration.into();
+        let recv_until = send_until + DRAIN_TIME;
+        // botごとに送信のタイミングをずらして、全てのbotが同時に送らないようにする。
+        let period = Duration::from_secs_f64(1.0 / self.args.rate);
+        let offset = period.mul_f64(self.index as f64 / self.args.clients as f64);
+        let mut interval = tokio::time::interval_at(start + offset, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let deadline = tokio::time::sleep_until(recv_until);
+        tokio::pin!(deadline);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick(), if Instant::now() < send_until => {
+                    let message = app::client_message::Data::SendMessage(app::SendMessage {
+                        target_ids: Vec::new(),
+                        room_id: self.room_id.clone(),
+                        body: self.body(),
+                        delivery: app::Delivery::Reliable as i32,
+                    });
+                    if send(&client, message).is_err() {
+                        stats.error("disconnected");
+                        return;
+                    }
+                    stats.sent += 1;
+                }
+                message = client.recv() => {
+                    let message = match message {
+                        Some(message) => message,
+                        None => {
+                            stats.error("disconnected");
+                            return;
+                        }
+                    };
+                    match message.data {
+                        Some(app::server_message::Data::MessageNotification(notification)) => {
+                            stats.receive(self.epoch, &notification.body);
+                        }
+                        Some(app::server_message::Data::SendMessageErrorNotification(_)) => {
+                            stats.error("send_message");
+                        }
+                        Some(app::server_message::Data::ErrorNotification(_)) => {
+                            stats.error("server");
+                        }
+                        _ => {}
+                    }
+                }
+                _ = &mut deadline => return,
+            }
+        }
+    }
+
+    /// 先頭に送信時刻を入れた本文を作る。
+    fn body(&self) -> Vec<u8> {
+        let timestamp = self.epoch.elapsed().as_nanos() as u64;
+        let mut body = vec![0; self.args.size];
+        body[..TIMESTAMP_SIZE].copy_from_slice(&timestamp.to_be_bytes());
+        body
+    }
+}
+
+fn send(
+    client: &Client,
+    data: app::client_message::Data,
+) -> Result<(), tokio::sync::mpsc::error::SendError<app::ClientMessage>> {
+    client.send(app::ClientMessage { data: Some(data) })
+}
+
+/// 条件に一致するメッセージまで読み飛ばす。接続が切れた場合はNoneを返す。
+async fn recv_until<T>(
+    client: &mut Client,
+    f: impl Fn(app::server_message::Data) -> Option<T>,
+) -> Option<T> {
+    loop {
+        if let Some(data) = client.recv().await?.data {
+            if let Some(value) = f(data) {
+                return Some(value);
+            }
+        }
+    }
+}
+
+fn is_ok(error: Option<app::Error>) -> bool {
+    error.is_none_or(|error| error.code == app::ErrorCode::None as i32)
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    connected: usize,
+    sent: u64,
+    received: u64,
+    received_bytes: u64,
+    latencies: Vec<Duration>,
+    errors: BTreeMap<&'static str, u64>,
+}
+
+impl Stats {
+    fn receive(&mut self, epoch: Instant, body: &[u8]) {
+        self.received += 1;
+        self.received_bytes += body.len() as u64;
+        if let Some(timestamp) = body.get(..TIMESTAMP_SIZE) {
+            let sent_at = Duration::from_nanos(u64::from_be_bytes(timestamp.try_into().unwrap()));
+            self.latencies.push(epoch.elapsed().saturating_sub(sent_at));
+        }
+    }
+
+    fn error(&mut self, kind: &'static str) {
+        *self.errors.entry(kind).or_default() += 1;
+    }
+
+    fn merge(&mut self, other: Stats) {
+        self.connected += other.connected;
+        self.sent += other.sent;
+        self.received += other.received;
+        self.received_bytes += other.received_bytes;
+        self.latencies.extend(other.latencies);
+        for (kind, count) in other.errors {
+            *self.errors.entry(kind).or_default() += count;
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    protocol: Protocol,
+    clients: usize,
+    connected: usize,
+    room_size: usize,
+    rate: f64,
+    size: usize,
+    duration_secs: f64,
+    sent: u64,
+    received: u64,
+    throughput: Throughput,
+    latency_ms: Latency,
+    errors: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Throughput {
+    sent_per_sec: f64,
+    received_per_sec: f64,
+    received_bytes_per_sec: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct Latency {
+    p50: Option<f64>,
+    p99: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Report {
+    fn new(args: &Args, mut stats: Stats) -> Self {
+        let duration_secs = Duration::from(args.duration).as_secs_f64();
+        stats.latencies.sort_unstable();
+        Self {
+            protocol: args.protocol,
+            clients: args.clients,
+            connected: stats.connected,
+            room_size: args.room_size,
+            rate: args.rate,
+            size: args.size,
+            duration_secs,
+            sent: stats.sent,
+            received: stats.received,
+            throughput: Throughput {
+                sent_per_sec: stats.sent as f64 / duration_secs,
+                received_per_sec: stats.received as f64 / duration_secs,
+                received_bytes_per_sec: stats.received_bytes as f64 / duration_secs,
+            },
+            latency_ms: Latency {
+                p50: percentile(&stats.latencies, 0.5),
+                p99: percentile(&stats.latencies, 0.99),
+                max: stats.latencies.last().map(as_millis),
+            },
+            errors: stats.errors,
+        }
+    }
+}
+
+/// ソート済みの値から、最も近い順位の値を返す。
+fn percentile(sorted: &[Duration], p: f64) -> Option<f64> {
+    let rank = (sorted.len() as f64 * p).ceil() as usize;
+    sorted.get(rank.saturating_sub(1)).map(as_millis)
+}
+
+fn as_millis(duration: &Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// 秒数で指定する時間。
+#[derive(Clone, Copy, Debug)]
+struct Secs(f64);
+
+impl std::str::FromStr for Secs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<f64>() {
+            Ok(secs) if secs > 0.0 && secs.is_finite() => Ok(Self(secs)),
+            _ => Err(format!(
+                "expected a positive number of seconds but got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+impl From<Secs> for Duration {
+    fn from(secs: Secs) -> Self {
+        Duration::from_secs_f64(secs.0)
+    }
+}
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    #[clap(
+        short = 'p',
+        long = "protocol",
+        value_enum,
+        default_value = "websocket"
+    )]
+    protocol: Protocol,
+
+    /// Server address. tcp also accepts unix:<PATH>
+    #[clap(short = 'a', long = "addr", default_value = "127.0.0.1:8000")]
+    address: ListenAddr,
+
+    #[clap(long = "auth-bearer", default_value = "test")]
+    auth_bearer: String,
+
+    /// Number of simulated clients
+    #[clap(short = 'c', long = "clients", default_value = "100")]
+    clients: usize,
+
+    /// Number of clients that join the same room
+    #[clap(long = "room-size", default_value = "10")]
+    room_size: usize,
+
+    /// Messages per second sent by each client
+    #[clap(long = "rate", default_value = "10")]
+    rate: f64,
+
+    /// Size in bytes of a message body (at least 8)
+    #[clap(long = "size", default_value = "64")]
+    size: usize,
+
+    /// Seconds to keep sending messages
+    #[clap(short = 'd', long = "duration", default_value = "10")]
+    duration: Secs,
+
+    /// Seconds to wait for a client to log in and join its room
+    #[clap(long = "setup-timeout", default_value = "10")]
+    setup_timeout: Secs,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_rank_percentile() {
+        let sorted: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(Some(50.0), percentile(&sorted, 0.5));
+        assert_eq!(Some(99.0), percentile(&sorted, 0.99));
+        assert_eq!(Some(1.0), percentile(&sorted[..1], 0.99));
+        assert_eq!(None, percentile(&[], 0.5));
+    }
+}
@@ -0,0 +1,45 @@
+//! サーバに接続するクライアント。負荷試験用のbotやE2Eテストから使う。
+//! 接続ごとにタスクを起動し、送受信するメッセージはチャネルでやり取りする。
+//! 接続が切れるかエラーが起きるとタスクが終了し、`recv`がNoneを返すようになる。
+//! サーバには不要なので、`client` featureを有効にした場合だけ含める。
+
+pub mod grpc;
+pub mod tcp;
+pub mod websocket;
+
+use tokio::sync::mpsc;
+
+use crate::protobuf::app::{ClientMessage, ServerMessage};
+
+pub struct Client {
+    tx: mpsc::UnboundedSender<ClientMessage>,
+    rx: mpsc::UnboundedReceiver<ServerMessage>,
+}
+
+impl Client {
+    /// 接続を処理するタスクに渡すチャネルを作る。
+    fn channel() -> (
+        Self,
+        mpsc::UnboundedReceiver<ClientMessage>,
+        mpsc::UnboundedSender<ServerMessage>,
+    ) {
+        let (input_tx, input_rx) = mpsc::unbounded_channel();
+        let (output_tx, output_rx) = mpsc::unbounded_channel();
+        let client = Self {
+            tx: input_tx,
+            rx: output_rx,
+        };
+        (client, input_rx, output_tx)
+    }
+
+    pub fn send(
+        &self,
+        message: ClientMessage,
+    ) -> Result<(), mpsc::error::SendError<ClientMessage>> {
+        self.tx.send(message)
+    }
+
+    pub async fn recv(&mut self) -> Option<ServerMessage> {
+        self.rx.recv().await
+    }
+}
@@ -0,0 +1,31 @@
+use anyhow::Result;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::error;
+
+use super::Client;
+use crate::protobuf::app::app_client::AppClient;
+
+/// `http://host:port`のようなURLに接続する。
+pub fn connect(url: String) -> Client {
+    let (client, input_rx, output_tx) = Client::channel();
+    tokio::spawn(async move {
+        let result: Result<()> = async move {
+            let mut client = AppClient::connect(url).await?;
+            let mut inbound = client
+                .start(UnboundedReceiverStream::new(input_rx))
+                .await?
+                .into_inner();
+            while let Some(message) = inbound.message().await? {
+                if output_tx.send(message).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(err) = result {
+            error!("gRPC client error. {:?}", err);
+        }
+    });
+    client
+}
@@ -0,0 +1,70 @@
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use futures::{Future, SinkExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tracing::error;
+
+use super::Client;
+use crate::network_protocol::codec::Codec;
+
+/// TCPで接続する。protobuf以外の場合は、接続直後にコーデック選択フレームを送る。
+pub fn connect(addr: SocketAddr, codec: Codec) -> Client {
+    spawn(
+        async move {
+            let conn = TcpStream::connect(addr).await?;
+            conn.set_nodelay(true)?;
+            Ok(conn)
+        },
+        codec,
+    )
+}
+
+/// `connect`で接続したストリームで、TCPと同じフレームを使ってやり取りする。
+pub fn spawn<S, Fut>(connect: Fut, codec: Codec) -> Client
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    Fut: Future<Output = Result<S>> + Send + 'static,
+{
+    let (client, mut input_rx, output_tx) = Client::channel();
+    tokio::spawn(async move {
+        let result: Result<()> = async move {
+            let (reader, writer) = tokio::io::split(connect.await?);
+            let mut framed_reader = FramedRead::new(reader, LengthDelimitedCodec::new());
+            let mut framed_writer = FramedWrite::new(writer, LengthDelimitedCodec::new());
+            if codec != Codec::Protobuf {
+                framed_writer.send(codec.selection_frame().into()).await?;
+            }
+            loop {
+                tokio::select! {
+                    input_msg = input_rx.recv() => {
+                        match input_msg {
+                            Some(input_msg) => framed_writer.send(codec.encode(&input_msg)?.into()).await?,
+                            // Clientが破棄された。
+                            None => return Ok(()),
+                        }
+                    }
+                    output_msg = framed_reader.next() => {
+                        match output_msg {
+                            Some(output_msg) => {
+                                if output_tx.send(codec.decode(output_msg?.as_ref())?).is_err() {
+                                    return Ok(());
+                                }
+                            }
+                            // サーバが切断した。
+                            None => return Ok(()),
+                        }
+                    }
+                }
+            }
+        }
+        .await;
+        if let Err(err) = result {
+            error!("TCP client error. {:?}", err);
+        }
+    });
+    client
+}
@@ -0,0 +1,69 @@
+use anyhow::{anyhow, Result};
+use futures::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::error;
+
+use super::Client;
+use crate::network_protocol::websocket;
+
+/// `ws://host:port/app`のようなURLに接続する。コーデックは`subprotocol`で選ぶ。
+pub fn connect(url: String, subprotocol: &'static str) -> Client {
+    let (client, mut input_rx, output_tx) = Client::channel();
+    tokio::spawn(async move {
+        let result: Result<()> = async move {
+            let (codec, _) = websocket::negotiate(subprotocol)
+                .ok_or_else(|| anyhow!("unknown subprotocol. subprotocol={}", subprotocol))?;
+            let mut request = url.into_client_request()?;
+            request.headers_mut().insert(
+                "sec-websocket-protocol",
+                HeaderValue::from_static(subprotocol),
+            );
+            let (ws_stream, response) = tokio_tungstenite::connect_async(request).await?;
+            if response.headers().get("sec-websocket-protocol")
+                != Some(&HeaderValue::from_static(subprotocol))
+            {
+                return Err(anyhow!("subprotocol is not accepted. subprotocol={}", subprotocol));
+            }
+            let (mut tx, mut rx) = ws_stream.split();
+            loop {
+                tokio::select! {
+                    input_msg = input_rx.recv() => {
+                        let input_msg = match input_msg {
+                            Some(input_msg) => input_msg,
+                            // Clientが破棄された。
+                            None => return Ok(()),
+                        };
+                        let data = codec.encode(&input_msg)?;
+                        let input_msg = if codec.is_text() {
+                            Message::text(String::from_utf8(data)?)
+                        } else {
+                            Message::binary(data)
+                        };
+                        tx.send(input_msg).await?;
+                    }
+                    output_msg = rx.next() => {
+                        let message = match output_msg {
+                            Some(Ok(Message::Binary(output_msg))) if !codec.is_text() => codec.decode(&output_msg)?,
+                            Some(Ok(Message::Text(output_msg))) if codec.is_text() => codec.decode(output_msg.as_bytes())?,
+                            Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                            // サーバが切断した。
+                            Some(Ok(Message::Close(_))) | None => return Ok(()),
+                            Some(Ok(output_msg)) => return Err(anyhow!("unexpected message. {:?}", output_msg)),
+                            Some(Err(err)) => return Err(err.into()),
+                        };
+                        if output_tx.send(message).is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+        .await;
+        if let Err(err) = result {
+            error!("WebSocket client error. {:?}", err);
+        }
+    });
+    client
+}
@@ -1,15 +1,23 @@
 use std::fmt::Debug;
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub auth: Auth,
     pub tls: Tls,
+    pub compression: Compression,
+    pub compatibility: Compatibility,
+    pub limits: Limits,
+    pub outbound: Outbound,
+    pub admin: Admin,
 }
 
 #[derive(Clone, Debug)]
 pub struct Auth {
     pub enable_bearer: bool,
     pub bearer: String,
+    // Unix domain socketで接続したプロセスのうち、このユーザーのものはトークン無しでログインできる。
+    pub trusted_unix_uids: Vec<u32>,
 }
 
 #[derive(Clone, Debug)]
@@ -18,3 +26,62 @@ pub struct Tls {
     pub cert_file_path: String,
     pub key_file_path: String,
 }
+
+#[derive(Clone, Debug)]
+pub struct Compression {
+    pub enable: bool,
+    // この大きさ(バイト)未満のメッセージは圧縮せずに送る。
+    pub threshold: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Compatibility {
+    // これより古いprotocol_versionのクライアントはログインできない。0の場合は全て受け付ける。
+    pub min_protocol_version: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Limits {
+    // クライアントから受け付けるフレーム(WebSocketではメッセージ)の最大の大きさ(バイト)。
+    pub max_frame_size: usize,
+    // SendMessageなどのメッセージの本文の最大の大きさ(バイト)。
+    pub max_body_size: usize,
+    // ReserveSeatsRequestで指定できる予約の有効期間の上限。長い場合はこの値に切り詰める。
+    pub max_reservation_timeout: Duration,
+    // 1人のプレイヤーが1つのRoomに持てる予約の数。
+    pub max_reservations_per_requester: u32,
+    // UDPのサーバで同時に扱うセッションの数。超えた場合は新しいConnectを拒否する。
+    pub max_udp_sessions: usize,
+    // HTTPフォールバックのサーバで同時に扱うセッションの数。超えた場合は新しいセッションを作らない。
+    pub max_http_sessions: usize,
+    // HTTPフォールバックのサーバで、1つのIPアドレスから同時に作れるセッションの数。
+    pub max_http_sessions_per_addr: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Outbound {
+    // プレイヤーごとの送信キューに溜められるメッセージの数。
+    pub queue_size: usize,
+    // 送信キューが溢れた場合の扱い。
+    pub overflow_policy: OverflowPolicy,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Admin {
+    // 管理用のAPIの認証に使うBearerトークン。未設定の場合は全て拒否する。
+    pub token: Option<String>,
+    // gRPCのサーバでAppサービスと一緒にAdminサービスも提供する。
+    pub enable_grpc: bool,
+}
+
+/// クライアントが受信しきれずに送信キューが溢れた場合の扱い。
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OverflowPolicy {
+    // 切断する。
+    Disconnect,
+    // キュー内の最も古い信頼性の無いメッセージを捨てる。捨てられるものが無ければ切断する。
+    DropOldestUnreliable,
+    // 同じ送信者の信頼性の無いメッセージや同じプレイヤーのプレゼンスを新しいもので置き換える。
+    // 置き換えられるものが無ければ切断する。
+    Coalesce,
+}
@@ -1,6 +1,8 @@
 //! 共通ロジック
 
+mod party;
 mod player;
 mod room;
+pub use party::*;
 pub use player::*;
 pub use room::*;
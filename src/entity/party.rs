@@ -0,0 +1,193 @@
+use std::{collections::HashSet, fmt::Debug};
+
+use thiserror::Error;
+use tracing::warn;
+
+use super::player::*;
+use super::room::*;
+
+pub type PartyId = String;
+
+type Result<T> = std::result::Result<T, PartyError>;
+
+#[derive(Error, Clone, Debug, PartialEq)]
+pub enum PartyError {
+    #[error("the player is not the party leader. partyId={0}, playerId={1}")]
+    NotPartyLeader(PartyId, PlayerId),
+    #[error("the player has not been invited to the party. partyId={0}, playerId={1}")]
+    NotInvited(PartyId, PlayerId),
+    #[error("the player has already joined the party. partyId={0}, playerId={1}")]
+    AlreadyJoinedParty(PartyId, PlayerId),
+    #[error("the player is not found. partyId={0}, playerId={1}")]
+    PlayerNotFound(PartyId, PlayerId),
+    #[error("the party is not found. partyId={0}, playerId={1}")]
+    PartyNotFound(PartyId, PlayerId),
+    #[error("the party is already moving to another room. partyId={0}, playerId={1}")]
+    MovingRoom(PartyId, PlayerId),
+    #[error("the room is not found. roomId={0}")]
+    RoomNotFound(RoomId),
+    #[error(transparent)]
+    Room(#[from] RoomError),
+}
+
+impl PartyError {
+    pub fn party_id(&self) -> Option<&PartyId> {
+        match self {
+            PartyError::NotPartyLeader(party_id, _)
+            | PartyError::NotInvited(party_id, _)
+            | PartyError::AlreadyJoinedParty(party_id, _)
+            | PartyError::PlayerNotFound(party_id, _)
+            | PartyError::PartyNotFound(party_id, _)
+            | PartyError::MovingRoom(party_id, _) => Some(party_id),
+            PartyError::RoomNotFound(_) | PartyError::Room(_) => None,
+        }
+    }
+}
+
+/// Roomとは独立したプレイヤーのグループ。
+/// リーダーが招待したプレイヤーだけが参加でき、Roomを移動してもメンバーは維持される。
+#[derive(Debug)]
+pub struct Party<OutputMessageT> {
+    pub id: PartyId,
+    pub leader_id: PlayerId,
+    // 参加順。リーダーが抜けた場合は先頭のメンバーが次のリーダーになる。
+    pub members: Vec<Player<OutputMessageT>>,
+    pub invitations: HashSet<PlayerId>,
+    // 最後にパーティで移動したRoom。
+    pub room_id: Option<RoomId>,
+}
+
+impl<OutputMessageT> Party<OutputMessageT> {
+    pub fn new(id: PartyId, leader: Player<OutputMessageT>) -> Self {
+        Self {
+            id,
+            leader_id: leader.id.clone(),
+            members: vec![leader],
+            invitations: HashSet::new(),
+            room_id: None,
+        }
+    }
+
+    pub fn invite(&mut self, inviter_id: &PlayerId, player_id: PlayerId) -> Result<()> {
+        self.check_leader(inviter_id)?;
+
+        if self.is_member(&player_id) {
+            return Err(PartyError::AlreadyJoinedParty(self.id.clone(), player_id));
+        }
+
+        self.invitations.insert(player_id);
+        Ok(())
+    }
+
+    pub fn add_member(&mut self, player: Player<OutputMessageT>) -> Result<()> {
+        if self.is_member(&player.id) {
+            return Err(PartyError::AlreadyJoinedParty(self.id.clone(), player.id));
+        }
+
+        if !self.invitations.remove(&player.id) {
+            return Err(PartyError::NotInvited(self.id.clone(), player.id));
+        }
+
+        self.members.push(player);
+        Ok(())
+    }
+
+    /// メンバーを削除する。リーダーが抜けた場合は次のメンバーをリーダーにする。
+    pub fn remove_member(&mut self, player_id: &PlayerId) -> bool {
+        let len = self.members.len();
+        self.members.retain(|member| &member.id != player_id);
+        if self.members.len() == len {
+            return false;
+        }
+
+        if &self.leader_id == player_id {
+            if let Some(member) = self.members.first() {
+                self.leader_id = member.id.clone();
+            }
+        }
+        true
+    }
+
+    pub fn check_leader(&self, player_id: &PlayerId) -> Result<()> {
+        if &self.leader_id != player_id {
+            return Err(PartyError::NotPartyLeader(self.id.clone(), player_id.clone()));
+        }
+        Ok(())
+    }
+
+    pub fn is_member(&self, player_id: &PlayerId) -> bool {
+        self.members.iter().any(|member| &member.id == player_id)
+    }
+
+    pub fn member_ids(&self) -> Vec<PlayerId> {
+        self.members.iter().map(|member| member.id.clone()).collect()
+    }
+
+    pub fn num_members(&self) -> u32 {
+        self.members.len() as u32
+    }
+
+    pub fn broadcast(&mut self, event: OutputMessageT)
+    where
+        OutputMessageT: Clone,
+    {
+        self.members.iter_mut().for_each(|member| {
+            if let Err(err) = member.send(event.clone()) {
+                // 切断したか、キューが溢れて送信できなかったケース。溢れた場合はPlayer actorが切断する。
+                // 切断したプレイヤーはPlayer actorからLeaveが届くので、ここではログだけ出しておく。
+                warn!(
+                    "failed to send message. player_id={}, error={}",
+                    member.id,
+                    err.to_string(),
+                );
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn only_invited_players_can_join_party() {
+        let (tx, _) = mpsc::channel::<()>(1);
+        let leader_id = "leader".to_string();
+        let mut party = Party::new("test".to_string(), Player::new(leader_id.clone(), tx.clone()));
+
+        let p1_id = "p1".to_string();
+        let result = party.add_member(Player::new(p1_id.clone(), tx.clone()));
+        assert_eq!(
+            PartyError::NotInvited(party.id.clone(), p1_id.clone()),
+            result.err().unwrap()
+        );
+
+        let result = party.invite(&p1_id, "p2".to_string());
+        assert_eq!(
+            PartyError::NotPartyLeader(party.id.clone(), p1_id.clone()),
+            result.err().unwrap()
+        );
+
+        party.invite(&leader_id, p1_id.clone()).unwrap();
+        party.add_member(Player::new(p1_id.clone(), tx)).unwrap();
+        assert_eq!(vec![leader_id, p1_id], party.member_ids());
+        assert!(party.invitations.is_empty());
+    }
+
+    #[test]
+    fn leader_is_handed_over_when_leader_leaves() {
+        let (tx, _) = mpsc::channel::<()>(1);
+        let leader_id = "leader".to_string();
+        let mut party = Party::new("test".to_string(), Player::new(leader_id.clone(), tx.clone()));
+
+        let p1_id = "p1".to_string();
+        party.invite(&leader_id, p1_id.clone()).unwrap();
+        party.add_member(Player::new(p1_id.clone(), tx)).unwrap();
+
+        assert!(party.remove_member(&leader_id));
+        assert_eq!(p1_id, party.leader_id);
+        assert_eq!(1, party.num_members());
+        assert!(!party.remove_member(&leader_id));
+    }
+}
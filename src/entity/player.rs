@@ -1,24 +1,82 @@
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use tokio::sync::mpsc;
 
+use super::room::RoomId;
+
 pub type PlayerId = String;
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum PresenceStatus {
+    Offline,
+    Online,
+    InRoom,
+}
+
+/// 他のプレイヤーから見たプレイヤーの状態。
+#[derive(Clone, Debug, PartialEq)]
+pub struct Presence {
+    pub player_id: PlayerId,
+    pub status: PresenceStatus,
+    pub room_ids: Vec<RoomId>,
+}
+
+impl Presence {
+    pub fn online(player_id: PlayerId, room_ids: Vec<RoomId>) -> Self {
+        let status = if room_ids.is_empty() {
+            PresenceStatus::Online
+        } else {
+            PresenceStatus::InRoom
+        };
+        Self {
+            player_id,
+            status,
+            room_ids,
+        }
+    }
+
+    pub fn offline(player_id: PlayerId) -> Self {
+        Self {
+            player_id,
+            status: PresenceStatus::Offline,
+            room_ids: Vec::new(),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Player<OutputMessageT> {
     pub id: PlayerId,
-    pub sender: mpsc::UnboundedSender<OutputMessageT>,
+    pub sender: mpsc::Sender<OutputMessageT>,
+    // 受信側のキューが溢れてイベントを捨てたか。複製したPlayerの間で共有する。
+    overflowed: Arc<AtomicBool>,
 }
 
 impl<OutputMessageT> Player<OutputMessageT> {
-    pub fn new(id: PlayerId, sender: mpsc::UnboundedSender<OutputMessageT>) -> Self {
-        Self { id, sender }
+    pub fn new(id: PlayerId, sender: mpsc::Sender<OutputMessageT>) -> Self {
+        Self {
+            id,
+            sender,
+            overflowed: Arc::new(AtomicBool::new(false)),
+        }
     }
 
+    /// 受信側のキューが一杯の場合は待たずにエラーを返す。
+    /// 捨てたイベントは届けられないので、受信側は`is_overflowed`を確認して切断する必要がある。
     pub fn send(
         &mut self,
         event: OutputMessageT,
-    ) -> std::result::Result<(), mpsc::error::SendError<OutputMessageT>> {
-        self.sender.send(event)
+    ) -> std::result::Result<(), mpsc::error::TrySendError<OutputMessageT>> {
+        let result = self.sender.try_send(event);
+        if let Err(mpsc::error::TrySendError::Full(_)) = &result {
+            self.overflowed.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    pub fn is_overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::Relaxed)
     }
 }
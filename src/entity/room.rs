@@ -1,11 +1,17 @@
-use std::{collections::HashMap, fmt::Debug};
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    time::Instant,
+};
 
-use log::warn;
 use thiserror::Error;
+use tracing::warn;
 
+use super::party::PartyId;
 use super::player::*;
 
 pub type RoomId = String;
+pub type RoomProperties = HashMap<String, String>;
 
 type Result<T> = std::result::Result<T, RoomError>;
 
@@ -17,6 +23,38 @@ pub enum RoomError {
     RoomConfigDoesNotMatch(RoomId, PlayerId),
     #[error("the room is full. roomId={0}, playerId={1}")]
     RoomIsFull(RoomId, PlayerId),
+    #[error("the room has been closed. roomId={0}, playerId={1}")]
+    RoomClosed(RoomId, PlayerId),
+    #[error("the requester holds too many reservations in the room. roomId={0}, requester={1}")]
+    TooManyReservations(RoomId, RequesterId),
+}
+
+impl RoomError {
+    pub fn room_id(&self) -> &RoomId {
+        match self {
+            RoomError::AlreadyJoinedRoom(room_id, _)
+            | RoomError::RoomConfigDoesNotMatch(room_id, _)
+            | RoomError::RoomIsFull(room_id, _)
+            | RoomError::RoomClosed(room_id, _)
+            | RoomError::TooManyReservations(room_id, _) => room_id,
+        }
+    }
+}
+
+/// 席を予約した主体。プレイヤーが直接予約する場合とパーティがメンバー分を予約する場合がある。
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RequesterId {
+    Player(PlayerId),
+    Party(PartyId),
+}
+
+impl fmt::Display for RequesterId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequesterId::Player(id) => write!(f, "player:{}", id),
+            RequesterId::Party(id) => write!(f, "party:{}", id),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -24,27 +62,52 @@ pub struct RoomConfig {
     pub max_players: u32,
 }
 
-impl RoomConfig {
-    pub fn default() -> Self {
+impl Default for RoomConfig {
+    fn default() -> Self {
         RoomConfig {
             max_players: 2,
         }
     }
 }
 
+/// `properties`が`filter`の全てのキーと値を含んでいればtrue。
+/// 空の`filter`は全てのRoomに一致する。
+pub fn match_room_properties(properties: &RoomProperties, filter: &RoomProperties) -> bool {
+    filter
+        .iter()
+        .all(|(key, value)| properties.get(key) == Some(value))
+}
+
 #[derive(Debug)]
 pub struct Room<OutputMessageT> {
     pub id: RoomId,
     pub config: RoomConfig,
+    pub properties: RoomProperties,
     pub players: HashMap<PlayerId, Player<OutputMessageT>>,
+    // 予約済みの席。
+    // 予約された席はmax_playersに含まれ、予約されたプレイヤーしかJoinできない。
+    pub reservations: HashMap<PlayerId, Reservation>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reservation {
+    pub deadline: Instant,
+    // 予約した主体。同じRoomに持てる予約の数の制限と、予約の取り消しに使う。
+    pub requester_id: RequesterId,
 }
 
 impl<OutputMessageT> Room<OutputMessageT> {
     pub fn new(id: RoomId, config: RoomConfig) -> Self {
+        Self::with_properties(id, config, RoomProperties::new())
+    }
+
+    pub fn with_properties(id: RoomId, config: RoomConfig, properties: RoomProperties) -> Self {
         Self {
             id,
             config,
+            properties,
             players: HashMap::new(),
+            reservations: HashMap::new(),
         }
     }
 
@@ -64,16 +127,142 @@ impl<OutputMessageT> Room<OutputMessageT> {
             return Err(RoomError::AlreadyJoinedRoom(self.id.clone(), player.id));
         }
 
-        if self.num_players() >= self.config.max_players {
+        // 予約済みのプレイヤーは予約した席を使うので空きを確認しない。
+        if self.reservations.remove(&player.id).is_none()
+            && self.num_occupied_seats() >= self.config.max_players
+        {
             return Err(RoomError::RoomIsFull(self.id.clone(), player.id));
         }
 
-
         self.players.insert(player.id.clone(), player);
 
         Ok(())
     }
 
+    /// 指定したプレイヤー達の席を`deadline`まで予約する。
+    /// 全員分の席を確保できない場合は一人も予約しない。
+    /// 予約済みのプレイヤーを再度予約した場合は期限を更新し、予約したプレイヤーを`requester_id`にする。
+    /// `requester_id`の予約がこのRoomで`max_reservations`を超える場合も一人も予約しない。
+    pub fn reserve(
+        &mut self,
+        requester_id: &RequesterId,
+        player_ids: &[PlayerId],
+        config: &RoomConfig,
+        deadline: Instant,
+        max_reservations: u32,
+    ) -> Result<()> {
+        if let Some(player_id) = player_ids.first() {
+            if self.config != *config {
+                return Err(RoomError::RoomConfigDoesNotMatch(
+                    self.id.clone(),
+                    player_id.clone(),
+                ));
+            }
+        }
+
+        let mut new_reservations = 0;
+        for (i, player_id) in player_ids.iter().enumerate() {
+            if self.players.contains_key(player_id) {
+                return Err(RoomError::AlreadyJoinedRoom(
+                    self.id.clone(),
+                    player_id.clone(),
+                ));
+            }
+
+            if !self.reservations.contains_key(player_id) && !player_ids[..i].contains(player_id) {
+                new_reservations += 1;
+                if self.num_occupied_seats() + new_reservations > self.config.max_players {
+                    return Err(RoomError::RoomIsFull(self.id.clone(), player_id.clone()));
+                }
+            }
+        }
+
+        let mut requested: Vec<&PlayerId> = player_ids.iter().collect();
+        requested.sort();
+        requested.dedup();
+        let held = self
+            .reservations
+            .iter()
+            .filter(|(player_id, reservation)| {
+                reservation.requester_id == *requester_id && !player_ids.contains(player_id)
+            })
+            .count();
+        if held + requested.len() > max_reservations as usize {
+            return Err(RoomError::TooManyReservations(
+                self.id.clone(),
+                requester_id.clone(),
+            ));
+        }
+
+        player_ids.iter().for_each(|player_id| {
+            self.reservations.insert(
+                player_id.clone(),
+                Reservation {
+                    deadline,
+                    requester_id: requester_id.clone(),
+                },
+            );
+        });
+
+        Ok(())
+    }
+
+    /// 期限切れの予約を解放し、解放したプレイヤーのIDを返す。
+    pub fn release_expired_reservations(&mut self, now: Instant) -> Vec<PlayerId> {
+        let expired: Vec<PlayerId> = self
+            .reservations
+            .iter()
+            .filter(|(_, reservation)| reservation.deadline <= now)
+            .map(|(player_id, _)| player_id.clone())
+            .collect();
+        expired.iter().for_each(|player_id| {
+            self.reservations.remove(player_id);
+        });
+        expired
+    }
+
+    /// `requester_id`が予約した`player_ids`の席を解放し、解放したプレイヤーのIDを返す。
+    /// 他の主体が予約し直した席は解放しない。
+    pub fn cancel_reservations(
+        &mut self,
+        requester_id: &RequesterId,
+        player_ids: &[PlayerId],
+    ) -> Vec<PlayerId> {
+        let canceled: Vec<PlayerId> = player_ids
+            .iter()
+            .filter(|player_id| {
+                self.reservations
+                    .get(*player_id)
+                    .is_some_and(|reservation| reservation.requester_id == *requester_id)
+            })
+            .cloned()
+            .collect();
+        canceled.iter().for_each(|player_id| {
+            self.reservations.remove(player_id);
+        });
+        canceled
+    }
+
+    pub fn next_reservation_deadline(&self) -> Option<Instant> {
+        self.reservations
+            .values()
+            .map(|reservation| reservation.deadline)
+            .min()
+    }
+
+    pub fn is_reserved(&self, player_id: &PlayerId) -> bool {
+        self.reservations.contains_key(player_id)
+    }
+
+    pub fn num_reservations(&self) -> u32 {
+        self.reservations.len() as u32
+    }
+
+    /// 参加中のプレイヤーと予約済みの席の合計。
+    pub fn num_occupied_seats(&self) -> u32 {
+        self.num_players() + self.num_reservations()
+    }
+
     pub fn remove_player(&mut self, player_id: &PlayerId) -> bool {
         self.players.remove(player_id).is_some()
     }
@@ -88,7 +277,7 @@ impl<OutputMessageT> Room<OutputMessageT> {
     {
         self.players.iter_mut().for_each(|(_, player)| {
             if let Err(err) = player.send(event.clone()) {
-                // 切断によって送信できなかったケース。
+                // 切断したか、キューが溢れて送信できなかったケース。溢れた場合はPlayer actorが切断する。
                 // このプレイヤーをRoomから削除する等はRoomを利用する側の責務として、ここではログだけ出しておく。
                 warn!(
                     "failed to send message. player_id={}, error={}",
@@ -102,7 +291,7 @@ impl<OutputMessageT> Room<OutputMessageT> {
     pub fn send(&mut self, player_id: &PlayerId, event: OutputMessageT) {
         if let Some(player) = self.players.get_mut(player_id) {
             if let Err(err) = player.send(event) {
-                // 切断によって送信できなかったケース。
+                // 切断したか、キューが溢れて送信できなかったケース。溢れた場合はPlayer actorが切断する。
                 // このプレイヤーをRoomから削除する等はRoomを利用する側の責務として、ここではログだけ出しておく。
                 warn!(
                     "failed to send message. player_id={}, error={}",
@@ -117,6 +306,12 @@ impl<OutputMessageT> Room<OutputMessageT> {
         self.players.contains_key(player_id)
     }
 
+    /// 全てのプレイヤーと予約を取り除く。
+    pub fn clear(&mut self) {
+        self.players.clear();
+        self.reservations.clear();
+    }
+
 }
 
 #[cfg(test)]
@@ -124,6 +319,8 @@ mod tests {
     use super::*;
     use tokio::sync::mpsc;
 
+    const MAX_RESERVATIONS: u32 = 8;
+
     #[tokio::test]
     async fn join_room_and_send_message_normal() {
         let mut room = Room::new(
@@ -133,11 +330,11 @@ mod tests {
             },
         );
 
-        let (p1_tx, mut p1_rx) = mpsc::unbounded_channel();
+        let (p1_tx, mut p1_rx) = mpsc::channel(16);
         let p1_id = "p1".to_string();
         let p1 = Player::new(p1_id.clone(), p1_tx);
 
-        let (p2_tx, mut p2_rx) = mpsc::unbounded_channel();
+        let (p2_tx, mut p2_rx) = mpsc::channel(16);
         let p2 = Player::new("p2".to_string(), p2_tx);
 
         let result = room.add_player(
@@ -174,7 +371,7 @@ mod tests {
         };
         let mut room = Room::new("test".to_string(), room_config.clone());
 
-        let (tx, _) = mpsc::unbounded_channel::<()>();
+        let (tx, _) = mpsc::channel::<()>(1);
         let p1 = Player::new("p1".to_string(), tx.clone());
         let p2_id = "p2".to_string();
         let p2 = Player::new(p2_id.clone(), tx);
@@ -194,7 +391,7 @@ mod tests {
             },
         );
 
-        let (tx, _) = mpsc::unbounded_channel::<()>();
+        let (tx, _) = mpsc::channel::<()>(1);
         let p1_id = "p1".to_string();
         let p1 = Player::new(p1_id.clone(), tx);
 
@@ -217,7 +414,7 @@ mod tests {
         };
         let mut room = Room::new("test".to_string(), room_config.clone());
 
-        let (tx, _) = mpsc::unbounded_channel::<()>();
+        let (tx, _) = mpsc::channel::<()>(1);
         let p1 = Player::new("p1".to_string(), tx.clone());
         let p2_id = "p2".to_string();
         let p2 = Player::new(p2_id.clone(), tx);
@@ -229,4 +426,191 @@ mod tests {
         assert!(room.remove_player(&p2_id));
         assert_eq!(1, room.num_players());
     }
+
+    #[test]
+    fn reserved_seats_can_only_be_claimed_by_reserved_players() {
+        let room_config = RoomConfig {
+            max_players: 2,
+        };
+        let mut room = Room::new("test".to_string(), room_config.clone());
+        let deadline = Instant::now() + std::time::Duration::from_secs(10);
+
+        let (tx, _) = mpsc::channel::<()>(1);
+        let p1 = Player::new("p1".to_string(), tx.clone());
+        let p2_id = "p2".to_string();
+        let p2 = Player::new(p2_id.clone(), tx.clone());
+        let p3_id = "p3".to_string();
+        let p3 = Player::new(p3_id.clone(), tx);
+
+        room.add_player(p1, &room_config).unwrap();
+        room.reserve(
+            &RequesterId::Player("p1".to_string()),
+            std::slice::from_ref(&p2_id),
+            &room_config,
+            deadline,
+            MAX_RESERVATIONS,
+        )
+        .unwrap();
+        assert_eq!(2, room.num_occupied_seats());
+
+        let result = room.add_player(p3, &room_config);
+        assert_eq!(
+            RoomError::RoomIsFull(room.id.clone(), p3_id),
+            result.err().unwrap()
+        );
+
+        room.add_player(p2, &room_config).unwrap();
+        assert!(!room.is_reserved(&p2_id));
+        assert_eq!(2, room.num_players());
+    }
+
+    #[test]
+    fn failed_to_reserve_seats_over_capacity() {
+        let room_config = RoomConfig {
+            max_players: 2,
+        };
+        let mut room = Room::<()>::new("test".to_string(), room_config.clone());
+        let deadline = Instant::now() + std::time::Duration::from_secs(10);
+
+        let player_ids = vec!["p1".to_string(), "p2".to_string(), "p3".to_string()];
+        let result = room.reserve(
+            &RequesterId::Player("p1".to_string()),
+            &player_ids,
+            &room_config,
+            deadline,
+            MAX_RESERVATIONS,
+        );
+        assert_eq!(
+            RoomError::RoomIsFull(room.id.clone(), "p3".to_string()),
+            result.err().unwrap()
+        );
+        // 一人も予約されない。
+        assert_eq!(0, room.num_reservations());
+    }
+
+    #[test]
+    fn release_expired_reservations_normal() {
+        let room_config = RoomConfig {
+            max_players: 2,
+        };
+        let mut room = Room::<()>::new("test".to_string(), room_config.clone());
+        let now = Instant::now();
+
+        room.reserve(
+            &RequesterId::Player("p0".to_string()),
+            &["p1".to_string()],
+            &room_config,
+            now + std::time::Duration::from_secs(1),
+            MAX_RESERVATIONS,
+        )
+        .unwrap();
+        room.reserve(
+            &RequesterId::Player("p0".to_string()),
+            &["p2".to_string()],
+            &room_config,
+            now + std::time::Duration::from_secs(10),
+            MAX_RESERVATIONS,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(now + std::time::Duration::from_secs(1)),
+            room.next_reservation_deadline()
+        );
+
+        let expired = room.release_expired_reservations(now + std::time::Duration::from_secs(5));
+        assert_eq!(vec!["p1".to_string()], expired);
+        assert!(room.is_reserved(&"p2".to_string()));
+        assert_eq!(1, room.num_reservations());
+    }
+
+    #[test]
+    fn limit_reservations_per_requester() {
+        let room_config = RoomConfig {
+            max_players: 4,
+        };
+        let mut room = Room::<()>::new("test".to_string(), room_config.clone());
+        let deadline = Instant::now() + std::time::Duration::from_secs(10);
+        let requester_id = RequesterId::Player("p0".to_string());
+
+        room.reserve(
+            &requester_id,
+            &["p1".to_string(), "p2".to_string()],
+            &room_config,
+            deadline,
+            2,
+        )
+        .unwrap();
+        // 自分の予約の更新は数が増えないので成功する。
+        room.reserve(&requester_id, &["p2".to_string()], &room_config, deadline, 2)
+            .unwrap();
+
+        let result = room.reserve(&requester_id, &["p3".to_string()], &room_config, deadline, 2);
+        assert_eq!(
+            RoomError::TooManyReservations(room.id.clone(), requester_id),
+            result.err().unwrap()
+        );
+        assert_eq!(2, room.num_reservations());
+
+        // 他のプレイヤーは自分の上限まで予約できる。
+        let other_id = RequesterId::Player("p9".to_string());
+        room.reserve(&other_id, &["p3".to_string()], &room_config, deadline, 2)
+            .unwrap();
+        assert_eq!(3, room.num_reservations());
+    }
+
+    #[test]
+    fn cancel_reservations_only_held_by_requester() {
+        let room_config = RoomConfig {
+            max_players: 4,
+        };
+        let mut room = Room::<()>::new("test".to_string(), room_config.clone());
+        let deadline = Instant::now() + std::time::Duration::from_secs(10);
+        let party_id = RequesterId::Party("party".to_string());
+
+        room.reserve(
+            &party_id,
+            &["p1".to_string(), "p2".to_string()],
+            &room_config,
+            deadline,
+            MAX_RESERVATIONS,
+        )
+        .unwrap();
+        // p2は別のプレイヤーが予約し直したので、パーティからは取り消せない。
+        room.reserve(
+            &RequesterId::Player("p0".to_string()),
+            &["p2".to_string()],
+            &room_config,
+            deadline,
+            MAX_RESERVATIONS,
+        )
+        .unwrap();
+
+        let canceled =
+            room.cancel_reservations(&party_id, &["p1".to_string(), "p2".to_string()]);
+        assert_eq!(vec!["p1".to_string()], canceled);
+        assert!(!room.is_reserved(&"p1".to_string()));
+        assert!(room.is_reserved(&"p2".to_string()));
+    }
+
+    #[test]
+    fn match_room_properties_normal() {
+        let properties = RoomProperties::from([
+            ("mode".to_string(), "casual".to_string()),
+            ("map".to_string(), "forest".to_string()),
+        ]);
+
+        assert!(match_room_properties(&properties, &RoomProperties::new()));
+        assert!(match_room_properties(
+            &properties,
+            &RoomProperties::from([("mode".to_string(), "casual".to_string())]),
+        ));
+        assert!(!match_room_properties(
+            &properties,
+            &RoomProperties::from([("mode".to_string(), "ranked".to_string())]),
+        ));
+        assert!(!match_room_properties(
+            &properties,
+            &RoomProperties::from([("region".to_string(), "eu".to_string())]),
+        ));
+    }
 }
@@ -1,6 +1,11 @@
 //! for tests
 pub mod actor;
+pub mod admin;
+#[cfg(feature = "client")]
+pub mod client;
 pub mod config;
 pub mod entity;
+pub mod logging;
+pub mod metrics;
 pub mod protobuf;
 pub mod network_protocol;
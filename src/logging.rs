@@ -0,0 +1,111 @@
+//! ログの出力の設定。
+//! 接続ごとのスパンにはプロトコルと接続元のアドレス、ログイン後はプレイヤーのIDを持たせる。
+//! RoomとPartyのactorはそれぞれのIDを持つスパンで動く。
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clap::ValueEnum;
+use tracing::{field, info_span, Span};
+use tracing_subscriber::EnvFilter;
+
+use crate::network_protocol::server::Protocol;
+use crate::protobuf::app::{client_message::Data, ClientMessage};
+
+// メッセージの中身をログに出すかどうか。本文やトークンを含むので、既定では伏せる。
+static LOG_PAYLOADS: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    // 人が読むための複数行の形式。
+    Pretty,
+    // 1行に1つのJSONのオブジェクト。スパンのフィールドも含める。
+    Json,
+}
+
+/// グローバルなsubscriberを設定する。`RUST_LOG`が無い場合はinfo以上を出力する。
+/// `log`クレートを使っている依存クレートのログも同じ形式で出力される。
+pub fn init(format: LogFormat, log_payloads: bool) {
+    LOG_PAYLOADS.store(log_payloads, Ordering::Relaxed);
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Pretty => builder.pretty().init(),
+        LogFormat::Json => builder.json().with_span_list(true).init(),
+    }
+}
+
+/// クライアントとの接続のスパン。`player_id`はPlayer actorがログインの成功時に記録する。
+pub fn connection_span(protocol: Protocol, remote_addr: impl fmt::Debug) -> Span {
+    let protocol = protocol
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_string());
+    info_span!(
+        "connection",
+        %protocol,
+        remote_addr = ?remote_addr,
+        player_id = field::Empty,
+    )
+}
+
+/// ログに出すメッセージの中身。`--log-payloads`が指定されていない場合は伏せる。
+pub fn payload<T: fmt::Debug>(value: T) -> Payload<T> {
+    Payload(value)
+}
+
+pub struct Payload<T>(T);
+
+impl<T: fmt::Debug> fmt::Debug for Payload<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if LOG_PAYLOADS.load(Ordering::Relaxed) {
+            self.0.fmt(f)
+        } else {
+            f.write_str("<redacted>")
+        }
+    }
+}
+
+/// ClientMessageの種類。中身を伏せていても、何を受け取ったかは分かるようにする。
+pub fn client_message_kind(message: &ClientMessage) -> &'static str {
+    match &message.data {
+        None => "Empty",
+        Some(Data::LoginRequest(_)) => "LoginRequest",
+        Some(Data::JoinRequest(_)) => "JoinRequest",
+        Some(Data::LeaveRequest(_)) => "LeaveRequest",
+        Some(Data::SendMessage(_)) => "SendMessage",
+        Some(Data::JoinRandomRoomRequest(_)) => "JoinRandomRoomRequest",
+        Some(Data::ReserveSeatsRequest(_)) => "ReserveSeatsRequest",
+        Some(Data::CreatePartyRequest(_)) => "CreatePartyRequest",
+        Some(Data::InvitePartyRequest(_)) => "InvitePartyRequest",
+        Some(Data::JoinPartyRequest(_)) => "JoinPartyRequest",
+        Some(Data::LeavePartyRequest(_)) => "LeavePartyRequest",
+        Some(Data::PartyJoinRoomRequest(_)) => "PartyJoinRoomRequest",
+        Some(Data::PartyMessageRequest(_)) => "PartyMessageRequest",
+        Some(Data::DirectMessageRequest(_)) => "DirectMessageRequest",
+        Some(Data::PresenceSubscribeRequest(_)) => "PresenceSubscribeRequest",
+        Some(Data::PresenceUnsubscribeRequest(_)) => "PresenceUnsubscribeRequest",
+        Some(Data::ChannelSubscribeRequest(_)) => "ChannelSubscribeRequest",
+        Some(Data::ChannelUnsubscribeRequest(_)) => "ChannelUnsubscribeRequest",
+        Some(Data::ChannelPublishRequest(_)) => "ChannelPublishRequest",
+        Some(Data::CompressedMessage(_)) => "CompressedMessage",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_payload_unless_enabled() {
+        let message = ClientMessage {
+            data: Some(Data::LoginRequest(Default::default())),
+        };
+        assert_eq!("<redacted>", format!("{:?}", payload(&message)));
+        assert_eq!("LoginRequest", client_message_kind(&message));
+
+        LOG_PAYLOADS.store(true, Ordering::Relaxed);
+        let logged = format!("{:?}", payload(&message));
+        LOG_PAYLOADS.store(false, Ordering::Relaxed);
+        assert_eq!(format!("{:?}", message), logged);
+    }
+}
@@ -1,65 +1,130 @@
-use std::env;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use clap::Parser;
-use env_logger;
-use log::info;
+use tracing::{error, info};
 
-mod actor;
-mod config;
-mod entity;
-mod protobuf;
-mod network_protocol;
-
-use network_protocol::*;
+use mini_realtime_server::actor;
+use mini_realtime_server::admin;
+use mini_realtime_server::config;
+use mini_realtime_server::logging;
+use mini_realtime_server::network_protocol::*;
 
 #[tokio::main]
 async fn main() {
-    if env::var("RUST_LOG").is_err() {
-        env::set_var("RUST_LOG", "info");
-    }
-
-    env_logger::init();
-
     let args = Args::parse();
-    let addr = args.address.parse::<SocketAddr>().unwrap();
+    logging::init(args.log_format, args.log_payloads);
+
     let config = Arc::new(config::Config {
         auth: config::Auth {
             enable_bearer: args.enable_auth_bearer,
             bearer: args.auth_bearer,
+            trusted_unix_uids: args.trusted_unix_uids,
         },
         tls: config::Tls {
             enable: args.enable_tls,
             cert_file_path: args.tls_cert_file_path,
             key_file_path: args.tls_key_file_path,
         },
+        compression: config::Compression {
+            enable: args.enable_compression,
+            threshold: args.compression_threshold,
+        },
+        compatibility: config::Compatibility {
+            min_protocol_version: args.min_protocol_version,
+        },
+        limits: config::Limits {
+            max_frame_size: args.max_frame_size,
+            max_body_size: args.max_body_size,
+            max_reservation_timeout: Duration::from_millis(args.max_reservation_timeout_ms),
+            max_reservations_per_requester: args.max_reservations_per_requester,
+            max_udp_sessions: args.max_udp_sessions,
+            max_http_sessions: args.max_http_sessions,
+            max_http_sessions_per_addr: args.max_http_sessions_per_addr,
+        },
+        outbound: config::Outbound {
+            queue_size: args.output_queue_size,
+            overflow_policy: args.overflow_policy,
+        },
+        admin: config::Admin {
+            token: args.admin_token,
+            enable_grpc: args.enable_admin_grpc,
+        },
     });
-    info!("Start server. address={:?}", args.address);
-    match args.protocol.as_str() {
-        "websocket" => {
-            server::run::<websocket::ServerImpl, _, _>(addr, config, server::wait_signal())
-                .await
-                .unwrap()
-        }
-        "grpc" => server::run::<grpc::ServerImpl, _, _>(addr, config, server::wait_signal())
-            .await
-            .unwrap(),
-        "tcp" => server::run::<tcp::ServerImpl, _, _>(addr, config, server::wait_signal())
-            .await
-            .unwrap(),
-        _ => panic!("invalid protocol"),
+    // --listenが指定されていない場合は、--protocolと--addrの1つだけを起動する。
+    let listeners = if args.listeners.is_empty() {
+        vec![(args.protocol, args.address.into())]
+    } else {
+        args.listeners
     };
+    info!("Start server. listeners={:?}", listeners);
+    let registries = Arc::new(actor::Registries::new());
+    if let Some(admin_addr) = args.admin_address {
+        let registries = registries.clone();
+        let admin_token = config.admin.token.clone();
+        tokio::spawn(async move {
+            if let Err(err) =
+                admin::run(admin_addr, registries, admin_token, server::wait_signal()).await
+            {
+                error!("Failed to run admin server. {:?}", err);
+            }
+        });
+    }
+    server::run_all(listeners, config, registries, server::wait_signal())
+        .await
+        .unwrap();
+}
+
+fn parse_listener(s: &str) -> Result<(server::Protocol, server::ListenAddr), String> {
+    let (protocol, addr) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <PROTOCOL>=<ADDR> but got `{}`", s))?;
+    let protocol = <server::Protocol as clap::ValueEnum>::from_str(protocol, true)?;
+    let addr = addr.parse::<server::ListenAddr>()?;
+    if matches!(addr, server::ListenAddr::Unix(_)) && protocol != server::Protocol::Tcp {
+        return Err("unix domain socket is only supported by tcp".to_string());
+    }
+    Ok((protocol, addr))
 }
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    #[clap(short = 'p', long = "protocol", default_value = "websocket")]
-    protocol: String,
+    #[clap(
+        short = 'p',
+        long = "protocol",
+        value_enum,
+        default_value = "websocket"
+    )]
+    protocol: server::Protocol,
 
     #[clap(short = 'a', long = "addr", default_value = "127.0.0.1:8000")]
-    address: String,
+    address: SocketAddr,
+
+    /// Serve multiple protocols at once (e.g. -l websocket=0.0.0.0:8000 -l tcp=0.0.0.0:8002 -l tcp=unix:/tmp/app.sock)
+    #[clap(short = 'l', long = "listen", value_name = "PROTOCOL=ADDR", value_parser = parse_listener)]
+    listeners: Vec<(server::Protocol, server::ListenAddr)>,
+
+    /// Serve Prometheus metrics at /metrics and the admin API at /api on this address
+    #[clap(long = "admin-addr", value_name = "ADDR")]
+    admin_address: Option<SocketAddr>,
+
+    /// Bearer token required by the admin API. The API is disabled without it. Prefer the environment variable, since command-line flags are visible to other users in the process list
+    #[clap(long = "admin-token", value_name = "TOKEN", env = "ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+
+    /// Serve the Admin gRPC service on the gRPC listeners alongside the App service
+    #[clap(long = "enable-admin-grpc", action = clap::ArgAction::Set, default_value = "false")]
+    enable_admin_grpc: bool,
+
+    /// Format of log output. The level is set with RUST_LOG (default: info)
+    #[clap(long = "log-format", value_enum, default_value = "pretty")]
+    log_format: logging::LogFormat,
+
+    /// Include message payloads such as bodies and tokens in debug and trace logs
+    #[clap(long = "log-payloads")]
+    log_payloads: bool,
 
     #[clap(long = "enable-auth-bearer", action = clap::ArgAction::Set, default_value = "true")]
     enable_auth_bearer: bool,
@@ -67,6 +132,10 @@ struct Args {
     #[clap(long = "auth-bearer", default_value = "test")]
     auth_bearer: String,
 
+    /// Allow processes of this user to log in over a unix domain socket without a token
+    #[clap(long = "trusted-unix-uid", value_name = "UID")]
+    trusted_unix_uids: Vec<u32>,
+
     #[clap(long = "enable-tls", action = clap::ArgAction::Set, default_value = "false")]
     enable_tls: bool,
 
@@ -81,4 +150,52 @@ struct Args {
         default_value = "./server.key"
     )]
     tls_key_file_path: String,
+
+    /// Accept compression requested by clients at login and permessage-deflate offered in WebSocket handshakes. When disabled, compressed messages from clients are rejected
+    #[clap(long = "enable-compression", action = clap::ArgAction::Set, default_value = "true")]
+    enable_compression: bool,
+
+    /// Messages and WebSocket frames smaller than this size in bytes are sent uncompressed
+    #[clap(long = "compression-threshold", default_value = "1024")]
+    compression_threshold: usize,
+
+    /// Reject clients whose protocol version is older than this
+    #[clap(long = "min-protocol-version", default_value = "0")]
+    min_protocol_version: u32,
+
+    /// Maximum size in bytes of a frame, WebSocket message or gRPC message exchanged with a client. Also caps the out-of-order UDP payloads buffered per session
+    #[clap(long = "max-frame-size", default_value = "1048576")]
+    max_frame_size: usize,
+
+    /// Maximum size in bytes of a message body such as SendMessage.body
+    #[clap(long = "max-body-size", default_value = "65536")]
+    max_body_size: usize,
+
+    /// Maximum lifetime in milliseconds of a seat reservation. Longer requested timeouts are clamped
+    #[clap(long = "max-reservation-timeout-ms", default_value = "30000")]
+    max_reservation_timeout_ms: u64,
+
+    /// Maximum number of seats one player can hold reserved in a room
+    #[clap(long = "max-reservations-per-requester", default_value = "8")]
+    max_reservations_per_requester: u32,
+
+    /// Maximum number of concurrent UDP sessions. New connections beyond this are rejected
+    #[clap(long = "max-udp-sessions", default_value = "10000")]
+    max_udp_sessions: usize,
+
+    /// Maximum number of concurrent HTTP fallback sessions. New sessions beyond this are rejected with 503
+    #[clap(long = "max-http-sessions", default_value = "10000")]
+    max_http_sessions: usize,
+
+    /// Maximum number of concurrent HTTP fallback sessions from one IP address. Excess sessions are rejected with 429
+    #[clap(long = "max-http-sessions-per-addr", default_value = "16")]
+    max_http_sessions_per_addr: usize,
+
+    /// Maximum number of messages queued for a player who is not reading them
+    #[clap(long = "output-queue-size", default_value = "1024")]
+    output_queue_size: usize,
+
+    /// What to do when a player's output queue is full
+    #[clap(long = "overflow-policy", value_enum, default_value = "disconnect")]
+    overflow_policy: config::OverflowPolicy,
 }
@@ -0,0 +1,506 @@
+//! サーバ全体で集計するメトリクス。
+//! 管理用のポートの`/metrics`から、Prometheusのテキスト形式で公開する。
+
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use crate::actor::Registries;
+use crate::network_protocol::server::Protocol;
+use crate::protobuf::app::ErrorCode;
+
+pub static METRICS: Metrics = Metrics::new();
+
+const PREFIX: &str = "mini_realtime";
+
+// server::Protocolのバリアントの数。
+// バリアントを増やすとmatchが網羅的でなくなるので、ここで最後のバリアントを直し忘れない。
+const NUM_PROTOCOLS: usize = {
+    const fn last(protocol: Protocol) -> Protocol {
+        match protocol {
+            Protocol::Websocket
+            | Protocol::Grpc
+            | Protocol::Tcp
+            | Protocol::Udp
+            | Protocol::Quic
+            | Protocol::HttpFallback => Protocol::HttpFallback,
+        }
+    }
+    last(Protocol::Websocket) as usize + 1
+};
+// ErrorCodeの値の上限。これ以上の値はまとめて数える。
+const NUM_ERROR_CODES: usize = 32;
+
+/// Roomの参加人数のバケットの上限。
+const ROOM_SIZE_BUCKETS: [f64; 11] = [
+    1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0, 512.0, 1024.0,
+];
+
+/// プレイヤーがメッセージを送ってから、Roomが全員に配り終えるまでの時間(秒)のバケット。
+const ROOM_MESSAGE_LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+#[derive(Debug)]
+pub struct Metrics {
+    slow_consumer_disconnects: AtomicU64,
+    dropped_messages: AtomicU64,
+    coalesced_messages: AtomicU64,
+    protocols: [ProtocolMetrics; NUM_PROTOCOLS],
+    login_failures: [AtomicU64; NUM_ERROR_CODES],
+    active_rooms: AtomicI64,
+    // 参加者のいるRoomを現在の人数のバケットごとに数える。最後の要素はどの上限よりも多いRoom。
+    // 増減するのでヒストグラムではなくゲージとして書き出す。
+    rooms_by_size: [AtomicI64; ROOM_SIZE_BUCKETS.len() + 1],
+    room_message_latency: Histogram<12>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            slow_consumer_disconnects: AtomicU64::new(0),
+            dropped_messages: AtomicU64::new(0),
+            coalesced_messages: AtomicU64::new(0),
+            protocols: [const { ProtocolMetrics::new() }; NUM_PROTOCOLS],
+            login_failures: [const { AtomicU64::new(0) }; NUM_ERROR_CODES],
+            active_rooms: AtomicI64::new(0),
+            rooms_by_size: [const { AtomicI64::new(0) }; ROOM_SIZE_BUCKETS.len() + 1],
+            room_message_latency: Histogram::new(ROOM_MESSAGE_LATENCY_BUCKETS),
+        }
+    }
+
+    /// 送信キューが溢れたために切断したプレイヤーの数。
+    pub fn slow_consumer_disconnects(&self) -> u64 {
+        self.slow_consumer_disconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn inc_slow_consumer_disconnects(&self) {
+        self.slow_consumer_disconnects
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 送信キューが溢れたために捨てた信頼性の無いメッセージの数。
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped_messages.load(Ordering::Relaxed)
+    }
+
+    pub fn inc_dropped_messages(&self) {
+        self.dropped_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 送信キューが溢れたために新しいメッセージで置き換えたメッセージの数。
+    pub fn coalesced_messages(&self) -> u64 {
+        self.coalesced_messages.load(Ordering::Relaxed)
+    }
+
+    pub fn inc_coalesced_messages(&self) {
+        self.coalesced_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// プレイヤーの接続を数え始める。返り値をDropすると切断したとみなす。
+    pub fn connect(&'static self, protocol: Protocol) -> Connection {
+        let metrics = &self.protocols[protocol as usize];
+        metrics.connected_players.fetch_add(1, Ordering::Relaxed);
+        Connection { metrics }
+    }
+
+    pub fn inc_login_failures(&self, code: ErrorCode) {
+        let i = (code as usize).min(NUM_ERROR_CODES - 1);
+        self.login_failures[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_active_rooms(&self) {
+        self.active_rooms.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec_active_rooms(&self) {
+        self.active_rooms.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Roomの人数が変わった時に呼ぶ。0人のRoomは分布に含めない。
+    pub fn resize_room(&self, before: u32, after: u32) {
+        if before == after {
+            return;
+        }
+        if before > 0 {
+            self.rooms_by_size[room_size_bucket(before)].fetch_sub(1, Ordering::Relaxed);
+        }
+        if after > 0 {
+            self.rooms_by_size[room_size_bucket(after)].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn observe_room_message_latency(&self, latency: Duration) {
+        self.room_message_latency.observe(latency.as_secs_f64());
+    }
+
+    /// Prometheusのテキスト形式で書き出す。キューの長さは書き出す時点の値を`registries`から集める。
+    pub fn encode(&self, registries: &Registries) -> String {
+        let mut out = String::new();
+        let protocols = Protocol::value_variants();
+
+        write_header(
+            &mut out,
+            "connected_players",
+            "gauge",
+            "Number of connected players.",
+        );
+        for protocol in protocols {
+            let value = self.protocols[*protocol as usize]
+                .connected_players
+                .load(Ordering::Relaxed);
+            write_sample(
+                &mut out,
+                "connected_players",
+                &protocol_label(*protocol),
+                value,
+            );
+        }
+
+        let counters: [(&str, &str, CounterField); 4] = [
+            (
+                "messages_received_total",
+                "Number of messages received from clients.",
+                |m| &m.messages_received,
+            ),
+            (
+                "messages_sent_total",
+                "Number of messages sent to clients.",
+                |m| &m.messages_sent,
+            ),
+            (
+                "received_bytes_total",
+                "Bytes received from clients.",
+                |m| &m.received_bytes,
+            ),
+            ("sent_bytes_total", "Bytes sent to clients.", |m| {
+                &m.sent_bytes
+            }),
+        ];
+        for (name, help, counter) in counters {
+            write_header(&mut out, name, "counter", help);
+            for protocol in protocols {
+                let value = counter(&self.protocols[*protocol as usize]).load(Ordering::Relaxed);
+                write_sample(&mut out, name, &protocol_label(*protocol), value);
+            }
+        }
+
+        write_header(
+            &mut out,
+            "login_failures_total",
+            "counter",
+            "Number of failed logins by error code.",
+        );
+        for (i, counter) in self.login_failures.iter().enumerate() {
+            let value = counter.load(Ordering::Relaxed);
+            // 一度も起きていないエラーコードは省く。
+            if value == 0 {
+                continue;
+            }
+            let code = ErrorCode::try_from(i as i32).map_or("UNKNOWN", |code| code.as_str_name());
+            write_sample(
+                &mut out,
+                "login_failures_total",
+                &format!("code=\"{}\"", code),
+                value,
+            );
+        }
+
+        write_header(
+            &mut out,
+            "active_rooms",
+            "gauge",
+            "Number of running rooms.",
+        );
+        write_sample(
+            &mut out,
+            "active_rooms",
+            "",
+            self.active_rooms.load(Ordering::Relaxed),
+        );
+
+        write_header(
+            &mut out,
+            "rooms_by_size",
+            "gauge",
+            "Number of rooms that have players, by the upper bound of their player count.",
+        );
+        for (i, rooms) in self.rooms_by_size.iter().enumerate() {
+            let max_players = ROOM_SIZE_BUCKETS
+                .get(i)
+                .map_or_else(|| "+Inf".to_string(), |bound| bound.to_string());
+            write_sample(
+                &mut out,
+                "rooms_by_size",
+                &format!("max_players=\"{}\"", max_players),
+                rooms.load(Ordering::Relaxed),
+            );
+        }
+
+        write_header(
+            &mut out,
+            "room_message_latency_seconds",
+            "histogram",
+            "Time from a player sending a message to the room delivering it to the recipients.",
+        );
+        self.room_message_latency
+            .write(&mut out, "room_message_latency_seconds");
+
+        let queue_depths = registries.queue_depths();
+        let queues = [
+            ("player", queue_depths.player),
+            ("room", queue_depths.room),
+            ("party", queue_depths.party),
+        ];
+        write_header(
+            &mut out,
+            "queue_depth",
+            "gauge",
+            "Number of events queued in actor channels.",
+        );
+        for (queue, depth) in &queues {
+            write_sample(
+                &mut out,
+                "queue_depth",
+                &format!("queue=\"{}\"", queue),
+                depth.total,
+            );
+        }
+        write_header(
+            &mut out,
+            "queue_depth_max",
+            "gauge",
+            "Largest number of events queued in a single actor channel.",
+        );
+        for (queue, depth) in &queues {
+            write_sample(
+                &mut out,
+                "queue_depth_max",
+                &format!("queue=\"{}\"", queue),
+                depth.max,
+            );
+        }
+
+        let outbound = [
+            (
+                "slow_consumer_disconnects_total",
+                "Number of players disconnected because their output queue overflowed.",
+                self.slow_consumer_disconnects(),
+            ),
+            (
+                "dropped_messages_total",
+                "Number of unreliable messages dropped because an output queue overflowed.",
+                self.dropped_messages(),
+            ),
+            (
+                "coalesced_messages_total",
+                "Number of messages replaced by newer ones because an output queue overflowed.",
+                self.coalesced_messages(),
+            ),
+        ];
+        for (name, help, value) in outbound {
+            write_header(&mut out, name, "counter", help);
+            write_sample(&mut out, name, "", value);
+        }
+        out
+    }
+}
+
+// ProtocolMetricsからカウンタを1つ取り出す関数。
+type CounterField = fn(&ProtocolMetrics) -> &AtomicU64;
+
+#[derive(Debug)]
+struct ProtocolMetrics {
+    connected_players: AtomicI64,
+    messages_received: AtomicU64,
+    messages_sent: AtomicU64,
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+}
+
+impl ProtocolMetrics {
+    const fn new() -> Self {
+        Self {
+            connected_players: AtomicI64::new(0),
+            messages_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            received_bytes: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+        }
+    }
+}
+
+/// 1つの接続で送受信したメッセージを数える。
+#[derive(Debug)]
+pub struct Connection {
+    metrics: &'static ProtocolMetrics,
+}
+
+impl Connection {
+    pub fn received(&self, bytes: usize) {
+        self.metrics
+            .messages_received
+            .fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .received_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.metrics.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.metrics
+            .sent_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.metrics
+            .connected_players
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 上限が`bounds`のバケットに値を数えるヒストグラム。
+#[derive(Debug)]
+struct Histogram<const N: usize> {
+    bounds: [f64; N],
+    // 累積ではない各バケットの数。
+    buckets: [AtomicU64; N],
+    // どのバケットの上限よりも大きい値の数。
+    overflow: AtomicU64,
+    // 合計値はf64のビット列で持つ。
+    sum: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    const fn new(bounds: [f64; N]) -> Self {
+        Self {
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; N],
+            overflow: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn bucket(&self, value: f64) -> &AtomicU64 {
+        match self.bounds.iter().position(|bound| value <= *bound) {
+            Some(i) => &self.buckets[i],
+            None => &self.overflow,
+        }
+    }
+
+    fn add_sum(&self, delta: f64) {
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some((f64::from_bits(sum) + delta).to_bits())
+            });
+    }
+
+    fn observe(&self, value: f64) {
+        self.bucket(value).fetch_add(1, Ordering::Relaxed);
+        self.add_sum(value);
+    }
+
+    fn write(&self, out: &mut String, name: &str) {
+        let bucket_name = format!("{}_bucket", name);
+        let mut count = 0;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            count += bucket.load(Ordering::Relaxed);
+            write_sample(out, &bucket_name, &format!("le=\"{}\"", bound), count);
+        }
+        count += self.overflow.load(Ordering::Relaxed);
+        write_sample(out, &bucket_name, "le=\"+Inf\"", count);
+        write_sample(
+            out,
+            &format!("{}_sum", name),
+            "",
+            f64::from_bits(self.sum.load(Ordering::Relaxed)),
+        );
+        write_sample(out, &format!("{}_count", name), "", count);
+    }
+}
+
+// 人数が`players`のRoomを数えるrooms_by_sizeの添字。
+fn room_size_bucket(players: u32) -> usize {
+    ROOM_SIZE_BUCKETS
+        .iter()
+        .position(|bound| players as f64 <= *bound)
+        .unwrap_or(ROOM_SIZE_BUCKETS.len())
+}
+
+fn protocol_label(protocol: Protocol) -> String {
+    let name = protocol
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_string());
+    format!("protocol=\"{}\"", name)
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    if labels.is_empty() {
+        let _ = writeln!(out, "{}_{} {}", PREFIX, name, value);
+    } else {
+        let _ = writeln!(out, "{}_{}{{{}}} {}", PREFIX, name, labels, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new([1.0, 2.0, 4.0]);
+        histogram.observe(1.0);
+        histogram.observe(3.0);
+        histogram.observe(3.0);
+        histogram.observe(8.0);
+
+        let mut out = String::new();
+        histogram.write(&mut out, "size");
+        assert_eq!(
+            [
+                "mini_realtime_size_bucket{le=\"1\"} 1",
+                "mini_realtime_size_bucket{le=\"2\"} 1",
+                "mini_realtime_size_bucket{le=\"4\"} 3",
+                "mini_realtime_size_bucket{le=\"+Inf\"} 4",
+                "mini_realtime_size_sum 15",
+                "mini_realtime_size_count 4",
+            ]
+            .join("\n")
+                + "\n",
+            out
+        );
+    }
+
+    #[test]
+    fn num_protocols_matches_variants() {
+        assert_eq!(NUM_PROTOCOLS, Protocol::value_variants().len());
+    }
+
+    #[test]
+    fn rooms_move_between_size_buckets() {
+        assert_eq!(0, room_size_bucket(1));
+        assert_eq!(2, room_size_bucket(3));
+        assert_eq!(ROOM_SIZE_BUCKETS.len(), room_size_bucket(2000));
+
+        let metrics = Metrics::new();
+        metrics.resize_room(0, 1);
+        metrics.resize_room(1, 3);
+        metrics.resize_room(0, 2000);
+        metrics.resize_room(2000, 0);
+        let rooms = |i: usize| metrics.rooms_by_size[i].load(Ordering::Relaxed);
+        assert_eq!(0, rooms(0));
+        assert_eq!(1, rooms(2));
+        assert_eq!(0, rooms(ROOM_SIZE_BUCKETS.len()));
+    }
+}
@@ -1,6 +1,11 @@
 //! 各通信プロトコルの実装。
 
+pub mod codec;
+pub mod compression;
 pub mod grpc;
+pub mod http_fallback;
+pub mod quic;
 pub mod server;
 pub mod tcp;
+pub mod udp;
 pub mod websocket;
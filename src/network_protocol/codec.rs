@@ -0,0 +1,180 @@
+//! メッセージのエンコード方式。トランスポートとは独立して、接続ごとに選択される。
+//! WebSocketではサブプロトコルで、TCP、QUIC、UDPでは接続直後のコーデック選択フレームで選択する。
+//! gRPCはprotobufでのやり取りが前提なので対象外。
+
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+/// フレームを持つプロトコル(TCP, QUIC, UDP)で、接続直後に送ってコーデックを選択するフレームの接頭辞。
+/// 例えば`codec:json`。送らなかった場合はprotobufになる。
+/// 先頭の`c`はprotobufではfield 12のgroup開始タグになり、ClientMessageとしては不正なので通常のメッセージと区別できる。
+pub const CODEC_SELECTION_PREFIX: &[u8] = b"codec:";
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("unknown codec. name={0}")]
+    UnknownCodec(String),
+    #[error(transparent)]
+    ProtobufDecode(#[from] prost::DecodeError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+    #[error(transparent)]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Protobuf,
+    // protobufのJSONマッピング。bytesはbase64の文字列になる。
+    Json,
+    // JSONマッピングと同じ構造をMessagePackで表現したもの。
+    MessagePack,
+}
+
+impl Codec {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Protobuf => "protobuf",
+            Codec::Json => "json",
+            Codec::MessagePack => "msgpack",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, CodecError> {
+        [Codec::Protobuf, Codec::Json, Codec::MessagePack]
+            .into_iter()
+            .find(|codec| codec.name() == name)
+            .ok_or_else(|| CodecError::UnknownCodec(name.to_string()))
+    }
+
+    /// テキストとして扱えるエンコードかどうか。WebSocketのフレームの種類の選択に使う。
+    pub fn is_text(&self) -> bool {
+        *self == Codec::Json
+    }
+
+    pub fn encode<M>(&self, message: &M) -> Result<Vec<u8>, CodecError>
+    where
+        M: prost::Message + Serialize,
+    {
+        match self {
+            Codec::Protobuf => Ok(message.encode_to_vec()),
+            Codec::Json => Ok(serde_json::to_vec(message)?),
+            // pbjsonのシリアライズはフィールドを省略するので、配列ではなくフィールド名付きのmapにする。
+            Codec::MessagePack => Ok(rmp_serde::to_vec_named(message)?),
+        }
+    }
+
+    pub fn decode<M>(&self, buf: &[u8]) -> Result<M, CodecError>
+    where
+        M: prost::Message + DeserializeOwned + Default,
+    {
+        match self {
+            Codec::Protobuf => Ok(M::decode(buf)?),
+            Codec::Json => Ok(serde_json::from_slice(buf)?),
+            Codec::MessagePack => Ok(rmp_serde::from_slice(buf)?),
+        }
+    }
+
+    /// `CODEC_SELECTION_PREFIX`から始まるフレームの場合は、選択されたコーデックを返す。
+    pub fn from_selection_frame(frame: &[u8]) -> Option<Result<Self, CodecError>> {
+        let name = frame.strip_prefix(CODEC_SELECTION_PREFIX)?;
+        Some(Self::from_name(&String::from_utf8_lossy(name)))
+    }
+
+    pub fn selection_frame(&self) -> Vec<u8> {
+        [CODEC_SELECTION_PREFIX, self.name().as_bytes()].concat()
+    }
+}
+
+/// 接続直後のコーデック選択フレームを扱いながら、受信したフレームをデコードする。
+#[derive(Debug, Default)]
+pub struct CodecSelector {
+    codec: Codec,
+    received: bool,
+}
+
+impl CodecSelector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// コーデック選択フレームを受信した場合はNoneを返す。選択できるのは最初のフレームだけ。
+    pub fn decode<M>(&mut self, frame: &[u8]) -> Result<Option<M>, CodecError>
+    where
+        M: prost::Message + DeserializeOwned + Default,
+    {
+        if !self.received {
+            self.received = true;
+            if let Some(codec) = Codec::from_selection_frame(frame) {
+                self.codec = codec?;
+                return Ok(None);
+            }
+        }
+        self.codec.decode(frame).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::app;
+
+    #[test]
+    fn encode_and_decode_with_each_codec() {
+        let message = app::ClientMessage {
+            data: Some(app::client_message::Data::SendMessage(app::SendMessage {
+                target_ids: vec!["p1".to_string()],
+                room_id: "room".to_string(),
+                body: vec![0, 1, 2, 255],
+                delivery: app::Delivery::Unreliable as i32,
+            })),
+        };
+        for codec in [Codec::Protobuf, Codec::Json, Codec::MessagePack] {
+            let buf = codec.encode(&message).unwrap();
+            assert_eq!(message, codec.decode(&buf).unwrap());
+            assert_eq!(codec, Codec::from_name(codec.name()).unwrap());
+        }
+
+        let json = String::from_utf8(Codec::Json.encode(&message).unwrap()).unwrap();
+        assert!(json.contains(r#""body":"AAEC/w==""#));
+        assert!(json.contains(r#""delivery":"UNRELIABLE""#));
+    }
+
+    #[test]
+    fn selection_frame_is_not_a_valid_protobuf_message() {
+        let frame = Codec::MessagePack.selection_frame();
+        assert_eq!(
+            Codec::MessagePack,
+            Codec::from_selection_frame(&frame).unwrap().unwrap()
+        );
+        assert!(Codec::Protobuf
+            .decode::<crate::protobuf::app::ClientMessage>(&frame)
+            .is_err());
+        assert!(Codec::from_selection_frame(b"codec:xml").unwrap().is_err());
+        assert!(Codec::from_selection_frame(&[0x0a, 0x00]).is_none());
+    }
+
+    #[test]
+    fn codec_can_be_selected_only_by_first_frame() {
+        let message = app::ClientMessage::default();
+        let mut selector = CodecSelector::new();
+        let selected: Option<app::ClientMessage> =
+            selector.decode(&Codec::Json.selection_frame()).unwrap();
+        assert!(selected.is_none());
+        assert_eq!(Codec::Json, selector.codec());
+
+        let buf = Codec::Json.encode(&message).unwrap();
+        assert_eq!(Some(message), selector.decode(&buf).unwrap());
+        // 2回目以降の選択フレームは通常のメッセージとして扱われるので不正になる。
+        assert!(selector
+            .decode::<app::ClientMessage>(&Codec::MessagePack.selection_frame())
+            .is_err());
+    }
+}
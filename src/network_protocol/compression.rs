@@ -0,0 +1,334 @@
+//! ログイン時に合意した方式によるメッセージ単位の圧縮。
+//! 圧縮したメッセージは`CompressedMessage`に包んで送るので、全てのトランスポートで同じように扱える。
+//! サーバからはログイン成功後、閾値以上の大きさのメッセージだけを圧縮して送る。
+//!
+//! WebSocketではこれとは別に、ハンドシェイクで合意したpermessage-deflate拡張でフレームを圧縮する(`websocket::deflate`)。
+
+use std::io::{Read, Write};
+
+use bytes::Bytes;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use prost::Message as _;
+use thiserror::Error;
+use tracing::error;
+
+use super::codec::{Codec, CodecError};
+use crate::actor::{OutputMessage, SharedMessage, NUM_FRAME_SLOTS};
+use crate::config;
+use crate::protobuf::app::{
+    client_message, server_message, ClientMessage, CompressedMessage, Compression, ErrorCode,
+    ServerMessage,
+};
+
+const ZSTD_LEVEL: i32 = 3;
+
+// 共有するメッセージのフレームは、コーデックと圧縮方式の組み合わせごとに置く。
+const NUM_CODECS: usize = 3;
+const NUM_COMPRESSIONS: usize = 3;
+const _: () = assert!(NUM_CODECS * NUM_COMPRESSIONS <= NUM_FRAME_SLOTS);
+
+#[derive(Error, Debug)]
+pub enum CompressionError {
+    #[error("compression is disabled on this server")]
+    Disabled,
+    #[error("unknown compression. compression={0}")]
+    UnknownCompression(i32),
+    #[error("decompressed message is too large. max={0}")]
+    TooLarge(usize),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Decode(#[from] prost::DecodeError),
+}
+
+pub fn compress(compression: Compression, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+    match compression {
+        Compression::Uncompressed => Ok(data.to_vec()),
+        Compression::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+        Compression::Zstd => Ok(zstd::stream::encode_all(data, ZSTD_LEVEL)?),
+    }
+}
+
+/// `max_size`より大きく展開されるデータは拒否する。圧縮率の高いデータによってメモリを使い切らないようにする。
+pub fn decompress(
+    compression: Compression,
+    data: &[u8],
+    max_size: usize,
+) -> Result<Vec<u8>, CompressionError> {
+    let reader: Box<dyn Read + '_> = match compression {
+        Compression::Uncompressed => Box::new(data),
+        Compression::Deflate => Box::new(DeflateDecoder::new(data)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(data)?),
+    };
+    let mut buf = Vec::new();
+    reader
+        .take((max_size as u64).saturating_add(1))
+        .read_to_end(&mut buf)?;
+    if buf.len() > max_size {
+        return Err(CompressionError::TooLarge(max_size));
+    }
+    Ok(buf)
+}
+
+/// 共有されているメッセージをエンコードする。
+/// コーデックと圧縮方式の組み合わせごとに一度だけエンコードして、全ての受信者で同じバッファを使う。
+pub fn encode_shared(
+    shared: &SharedMessage,
+    codec: Codec,
+    compression: Compression,
+) -> Result<Bytes, CodecError> {
+    let codec_index = match codec {
+        Codec::Protobuf => 0,
+        Codec::Json => 1,
+        Codec::MessagePack => 2,
+    };
+    shared.frame(
+        codec_index * NUM_COMPRESSIONS + compression as usize,
+        |message| {
+            if compression == Compression::Uncompressed {
+                return Ok(codec.encode(message)?.into());
+            }
+            let data = encode_shared(shared, Codec::Protobuf, Compression::Uncompressed)?;
+            match compress(compression, &data) {
+                Ok(data) => {
+                    let message = ServerMessage {
+                        data: Some(server_message::Data::CompressedMessage(CompressedMessage {
+                            compression: compression as i32,
+                            data,
+                        })),
+                    };
+                    Ok(codec.encode(&message)?.into())
+                }
+                Err(err) => {
+                    error!("Failed to compress message. {:?}", err);
+                    encode_shared(shared, codec, Compression::Uncompressed)
+                }
+            }
+        },
+    )
+}
+
+/// 接続ごとの圧縮の状態。LoginResponseを送る時に合意した圧縮方式を記録する。
+#[derive(Debug)]
+pub struct Compressor {
+    compression: Compression,
+    threshold: usize,
+    // 無効の場合はクライアントからの圧縮されたメッセージも受け付けない。
+    enable: bool,
+    // 展開後の大きさの上限。圧縮していないメッセージと同じ`limits.max_frame_size`を使う。
+    max_size: usize,
+}
+
+impl Compressor {
+    pub fn new(config: &config::Compression, max_size: usize) -> Self {
+        Self {
+            compression: Compression::Uncompressed,
+            threshold: config.threshold,
+            enable: config.enable,
+            max_size,
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// 閾値以上の大きさのメッセージを圧縮する。圧縮に失敗した場合はそのまま送る。
+    pub fn compress(&mut self, message: ServerMessage) -> ServerMessage {
+        if let Some(server_message::Data::LoginResponse(res)) = &message.data {
+            let ok = res
+                .error
+                .as_ref()
+                .is_none_or(|error| error.code == ErrorCode::None as i32);
+            if ok {
+                self.compression =
+                    Compression::try_from(res.compression).unwrap_or(Compression::Uncompressed);
+            }
+            return message;
+        }
+
+        if self.compression == Compression::Uncompressed || message.encoded_len() < self.threshold {
+            return message;
+        }
+        match compress(self.compression, &message.encode_to_vec()) {
+            Ok(data) => ServerMessage {
+                data: Some(server_message::Data::CompressedMessage(CompressedMessage {
+                    compression: self.compression as i32,
+                    data,
+                })),
+            },
+            Err(err) => {
+                error!("Failed to compress message. {:?}", err);
+                message
+            }
+        }
+    }
+
+    /// 送信するフレームにエンコードする。共有されているメッセージは、エンコード済みのフレームを使う。
+    pub fn encode(&mut self, message: OutputMessage, codec: Codec) -> Result<Bytes, CodecError> {
+        match message {
+            OutputMessage::Owned(message) => Ok(codec.encode(&self.compress(message))?.into()),
+            OutputMessage::Shared(shared) => {
+                let compression = if shared.encoded_len() < self.threshold {
+                    Compression::Uncompressed
+                } else {
+                    self.compression
+                };
+                encode_shared(&shared, codec, compression)
+            }
+        }
+    }
+
+    /// `CompressedMessage`の場合は展開する。クライアントはサーバと同じ方式で圧縮する必要は無い。
+    pub fn decompress(&self, message: ClientMessage) -> Result<ClientMessage, CompressionError> {
+        match message.data {
+            Some(client_message::Data::CompressedMessage(_)) if !self.enable => {
+                Err(CompressionError::Disabled)
+            }
+            Some(client_message::Data::CompressedMessage(compressed)) => {
+                let compression = Compression::try_from(compressed.compression)
+                    .map_err(|_| CompressionError::UnknownCompression(compressed.compression))?;
+                let data = decompress(compression, &compressed.data, self.max_size)?;
+                Ok(ClientMessage::decode(data.as_slice())?)
+            }
+            _ => Ok(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::app;
+
+    fn login_response(compression: Compression) -> ServerMessage {
+        ServerMessage {
+            data: Some(server_message::Data::LoginResponse(app::LoginResponse {
+                error: Some(app::Error {
+                    code: app::ErrorCode::None as i32,
+                    message: String::new(),
+                }),
+                compression: compression as i32,
+                protocol_version: crate::protobuf::PROTOCOL_VERSION,
+                capabilities: Vec::new(),
+            })),
+        }
+    }
+
+    fn message_notification(body: Vec<u8>) -> ServerMessage {
+        ServerMessage {
+            data: Some(server_message::Data::MessageNotification(
+                app::MessageNotification {
+                    room_id: "room".to_string(),
+                    sender_id: "p1".to_string(),
+                    body,
+                    delivery: app::Delivery::Reliable as i32,
+                },
+            )),
+        }
+    }
+
+    #[test]
+    fn compress_only_large_messages_after_login() {
+        for compression in [Compression::Deflate, Compression::Zstd] {
+            let mut compressor = Compressor::new(
+                &config::Compression {
+                    enable: true,
+                    threshold: 1024,
+                },
+                1024 * 1024,
+            );
+            let large = message_notification(vec![1; 4096]);
+            assert_eq!(large, compressor.compress(large.clone()));
+
+            compressor.compress(login_response(compression));
+            assert_eq!(compression, compressor.compression());
+
+            let small = message_notification(vec![1; 16]);
+            assert_eq!(small, compressor.compress(small.clone()));
+
+            let compressed = compressor.compress(large.clone());
+            if let Some(server_message::Data::CompressedMessage(compressed)) = compressed.data {
+                assert_eq!(compression as i32, compressed.compression);
+                assert!(compressed.data.len() < 1024);
+                let data = decompress(compression, &compressed.data, large.encoded_len()).unwrap();
+                assert_eq!(large, ServerMessage::decode(data.as_slice()).unwrap());
+            } else {
+                panic!("Unexpected message. {:?}", compressed);
+            }
+        }
+    }
+
+    #[test]
+    fn encode_shared_message_once() {
+        let message = message_notification(vec![1; 16]);
+        let shared = SharedMessage::new(message.clone());
+        for codec in [Codec::Protobuf, Codec::Json, Codec::MessagePack] {
+            let frame = encode_shared(&shared, codec, Compression::Uncompressed).unwrap();
+            // 2回目以降は同じバッファを返す。
+            let again = encode_shared(&shared, codec, Compression::Uncompressed).unwrap();
+            assert_eq!(frame.as_ptr(), again.as_ptr());
+            assert_eq!(message, codec.decode::<ServerMessage>(&frame).unwrap());
+        }
+
+        let frame = encode_shared(&shared, Codec::Protobuf, Compression::Zstd).unwrap();
+        let compressed = ServerMessage::decode(frame.as_ref()).unwrap();
+        if let Some(server_message::Data::CompressedMessage(compressed)) = compressed.data {
+            let data = decompress(Compression::Zstd, &compressed.data, usize::MAX).unwrap();
+            assert_eq!(message, ServerMessage::decode(data.as_slice()).unwrap());
+        } else {
+            panic!("Unexpected message. {:?}", compressed);
+        }
+    }
+
+    #[test]
+    fn reject_too_large_decompressed_message() {
+        let data = compress(Compression::Zstd, &vec![0; 1025]).unwrap();
+        assert!(matches!(
+            decompress(Compression::Zstd, &data, 1024),
+            Err(CompressionError::TooLarge(1024))
+        ));
+        assert_eq!(
+            1025,
+            decompress(Compression::Zstd, &data, 1025).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn reject_compressed_message_when_disabled() {
+        let message = ClientMessage {
+            data: Some(client_message::Data::CompressedMessage(CompressedMessage {
+                compression: Compression::Deflate as i32,
+                data: compress(Compression::Deflate, &[]).unwrap(),
+            })),
+        };
+        let compressor = Compressor::new(
+            &config::Compression {
+                enable: false,
+                threshold: 1024,
+            },
+            1024 * 1024,
+        );
+        assert!(matches!(
+            compressor.decompress(message.clone()),
+            Err(CompressionError::Disabled)
+        ));
+
+        let compressor = Compressor::new(
+            &config::Compression {
+                enable: true,
+                threshold: 1024,
+            },
+            1024 * 1024,
+        );
+        assert_eq!(
+            ClientMessage::default(),
+            compressor.decompress(message).unwrap()
+        );
+    }
+}
@@ -2,24 +2,35 @@ use std::sync::Arc;
 use std::{error::Error, io::ErrorKind, net::ToSocketAddrs, pin::Pin};
 
 use futures::{Future, Stream};
-use log::info;
+use prost::Message as _;
 use tokio::sync::mpsc;
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tonic::transport::{Identity, ServerTlsConfig};
 use tonic::{transport::Server, Response, Status};
+use tracing::{info, Instrument};
 
+use super::compression::Compressor;
 use super::server;
 use crate::actor;
+use crate::admin;
 use crate::config;
+use crate::logging;
+use crate::metrics::METRICS;
 use crate::protobuf;
 
 pub struct ServerImpl {
     config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
 }
 
 #[tonic::async_trait]
 impl server::Server for ServerImpl {
-    async fn run<A, F>(addr: A, config: Arc<config::Config>, shutdown: F) -> anyhow::Result<()>
+    async fn run<A, F>(
+        addr: A,
+        config: Arc<config::Config>,
+        registries: Arc<actor::Registries>,
+        shutdown: F,
+    ) -> anyhow::Result<()>
     where
         A: ToSocketAddrs + Send + 'static,
         std::net::SocketAddr: From<A>,
@@ -27,8 +38,13 @@ impl server::Server for ServerImpl {
     {
         info!("Start gRPC server");
         let addr = addr.to_socket_addrs().unwrap().next().unwrap();
+        let admin_service = config
+            .admin
+            .enable_grpc
+            .then(|| admin::grpc::service(&config, registries.clone()));
         let server = Self {
             config: config.clone(),
+            registries,
         };
         let mut builder = Server::builder();
         if config.tls.enable {
@@ -39,8 +55,17 @@ impl server::Server for ServerImpl {
             let tls_config = ServerTlsConfig::new().identity(identity);
             builder = builder.tls_config(tls_config)?;
         }
+        if admin_service.is_some() {
+            info!("Enabled Admin service for gRPC server");
+        }
+        // 送受信とも、他のトランスポートのフレームと同じ上限を超えるメッセージは扱わない。
+        let max_message_size = config.limits.max_frame_size;
+        let app_service = protobuf::app::app_server::AppServer::new(server)
+            .max_decoding_message_size(max_message_size)
+            .max_encoding_message_size(max_message_size);
         builder
-            .add_service(protobuf::app::app_server::AppServer::new(server))
+            .add_service(app_service)
+            .add_optional_service(admin_service)
             .serve_with_shutdown(addr, shutdown)
             .await?;
         Ok(())
@@ -55,16 +80,24 @@ impl protobuf::app::app_server::App for ServerImpl {
         &self,
         req: tonic::Request<tonic::Streaming<protobuf::app::ClientMessage>>,
     ) -> Result<tonic::Response<Self::StartStream>, Status> {
-        info!("Connected player");
+        let span = logging::connection_span(server::Protocol::Grpc, req.remote_addr());
+        let connection = actor::ConnectionInfo::new(server::Protocol::Grpc, req.remote_addr());
         let (tx, rx) = mpsc::channel(128);
         let mut in_stream = req.into_inner();
         let config = self.config.clone();
+        let registries = self.registries.clone();
         tokio::spawn(async move {
-            let mut player_actor = actor::Player::new(config);
+            info!("Connected player");
+            let mut compressor = Compressor::new(&config.compression, config.limits.max_frame_size);
+            let mut player_actor = actor::Player::with_connection(config, registries, connection);
+            let metrics = METRICS.connect(server::Protocol::Grpc);
             loop {
                 tokio::select! {
                     server_msg = player_actor.recv() => {
                         if let Some(server_msg) = server_msg {
+                            // tonicがエンコードするので、共有されているメッセージも複製して渡す。
+                            let server_msg = compressor.compress(server_msg.into_message());
+                            metrics.sent(server_msg.encoded_len());
                             if tx.send(Ok(server_msg)).await.is_err() {
                                 info!("Disconnected player");
                                 return;
@@ -78,8 +111,16 @@ impl protobuf::app::app_server::App for ServerImpl {
                         if let Some(client_msg) = client_msg {
                             match client_msg {
                                 Ok(client_msg) => {
+                                    metrics.received(client_msg.encoded_len());
+                                    let client_msg = match compressor.decompress(client_msg) {
+                                        Ok(client_msg) => client_msg,
+                                        Err(err) => {
+                                            let _ = tx.send(Err(Status::invalid_argument(err.to_string()))).await;
+                                            return;
+                                        }
+                                    };
                                     // player_actorがDropしない限り失敗しないはず。
-                                    player_actor.send(client_msg).unwrap();
+                                    player_actor.send(client_msg).await.unwrap();
                                     continue;
                                 },
                                 Err(err) => {
@@ -98,7 +139,7 @@ impl protobuf::app::app_server::App for ServerImpl {
                     }
                 }
             }
-        });
+        }.instrument(span));
 
         let out_stream = ReceiverStream::new(rx);
         Ok(Response::new(Box::pin(out_stream) as Self::StartStream))
@@ -122,9 +163,6 @@ fn match_for_io_error(err_status: &Status) -> Option<&std::io::Error> {
             }
         }
 
-        err = match err.source() {
-            Some(err) => err,
-            None => return None,
-        };
+        err = err.source()?;
     }
 }
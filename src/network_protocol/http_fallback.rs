@@ -0,0 +1,459 @@
+//! WebSocketが使えないネットワーク向けの、HTTPだけで通信するサーバ実装。
+//! セッションを作成した後、ClientMessageはHTTP POSTで送り、ServerMessageはSSEかロングポーリングで受け取る。
+//!
+//! - `POST /app/sessions`: セッションを作成する。レスポンスは`{"sessionId": "..."}`。
+//!   セッション数が上限に達している場合は503、同じアドレスからのセッションが多すぎる場合は429を返す。
+//! - `POST /app/sessions/{id}/messages`: ClientMessageを送る。エンコードはContent-Typeで選択する。
+//! - `GET /app/sessions/{id}/events`: ServerMessageをJSONマッピングのSSEで受け取る。
+//! - `GET /app/sessions/{id}/poll`: 届いているServerMessageをJSONの配列で受け取る。無い場合は届くまで待つ。
+//! - `DELETE /app/sessions/{id}`: セッションを終了する。
+//!
+//! 受信用のストリームは1つのセッションで同時に1つだけ開ける。
+//! 送信はContent-Typeでコーデックを選べるが、受信(SSEとロングポーリング)は常にJSONマッピングで返す。
+//! SSEはテキストしか送れないので、バイナリのコーデックを使う場合はWebSocketかgRPCを使う。
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream, Future, StreamExt};
+use prost::Message as _;
+use tokio::sync::{mpsc, Mutex, OwnedMutexGuard, RwLock};
+use tracing::{error, info, Instrument};
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{sse, Filter, Rejection, Reply};
+
+use super::codec::Codec;
+use super::compression::Compressor;
+use super::server;
+use crate::actor;
+use crate::config;
+use crate::logging;
+use crate::metrics::METRICS;
+use crate::protobuf;
+
+/// メッセージの送信も受信用のストリームも無い状態がこの時間続いたセッションは終了する。
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// ロングポーリングでメッセージを待つ最大の時間。
+pub const LONG_POLL_TIMEOUT: Duration = Duration::from_secs(25);
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// セッションのリクエストとストリームの間のキューの大きさ。
+// 受信用のストリームが開かれずに溢れた場合は、Player actorの送信キューの設定に従う。
+const SESSION_CHANNEL_CAPACITY: usize = 64;
+
+type SessionId = String;
+// JSONマッピングでエンコード済みのServerMessageを受け取る。
+type OutputReceiver = Arc<Mutex<mpsc::Receiver<Bytes>>>;
+
+#[derive(Clone)]
+struct Session {
+    // アドレスごとのセッション数の制限に使う。HTTPの接続ごとにポートは変わるのでIPアドレスで数える。
+    ip: Option<IpAddr>,
+    input_tx: mpsc::Sender<protobuf::app::ClientMessage>,
+    output_rx: OutputReceiver,
+}
+
+type Sessions = Arc<RwLock<HashMap<SessionId, Session>>>;
+
+pub struct ServerImpl {}
+
+#[async_trait]
+impl server::Server for ServerImpl {
+    async fn run<A, F>(
+        addr: A,
+        config: Arc<config::Config>,
+        registries: Arc<actor::Registries>,
+        shutdown: F,
+    ) -> anyhow::Result<()>
+    where
+        A: ToSocketAddrs + Send + 'static,
+        std::net::SocketAddr: From<A>,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        info!("Start HTTP fallback server");
+        let sessions: Sessions = Arc::new(RwLock::new(HashMap::new()));
+        let with_sessions = {
+            let sessions = sessions.clone();
+            warp::any().map(move || sessions.clone())
+        };
+        let with_config = {
+            let config = config.clone();
+            warp::any().map(move || config.clone())
+        };
+        let with_registries = warp::any().map(move || registries.clone());
+
+        let create = warp::path!("app" / "sessions")
+            .and(warp::post())
+            .and(warp::addr::remote())
+            .and(with_sessions.clone())
+            .and(with_config)
+            .and(with_registries)
+            .then(create_session);
+        let send = warp::path!("app" / "sessions" / SessionId / "messages")
+            .and(warp::post())
+            .and(warp::header::optional::<String>("content-type"))
+            // 上限を超えるボディは413で拒否する。
+            .and(warp::body::content_length_limit(
+                config.limits.max_frame_size as u64,
+            ))
+            .and(warp::body::bytes())
+            .and(with_sessions.clone())
+            .then(send_message);
+        let events = warp::path!("app" / "sessions" / SessionId / "events")
+            .and(warp::get())
+            .and(with_sessions.clone())
+            .then(open_event_stream);
+        let poll = warp::path!("app" / "sessions" / SessionId / "poll")
+            .and(warp::get())
+            .and(with_sessions.clone())
+            .then(poll_messages);
+        let delete = warp::path!("app" / "sessions" / SessionId)
+            .and(warp::delete())
+            .and(with_sessions)
+            .then(delete_session);
+        let routes = create
+            .or(send)
+            .unify()
+            .or(events)
+            .unify()
+            .or(poll)
+            .unify()
+            .or(delete)
+            .unify()
+            .recover(handle_rejection);
+
+        if config.tls.enable {
+            info!("Enabled TLS for HTTP fallback server.");
+            warp::serve(routes)
+                .tls()
+                .cert_path(&config.tls.cert_file_path)
+                .key_path(&config.tls.key_file_path)
+                .bind_with_graceful_shutdown(addr, shutdown)
+                .1
+                .await;
+        } else {
+            warp::serve(routes)
+                .bind_with_graceful_shutdown(addr, shutdown)
+                .1
+                .await;
+        }
+        Ok(())
+    }
+}
+
+async fn create_session(
+    addr: Option<SocketAddr>,
+    sessions: Sessions,
+    config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
+) -> warp::reply::Response {
+    let session_id = Uuid::new_v4().to_string();
+    let (input_tx, input_rx) = mpsc::channel(SESSION_CHANNEL_CAPACITY);
+    let (output_tx, output_rx) = mpsc::channel(SESSION_CHANNEL_CAPACITY);
+    let session = Session {
+        ip: addr.map(|addr| addr.ip()),
+        input_tx,
+        output_rx: Arc::new(Mutex::new(output_rx)),
+    };
+    {
+        // 上限の確認と追加の間に他のセッションが作られないように、書き込みロックを取ってから数える。
+        let mut sessions = sessions.write().await;
+        if let Err(status) = check_session_limits(&sessions, session.ip, &config.limits) {
+            info!("Rejected new session. addr={:?}, status={}", addr, status);
+            return status.into_response();
+        }
+        sessions.insert(session_id.clone(), session.clone());
+    }
+    let span = logging::connection_span(server::Protocol::HttpFallback, addr);
+    info!(parent: &span, "Connected player. session_id={}", session_id);
+    let connection = actor::ConnectionInfo::new(server::Protocol::HttpFallback, addr);
+    // Player actorも接続のスパンで動かす。
+    let player_actor =
+        span.in_scope(|| actor::Player::with_connection(config.clone(), registries, connection));
+    tokio::spawn(
+        run_session(
+            session_id.clone(),
+            sessions,
+            player_actor,
+            Compressor::new(&config.compression, config.limits.max_frame_size),
+            input_rx,
+            output_tx,
+            session.output_rx,
+        )
+        .instrument(span),
+    );
+
+    let body = serde_json::json!({ "sessionId": session_id });
+    warp::reply::with_status(warp::reply::json(&body), StatusCode::CREATED).into_response()
+}
+
+/// 全体とアドレスごとのセッション数の上限を確認する。
+fn check_session_limits(
+    sessions: &HashMap<SessionId, Session>,
+    ip: Option<IpAddr>,
+    limits: &config::Limits,
+) -> Result<(), StatusCode> {
+    if sessions.len() >= limits.max_http_sessions {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    let sessions_from_ip = sessions
+        .values()
+        .filter(|session| ip.is_some() && session.ip == ip)
+        .count();
+    if sessions_from_ip >= limits.max_http_sessions_per_addr {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+    Ok(())
+}
+
+/// セッションとPlayer actorを繋ぐ。Player actorが終了するか、セッションが削除されるかアイドル状態が続くと終了する。
+async fn run_session(
+    session_id: SessionId,
+    sessions: Sessions,
+    mut player_actor: actor::Player,
+    mut compressor: Compressor,
+    mut input_rx: mpsc::Receiver<protobuf::app::ClientMessage>,
+    output_tx: mpsc::Sender<Bytes>,
+    output_rx: OutputReceiver,
+) {
+    let metrics = METRICS.connect(server::Protocol::HttpFallback);
+    let mut last_active_at = Instant::now();
+    let mut interval = tokio::time::interval(SESSION_CHECK_INTERVAL);
+    // チャネルが一杯で渡せていないメッセージ。渡せるまではPlayer actorから受信しない。
+    let mut pending: Option<Bytes> = None;
+    loop {
+        tokio::select! {
+            server_msg = player_actor.recv(), if pending.is_none() => {
+                match server_msg {
+                    Some(server_msg) => match compressor.encode(server_msg, Codec::Json) {
+                        Ok(json) => {
+                            metrics.sent(json.len());
+                            pending = Some(json);
+                        }
+                        Err(err) => error!("Failed to encode message to JSON. {:?}", err),
+                    },
+                    None => break,
+                }
+            }
+            permit = output_tx.reserve(), if pending.is_some() => {
+                // 受信用のストリームが開かれていない間はチャネルに溜めておく。
+                match (permit, pending.take()) {
+                    (Ok(permit), Some(server_msg)) => permit.send(server_msg),
+                    _ => break,
+                }
+            }
+            client_msg = input_rx.recv() => {
+                match client_msg {
+                    Some(client_msg) => {
+                        last_active_at = Instant::now();
+                        metrics.received(client_msg.encoded_len());
+                        match compressor.decompress(client_msg) {
+                            Ok(client_msg) => {
+                                // player_actorがDropしない限り失敗しないはず。
+                                let _ = player_actor.send(client_msg).await;
+                            }
+                            Err(err) => {
+                                error!("Received invalid compressed message. {:?}", err);
+                                break;
+                            }
+                        }
+                    }
+                    // セッションが削除された。
+                    None => break,
+                }
+            }
+            _ = interval.tick() => {
+                // ストリームを開いている間はロックされている。
+                if output_rx.try_lock().is_err() {
+                    last_active_at = Instant::now();
+                } else if last_active_at.elapsed() >= SESSION_IDLE_TIMEOUT {
+                    info!("Session timed out");
+                    break;
+                }
+            }
+        }
+    }
+
+    sessions.write().await.remove(&session_id);
+    info!("Disconnected player");
+}
+
+async fn get_session(sessions: &Sessions, session_id: &SessionId) -> Option<Session> {
+    sessions.read().await.get(session_id).cloned()
+}
+
+fn codec_from_content_type(content_type: Option<&str>) -> Codec {
+    let mime = content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .map(str::trim);
+    match mime {
+        Some("application/json") => Codec::Json,
+        Some("application/msgpack") | Some("application/x-msgpack") => Codec::MessagePack,
+        _ => Codec::Protobuf,
+    }
+}
+
+async fn send_message(
+    session_id: SessionId,
+    content_type: Option<String>,
+    body: Bytes,
+    sessions: Sessions,
+) -> warp::reply::Response {
+    let session = match get_session(&sessions, &session_id).await {
+        Some(session) => session,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let codec = codec_from_content_type(content_type.as_deref());
+    let message = match codec.decode::<protobuf::app::ClientMessage>(&body) {
+        Ok(message) => message,
+        Err(err) => {
+            error!("Received invalid message. {:?}", err);
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
+    if session.input_tx.send(message).await.is_err() {
+        // セッションが終了する直前だった。
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    StatusCode::ACCEPTED.into_response()
+}
+
+/// 受信用のストリームを開く。既に開かれている場合はCONFLICTを返す。
+async fn lock_output(
+    sessions: &Sessions,
+    session_id: &SessionId,
+) -> Result<OwnedMutexGuard<mpsc::Receiver<Bytes>>, StatusCode> {
+    let session = get_session(sessions, session_id)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+    session
+        .output_rx
+        .try_lock_owned()
+        .map_err(|_| StatusCode::CONFLICT)
+}
+
+async fn open_event_stream(session_id: SessionId, sessions: Sessions) -> warp::reply::Response {
+    let output_rx = match lock_output(&sessions, &session_id).await {
+        Ok(output_rx) => output_rx,
+        Err(status) => return status.into_response(),
+    };
+    // Player actorが終了してチャネルが閉じるとストリームも終了する。
+    let events = stream::unfold(output_rx, |mut output_rx| async move {
+        let message = output_rx.recv().await?;
+        Some((message, output_rx))
+    })
+    .map(|json| {
+        Ok::<_, Infallible>(
+            sse::Event::default()
+                .event("message")
+                .data(String::from_utf8_lossy(&json)),
+        )
+    });
+    sse::reply(sse::keep_alive().stream(events)).into_response()
+}
+
+async fn poll_messages(session_id: SessionId, sessions: Sessions) -> warp::reply::Response {
+    let mut output_rx = match lock_output(&sessions, &session_id).await {
+        Ok(output_rx) => output_rx,
+        Err(status) => return status.into_response(),
+    };
+    let mut messages = Vec::new();
+    match tokio::time::timeout(LONG_POLL_TIMEOUT, output_rx.recv()).await {
+        Ok(Some(message)) => messages.push(message),
+        // セッションが終了した。
+        Ok(None) => return StatusCode::GONE.into_response(),
+        Err(_) => {}
+    }
+    while let Ok(message) = output_rx.try_recv() {
+        messages.push(message);
+    }
+    let mut body = vec![b'['];
+    body.extend_from_slice(&messages.join(&b','));
+    body.push(b']');
+    warp::reply::with_header(body, "content-type", "application/json").into_response()
+}
+
+async fn delete_session(session_id: SessionId, sessions: Sessions) -> warp::reply::Response {
+    // 送信側がDropすると、セッションのタスクが終了してPlayer actorも終了する。
+    match sessions.write().await.remove(&session_id) {
+        Some(_) => StatusCode::NO_CONTENT.into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<warp::reply::Response, Infallible> {
+    let status = if rejection.is_not_found() {
+        StatusCode::NOT_FOUND
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        StatusCode::METHOD_NOT_ALLOWED
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    Ok(status.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_codec_by_content_type() {
+        assert_eq!(Codec::Protobuf, codec_from_content_type(None));
+        assert_eq!(
+            Codec::Json,
+            codec_from_content_type(Some("application/json; charset=utf-8"))
+        );
+        assert_eq!(
+            Codec::MessagePack,
+            codec_from_content_type(Some("application/msgpack"))
+        );
+        assert_eq!(
+            Codec::Protobuf,
+            codec_from_content_type(Some("application/x-protobuf"))
+        );
+    }
+
+    #[test]
+    fn limit_sessions_in_total_and_per_addr() {
+        let limits = config::Limits {
+            max_frame_size: 1024 * 1024,
+            max_body_size: 64 * 1024,
+            max_reservation_timeout: actor::DEFAULT_RESERVATION_TIMEOUT,
+            max_reservations_per_requester: 8,
+            max_udp_sessions: 1024,
+            max_http_sessions: 3,
+            max_http_sessions_per_addr: 2,
+        };
+        let session = |ip: &str| {
+            let (input_tx, _) = mpsc::channel(1);
+            let (_, output_rx) = mpsc::channel(1);
+            Session {
+                ip: Some(ip.parse().unwrap()),
+                input_tx,
+                output_rx: Arc::new(Mutex::new(output_rx)),
+            }
+        };
+        let ip1: IpAddr = "192.0.2.1".parse().unwrap();
+        let ip2: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let mut sessions = HashMap::new();
+        sessions.insert("s1".to_string(), session("192.0.2.1"));
+        assert_eq!(Ok(()), check_session_limits(&sessions, Some(ip1), &limits));
+        sessions.insert("s2".to_string(), session("192.0.2.1"));
+        assert_eq!(
+            Err(StatusCode::TOO_MANY_REQUESTS),
+            check_session_limits(&sessions, Some(ip1), &limits)
+        );
+        assert_eq!(Ok(()), check_session_limits(&sessions, Some(ip2), &limits));
+        sessions.insert("s3".to_string(), session("192.0.2.2"));
+        assert_eq!(
+            Err(StatusCode::SERVICE_UNAVAILABLE),
+            check_session_limits(&sessions, Some(ip2), &limits)
+        );
+    }
+}
@@ -0,0 +1,247 @@
+//! QUICのサーバ実装。
+//! クライアントが最初に開いた双方向ストリームで、TCPと同じ長さ付きフレームのメッセージをやり取りする。
+//! `Delivery::Unreliable`が指定されたMessageNotificationはデータグラムで送り、
+//! クライアントもSendMessageをデータグラムで送ることができる。
+//!
+//! QUICはTLSが必須なので、`config::Tls`の証明書と秘密鍵を`enable`の指定に関わらず使う。
+//! コネクションマイグレーションを有効にしているので、クライアントのアドレスが変わってもセッションは維持される。
+
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::Future;
+use futures::SinkExt;
+use tokio_rustls::rustls;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tracing::{error, info, trace, warn, Instrument};
+
+use super::codec::{Codec, CodecSelector};
+use super::compression::{CompressionError, Compressor};
+use super::server;
+use super::tcp::{self, load_certs, load_key};
+use crate::actor;
+use crate::config;
+use crate::logging;
+use crate::metrics::METRICS;
+use crate::protobuf;
+
+/// TLSのALPNで使うプロトコル名。
+pub const ALPN: &[u8] = b"mini-realtime-server";
+
+pub struct ServerImpl {}
+
+#[async_trait]
+impl server::Server for ServerImpl {
+    async fn run<A, F>(
+        addr: A,
+        config: Arc<config::Config>,
+        registries: Arc<actor::Registries>,
+        shutdown: F,
+    ) -> anyhow::Result<()>
+    where
+        A: ToSocketAddrs + Send + 'static,
+        std::net::SocketAddr: From<A>,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        info!("Start QUIC server");
+        let addr = addr.to_socket_addrs()?.next().unwrap();
+
+        let certs = load_certs(&config.tls.cert_file_path)?;
+        let key = load_key(&config.tls.key_file_path)?;
+        let mut tls_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        tls_config.alpn_protocols = vec![ALPN.to_vec()];
+        let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_config));
+        server_config.migration(true);
+
+        let endpoint = quinn::Endpoint::server(server_config, addr)?;
+        let (internal_shutdown_tx, mut internal_shutdown_rx) = tokio::sync::oneshot::channel();
+        let accept_endpoint = endpoint.clone();
+        let listener_join_handle = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    connecting = accept_endpoint.accept() => {
+                        match connecting {
+                            Some(connecting) => {
+                                let config = config.clone();
+                                let registries = registries.clone();
+                                let span = logging::connection_span(server::Protocol::Quic, connecting.remote_address());
+                                let connection = actor::ConnectionInfo::new(server::Protocol::Quic, Some(connecting.remote_address()));
+                                tokio::spawn(async move {
+                                    if let Err(err) = handle_connection(connecting, connection, config, registries).await {
+                                        error!("Connection error. {:?}", err);
+                                    }
+                                }.instrument(span));
+                            }
+                            // Endpointが閉じられた。
+                            None => return,
+                        }
+                    }
+                    _ = &mut internal_shutdown_rx => {
+                        // TODO: 全員抜けるまで待つ。
+                        return;
+                    }
+                }
+            }
+        });
+
+        shutdown.await;
+        info!("shutdown QUIC server");
+        let _ = internal_shutdown_tx.send(());
+        tokio::join!(listener_join_handle).0?;
+        endpoint.close(0u32.into(), b"shutdown");
+        endpoint.wait_idle().await;
+        Ok(())
+    }
+}
+
+async fn handle_connection(
+    connecting: quinn::Connecting,
+    connection_info: actor::ConnectionInfo,
+    config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
+) -> anyhow::Result<()> {
+    let connection = connecting.await?;
+    info!("Connected player");
+
+    let (send, recv) = connection.accept_bi().await?;
+    let mut frame_reader = FramedRead::new(
+        recv,
+        LengthDelimitedCodec::builder()
+            .max_frame_length(config.limits.max_frame_size)
+            .new_codec(),
+    );
+    // 上限を超えるフレームはクライアントが受け取れないので、送る前にエラーにする。
+    let mut framed_writer = FramedWrite::new(
+        send,
+        LengthDelimitedCodec::builder()
+            .max_frame_length(config.limits.max_frame_size)
+            .new_codec(),
+    );
+    // コーデック選択フレームはストリームで送る。データグラムは選択済みのコーデックでデコードする。
+    let mut codec_selector = CodecSelector::new();
+    let mut compressor = Compressor::new(&config.compression, config.limits.max_frame_size);
+    let mut player_actor = actor::Player::with_connection(config, registries, connection_info);
+    let metrics = METRICS.connect(server::Protocol::Quic);
+    loop {
+        tokio::select! {
+            server_msg = player_actor.recv() => {
+                let server_msg = match server_msg {
+                    Some(server_msg) => server_msg,
+                    None => {
+                        info!("Disconnected player");
+                        // 送信済みのメッセージが届いてから閉じる。
+                        framed_writer.close().await?;
+                        connection.close(0u32.into(), b"closed");
+                        return Ok(());
+                    }
+                };
+
+                let is_unreliable = matches!(
+                    &server_msg.message().data,
+                    Some(protobuf::app::server_message::Data::MessageNotification(notification))
+                        if notification.delivery == protobuf::app::Delivery::Unreliable as i32
+                );
+                let data = compressor.encode(server_msg, codec_selector.codec())?;
+                metrics.sent(data.len());
+                // データグラムに収まらない場合やクライアントが対応していない場合はストリームで送る。
+                let fits_datagram = connection
+                    .max_datagram_size()
+                    .is_some_and(|max_size| data.len() <= max_size);
+                if is_unreliable && fits_datagram {
+                    if let Err(err) = connection.send_datagram(data) {
+                        // 信頼性の無い配送なので、送れなかったメッセージは捨てる。
+                        warn!("Failed to send datagram. {:?}", err);
+                    }
+                    continue;
+                }
+                framed_writer.send(data).await?;
+            }
+            frame = frame_reader.next() => {
+                let message = match frame {
+                    Some(Ok(frame)) => frame,
+                    Some(Err(err)) => {
+                        let notification = tcp::frame_error_notification(&err);
+                        send_error_notification(&mut framed_writer, codec_selector.codec(), notification).await;
+                        return Err(err.into());
+                    }
+                    None => {
+                        info!("Disconnected player");
+                        return Ok(());
+                    }
+                };
+                trace!(len = message.len(), payload = ?logging::payload(&message), "Received frame");
+                metrics.received(message.len());
+                match decode_stream_message(&mut codec_selector, &compressor, &message) {
+                    // player_actorがDropしない限り失敗しないはず。
+                    Ok(Some(message)) => {
+                        let _ = player_actor.send(message).await;
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        let notification = decode_error_notification(&err);
+                        send_error_notification(&mut framed_writer, codec_selector.codec(), notification).await;
+                        return Err(err);
+                    }
+                }
+            }
+            datagram = connection.read_datagram() => {
+                let datagram = datagram?;
+                metrics.received(datagram.len());
+                match decode_datagram(codec_selector.codec(), &compressor, &datagram) {
+                    Ok(message) => {
+                        let _ = player_actor.send(message).await;
+                    }
+                    Err(err) => {
+                        let notification = decode_error_notification(&err);
+                        send_error_notification(&mut framed_writer, codec_selector.codec(), notification).await;
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn decode_stream_message(
+    codec_selector: &mut CodecSelector,
+    compressor: &Compressor,
+    frame: &[u8],
+) -> anyhow::Result<Option<protobuf::app::ClientMessage>> {
+    match codec_selector.decode(frame)? {
+        Some(message) => Ok(Some(compressor.decompress(message)?)),
+        None => Ok(None),
+    }
+}
+
+fn decode_datagram(
+    codec: Codec,
+    compressor: &Compressor,
+    datagram: &[u8],
+) -> anyhow::Result<protobuf::app::ClientMessage> {
+    Ok(compressor.decompress(codec.decode(datagram)?)?)
+}
+
+fn decode_error_notification(err: &anyhow::Error) -> protobuf::app::ServerMessage {
+    match err.downcast_ref::<CompressionError>() {
+        Some(err) => tcp::compression_error_notification(err),
+        None => tcp::invalid_message_notification(err),
+    }
+}
+
+/// 切断する前にエラーを通知する。送れなくても切断するので、送信の失敗は無視する。
+async fn send_error_notification(
+    framed_writer: &mut FramedWrite<quinn::SendStream, LengthDelimitedCodec>,
+    codec: Codec,
+    notification: protobuf::app::ServerMessage,
+) {
+    if let Ok(data) = codec.encode(&notification) {
+        if framed_writer.send(data.into()).await.is_ok() {
+            let _ = framed_writer.close().await;
+        }
+    }
+}
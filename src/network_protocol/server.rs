@@ -1,28 +1,171 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::{net::ToSocketAddrs, sync::Arc};
 
+use anyhow::{anyhow, Context as _};
 use async_trait::async_trait;
 use futures::Future;
 use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::error;
 
+use super::{grpc, http_fallback, quic, tcp, udp, websocket};
+use crate::actor;
 use crate::config;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Protocol {
+    Websocket,
+    Grpc,
+    Tcp,
+    Udp,
+    Quic,
+    // WebSocketが使えない環境向けのHTTP POSTとSSE。
+    HttpFallback,
+}
+
+/// サーバの待ち受けアドレス。Unix domain socketはTCPのサーバだけが対応している。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Inet(addr)
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Inet(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    /// `unix:`から始まる場合はUnix domain socketのパスとして扱う。
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => s
+                .parse::<SocketAddr>()
+                .map(Self::Inet)
+                .map_err(|err| err.to_string()),
+        }
+    }
+}
+
 #[async_trait]
 pub trait Server {
-    async fn run<A, F>(addr: A, config: Arc<config::Config>, shutdown: F) -> anyhow::Result<()>
+    async fn run<A, F>(
+        addr: A,
+        config: Arc<config::Config>,
+        registries: Arc<actor::Registries>,
+        shutdown: F,
+    ) -> anyhow::Result<()>
     where
         A: ToSocketAddrs + Send + 'static,
         std::net::SocketAddr: From<A>,
         F: Future<Output = ()> + Send + 'static;
 }
 
-pub async fn run<S, A, F>(addr: A, config: Arc<config::Config>, shutdown: F) -> anyhow::Result<()>
+/// `registries`を共有するサーバ同士は同じプレイヤーやRoomを扱う。
+/// 独立したサーバを同じプロセスで動かす場合は、それぞれに別のインスタンスを渡す。
+pub async fn run<S, A, F>(
+    addr: A,
+    config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
+    shutdown: F,
+) -> anyhow::Result<()>
 where
     S: Server,
     A: ToSocketAddrs + Send + 'static,
     std::net::SocketAddr: From<A>,
     F: Future<Output = ()> + Send + 'static,
 {
-    S::run(addr, config, shutdown).await
+    S::run(addr, config, registries, shutdown).await
+}
+
+/// 複数のプロトコルのサーバを同じプロセスで起動する。
+/// `registries`は全てのサーバで共有されるので、異なるプロトコルのクライアント同士が同じRoomで遊べる。
+/// `shutdown`が完了するか、どれか1つのサーバが終了した場合は全てのサーバをシャットダウンする。
+pub async fn run_all<F>(
+    listeners: Vec<(Protocol, ListenAddr)>,
+    config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
+    shutdown: F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let mut servers = JoinSet::new();
+    for (protocol, addr) in listeners {
+        let config = config.clone();
+        let registries = registries.clone();
+        let mut shutdown_rx = shutdown_rx.clone();
+        servers.spawn(async move {
+            // 送信側がDropした場合もシャットダウンとして扱う。
+            let shutdown = async move {
+                let _ = shutdown_rx.changed().await;
+            };
+            let result = match (protocol, &addr) {
+                (Protocol::Websocket, ListenAddr::Inet(addr)) => {
+                    run::<websocket::ServerImpl, _, _>(*addr, config, registries, shutdown).await
+                }
+                (Protocol::Grpc, ListenAddr::Inet(addr)) => {
+                    run::<grpc::ServerImpl, _, _>(*addr, config, registries, shutdown).await
+                }
+                (Protocol::Tcp, ListenAddr::Inet(addr)) => {
+                    run::<tcp::ServerImpl, _, _>(*addr, config, registries, shutdown).await
+                }
+                (Protocol::Tcp, ListenAddr::Unix(path)) => {
+                    tcp::run_unix(path, config, registries, shutdown).await
+                }
+                (Protocol::Udp, ListenAddr::Inet(addr)) => {
+                    run::<udp::ServerImpl, _, _>(*addr, config, registries, shutdown).await
+                }
+                (Protocol::Quic, ListenAddr::Inet(addr)) => {
+                    run::<quic::ServerImpl, _, _>(*addr, config, registries, shutdown).await
+                }
+                (Protocol::HttpFallback, ListenAddr::Inet(addr)) => {
+                    run::<http_fallback::ServerImpl, _, _>(*addr, config, registries, shutdown)
+                        .await
+                }
+                (_, ListenAddr::Unix(_)) => Err(anyhow!("unix domain socket is only supported by tcp")),
+            };
+            result.with_context(|| format!("{:?} server failed. addr={}", protocol, addr))
+        });
+    }
+
+    let mut result = Ok(());
+    tokio::select! {
+        _ = shutdown => {}
+        Some(joined) = servers.join_next() => {
+            // シャットダウン前にサーバが終了した。起動に失敗した場合など。
+            result = joined?;
+        }
+    }
+
+    let _ = shutdown_tx.send(());
+    while let Some(joined) = servers.join_next().await {
+        if let Err(err) = joined? {
+            error!("{:?}", err);
+            if result.is_ok() {
+                result = Err(err);
+            }
+        }
+    }
+    result
 }
 
 pub async fn wait_signal() {
@@ -1,32 +1,43 @@
 use std::net::ToSocketAddrs;
+use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
-use std::{net::SocketAddr, sync::Arc};
+use std::sync::Arc;
 
 use anyhow::anyhow;
 use async_trait::async_trait;
 use futures::Future;
 use futures::SinkExt;
-use log::{error, info};
-use prost::Message as _;
 use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
 use tokio_rustls::rustls;
 use tokio_rustls::rustls::{Certificate, PrivateKey};
 use tokio_rustls::server::TlsStream;
 use tokio_rustls::TlsAcceptor;
 use tokio_stream::StreamExt;
-use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use tokio_util::codec::{
+    length_delimited::LengthDelimitedCodecError, FramedRead, FramedWrite, LengthDelimitedCodec,
+};
+use tracing::{debug, error, info, trace, warn, Instrument};
 
+use super::codec::{Codec, CodecError, CodecSelector};
+use super::compression::{CompressionError, Compressor};
 use super::server;
 use crate::actor;
 use crate::config;
+use crate::logging;
+use crate::metrics::METRICS;
 use crate::protobuf;
 
 pub struct ServerImpl {}
 
 #[async_trait]
 impl server::Server for ServerImpl {
-    async fn run<A, F>(addr: A, config: Arc<config::Config>, shutdown: F) -> anyhow::Result<()>
+    async fn run<A, F>(
+        addr: A,
+        config: Arc<config::Config>,
+        registries: Arc<actor::Registries>,
+        shutdown: F,
+    ) -> anyhow::Result<()>
     where
         A: ToSocketAddrs + Send + 'static,
         std::net::SocketAddr: From<A>,
@@ -38,13 +49,7 @@ impl server::Server for ServerImpl {
         let mut tls_acceptor = None;
         if config.tls.enable {
             info!("Enabled TLS for TCP server");
-            let certs = load_certs(&config.tls.cert_file_path)?;
-            let key = load_key(&config.tls.key_file_path)?;
-            let tls_config = rustls::ServerConfig::builder()
-                .with_safe_defaults()
-                .with_no_client_auth()
-                .with_single_cert(certs, key)?;
-            tls_acceptor = Some(TlsAcceptor::from(Arc::new(tls_config)));
+            tls_acceptor = Some(new_tls_acceptor(&config.tls)?);
         }
 
         let listener = TcpListener::bind(addr).await?;
@@ -56,10 +61,13 @@ impl server::Server for ServerImpl {
                         match result {
                             Ok((client, addr)) => {
                                 let config = config.clone();
+                                let registries = registries.clone();
                                 let tls_acceptor = tls_acceptor.clone();
+                                let span = logging::connection_span(server::Protocol::Tcp, addr);
+                                let connection = actor::ConnectionInfo::new(server::Protocol::Tcp, Some(addr));
                                 tokio::spawn(async move {
-                                    handle_tcp_connection(client, tls_acceptor, addr, config).await;
-                                });
+                                    handle_tcp_connection(client, tls_acceptor, connection, config, registries).await;
+                                }.instrument(span));
                             }
                             Err(err) => {
                                 error!("Connection error. {:?}", err);
@@ -84,11 +92,87 @@ impl server::Server for ServerImpl {
     }
 }
 
+/// TCPと同じフレームでUnix domain socketを待ち受ける。同じホストのプロセスからの接続向けなのでTLSは使わない。
+/// 接続元のプロセスの情報はPlayer actorに渡され、ログイン時の認証に使われる。
+pub async fn run_unix<P, F>(
+    path: P,
+    config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
+    shutdown: F,
+) -> anyhow::Result<()>
+where
+    P: AsRef<Path>,
+    F: Future<Output = ()> + Send + 'static,
+{
+    let path = path.as_ref();
+    info!("Start TCP server on unix domain socket. path={:?}", path);
+    // 前回起動時のソケットファイルが残っているとbindできないので削除する。ソケット以外のファイルは消さない。
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            result = listener.accept() => {
+                match result {
+                    Ok((client, addr)) => {
+                        let config = config.clone();
+                        let registries = registries.clone();
+                        let span = logging::connection_span(server::Protocol::Tcp, addr);
+                        tokio::spawn(async move {
+                            handle_unix_connection(client, config, registries).await;
+                        }.instrument(span));
+                    }
+                    Err(err) => {
+                        error!("Connection error. {:?}", err);
+                    }
+                };
+            }
+            _ = &mut shutdown => {
+                break;
+            }
+        }
+    }
+
+    info!("shutdown TCP server on unix domain socket");
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+async fn handle_unix_connection(
+    stream: UnixStream,
+    config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
+) {
+    let peer_credentials = match stream.peer_cred() {
+        Ok(cred) => Some(actor::PeerCredentials {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+        }),
+        Err(err) => {
+            warn!("Failed to get peer credentials. {:?}", err);
+            None
+        }
+    };
+    info!("Connected player. peer_credentials={:?}", peer_credentials);
+    let connection = actor::ConnectionInfo {
+        peer_credentials,
+        ..actor::ConnectionInfo::new(server::Protocol::Tcp, None)
+    };
+    let (reader, writer) = tokio::io::split(stream);
+    handle_rw_stream(reader, writer, connection, config, registries).await;
+}
+
 async fn handle_tcp_connection(
     stream: TcpStream,
     acceptor: Option<TlsAcceptor>,
-    addr: SocketAddr,
+    connection: actor::ConnectionInfo,
     config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
 ) {
     let result = TcpStreamType::from(stream, acceptor).await;
     match result {
@@ -96,11 +180,11 @@ async fn handle_tcp_connection(
             match stream_type {
                 TcpStreamType::Plain(stream) => {
                     let (reader, writer) = tokio::io::split(stream);
-                    handle_rw_stream(reader, writer, addr, config).await;
+                    handle_rw_stream(reader, writer, connection, config, registries).await;
                 }
                 TcpStreamType::Tls(stream) => {
                     let (reader, writer) = tokio::io::split(stream);
-                    handle_rw_stream(reader, writer, addr, config).await;
+                    handle_rw_stream(reader, writer, connection, config, registries).await;
                 }
             };
         }
@@ -113,17 +197,41 @@ async fn handle_tcp_connection(
 async fn handle_rw_stream(
     reader: ReadHalf<impl AsyncRead>,
     writer: WriteHalf<impl AsyncWrite>,
-    addr: SocketAddr,
+    connection: actor::ConnectionInfo,
     config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
 ) {
-    let mut frame_reader = FramedRead::new(reader, LengthDelimitedCodec::new());
-    let mut framed_writer = FramedWrite::new(writer, LengthDelimitedCodec::new());
-    let mut player_actor = actor::Player::new(config);
+    let mut frame_reader = FramedRead::new(
+        reader,
+        LengthDelimitedCodec::builder()
+            .max_frame_length(config.limits.max_frame_size)
+            .new_codec(),
+    );
+    // 上限を超えるフレームはクライアントが受け取れないので、送る前にエラーにする。
+    let mut framed_writer = FramedWrite::new(
+        writer,
+        LengthDelimitedCodec::builder()
+            .max_frame_length(config.limits.max_frame_size)
+            .new_codec(),
+    );
+    let mut codec_selector = CodecSelector::new();
+    let mut compressor = Compressor::new(&config.compression, config.limits.max_frame_size);
+    let mut player_actor = actor::Player::with_connection(config, registries, connection);
+    let metrics = METRICS.connect(server::Protocol::Tcp);
     loop {
         tokio::select! {
             server_msg = player_actor.recv() => {
                 if let Some(server_msg) = server_msg {
-                    framed_writer.send(server_msg.encode_to_vec().into()).await.unwrap();
+                    match compressor.encode(server_msg, codec_selector.codec()) {
+                        Ok(data) => {
+                            metrics.sent(data.len());
+                            if let Err(err) = framed_writer.send(data).await {
+                                error!("Failed to send frame. {:?}", err);
+                                return;
+                            }
+                        }
+                        Err(err) => error!("Failed to encode message. {:?}", err),
+                    }
                     continue;
                 }
 
@@ -134,32 +242,101 @@ async fn handle_rw_stream(
                 if let Some(frame) = frame {
                     match frame {
                         Ok(message) => {
-                            info!("received: addr={:?}, data={:?}", addr, message);
-                            let message: Result<protobuf::app::ClientMessage, prost::DecodeError> =
-                                prost::Message::decode(message);
+                            trace!(len = message.len(), payload = ?logging::payload(&message), "Received frame");
+                            metrics.received(message.len());
+                            let message: Result<Option<protobuf::app::ClientMessage>, CodecError> =
+                                codec_selector.decode(&message);
 
                             match message {
-                                Ok(message) => {
-                                    // player_actorがDropしない限り失敗しないはず。
-                                    let _ = player_actor.send(message);
+                                Ok(Some(message)) => match compressor.decompress(message) {
+                                    Ok(message) => {
+                                        // player_actorがDropしない限り失敗しないはず。
+                                        let _ = player_actor.send(message).await;
+                                    }
+                                    Err(err) => {
+                                        error!("Received invalid compressed message. {:?}", err);
+                                        let notification = compression_error_notification(&err);
+                                        send_error_notification(&mut framed_writer, codec_selector.codec(), notification).await;
+                                        return;
+                                    }
+                                },
+                                Ok(None) => {
+                                    debug!("Selected codec. codec={}", codec_selector.codec().name());
                                 }
                                 Err(err) => {
                                     error!("Received invalid message. {:?}", err);
+                                    let notification = invalid_message_notification(&err);
+                                    send_error_notification(&mut framed_writer, codec_selector.codec(), notification).await;
                                     return;
                                 }
                             }
                         }
                         Err(err) => {
-                            error!("Received invalid message. addr={:?}, error={:?}", addr, err);
+                            error!("Received invalid message. {:?}", err);
+                            let notification = frame_error_notification(&err);
+                            send_error_notification(&mut framed_writer, codec_selector.codec(), notification).await;
                             return;
                         }
                     }
+                } else {
+                    // クライアントが切断した。
+                    info!("Disconnected player");
+                    return;
                 }
             }
         }
     }
 }
 
+/// デコードできないメッセージを受信した場合に、切断前に送る通知。
+pub(super) fn invalid_message_notification(err: &impl ToString) -> protobuf::app::ServerMessage {
+    actor::error_notification(protobuf::app::Error {
+        code: protobuf::app::ErrorCode::InvalidMessage as i32,
+        message: err.to_string(),
+    })
+}
+
+/// フレームを読めなかった場合に、切断前に送る通知。上限を超える大きさのフレームもここでエラーになる。
+pub(super) fn frame_error_notification(err: &std::io::Error) -> protobuf::app::ServerMessage {
+    let too_large = err
+        .get_ref()
+        .is_some_and(|err| err.is::<LengthDelimitedCodecError>());
+    if !too_large {
+        return invalid_message_notification(err);
+    }
+    message_too_large_notification(err)
+}
+
+/// 展開できなかった場合に、切断前に送る通知。上限を超える大きさに展開されるメッセージもここでエラーになる。
+pub(super) fn compression_error_notification(err: &CompressionError) -> protobuf::app::ServerMessage {
+    match err {
+        CompressionError::TooLarge(_) => message_too_large_notification(err),
+        _ => invalid_message_notification(err),
+    }
+}
+
+pub(super) fn message_too_large_notification(err: &impl ToString) -> protobuf::app::ServerMessage {
+    actor::error_notification(protobuf::app::Error {
+        code: protobuf::app::ErrorCode::MessageTooLarge as i32,
+        message: err.to_string(),
+    })
+}
+
+async fn send_error_notification(
+    framed_writer: &mut FramedWrite<WriteHalf<impl AsyncWrite>, LengthDelimitedCodec>,
+    codec: Codec,
+    notification: protobuf::app::ServerMessage,
+) {
+    match codec.encode(&notification) {
+        Ok(data) => {
+            if let Err(err) = framed_writer.send(data.into()).await {
+                debug!("Failed to send error notification. {:?}", err);
+            }
+        }
+        Err(err) => error!("Failed to encode message. {:?}", err),
+    }
+}
+
 enum TcpStreamType {
     Tls(Box<TlsStream<TcpStream>>),
     Plain(Box<TcpStream>),
@@ -174,6 +351,17 @@ impl TcpStreamType {
     }
 }
 
+/// 設定された証明書と秘密鍵でTLSの接続を受け付ける。WebSocketのサーバでも使う。
+pub(super) fn new_tls_acceptor(tls: &config::Tls) -> anyhow::Result<TlsAcceptor> {
+    let certs = load_certs(&tls.cert_file_path)?;
+    let key = load_key(&tls.key_file_path)?;
+    let tls_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
+
 pub fn load_certs(path: impl AsRef<Path>) -> anyhow::Result<Vec<Certificate>> {
     let fd = std::fs::File::open(path)?;
     let mut buf = std::io::BufReader::new(&fd);
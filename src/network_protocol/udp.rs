@@ -0,0 +1,575 @@
+//! UDPのサーバ実装。
+//! 1つのソケットで全てのセッションを扱い、ハンドシェイクでセッションIDを割り当てる。
+//! ハンドシェイクではCookieを往復させて送信元アドレスを確かめるまで、セッションを作らない。
+//! メッセージは順序通りに再送されるReliableレーンと、再送しないUnreliableレーンのどちらかで送られる。
+//! サーバからは`Delivery::Unreliable`が指定されたMessageNotificationだけをUnreliableレーンで送る。
+//! コーデックを選択する場合は、Accept受信後に最初のメッセージとしてReliableレーンで選択フレームを送る。
+//!
+//! DTLSには対応していないので、TLSを有効にしても暗号化はされない。
+
+mod cookie;
+mod lane;
+mod packet;
+
+pub use lane::*;
+pub use packet::*;
+
+use cookie::Cookies;
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Future;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{debug, error, info, warn, Instrument, Span};
+use uuid::Uuid;
+
+use super::codec::{CodecError, CodecSelector};
+use super::compression::Compressor;
+use super::server;
+use crate::actor;
+use crate::config;
+use crate::logging;
+use crate::metrics::{self, METRICS};
+use crate::protobuf;
+
+/// 再送やタイムアウトを確認する間隔。
+const TICK_INTERVAL: Duration = Duration::from_millis(50);
+/// この時間何も受信しなかったセッションは切断する。クライアントは無通信時にHeartbeatを送る必要がある。
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(10);
+// セッションごとに、Player actorに渡す前のメッセージを溜められる数。溢れた場合はクライアントが送りすぎているので切断する。
+const INPUT_CHANNEL_CAPACITY: usize = 256;
+// 全てのセッションのPlayer actorから、ソケットを扱うタスクに渡すメッセージを溜められる数。
+const OUTPUT_CHANNEL_CAPACITY: usize = 1024;
+
+pub struct ServerImpl {}
+
+#[async_trait]
+impl server::Server for ServerImpl {
+    async fn run<A, F>(
+        addr: A,
+        config: Arc<config::Config>,
+        registries: Arc<actor::Registries>,
+        shutdown: F,
+    ) -> anyhow::Result<()>
+    where
+        A: ToSocketAddrs + Send + 'static,
+        std::net::SocketAddr: From<A>,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        info!("Start UDP server");
+        let addr = addr.to_socket_addrs()?.next().unwrap();
+        if config.tls.enable {
+            warn!("TLS is not supported for UDP server");
+        }
+
+        let socket = UdpSocket::bind(addr).await?;
+        let (internal_shutdown_tx, internal_shutdown_rx) = tokio::sync::oneshot::channel();
+        let join_handle = tokio::spawn(async move {
+            let mut sessions = Sessions::new(socket, config, registries);
+            sessions.run(internal_shutdown_rx).await;
+        });
+
+        shutdown.await;
+        info!("shutdown UDP server");
+        internal_shutdown_tx
+            .send(())
+            .map_err(|_| anyhow!("internal shutdown error"))?;
+        tokio::join!(join_handle).0?;
+        Ok(())
+    }
+}
+
+struct Session {
+    addr: SocketAddr,
+    lanes: Lanes,
+    codec_selector: CodecSelector,
+    compressor: Compressor,
+    input_tx: mpsc::Sender<protobuf::app::ClientMessage>,
+    // Ackされていない送信済みのReliableなメッセージの数の上限。Ackを受け取るたびに戻す。
+    send_window: Arc<Semaphore>,
+    last_received_at: Instant,
+    // Player actorが終了した後、送信済みのReliableなメッセージが届くのを待っている状態。
+    closing: bool,
+    metrics: metrics::Connection,
+    // 全てのセッションを1つのタスクで扱うので、セッションのログはこのスパンを親にして出す。
+    span: Span,
+}
+
+// Player actorからのメッセージ。Noneの場合はPlayer actorが終了した。
+type OutputMessage = (SessionId, Option<actor::OutputMessage>);
+
+struct Sessions {
+    socket: UdpSocket,
+    config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
+    sessions: HashMap<SessionId, Session>,
+    session_ids: HashMap<SocketAddr, SessionId>,
+    cookies: Cookies,
+    output_tx: mpsc::Sender<OutputMessage>,
+    output_rx: mpsc::Receiver<OutputMessage>,
+}
+
+impl Sessions {
+    fn new(
+        socket: UdpSocket,
+        config: Arc<config::Config>,
+        registries: Arc<actor::Registries>,
+    ) -> Self {
+        let (output_tx, output_rx) = mpsc::channel(OUTPUT_CHANNEL_CAPACITY);
+        Self {
+            socket,
+            config,
+            registries,
+            sessions: HashMap::new(),
+            session_ids: HashMap::new(),
+            cookies: Cookies::new(),
+            output_tx,
+            output_rx,
+        }
+    }
+
+    async fn run(&mut self, mut shutdown_rx: tokio::sync::oneshot::Receiver<()>) {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let mut tick = tokio::time::interval(TICK_INTERVAL);
+        loop {
+            tokio::select! {
+                result = self.socket.recv_from(&mut buf) => {
+                    match result {
+                        Ok((len, addr)) => {
+                            let data = Bytes::copy_from_slice(&buf[..len]);
+                            self.on_datagram(data, addr).await;
+                        }
+                        Err(err) => {
+                            // ICMPのport unreachableなどで返ることがあるので、ログだけ出して続ける。
+                            debug!("Failed to receive datagram. {:?}", err);
+                        }
+                    }
+                }
+                Some((session_id, message)) = self.output_rx.recv() => {
+                    self.on_output_message(session_id, message).await;
+                }
+                _ = tick.tick() => {
+                    self.on_tick().await;
+                }
+                _ = &mut shutdown_rx => {
+                    // TODO: 全員抜けるまで待つ。
+                    let session_ids: Vec<SessionId> = self.sessions.keys().copied().collect();
+                    for session_id in session_ids {
+                        self.close_session(session_id).await;
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn on_datagram(&mut self, data: Bytes, addr: SocketAddr) {
+        let packet = match Packet::decode(data) {
+            Ok(packet) => packet,
+            Err(err) => {
+                debug!("Received invalid packet. addr={:?}, error={:?}", addr, err);
+                return;
+            }
+        };
+
+        if let PacketBody::Connect { cookie } = packet.body {
+            self.on_connect(addr, cookie).await;
+            return;
+        }
+
+        // 別のアドレスからのパケットは受け付けない。
+        let session = match self.sessions.get_mut(&packet.session_id) {
+            Some(session) if session.addr == addr => session,
+            _ => {
+                debug!("Received packet for unknown session. addr={:?}", addr);
+                return;
+            }
+        };
+        session.last_received_at = Instant::now();
+
+        match packet.body {
+            PacketBody::Disconnect => {
+                info!(parent: &session.span, "Disconnected player");
+                self.remove_session(packet.session_id);
+            }
+            PacketBody::Heartbeat => {
+                let packet = Packet::new(packet.session_id, PacketBody::Heartbeat);
+                self.send_packet(&packet, addr).await;
+            }
+            body => {
+                let received = session.lanes.receive(body);
+                session.send_window.add_permits(received.acked);
+                if let Some(reply) = received.reply {
+                    self.send_packet(&reply, addr).await;
+                }
+                for payload in received.payloads {
+                    self.on_payload(packet.session_id, payload).await;
+                }
+            }
+        }
+    }
+
+    async fn on_connect(&mut self, addr: SocketAddr, cookie: Bytes) {
+        // Acceptが届かずにConnectが再送された場合は、同じセッションIDを返し直す。
+        if let Some(session_id) = self.session_ids.get(&addr) {
+            let packet = Packet::new(*session_id, PacketBody::Accept);
+            self.send_packet(&packet, addr).await;
+            return;
+        }
+
+        // 送信元を偽装したConnectでセッションを作られないように、Cookieを送り返せるか確かめる。
+        let now = Instant::now();
+        if !self.cookies.verify(addr, cookie, now) {
+            let cookie = self.cookies.generate(addr, now);
+            self.send_packet(&Packet::new(0, PacketBody::Challenge { cookie }), addr)
+                .await;
+            return;
+        }
+
+        if self.sessions.len() >= self.config.limits.max_udp_sessions {
+            warn!("Too many UDP sessions. addr={:?}", addr);
+            self.send_packet(&Packet::new(0, PacketBody::Disconnect), addr)
+                .await;
+            return;
+        }
+
+        let session_id = self.generate_session_id();
+        let span = logging::connection_span(server::Protocol::Udp, addr);
+        info!(parent: &span, "Connected player. session_id={}", session_id);
+        let send_window = Arc::new(Semaphore::new(self.config.outbound.queue_size.max(1)));
+        self.sessions.insert(
+            session_id,
+            Session {
+                addr,
+                lanes: Lanes::new(session_id, self.config.limits.max_frame_size),
+                codec_selector: CodecSelector::new(),
+                compressor: Compressor::new(&self.config.compression, self.config.limits.max_frame_size),
+                input_tx: spawn_player(
+                    session_id,
+                    actor::ConnectionInfo::new(server::Protocol::Udp, Some(addr)),
+                    self.config.clone(),
+                    self.registries.clone(),
+                    self.output_tx.clone(),
+                    send_window.clone(),
+                    span.clone(),
+                ),
+                send_window,
+                last_received_at: now,
+                closing: false,
+                metrics: METRICS.connect(server::Protocol::Udp),
+                span,
+            },
+        );
+        self.session_ids.insert(addr, session_id);
+        self.send_packet(&Packet::new(session_id, PacketBody::Accept), addr)
+            .await;
+    }
+
+    async fn on_payload(&mut self, session_id: SessionId, payload: Bytes) {
+        let session = match self.sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => return,
+        };
+        session.metrics.received(payload.len());
+        let message: Result<Option<protobuf::app::ClientMessage>, CodecError> =
+            session.codec_selector.decode(&payload);
+        match message {
+            Ok(Some(message)) => match session.compressor.decompress(message) {
+                Ok(message) => match session.input_tx.try_send(message) {
+                    Ok(()) => {}
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        warn!(parent: &session.span, "Input queue overflowed. Disconnect player. capacity={}", INPUT_CHANNEL_CAPACITY);
+                        self.close_session(session_id).await;
+                    }
+                    // Player actorが終了している場合は閉じている最中なので無視する。
+                    Err(mpsc::error::TrySendError::Closed(_)) => {}
+                },
+                Err(err) => {
+                    error!(parent: &session.span, "Received invalid compressed message. {:?}", err);
+                    self.close_session(session_id).await;
+                }
+            },
+            Ok(None) => {
+                debug!(
+                    parent: &session.span,
+                    "Selected codec. codec={}",
+                    session.codec_selector.codec().name()
+                );
+            }
+            Err(err) => {
+                error!(parent: &session.span, "Received invalid message. {:?}", err);
+                self.close_session(session_id).await;
+            }
+        }
+    }
+
+    async fn on_output_message(
+        &mut self,
+        session_id: SessionId,
+        message: Option<actor::OutputMessage>,
+    ) {
+        let session = match self.sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => return,
+        };
+        // Ackを待つReliableなパケットを送った場合以外は、送信ウィンドウをすぐに戻す。
+        let message = match message {
+            Some(message) => message,
+            None => {
+                // 送信済みのメッセージのAckを待ってから閉じる。
+                session.closing = true;
+                session.send_window.add_permits(1);
+                return;
+            }
+        };
+
+        let lane = match &message.message().data {
+            Some(protobuf::app::server_message::Data::MessageNotification(notification))
+                if notification.delivery == protobuf::app::Delivery::Unreliable as i32 =>
+            {
+                Lane::Unreliable
+            }
+            _ => Lane::Reliable,
+        };
+        let data = match session
+            .compressor
+            .encode(message, session.codec_selector.codec())
+        {
+            Ok(data) => data,
+            Err(err) => {
+                error!(parent: &session.span, "Failed to encode message. {:?}", err);
+                session.send_window.add_permits(1);
+                return;
+            }
+        };
+        let len = data.len();
+        let packet = match session.lanes.send(lane, data, Instant::now()) {
+            Ok(packet) => packet,
+            Err(err) => {
+                error!(parent: &session.span, "Failed to send message. {:?}", err);
+                session.send_window.add_permits(1);
+                return;
+            }
+        };
+        if lane == Lane::Unreliable {
+            session.send_window.add_permits(1);
+        }
+        session.metrics.sent(len);
+        let addr = session.addr;
+        self.send_packet(&packet, addr).await;
+    }
+
+    async fn on_tick(&mut self) {
+        let now = Instant::now();
+        let mut closed_session_ids = Vec::new();
+        let mut packets = Vec::new();
+        for (session_id, session) in self.sessions.iter_mut() {
+            if now.duration_since(session.last_received_at) >= SESSION_TIMEOUT {
+                info!(parent: &session.span, "Session timed out");
+                closed_session_ids.push(*session_id);
+                continue;
+            }
+            match session.lanes.retransmit(now) {
+                Ok(retransmits) => {
+                    packets.extend(retransmits.into_iter().map(|packet| (packet, session.addr)));
+                }
+                Err(err) => {
+                    info!(parent: &session.span, "Session lost. {:?}", err);
+                    closed_session_ids.push(*session_id);
+                    continue;
+                }
+            }
+            if session.closing && !session.lanes.has_unacked() {
+                closed_session_ids.push(*session_id);
+            }
+        }
+
+        for (packet, addr) in packets {
+            self.send_packet(&packet, addr).await;
+        }
+        for session_id in closed_session_ids {
+            self.close_session(session_id).await;
+        }
+    }
+
+    /// クライアントにDisconnectを送ってからセッションを削除する。
+    async fn close_session(&mut self, session_id: SessionId) {
+        if let Some(session) = self.sessions.get(&session_id) {
+            let addr = session.addr;
+            self.send_packet(&Packet::new(session_id, PacketBody::Disconnect), addr)
+                .await;
+        }
+        self.remove_session(session_id);
+    }
+
+    fn remove_session(&mut self, session_id: SessionId) {
+        // input_txがDropされることでPlayer actorも終了する。
+        if let Some(session) = self.sessions.remove(&session_id) {
+            self.session_ids.remove(&session.addr);
+        }
+    }
+
+    async fn send_packet(&self, packet: &Packet, addr: SocketAddr) {
+        if let Err(err) = self.socket.send_to(&packet.encode(), addr).await {
+            // 届かなかった場合はReliableなら再送、それ以外はタイムアウトで扱われる。
+            error!("Failed to send datagram. addr={:?}, error={:?}", addr, err);
+        }
+    }
+
+    fn generate_session_id(&self) -> SessionId {
+        loop {
+            // 0はハンドシェイク前を表すので使わない。
+            let session_id = Uuid::new_v4().as_u64_pair().0;
+            if session_id != 0 && !self.sessions.contains_key(&session_id) {
+                return session_id;
+            }
+        }
+    }
+}
+
+/// Player actorを起動して、セッションとの間でメッセージを中継するタスクを起動する。
+/// 送信ウィンドウが空くまではPlayer actorから受け取らないので、Ackを返さないクライアント宛てのメッセージは
+/// Player actorの送信キューに溜まり、溢れた場合は`config::OverflowPolicy`に従って扱われる。
+fn spawn_player(
+    session_id: SessionId,
+    connection: actor::ConnectionInfo,
+    config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
+    output_tx: mpsc::Sender<OutputMessage>,
+    send_window: Arc<Semaphore>,
+    span: Span,
+) -> mpsc::Sender<protobuf::app::ClientMessage> {
+    let (input_tx, mut input_rx) = mpsc::channel(INPUT_CHANNEL_CAPACITY);
+    tokio::spawn(async move {
+        let mut player_actor = actor::Player::with_connection(config, registries, connection);
+        loop {
+            tokio::select! {
+                (permit, server_msg) = async {
+                    let permit = send_window.clone().acquire_owned().await;
+                    (permit, player_actor.recv().await)
+                } => {
+                    // 送信ウィンドウはセッション側でAckを受け取った時に戻す。
+                    if let Ok(permit) = permit {
+                        permit.forget();
+                    }
+                    let closed = server_msg.is_none();
+                    if output_tx.send((session_id, server_msg)).await.is_err() || closed {
+                        return;
+                    }
+                }
+                client_msg = input_rx.recv() => {
+                    match client_msg {
+                        Some(client_msg) => {
+                            // player_actorがDropしない限り失敗しないはず。
+                            let _ = player_actor.send(client_msg).await;
+                        }
+                        // セッションが削除された。player_actorをDropして切断させる。
+                        None => return,
+                    }
+                }
+            }
+        }
+    }.instrument(span));
+    input_tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::app;
+
+    fn config(queue_size: usize) -> Arc<config::Config> {
+        Arc::new(config::Config {
+            auth: config::Auth {
+                enable_bearer: true,
+                bearer: "bearer".to_string(),
+                trusted_unix_uids: Vec::new(),
+            },
+            tls: config::Tls {
+                enable: false,
+                cert_file_path: "".to_string(),
+                key_file_path: "".to_string(),
+            },
+            compression: config::Compression {
+                enable: false,
+                threshold: 1024,
+            },
+            compatibility: config::Compatibility {
+                min_protocol_version: 0,
+            },
+            limits: config::Limits {
+                max_frame_size: 1024 * 1024,
+                max_body_size: 64 * 1024,
+                max_reservation_timeout: actor::DEFAULT_RESERVATION_TIMEOUT,
+                max_reservations_per_requester: 8,
+                max_udp_sessions: 1024,
+                max_http_sessions: 1024,
+                max_http_sessions_per_addr: 1024,
+            },
+            outbound: config::Outbound {
+                queue_size,
+                overflow_policy: config::OverflowPolicy::Disconnect,
+            },
+            admin: config::Admin::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn apply_overflow_policy_while_send_window_is_full() {
+        let registries = Arc::new(actor::Registries::new());
+        let (output_tx, mut output_rx) = mpsc::channel(OUTPUT_CHANNEL_CAPACITY);
+        // Ackを返さないクライアントと同じく、送信ウィンドウが空かない。
+        let send_window = Arc::new(Semaphore::new(0));
+        let input_tx = spawn_player(
+            1,
+            actor::ConnectionInfo::new(server::Protocol::Udp, None),
+            config(1),
+            registries.clone(),
+            output_tx,
+            send_window.clone(),
+            Span::none(),
+        );
+
+        let player_id = "udp".to_string();
+        input_tx.send(app::ClientMessage {
+            data: Some(app::client_message::Data::LoginRequest(app::LoginRequest {
+                player_id: player_id.clone(),
+                compression: app::Compression::Uncompressed as i32,
+                protocol_version: protobuf::PROTOCOL_VERSION,
+                capabilities: Vec::new(),
+                auth_config: Some(app::login_request::AuthConfig::Bearer(
+                    app::AuthConfigBearer {
+                        token: "bearer".to_string(),
+                    },
+                )),
+            })),
+        }).await.unwrap();
+        while registries.get_player(&player_id).is_none() {
+            tokio::task::yield_now().await;
+        }
+
+        // LoginResponseで送信キューが一杯の状態で、もう1つ応答を返させる。
+        input_tx.send(app::ClientMessage {
+            data: Some(app::client_message::Data::JoinRequest(app::JoinRequest {
+                room_id: player_id.clone(),
+                room_config: None,
+                room_properties: HashMap::new(),
+            })),
+        }).await.unwrap();
+        // 送信キューが溢れてPlayer actorが切断し、プレイヤーの登録が消えるまで待つ。
+        while registries.get_player(&player_id).is_some() {
+            tokio::task::yield_now().await;
+        }
+
+        // 溜まっていたメッセージは捨てられているので、ウィンドウが空くと終了だけが届く。
+        send_window.add_permits(1);
+        assert!(matches!(output_rx.recv().await, Some((1, None))));
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, Bytes};
+
+/// Cookieを作り直す間隔。直前の期間に作ったCookieも受け付けるので、有効期間はこの1倍から2倍になる。
+pub const COOKIE_PERIOD: Duration = Duration::from_secs(10);
+
+/// ハンドシェイクで返すCookieを作って検証する。
+/// Cookieは送信元アドレスと期間から作るので、サーバはConnectを受け取った時点では状態を持たない。
+/// 送信元を偽装したConnectではCookieを受け取れないため、セッションを作れない。
+pub struct Cookies {
+    // プロセスごとにランダムな鍵を持つSipHash。鍵を知らないクライアントはCookieを作れない。
+    key: RandomState,
+    started_at: Instant,
+}
+
+impl Cookies {
+    pub fn new() -> Self {
+        Self {
+            key: RandomState::new(),
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn generate(&self, addr: SocketAddr, now: Instant) -> Bytes {
+        Bytes::copy_from_slice(&self.hash(addr, self.period(now)).to_be_bytes())
+    }
+
+    pub fn verify(&self, addr: SocketAddr, mut cookie: Bytes, now: Instant) -> bool {
+        if cookie.len() != 8 {
+            return false;
+        }
+        let cookie = cookie.get_u64();
+        let period = self.period(now);
+        cookie == self.hash(addr, period) || (period > 0 && cookie == self.hash(addr, period - 1))
+    }
+
+    fn period(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.started_at).as_secs() / COOKIE_PERIOD.as_secs()
+    }
+
+    fn hash(&self, addr: SocketAddr, period: u64) -> u64 {
+        let mut hasher = self.key.build_hasher();
+        addr.hash(&mut hasher);
+        period.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_cookie_for_same_addr_within_period() {
+        let cookies = Cookies::new();
+        let addr: SocketAddr = "127.0.0.1:10000".parse().unwrap();
+        let now = Instant::now();
+        let cookie = cookies.generate(addr, now);
+
+        assert!(cookies.verify(addr, cookie.clone(), now));
+        assert!(cookies.verify(addr, cookie.clone(), now + COOKIE_PERIOD));
+        assert!(!cookies.verify(addr, cookie.clone(), now + COOKIE_PERIOD * 2));
+        assert!(!cookies.verify("127.0.0.1:10001".parse().unwrap(), cookie, now));
+        assert!(!cookies.verify(addr, Bytes::new(), now));
+        // 別のサーバ(鍵)で作られたCookieは受け付けない。
+        assert!(!Cookies::new().verify(addr, cookies.generate(addr, now), now));
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use super::packet::*;
+
+/// Ackが返ってこない場合に再送するまでの時間。
+pub const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+/// この回数再送してもAckが返ってこない場合は切断されたとみなす。
+pub const MAX_RETRANSMITS: u32 = 20;
+// 順序待ちとして受信側で保持できるパケット数。これより先のシーケンス番号は捨てて再送を待つ。
+// 保持するペイロードの合計の大きさは、セッションごとに`Lanes::new`で指定した上限までに抑える。
+const RECEIVE_WINDOW: u32 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lane {
+    Reliable,
+    Unreliable,
+}
+
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum LaneError {
+    #[error("the number of retransmissions exceeded the limit. seq={0}")]
+    RetransmitLimitExceeded(Sequence),
+    #[error("the payload does not fit in a datagram. len={0}")]
+    PayloadTooLarge(usize),
+}
+
+#[derive(Debug, Default)]
+pub struct Received {
+    // 順序通りに並べた、アプリケーションに渡すペイロード。
+    pub payloads: Vec<Bytes>,
+    // 相手に返すAck。
+    pub reply: Option<Packet>,
+    // Ackを受け取って再送の対象から外れた、送信済みのReliableなパケットの数。
+    pub acked: usize,
+}
+
+#[derive(Debug)]
+struct Unacked {
+    payload: Bytes,
+    sent_at: Instant,
+    retransmits: u32,
+}
+
+/// 1つのセッションのレーンごとの送受信の状態。サーバとクライアントのどちらでも使う。
+/// シーケンス番号はu32で一周するので、大小の比較は差分で行う。
+#[derive(Debug)]
+pub struct Lanes {
+    session_id: SessionId,
+    reliable_send_seq: Sequence,
+    unacked: HashMap<Sequence, Unacked>,
+    reliable_recv_seq: Sequence,
+    pending: HashMap<Sequence, Bytes>,
+    // 順序待ちのペイロードの合計の大きさと、その上限。
+    pending_bytes: usize,
+    max_pending_bytes: usize,
+    unreliable_send_seq: Sequence,
+    unreliable_recv_seq: Option<Sequence>,
+}
+
+impl Lanes {
+    /// `max_pending_bytes`は、順序待ちとして受信側で保持するペイロードの合計の上限。
+    pub fn new(session_id: SessionId, max_pending_bytes: usize) -> Self {
+        Self {
+            session_id,
+            reliable_send_seq: 0,
+            unacked: HashMap::new(),
+            reliable_recv_seq: 0,
+            pending: HashMap::new(),
+            pending_bytes: 0,
+            max_pending_bytes,
+            unreliable_send_seq: 0,
+            unreliable_recv_seq: None,
+        }
+    }
+
+    pub fn session_id(&self) -> SessionId {
+        self.session_id
+    }
+
+    /// 送信するパケットを作る。Reliableの場合はAckが返ってくるまで再送の対象になる。
+    /// 1つのデータグラムに収まらないペイロードは、再送しても届かないので送らずにエラーにする。
+    pub fn send(&mut self, lane: Lane, payload: Bytes, now: Instant) -> Result<Packet, LaneError> {
+        if payload.len() > MAX_PAYLOAD_SIZE {
+            return Err(LaneError::PayloadTooLarge(payload.len()));
+        }
+        let body = match lane {
+            Lane::Reliable => {
+                let seq = self.reliable_send_seq;
+                self.reliable_send_seq = seq.wrapping_add(1);
+                self.unacked.insert(
+                    seq,
+                    Unacked {
+                        payload: payload.clone(),
+                        sent_at: now,
+                        retransmits: 0,
+                    },
+                );
+                PacketBody::Reliable { seq, payload }
+            }
+            Lane::Unreliable => {
+                let seq = self.unreliable_send_seq;
+                self.unreliable_send_seq = seq.wrapping_add(1);
+                PacketBody::Unreliable { seq, payload }
+            }
+        };
+        Ok(Packet::new(self.session_id, body))
+    }
+
+    /// レーンに関するパケットを処理する。
+    /// Connect等のセッション管理のパケットは呼び出し元で扱うので、ここでは何もしない。
+    pub fn receive(&mut self, body: PacketBody) -> Received {
+        match body {
+            PacketBody::Reliable { seq, payload } => self.receive_reliable(seq, payload),
+            PacketBody::Unreliable { seq, payload } => {
+                let is_newer = match self.unreliable_recv_seq {
+                    Some(last) => is_newer(seq, last),
+                    None => true,
+                };
+                if !is_newer {
+                    return Received::default();
+                }
+                self.unreliable_recv_seq = Some(seq);
+                Received {
+                    payloads: vec![payload],
+                    ..Default::default()
+                }
+            }
+            PacketBody::Ack { seq } => Received {
+                acked: self.unacked.remove(&seq).map_or(0, |_| 1),
+                ..Default::default()
+            },
+            _ => Received::default(),
+        }
+    }
+
+    fn receive_reliable(&mut self, seq: Sequence, payload: Bytes) -> Received {
+        let offset = seq.wrapping_sub(self.reliable_recv_seq);
+        if offset >= RECEIVE_WINDOW && !is_newer(self.reliable_recv_seq, seq) {
+            // ウィンドウより先のパケット。Ackを返さずに再送を待つ。
+            return Received::default();
+        }
+
+        let reply = Some(Packet::new(self.session_id, PacketBody::Ack { seq }));
+        if offset >= RECEIVE_WINDOW {
+            // 受信済みのパケット。Ackが届いていないので再送されているため、Ackだけ返し直す。
+            return Received {
+                reply,
+                ..Default::default()
+            };
+        }
+
+        if !self.pending.contains_key(&seq) {
+            // 次に渡すパケットはすぐに取り出すので、順序待ちの上限に関係なく受け取る。
+            if offset != 0 && self.pending_bytes + payload.len() > self.max_pending_bytes {
+                // 順序待ちのペイロードが上限に達した。Ackを返さずに再送を待つ。
+                return Received::default();
+            }
+            self.pending_bytes += payload.len();
+            self.pending.insert(seq, payload);
+        }
+        let mut payloads = Vec::new();
+        while let Some(payload) = self.pending.remove(&self.reliable_recv_seq) {
+            self.pending_bytes -= payload.len();
+            payloads.push(payload);
+            self.reliable_recv_seq = self.reliable_recv_seq.wrapping_add(1);
+        }
+        Received {
+            payloads,
+            reply,
+            ..Default::default()
+        }
+    }
+
+    /// Ackが返ってこないまま`RETRANSMIT_TIMEOUT`経過したパケットを再送する。
+    pub fn retransmit(&mut self, now: Instant) -> Result<Vec<Packet>, LaneError> {
+        let mut seqs: Vec<Sequence> = self
+            .unacked
+            .iter()
+            .filter(|(_, unacked)| now.duration_since(unacked.sent_at) >= RETRANSMIT_TIMEOUT)
+            .map(|(seq, _)| *seq)
+            .collect();
+        // 送信した順に並べる。
+        seqs.sort_by_key(|seq| seq.wrapping_sub(self.reliable_send_seq));
+
+        let mut packets = Vec::with_capacity(seqs.len());
+        for seq in seqs {
+            let unacked = self.unacked.get_mut(&seq).unwrap();
+            if unacked.retransmits >= MAX_RETRANSMITS {
+                return Err(LaneError::RetransmitLimitExceeded(seq));
+            }
+            unacked.retransmits += 1;
+            unacked.sent_at = now;
+            packets.push(Packet::new(
+                self.session_id,
+                PacketBody::Reliable {
+                    seq,
+                    payload: unacked.payload.clone(),
+                },
+            ));
+        }
+        Ok(packets)
+    }
+
+    pub fn has_unacked(&self) -> bool {
+        !self.unacked.is_empty()
+    }
+}
+
+fn is_newer(seq: Sequence, than: Sequence) -> bool {
+    seq != than && seq.wrapping_sub(than) < Sequence::MAX / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reliable(seq: Sequence, payload: &'static [u8]) -> PacketBody {
+        PacketBody::Reliable {
+            seq,
+            payload: Bytes::from_static(payload),
+        }
+    }
+
+    #[test]
+    fn reliable_lane_delivers_in_order() {
+        let mut lanes = Lanes::new(1, usize::MAX);
+
+        let received = lanes.receive(reliable(1, b"b"));
+        assert!(received.payloads.is_empty());
+        assert_eq!(
+            Some(Packet::new(1, PacketBody::Ack { seq: 1 })),
+            received.reply
+        );
+
+        let received = lanes.receive(reliable(0, b"a"));
+        assert_eq!(
+            vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")],
+            received.payloads
+        );
+
+        // 重複したパケットは渡さずにAckだけ返し直す。
+        let received = lanes.receive(reliable(0, b"a"));
+        assert!(received.payloads.is_empty());
+        assert_eq!(
+            Some(Packet::new(1, PacketBody::Ack { seq: 0 })),
+            received.reply
+        );
+
+        // ウィンドウより先のパケットはAckも返さない。
+        let received = lanes.receive(reliable(2 + RECEIVE_WINDOW, b"c"));
+        assert!(received.payloads.is_empty());
+        assert!(received.reply.is_none());
+    }
+
+    #[test]
+    fn reliable_lane_limits_pending_bytes() {
+        let mut lanes = Lanes::new(1, 2);
+
+        assert!(lanes.receive(reliable(1, b"b")).reply.is_some());
+        assert!(lanes.receive(reliable(2, b"c")).reply.is_some());
+        // 順序待ちのペイロードが上限を超えるパケットは、Ackを返さずに捨てる。
+        let received = lanes.receive(reliable(3, b"d"));
+        assert!(received.payloads.is_empty());
+        assert!(received.reply.is_none());
+
+        // 次に渡すパケットは上限に関係なく受け取り、順序待ちが空いた分だけ受け取れるようになる。
+        let received = lanes.receive(reliable(0, b"a"));
+        assert_eq!(
+            vec![
+                Bytes::from_static(b"a"),
+                Bytes::from_static(b"b"),
+                Bytes::from_static(b"c")
+            ],
+            received.payloads
+        );
+        assert!(lanes.receive(reliable(4, b"e")).reply.is_some());
+        assert!(lanes.receive(reliable(5, b"f")).reply.is_some());
+        assert!(lanes.receive(reliable(6, b"g")).reply.is_none());
+    }
+
+    #[test]
+    fn unreliable_lane_drops_old_packets() {
+        let mut lanes = Lanes::new(1, usize::MAX);
+        let unreliable = |seq| PacketBody::Unreliable {
+            seq,
+            payload: Bytes::from_static(b"x"),
+        };
+
+        assert_eq!(1, lanes.receive(unreliable(u32::MAX)).payloads.len());
+        assert_eq!(1, lanes.receive(unreliable(1)).payloads.len());
+        assert!(lanes.receive(unreliable(0)).payloads.is_empty());
+        assert!(lanes.receive(unreliable(1)).payloads.is_empty());
+        assert_eq!(1, lanes.receive(unreliable(2)).payloads.len());
+    }
+
+    #[test]
+    fn retransmit_until_acked() {
+        let mut lanes = Lanes::new(1, usize::MAX);
+        let now = Instant::now();
+        lanes
+            .send(Lane::Reliable, Bytes::from_static(b"a"), now)
+            .unwrap();
+        lanes
+            .send(Lane::Reliable, Bytes::from_static(b"b"), now)
+            .unwrap();
+        lanes
+            .send(Lane::Unreliable, Bytes::from_static(b"c"), now)
+            .unwrap();
+
+        assert!(lanes.retransmit(now).unwrap().is_empty());
+
+        assert_eq!(1, lanes.receive(PacketBody::Ack { seq: 0 }).acked);
+        // 重複したAckは数えない。
+        assert_eq!(0, lanes.receive(PacketBody::Ack { seq: 0 }).acked);
+        let now = now + RETRANSMIT_TIMEOUT;
+        assert_eq!(
+            vec![Packet::new(1, reliable(1, b"b"))],
+            lanes.retransmit(now).unwrap()
+        );
+
+        let mut now = now;
+        for _ in 1..MAX_RETRANSMITS {
+            now += RETRANSMIT_TIMEOUT;
+            assert_eq!(1, lanes.retransmit(now).unwrap().len());
+        }
+        now += RETRANSMIT_TIMEOUT;
+        assert_eq!(
+            LaneError::RetransmitLimitExceeded(1),
+            lanes.retransmit(now).unwrap_err()
+        );
+
+        lanes.receive(PacketBody::Ack { seq: 1 });
+        assert!(!lanes.has_unacked());
+    }
+
+    #[test]
+    fn reject_payload_larger_than_datagram() {
+        let mut lanes = Lanes::new(1, usize::MAX);
+        let now = Instant::now();
+        let payload = Bytes::from(vec![0; MAX_PAYLOAD_SIZE + 1]);
+        assert_eq!(
+            LaneError::PayloadTooLarge(MAX_PAYLOAD_SIZE + 1),
+            lanes.send(Lane::Reliable, payload, now).unwrap_err()
+        );
+        // 再送の対象にもならない。
+        assert!(!lanes.has_unacked());
+
+        let packet = lanes
+            .send(Lane::Reliable, Bytes::from(vec![0; MAX_PAYLOAD_SIZE]), now)
+            .unwrap();
+        assert_eq!(MAX_DATAGRAM_SIZE, packet.encode().len());
+    }
+}
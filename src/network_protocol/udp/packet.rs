@@ -0,0 +1,200 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
+
+pub type SessionId = u64;
+pub type Sequence = u32;
+
+/// UDPパケットの最大サイズ(IPv4)。
+/// アプリケーション層での分割は行わないので、これを超えるメッセージは送信できない。
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+// 種別(1byte) + セッションID(8byte)
+const HEADER_SIZE: usize = 9;
+
+/// ReliableとUnreliableのパケットで送れるペイロードの最大サイズ。シーケンス番号(4byte)の分を除く。
+pub const MAX_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - HEADER_SIZE - 4;
+
+const CONNECT: u8 = 0x01;
+const ACCEPT: u8 = 0x02;
+const RELIABLE: u8 = 0x03;
+const ACK: u8 = 0x04;
+const UNRELIABLE: u8 = 0x05;
+const HEARTBEAT: u8 = 0x06;
+const DISCONNECT: u8 = 0x07;
+const CHALLENGE: u8 = 0x08;
+
+#[derive(Error, Clone, Debug, PartialEq, Eq)]
+pub enum PacketError {
+    #[error("the packet is too short. len={0}")]
+    TooShort(usize),
+    #[error("unknown packet type. type={0}")]
+    UnknownType(u8),
+}
+
+/// 全てのパケットは種別(1byte)とセッションID(8byte, big endian)から始まる。
+/// ハンドシェイク前のConnectのセッションIDは0で、サーバがAcceptで割り当てたIDを以降のパケットで使う。
+/// サーバは最初のConnectにはChallengeでCookieを返し、Cookieを付けて送り直されたConnectにだけAcceptを返す。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub session_id: SessionId,
+    pub body: PacketBody,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PacketBody {
+    // 最初のConnectのCookieは空。
+    Connect { cookie: Bytes },
+    // Connectの送信元アドレスを確かめるために、次のConnectで送り返してもらうCookie。
+    Challenge { cookie: Bytes },
+    Accept,
+    // 順序通りに届くまで再送されるレーン。受信側はAckを返す。
+    Reliable { seq: Sequence, payload: Bytes },
+    Ack { seq: Sequence },
+    // 再送されず、受信側は既に受け取ったものより古いシーケンス番号を捨てるレーン。
+    Unreliable { seq: Sequence, payload: Bytes },
+    // 無通信によるセッションのタイムアウトを防ぐためのパケット。
+    Heartbeat,
+    Disconnect,
+}
+
+impl Packet {
+    pub fn new(session_id: SessionId, body: PacketBody) -> Self {
+        Self { session_id, body }
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let payload_len = match &self.body {
+            PacketBody::Reliable { payload, .. } | PacketBody::Unreliable { payload, .. } => {
+                payload.len()
+            }
+            PacketBody::Connect { cookie } | PacketBody::Challenge { cookie } => cookie.len(),
+            _ => 0,
+        };
+        let mut buf = BytesMut::with_capacity(HEADER_SIZE + 4 + payload_len);
+        let packet_type = match &self.body {
+            PacketBody::Connect { .. } => CONNECT,
+            PacketBody::Challenge { .. } => CHALLENGE,
+            PacketBody::Accept => ACCEPT,
+            PacketBody::Reliable { .. } => RELIABLE,
+            PacketBody::Ack { .. } => ACK,
+            PacketBody::Unreliable { .. } => UNRELIABLE,
+            PacketBody::Heartbeat => HEARTBEAT,
+            PacketBody::Disconnect => DISCONNECT,
+        };
+        buf.put_u8(packet_type);
+        buf.put_u64(self.session_id);
+        match &self.body {
+            PacketBody::Reliable { seq, payload } | PacketBody::Unreliable { seq, payload } => {
+                buf.put_u32(*seq);
+                buf.put_slice(payload);
+            }
+            PacketBody::Ack { seq } => {
+                buf.put_u32(*seq);
+            }
+            PacketBody::Connect { cookie } | PacketBody::Challenge { cookie } => {
+                buf.put_slice(cookie);
+            }
+            _ => {}
+        }
+        buf.freeze()
+    }
+
+    pub fn decode(mut buf: Bytes) -> Result<Self, PacketError> {
+        let len = buf.len();
+        if len < HEADER_SIZE {
+            return Err(PacketError::TooShort(len));
+        }
+        let packet_type = buf.get_u8();
+        let session_id = buf.get_u64();
+        let body = match packet_type {
+            CONNECT => PacketBody::Connect { cookie: buf },
+            CHALLENGE => PacketBody::Challenge { cookie: buf },
+            ACCEPT => PacketBody::Accept,
+            HEARTBEAT => PacketBody::Heartbeat,
+            DISCONNECT => PacketBody::Disconnect,
+            RELIABLE | ACK | UNRELIABLE => {
+                if buf.len() < 4 {
+                    return Err(PacketError::TooShort(len));
+                }
+                let seq = buf.get_u32();
+                match packet_type {
+                    RELIABLE => PacketBody::Reliable { seq, payload: buf },
+                    UNRELIABLE => PacketBody::Unreliable { seq, payload: buf },
+                    _ => PacketBody::Ack { seq },
+                }
+            }
+            packet_type => return Err(PacketError::UnknownType(packet_type)),
+        };
+        Ok(Self { session_id, body })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode_packets() {
+        let packets = vec![
+            Packet::new(
+                0,
+                PacketBody::Connect {
+                    cookie: Bytes::new(),
+                },
+            ),
+            Packet::new(
+                0,
+                PacketBody::Challenge {
+                    cookie: Bytes::from_static(b"cookie"),
+                },
+            ),
+            Packet::new(
+                0,
+                PacketBody::Connect {
+                    cookie: Bytes::from_static(b"cookie"),
+                },
+            ),
+            Packet::new(1, PacketBody::Accept),
+            Packet::new(
+                2,
+                PacketBody::Reliable {
+                    seq: 3,
+                    payload: Bytes::from_static(b"reliable"),
+                },
+            ),
+            Packet::new(4, PacketBody::Ack { seq: u32::MAX }),
+            Packet::new(
+                5,
+                PacketBody::Unreliable {
+                    seq: 6,
+                    payload: Bytes::new(),
+                },
+            ),
+            Packet::new(7, PacketBody::Heartbeat),
+            Packet::new(u64::MAX, PacketBody::Disconnect),
+        ];
+        for packet in packets {
+            assert_eq!(packet, Packet::decode(packet.encode()).unwrap());
+        }
+    }
+
+    #[test]
+    fn failed_to_decode_invalid_packets() {
+        assert_eq!(
+            PacketError::TooShort(3),
+            Packet::decode(Bytes::from_static(&[RELIABLE, 0, 0])).unwrap_err()
+        );
+        let mut buf = Packet::new(1, PacketBody::Ack { seq: 1 }).encode().to_vec();
+        buf.truncate(HEADER_SIZE + 2);
+        assert_eq!(
+            PacketError::TooShort(HEADER_SIZE + 2),
+            Packet::decode(buf.into()).unwrap_err()
+        );
+        let mut buf = Packet::new(1, PacketBody::Heartbeat).encode().to_vec();
+        buf[0] = 0xff;
+        assert_eq!(
+            PacketError::UnknownType(0xff),
+            Packet::decode(buf.into()).unwrap_err()
+        );
+    }
+}
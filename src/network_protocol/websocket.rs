@@ -1,71 +1,257 @@
 use std::{
+    convert::Infallible,
     net::{SocketAddr, ToSocketAddrs},
     sync::Arc,
 };
 
 use async_trait::async_trait;
-use futures::{pin_mut, Future, SinkExt, StreamExt};
-use log::{debug, error, info};
-use prost::Message as _;
-use warp::{
-    ws::{Message, WebSocket},
-    Filter,
-};
+use bytes::Bytes;
+use futures::{pin_mut, Future};
+use hyper::header::{self, HeaderMap, HeaderName};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tracing::{debug, error, info, Instrument};
 
+use self::deflate::{Deflater, Inflater};
+use self::frame::{Message, MessageReader, MessageWriter, WebSocketError};
+use super::codec::Codec;
+use super::compression::{CompressionError, Compressor};
 use super::server;
+use super::tcp;
 use crate::actor;
 use crate::config;
+use crate::logging;
+use crate::metrics::METRICS;
 use crate::protobuf;
 
+mod deflate;
+mod frame;
+
+/// protobufのバイナリをバイナリフレームでやり取りするサブプロトコル。
+pub const SUBPROTOCOL_PROTOBUF: &str = "app.proto";
+/// protobufのJSONマッピング(bytesはbase64)をテキストフレームでやり取りするサブプロトコル。
+pub const SUBPROTOCOL_JSON: &str = "app.json";
+/// JSONマッピングと同じ構造のMessagePackをバイナリフレームでやり取りするサブプロトコル。
+pub const SUBPROTOCOL_MESSAGE_PACK: &str = "app.msgpack";
+
+/// クライアントが提示したサブプロトコルのうち、最初に対応しているものを選ぶ。
+pub fn negotiate(subprotocols: &str) -> Option<(Codec, &'static str)> {
+    subprotocols
+        .split(',')
+        .find_map(|subprotocol| match subprotocol.trim() {
+            SUBPROTOCOL_PROTOBUF => Some((Codec::Protobuf, SUBPROTOCOL_PROTOBUF)),
+            SUBPROTOCOL_JSON => Some((Codec::Json, SUBPROTOCOL_JSON)),
+            SUBPROTOCOL_MESSAGE_PACK => Some((Codec::MessagePack, SUBPROTOCOL_MESSAGE_PACK)),
+            _ => None,
+        })
+}
+
 pub struct ServerImpl {}
 
 #[async_trait]
 impl server::Server for ServerImpl {
-    async fn run<A, F>(addr: A, config: Arc<config::Config>, shutdown: F) -> anyhow::Result<()>
+    async fn run<A, F>(
+        addr: A,
+        config: Arc<config::Config>,
+        registries: Arc<actor::Registries>,
+        shutdown: F,
+    ) -> anyhow::Result<()>
     where
         A: ToSocketAddrs + Send + 'static,
         std::net::SocketAddr: From<A>,
         F: Future<Output = ()> + Send + 'static,
     {
         info!("Start WebSocket server");
-        let config_for_routes = config.clone();
-        let routes = warp::path("app")
-            .and(warp::ws())
-            .and(warp::addr::remote())
-            .map(move |ws: warp::ws::Ws, addr: Option<SocketAddr>| {
-                let config = config_for_routes.clone();
-                ws.on_upgrade(move |ws| handle_ws(ws, addr, config))
-            });
+        let addr = addr.to_socket_addrs()?.next().unwrap();
 
+        let mut tls_acceptor = None;
         if config.tls.enable {
             info!("Enabled TLS for WebSocket server.");
-            warp::serve(routes)
-                .tls()
-                .cert_path(&config.tls.cert_file_path)
-                .key_path(&config.tls.key_file_path)
-                .bind_with_graceful_shutdown(addr, shutdown)
-                .1
-                .await;
-        } else {
-            warp::serve(routes)
-                .bind_with_graceful_shutdown(addr, shutdown)
-                .1
-                .await;
+            tls_acceptor = Some(tcp::new_tls_acceptor(&config.tls)?);
+        }
+
+        let listener = TcpListener::bind(addr).await?;
+        pin_mut!(shutdown);
+        loop {
+            tokio::select! {
+                result = listener.accept() => {
+                    match result {
+                        Ok((stream, addr)) => {
+                            let config = config.clone();
+                            let registries = registries.clone();
+                            let tls_acceptor = tls_acceptor.clone();
+                            tokio::spawn(async move {
+                                match tls_acceptor {
+                                    Some(tls_acceptor) => match tls_acceptor.accept(stream).await {
+                                        Ok(stream) => serve_http(stream, addr, config, registries).await,
+                                        Err(err) => error!("TLS handshake error. {:?}", err),
+                                    },
+                                    None => serve_http(stream, addr, config, registries).await,
+                                }
+                            });
+                        }
+                        Err(err) => {
+                            error!("Connection error. {:?}", err);
+                        }
+                    }
+                }
+                _ = &mut shutdown => {
+                    break;
+                }
+            }
         }
         Ok(())
     }
 }
 
-async fn handle_ws(ws: WebSocket, addr: Option<SocketAddr>, config: Arc<config::Config>) {
-    let (tx, rx) = ws.split();
-    pin_mut!(tx, rx);
+/// WebSocketへのアップグレードを受け付けるHTTP/1.1の接続を処理する。
+/// permessage-deflateを合意するために、ハンドシェイクはwarpを使わずに行う。
+async fn serve_http<S>(
+    stream: S,
+    addr: SocketAddr,
+    config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |request| {
+        let response = upgrade(request, addr, config.clone(), registries.clone());
+        async move { Ok::<_, Infallible>(response) }
+    });
+    if let Err(err) = Http::new().serve_connection(stream, service).with_upgrades().await {
+        debug!("HTTP connection error. {:?}", err);
+    }
+}
+
+fn upgrade(
+    mut request: Request<Body>,
+    addr: SocketAddr,
+    config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
+) -> Response<Body> {
+    if request.uri().path() != "/app" {
+        return status_response(StatusCode::NOT_FOUND);
+    }
+    let headers = request.headers();
+    let is_upgrade = request.method() == Method::GET
+        && header_contains(headers, header::CONNECTION, "upgrade")
+        && header_contains(headers, header::UPGRADE, "websocket")
+        && headers.get(header::SEC_WEBSOCKET_VERSION).is_some_and(|version| version == "13");
+    let accept = match headers.get(header::SEC_WEBSOCKET_KEY) {
+        Some(key) if is_upgrade => derive_accept_key(key.as_bytes()),
+        _ => return status_response(StatusCode::BAD_REQUEST),
+    };
+    let negotiated = header_values(headers, header::SEC_WEBSOCKET_PROTOCOL)
+        .as_deref()
+        .and_then(negotiate);
+    // 圧縮を無効にしている場合は、permessage-deflateも合意しない。
+    let extension = header_values(headers, header::SEC_WEBSOCKET_EXTENSIONS)
+        .filter(|_| config.compression.enable)
+        .as_deref()
+        .and_then(deflate::negotiate);
+
+    // サブプロトコルの指定が無い場合は、最初に受信したフレームの種類に合わせる。
+    let codec = negotiated.map(|(codec, _)| codec);
+    let deflate = extension.is_some();
+    let span = logging::connection_span(server::Protocol::Websocket, Some(addr));
+    let connection = actor::ConnectionInfo::new(server::Protocol::Websocket, Some(addr));
+    let on_upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(
+        async move {
+            match on_upgrade.await {
+                Ok(upgraded) => handle_ws(upgraded, connection, config, registries, codec, deflate).await,
+                Err(err) => error!("Failed to upgrade connection. {:?}", err),
+            }
+        }
+        .instrument(span),
+    );
+
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept);
+    if let Some((_, subprotocol)) = negotiated {
+        response = response.header(header::SEC_WEBSOCKET_PROTOCOL, subprotocol);
+    }
+    if let Some(extension) = extension {
+        response = response.header(header::SEC_WEBSOCKET_EXTENSIONS, extension);
+    }
+    response.body(Body::empty()).unwrap_or_else(|err| {
+        error!("Failed to build handshake response. {:?}", err);
+        status_response(StatusCode::INTERNAL_SERVER_ERROR)
+    })
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// 同じ名前のヘッダーが複数ある場合は、カンマで区切って一つにまとめる。
+fn header_values(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    let values: Vec<_> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(","))
+}
+
+fn header_contains(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    header_values(headers, name).is_some_and(|value| {
+        value
+            .split(',')
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+async fn handle_ws(
+    ws: Upgraded,
+    connection: actor::ConnectionInfo,
+    config: Arc<config::Config>,
+    registries: Arc<actor::Registries>,
+    mut codec: Option<Codec>,
+    deflate: bool,
+) {
+    let (reader, writer) = tokio::io::split(ws);
+    let max_frame_size = config.limits.max_frame_size;
+    let (inflater, deflater) = if deflate {
+        (Some(Inflater::new(max_frame_size)), Some(Deflater::default()))
+    } else {
+        (None, None)
+    };
+    let mut rx = MessageReader::new(reader, inflater, max_frame_size);
+    let mut tx = MessageWriter::new(writer, deflater, config.compression.threshold);
     info!("Connected player");
-    let mut player_actor = actor::Player::new(config);
+    let mut compressor = Compressor::new(&config.compression, max_frame_size);
+    let mut player_actor = actor::Player::with_connection(config, registries, connection);
+    let metrics = METRICS.connect(server::Protocol::Websocket);
     loop {
         tokio::select! {
             server_msg = player_actor.recv() => {
                 if let Some(server_msg) = server_msg {
-                    tx.send(Message::binary(server_msg.encode_to_vec())).await.unwrap();
+                    let codec = codec.unwrap_or_default();
+                    let message = match compressor.encode(server_msg, codec) {
+                        Ok(data) => to_ws_message(codec, data),
+                        Err(err) => {
+                            error!("Failed to encode message. codec={}, error={:?}", codec.name(), err);
+                            continue;
+                        }
+                    };
+                    match tx.send(message).await {
+                        Ok(len) => metrics.sent(len),
+                        Err(err) => {
+                            error!("Failed to send message. {:?}", err);
+                            return;
+                        }
+                    }
                     continue;
                 }
 
@@ -73,48 +259,223 @@ async fn handle_ws(ws: WebSocket, addr: Option<SocketAddr>, config: Arc<config::
                 return;
             }
             message = rx.next() => {
-                if let Some(message) = message {
-                    let message = match message {
-                        Ok(message) => message,
-                        Err(err) => {
-                            error!("message error:{:?}", err);
-                            return;
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    Some(Err(err)) => {
+                        error!("message error:{:?}", err);
+                        let codec = codec.unwrap_or_default();
+                        match &err {
+                            WebSocketError::TooLarge(_) => {
+                                let notification = tcp::message_too_large_notification(&err);
+                                send_error_notification(&mut tx, codec, notification, frame::CLOSE_TOO_LARGE).await;
+                            }
+                            WebSocketError::Compression(err) => {
+                                let code = match err {
+                                    CompressionError::TooLarge(_) => frame::CLOSE_TOO_LARGE,
+                                    _ => frame::CLOSE_PROTOCOL_ERROR,
+                                };
+                                send_error_notification(&mut tx, codec, tcp::compression_error_notification(err), code).await;
+                            }
+                            WebSocketError::Protocol(_) => {
+                                let _ = tx.close(frame::CLOSE_PROTOCOL_ERROR).await;
+                            }
+                            WebSocketError::Io(_) => {}
                         }
-                    };
-
-                    if message.is_close() {
-                        info!("close client: {:?}", addr);
-                        tx.close().await.unwrap_or_else(|e| {
-                            error!("websocket close error: {:?}", e);
-                        });
                         return;
                     }
+                    None => {
+                        // クライアントが切断した。
+                        info!("Disconnected player");
+                        return;
+                    }
+                };
 
-                    if message.is_ping() {
+                let (codec, data) = match message {
+                    Message::Binary(data) => (*codec.get_or_insert(Codec::Protobuf), data),
+                    Message::Text(data) => (*codec.get_or_insert(Codec::Json), data),
+                    Message::Ping(data) => {
                         debug!("receive ping");
-                        tx.send(Message::pong(message)).await.unwrap_or_else(|e| {
+                        tx.send(Message::Pong(data)).await.unwrap_or_else(|e| {
                             error!("websocket pong error: {:?}", e);
+                            0
                         });
                         continue;
                     }
+                    Message::Pong(_) => continue,
+                    Message::Close => {
+                        info!("Closed by client");
+                        tx.close(frame::CLOSE_NORMAL).await.unwrap_or_else(|e| {
+                            error!("websocket close error: {:?}", e);
+                        });
+                        return;
+                    }
+                };
+                metrics.received(data.len());
+                let message = codec.decode::<protobuf::app::ClientMessage>(&data);
 
-                    if message.is_binary() {
-                        let message: Result<protobuf::app::ClientMessage, prost::DecodeError> =
-                            prost::Message::decode(message.as_bytes());
-
-                        match message {
-                            Ok(message) => {
-                                // player_actorがDropしない限り失敗しないはず。
-                                let _ = player_actor.send(message);
-                            }
-                            Err(err) => {
-                                error!("Received invalid message. {:?}", err);
-                                return;
-                            }
+                match message {
+                    Ok(message) => match compressor.decompress(message) {
+                        Ok(message) => {
+                            // player_actorがDropしない限り失敗しないはず。
+                            let _ = player_actor.send(message).await;
                         }
+                        Err(err) => {
+                            error!("Received invalid compressed message. {:?}", err);
+                            let notification = tcp::compression_error_notification(&err);
+                            send_error_notification(&mut tx, codec, notification, frame::CLOSE_PROTOCOL_ERROR).await;
+                            return;
+                        }
+                    },
+                    Err(err) => {
+                        error!("Received invalid message. {:?}", err);
+                        let notification = tcp::invalid_message_notification(&err);
+                        send_error_notification(&mut tx, codec, notification, frame::CLOSE_PROTOCOL_ERROR).await;
+                        return;
                     }
                 }
             }
         }
     }
 }
+
+fn to_ws_message(codec: Codec, data: Bytes) -> Message {
+    // JSONはUTF-8なので、そのままテキストフレームで送れる。
+    if codec.is_text() {
+        Message::Text(data)
+    } else {
+        Message::Binary(data)
+    }
+}
+
+/// 切断する前にエラーを通知する。送れなくても切断するので、送信の失敗は無視する。
+async fn send_error_notification<W: AsyncWrite + Unpin>(
+    tx: &mut MessageWriter<W>,
+    codec: Codec,
+    notification: protobuf::app::ServerMessage,
+    close_code: u16,
+) {
+    if let Ok(data) = codec.encode(&notification) {
+        if tx.send(to_ws_message(codec, data.into())).await.is_ok() {
+            let _ = tx.close(close_code).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message as _;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    use super::*;
+    use crate::protobuf::app;
+
+    fn config() -> Arc<config::Config> {
+        Arc::new(config::Config {
+            auth: config::Auth {
+                enable_bearer: true,
+                bearer: "bearer".to_string(),
+                trusted_unix_uids: Vec::new(),
+            },
+            tls: config::Tls {
+                enable: false,
+                cert_file_path: "".to_string(),
+                key_file_path: "".to_string(),
+            },
+            // 全てのメッセージをpermessage-deflateで圧縮する。
+            compression: config::Compression {
+                enable: true,
+                threshold: 0,
+            },
+            compatibility: config::Compatibility {
+                min_protocol_version: 0,
+            },
+            limits: config::Limits {
+                max_frame_size: 1024 * 1024,
+                max_body_size: 64 * 1024,
+                max_reservation_timeout: actor::DEFAULT_RESERVATION_TIMEOUT,
+                max_reservations_per_requester: 8,
+                max_udp_sessions: 1024,
+                max_http_sessions: 1024,
+                max_http_sessions_per_addr: 1024,
+            },
+            outbound: config::Outbound {
+                queue_size: 1024,
+                overflow_policy: config::OverflowPolicy::Disconnect,
+            },
+            admin: config::Admin::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn exchange_messages_with_permessage_deflate() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let addr = "127.0.0.1:10000".parse().unwrap();
+        tokio::spawn(serve_http(server, addr, config(), Arc::new(actor::Registries::new())));
+
+        let mut client = BufReader::new(client);
+        client
+            .write_all(
+                b"GET /app HTTP/1.1\r\n\
+                  Host: localhost\r\n\
+                  Connection: Upgrade\r\n\
+                  Upgrade: websocket\r\n\
+                  Sec-WebSocket-Version: 13\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                  Sec-WebSocket-Protocol: app.proto\r\n\
+                  Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\
+                  \r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        loop {
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            response.push(line.trim_end().to_ascii_lowercase());
+        }
+        assert_eq!(response[0], "http/1.1 101 switching protocols");
+        // RFC 6455 1.3の例と同じキー。
+        assert!(response.contains(&"sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo=".to_string()));
+        assert!(response.contains(&"sec-websocket-protocol: app.proto".to_string()));
+        assert!(response
+            .contains(&"sec-websocket-extensions: permessage-deflate; server_no_context_takeover".to_string()));
+
+        let login = app::ClientMessage {
+            data: Some(app::client_message::Data::LoginRequest(app::LoginRequest {
+                player_id: "p1".to_string(),
+                compression: app::Compression::Uncompressed as i32,
+                protocol_version: crate::protobuf::PROTOCOL_VERSION,
+                capabilities: Vec::new(),
+                auth_config: Some(app::login_request::AuthConfig::Bearer(app::AuthConfigBearer {
+                    token: "bearer".to_string(),
+                })),
+            })),
+        };
+        let payload = Deflater::default().compress(&login.encode_to_vec()).unwrap();
+        assert!(payload.len() < 126);
+        // RSV1を付けたマスク済みのバイナリフレーム。
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![0xc2, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        client.write_all(&frame).await.unwrap();
+
+        let mut header = [0; 2];
+        client.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], 0xc2, "compressed binary frame");
+        assert!(header[1] < 126);
+        let mut payload = vec![0; header[1] as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        let data = Inflater::new(1024).decompress(&payload).unwrap();
+        let response = app::ServerMessage::decode(&data[..]).unwrap();
+        match response.data {
+            Some(app::server_message::Data::LoginResponse(response)) => {
+                assert_eq!(response.error.unwrap().code, app::ErrorCode::None as i32);
+            }
+            data => panic!("unexpected message. {:?}", data),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! WebSocketのpermessage-deflate拡張(RFC 7692)。
+//! サーバは圧縮の状態をメッセージごとに初期化する(server_no_context_takeover)。
+//! クライアントは状態を引き継いで圧縮してよいので、展開の状態は接続の間保持する。
+
+use std::collections::HashSet;
+use std::io;
+
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress};
+
+use crate::network_protocol::compression::CompressionError;
+
+pub const EXTENSION_NAME: &str = "permessage-deflate";
+
+// 同期フラッシュで付く空のブロック。圧縮したメッセージからは取り除いて送る。
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// クライアントの`Sec-WebSocket-Extensions`から、受け入れられる最初のpermessage-deflateの提案を探し、
+/// 応答に入れる値を返す。
+pub fn negotiate(extensions: &str) -> Option<String> {
+    extensions.split(',').find_map(negotiate_offer)
+}
+
+fn negotiate_offer(offer: &str) -> Option<String> {
+    let mut params = offer.split(';').map(str::trim);
+    if params.next()? != EXTENSION_NAME {
+        return None;
+    }
+    let mut response = format!("{}; server_no_context_takeover", EXTENSION_NAME);
+    let mut names = HashSet::new();
+    for param in params {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None),
+        };
+        // 同じパラメータが複数ある提案は無効。
+        if !names.insert(name) {
+            return None;
+        }
+        match (name, value) {
+            ("server_no_context_takeover", None) | ("client_no_context_takeover", None) => {}
+            // 展開は最大のウィンドウで行うので、クライアントのウィンドウの大きさは制限しない。
+            ("client_max_window_bits", None) => {}
+            ("client_max_window_bits", Some(bits)) if is_window_bits(bits) => {}
+            // flate2の圧縮は常に最大のウィンドウを使うので、小さいウィンドウを求める提案は断る。
+            ("server_max_window_bits", Some("15")) => {
+                response.push_str("; server_max_window_bits=15");
+            }
+            _ => return None,
+        }
+    }
+    Some(response)
+}
+
+fn is_window_bits(bits: &str) -> bool {
+    bits.parse::<u8>()
+        .is_ok_and(|bits| (8..=15).contains(&bits))
+}
+
+/// 送信するメッセージを圧縮する。
+pub struct Deflater {
+    compress: Compress,
+}
+
+impl Default for Deflater {
+    fn default() -> Self {
+        Self {
+            compress: Compress::new(flate2::Compression::default(), false),
+        }
+    }
+}
+
+impl Deflater {
+    pub fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        let mut output = Vec::with_capacity(data.len() / 2 + 64);
+        let mut input = data;
+        loop {
+            let total_in = self.compress.total_in();
+            self.compress
+                .compress_vec(input, &mut output, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+            input = &input[(self.compress.total_in() - total_in) as usize..];
+            // 出力先に空きが残っていれば、フラッシュまで書き終わっている。
+            if input.is_empty() && output.len() < output.capacity() {
+                break;
+            }
+            output.reserve(output.capacity().max(64));
+        }
+        if output.ends_with(&TAIL) {
+            output.truncate(output.len() - TAIL.len());
+        }
+        self.compress.reset();
+        Ok(output)
+    }
+}
+
+/// 受信したメッセージを展開する。
+pub struct Inflater {
+    decompress: Decompress,
+    max_size: usize,
+}
+
+impl Inflater {
+    /// `max_size`より大きく展開されるメッセージは拒否する。
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            decompress: Decompress::new(false),
+            max_size,
+        }
+    }
+
+    pub fn decompress(&mut self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        let mut input = Vec::with_capacity(data.len() + TAIL.len());
+        input.extend_from_slice(data);
+        input.extend_from_slice(&TAIL);
+        let limit = self.max_size.saturating_add(1);
+        let mut output = Vec::with_capacity(data.len().saturating_mul(2).clamp(64, limit));
+        let mut input = &input[..];
+        loop {
+            let total_in = self.decompress.total_in();
+            let total_out = self.decompress.total_out();
+            self.decompress
+                .decompress_vec(input, &mut output, FlushDecompress::Sync)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let consumed = (self.decompress.total_in() - total_in) as usize;
+            let produced = self.decompress.total_out() - total_out;
+            input = &input[consumed..];
+            if output.len() > self.max_size {
+                return Err(CompressionError::TooLarge(self.max_size));
+            }
+            if input.is_empty() && output.len() < output.capacity() {
+                return Ok(output);
+            }
+            if output.len() == output.capacity() {
+                // 上限を1バイト超えるところまでしか広げない。
+                output.reserve_exact(output.capacity().max(64).min(limit - output.len()));
+            } else if consumed == 0 && produced == 0 {
+                return Err(
+                    io::Error::new(io::ErrorKind::InvalidData, "truncated deflate data").into(),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_offers() {
+        assert_eq!(
+            negotiate("permessage-deflate").as_deref(),
+            Some("permessage-deflate; server_no_context_takeover")
+        );
+        assert_eq!(
+            negotiate("permessage-deflate; client_max_window_bits").as_deref(),
+            Some("permessage-deflate; server_no_context_takeover")
+        );
+        assert_eq!(
+            negotiate("permessage-deflate; server_max_window_bits=15; client_no_context_takeover")
+                .as_deref(),
+            Some("permessage-deflate; server_no_context_takeover; server_max_window_bits=15")
+        );
+        // 受け入れられない提案は飛ばして、次の提案を選ぶ。
+        assert_eq!(
+            negotiate("permessage-deflate; server_max_window_bits=10, permessage-deflate")
+                .as_deref(),
+            Some("permessage-deflate; server_no_context_takeover")
+        );
+        assert_eq!(negotiate("permessage-deflate; unknown"), None);
+        assert_eq!(
+            negotiate("permessage-deflate; client_no_context_takeover; client_no_context_takeover"),
+            None
+        );
+        assert_eq!(negotiate("x-webkit-deflate-frame"), None);
+    }
+
+    #[test]
+    fn inflate_rfc_example() {
+        // RFC 7692 7.2.3.1の"Hello"。
+        let mut inflater = Inflater::new(1024);
+        let data = inflater
+            .decompress(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00])
+            .unwrap();
+        assert_eq!(data, b"Hello");
+    }
+
+    #[test]
+    fn deflate_and_inflate_messages() {
+        let mut deflater = Deflater::default();
+        let mut inflater = Inflater::new(1024 * 1024);
+        let first = vec![b'a'; 100_000];
+        let second = b"second message".to_vec();
+        let compressed = deflater.compress(&first).unwrap();
+        assert!(compressed.len() < first.len() / 10);
+        assert!(!compressed.ends_with(&TAIL));
+        assert_eq!(inflater.decompress(&compressed).unwrap(), first);
+        assert_eq!(
+            inflater
+                .decompress(&deflater.compress(&second).unwrap())
+                .unwrap(),
+            second
+        );
+    }
+
+    #[test]
+    fn reject_too_large_inflated_message() {
+        let compressed = Deflater::default().compress(&vec![0; 10_000]).unwrap();
+        assert!(matches!(
+            Inflater::new(9_999).decompress(&compressed),
+            Err(CompressionError::TooLarge(9_999))
+        ));
+        assert_eq!(
+            Inflater::new(10_000).decompress(&compressed).unwrap().len(),
+            10_000
+        );
+    }
+}
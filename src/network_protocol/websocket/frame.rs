@@ -0,0 +1,435 @@
+//! WebSocketのフレーム(RFC 6455)とメッセージ。
+//! permessage-deflateで圧縮したメッセージはRSV1ビットで示すので、フレームは自前でエンコードとデコードを行う。
+//! サーバ側だけを実装しているので、受信するフレームはマスクされている必要があり、送信するフレームはマスクしない。
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::SinkExt;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+
+use super::deflate::{Deflater, Inflater};
+use crate::network_protocol::compression::CompressionError;
+
+/// 正常な切断を表すクローズコード。
+pub const CLOSE_NORMAL: u16 = 1000;
+/// プロトコル違反で切断するときのクローズコード。
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+/// 大きすぎるメッセージを受け取って切断するときのクローズコード。
+pub const CLOSE_TOO_LARGE: u16 = 1009;
+
+// 制御フレームのペイロードの上限。
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+#[derive(Error, Debug)]
+pub enum WebSocketError {
+    #[error("frame or message is too large. max={0}")]
+    TooLarge(usize),
+    #[error("websocket protocol error. {0}")]
+    Protocol(&'static str),
+    #[error(transparent)]
+    Compression(#[from] CompressionError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpCode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl OpCode {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x0 => Some(OpCode::Continuation),
+            0x1 => Some(OpCode::Text),
+            0x2 => Some(OpCode::Binary),
+            0x8 => Some(OpCode::Close),
+            0x9 => Some(OpCode::Ping),
+            0xa => Some(OpCode::Pong),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xa,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    /// permessage-deflateで圧縮したメッセージの最初のフレームに付く。
+    pub rsv1: bool,
+    pub opcode: OpCode,
+    pub payload: Bytes,
+}
+
+pub struct FrameCodec {
+    max_frame_size: usize,
+}
+
+impl FrameCodec {
+    /// `max_frame_size`より大きいペイロードのフレームは受け取らない。
+    pub fn new(max_frame_size: usize) -> Self {
+        Self { max_frame_size }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = WebSocketError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, WebSocketError> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        let (first, second) = (src[0], src[1]);
+        if first & 0x30 != 0 {
+            return Err(WebSocketError::Protocol("RSV2 and RSV3 must be zero"));
+        }
+        let opcode =
+            OpCode::from_u8(first & 0x0f).ok_or(WebSocketError::Protocol("unknown opcode"))?;
+        let fin = first & 0x80 != 0;
+        if second & 0x80 == 0 {
+            return Err(WebSocketError::Protocol("client frames must be masked"));
+        }
+        let (len, header_len) = match second & 0x7f {
+            126 => {
+                if src.len() < 4 {
+                    return Ok(None);
+                }
+                (u16::from_be_bytes([src[2], src[3]]) as u64, 4)
+            }
+            127 => {
+                if src.len() < 10 {
+                    return Ok(None);
+                }
+                let mut len = [0; 8];
+                len.copy_from_slice(&src[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            len => (len as u64, 2),
+        };
+        if opcode.is_control() && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(WebSocketError::Protocol("invalid control frame"));
+        }
+        if len > self.max_frame_size as u64 {
+            return Err(WebSocketError::TooLarge(self.max_frame_size));
+        }
+        let len = len as usize;
+        let frame_len = header_len + 4 + len;
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+        let mut mask = [0; 4];
+        mask.copy_from_slice(&src[header_len..header_len + 4]);
+        src.advance(header_len + 4);
+        let mut payload = src.split_to(len);
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Some(Frame {
+            fin,
+            rsv1: first & 0x40 != 0,
+            opcode,
+            payload: payload.freeze(),
+        }))
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = WebSocketError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), WebSocketError> {
+        let len = frame.payload.len();
+        dst.reserve(len + 10);
+        dst.put_u8((frame.fin as u8) << 7 | (frame.rsv1 as u8) << 6 | frame.opcode.to_u8());
+        if len < 126 {
+            dst.put_u8(len as u8);
+        } else if len <= u16::MAX as usize {
+            dst.put_u8(126);
+            dst.put_u16(len as u16);
+        } else {
+            dst.put_u8(127);
+            dst.put_u64(len as u64);
+        }
+        dst.extend_from_slice(&frame.payload);
+        Ok(())
+    }
+}
+
+/// 分割されたフレームを組み立て、圧縮されていれば展開したメッセージ。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(Bytes),
+    Binary(Bytes),
+    Ping(Bytes),
+    Pong(Bytes),
+    Close,
+}
+
+// 組み立て中のメッセージ。
+struct Partial {
+    opcode: OpCode,
+    compressed: bool,
+    data: BytesMut,
+}
+
+pub struct MessageReader<R> {
+    frames: FramedRead<R, FrameCodec>,
+    inflater: Option<Inflater>,
+    partial: Option<Partial>,
+    max_message_size: usize,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    /// `inflater`はpermessage-deflateを合意した場合に渡す。
+    /// 組み立てたメッセージ、展開したメッセージとも`max_message_size`を超えるとエラーにする。
+    pub fn new(reader: R, inflater: Option<Inflater>, max_message_size: usize) -> Self {
+        Self {
+            frames: FramedRead::new(reader, FrameCodec::new(max_message_size)),
+            inflater,
+            partial: None,
+            max_message_size,
+        }
+    }
+
+    /// 接続が閉じられた場合は`None`を返す。
+    pub async fn next(&mut self) -> Option<Result<Message, WebSocketError>> {
+        loop {
+            let frame = match self.frames.next().await? {
+                Ok(frame) => frame,
+                Err(err) => return Some(Err(err)),
+            };
+            match self.push(frame) {
+                Ok(Some(message)) => return Some(Ok(message)),
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+
+    fn push(&mut self, frame: Frame) -> Result<Option<Message>, WebSocketError> {
+        if frame.opcode.is_control() {
+            if frame.rsv1 {
+                return Err(WebSocketError::Protocol(
+                    "control frames must not be compressed",
+                ));
+            }
+            // 制御フレームは分割されたメッセージの途中にも届く。
+            return Ok(Some(match frame.opcode {
+                OpCode::Ping => Message::Ping(frame.payload),
+                OpCode::Pong => Message::Pong(frame.payload),
+                _ => Message::Close,
+            }));
+        }
+        if frame.rsv1 && self.inflater.is_none() {
+            return Err(WebSocketError::Protocol(
+                "RSV1 is set without permessage-deflate",
+            ));
+        }
+        let mut partial = match (self.partial.take(), frame.opcode) {
+            (None, OpCode::Continuation) => {
+                return Err(WebSocketError::Protocol("unexpected continuation frame"));
+            }
+            (None, opcode) => Partial {
+                opcode,
+                compressed: frame.rsv1,
+                data: BytesMut::new(),
+            },
+            (Some(partial), OpCode::Continuation) if !frame.rsv1 => partial,
+            (Some(_), _) => {
+                return Err(WebSocketError::Protocol("expected continuation frame"));
+            }
+        };
+        if partial.data.len() + frame.payload.len() > self.max_message_size {
+            return Err(WebSocketError::TooLarge(self.max_message_size));
+        }
+        partial.data.extend_from_slice(&frame.payload);
+        if !frame.fin {
+            self.partial = Some(partial);
+            return Ok(None);
+        }
+
+        let data = match &mut self.inflater {
+            Some(inflater) if partial.compressed => {
+                Bytes::from(inflater.decompress(&partial.data)?)
+            }
+            _ => partial.data.freeze(),
+        };
+        Ok(Some(match partial.opcode {
+            OpCode::Text => Message::Text(data),
+            _ => Message::Binary(data),
+        }))
+    }
+}
+
+pub struct MessageWriter<W> {
+    frames: FramedWrite<W, FrameCodec>,
+    deflater: Option<Deflater>,
+    compression_threshold: usize,
+}
+
+impl<W: AsyncWrite + Unpin> MessageWriter<W> {
+    /// `deflater`はpermessage-deflateを合意した場合に渡す。
+    /// `compression_threshold`より小さいメッセージは圧縮せずに送る。
+    pub fn new(writer: W, deflater: Option<Deflater>, compression_threshold: usize) -> Self {
+        Self {
+            // 送信するフレームの大きさは制限しない。
+            frames: FramedWrite::new(writer, FrameCodec::new(usize::MAX)),
+            deflater,
+            compression_threshold,
+        }
+    }
+
+    /// 送ったフレームのペイロードの大きさを返す。
+    pub async fn send(&mut self, message: Message) -> Result<usize, WebSocketError> {
+        let (opcode, data) = match message {
+            Message::Text(data) => (OpCode::Text, data),
+            Message::Binary(data) => (OpCode::Binary, data),
+            Message::Ping(data) => (OpCode::Ping, data),
+            Message::Pong(data) => (OpCode::Pong, data),
+            Message::Close => {
+                self.close(CLOSE_NORMAL).await?;
+                return Ok(2);
+            }
+        };
+        let (payload, rsv1) = match &mut self.deflater {
+            Some(deflater) if !opcode.is_control() && data.len() >= self.compression_threshold => {
+                (Bytes::from(deflater.compress(&data)?), true)
+            }
+            _ => (data, false),
+        };
+        let len = payload.len();
+        self.frames
+            .send(Frame {
+                fin: true,
+                rsv1,
+                opcode,
+                payload,
+            })
+            .await?;
+        Ok(len)
+    }
+
+    /// クローズフレームを送って、書き込み側を閉じる。
+    pub async fn close(&mut self, code: u16) -> Result<(), WebSocketError> {
+        self.frames
+            .send(Frame {
+                fin: true,
+                rsv1: false,
+                opcode: OpCode::Close,
+                payload: Bytes::copy_from_slice(&code.to_be_bytes()),
+            })
+            .await?;
+        self.frames.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![first, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        frame
+    }
+
+    #[test]
+    fn decode_masked_frame() {
+        // RFC 6455 5.7のマスクされた"Hello"。
+        let mut src = BytesMut::from(
+            &[
+                0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+            ][..],
+        );
+        let frame = FrameCodec::new(1024).decode(&mut src).unwrap().unwrap();
+        assert_eq!(
+            frame,
+            Frame {
+                fin: true,
+                rsv1: false,
+                opcode: OpCode::Text,
+                payload: Bytes::from_static(b"Hello"),
+            }
+        );
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn reject_invalid_frames() {
+        let mut codec = FrameCodec::new(4);
+        // マスクされていない。
+        let mut src = BytesMut::from(&[0x81, 0x05, b'H', b'e', b'l', b'l', b'o'][..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(WebSocketError::Protocol(_))
+        ));
+        // 上限より大きい。
+        let mut src = BytesMut::from(&client_frame(0x82, b"Hello")[..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(WebSocketError::TooLarge(4))
+        ));
+    }
+
+    #[tokio::test]
+    async fn assemble_fragmented_message_around_ping() {
+        let mut data = client_frame(0x01, b"Hel");
+        data.extend(client_frame(0x89, b"ping"));
+        data.extend(client_frame(0x80, b"lo"));
+        let mut reader = MessageReader::new(&data[..], None, 1024);
+        assert_eq!(
+            reader.next().await.unwrap().unwrap(),
+            Message::Ping(Bytes::from_static(b"ping"))
+        );
+        assert_eq!(
+            reader.next().await.unwrap().unwrap(),
+            Message::Text(Bytes::from_static(b"Hello"))
+        );
+        assert!(reader.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reject_compressed_frame_without_extension() {
+        let data = client_frame(0xc2, &[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]);
+        let mut reader = MessageReader::new(&data[..], None, 1024);
+        assert!(matches!(
+            reader.next().await.unwrap(),
+            Err(WebSocketError::Protocol(_))
+        ));
+
+        let mut reader = MessageReader::new(&data[..], Some(Inflater::new(1024)), 1024);
+        assert_eq!(
+            reader.next().await.unwrap().unwrap(),
+            Message::Binary(Bytes::from_static(b"Hello"))
+        );
+    }
+}
@@ -1,5 +1,15 @@
 //! Protobufのコード生成。
 
+/// サーバが実装しているapp.protoのバージョン。クライアントに影響のある変更を加えた場合に上げる。
+pub const PROTOCOL_VERSION: u32 = 1;
+
 pub mod app {
     tonic::include_proto!("app");
+    // protobufのJSONマッピング。WebSocketのテキストフレームで使う。
+    include!(concat!(env!("OUT_DIR"), "/app.serde.rs"));
+}
+
+/// 運用ツール向けのAdminサービス。クライアントには公開しないので、JSONマッピングは生成しない。
+pub mod admin {
+    tonic::include_proto!("admin");
 }
@@ -3,35 +3,11 @@ use tokio::sync::mpsc;
 
 use super::*;
 
-pub struct ClientImpl {
-    tx: mpsc::UnboundedSender<protobuf::app::ClientMessage>,
-    rx: mpsc::UnboundedReceiver<protobuf::app::ServerMessage>,
-}
+pub struct ClientImpl(client::Client);
 
-// TODO: close, 雑なunwrap
 impl ClientImpl {
     pub fn new(addr: String) -> Self {
-        let (input_tx, mut input_rx) = mpsc::unbounded_channel();
-        let (output_tx, output_rx) = mpsc::unbounded_channel();
-        tokio::spawn(async move {
-            let mut client = protobuf::app::app_client::AppClient::connect(addr)
-                .await
-                .unwrap();
-            let stream = async_stream::stream! {
-                while let Some(message) = input_rx.recv().await {
-                    yield message;
-                }
-            };
-            let mut inbound = client.start(stream).await.unwrap().into_inner();
-            while let Some(message) = inbound.message().await.unwrap() {
-                output_tx.send(message).unwrap();
-            }
-        });
-
-        Self {
-            tx: input_tx,
-            rx: output_rx,
-        }
+        Self(client::grpc::connect(addr))
     }
 }
 
@@ -45,11 +21,11 @@ impl Client for ClientImpl {
         &self,
         message: protobuf::app::ClientMessage,
     ) -> Result<(), mpsc::error::SendError<protobuf::app::ClientMessage>> {
-        self.tx.send(message)
+        self.0.send(message)
     }
 
     async fn recv(&mut self) -> Option<protobuf::app::ServerMessage> {
-        self.rx.recv().await
+        self.0.recv().await
     }
 }
 
@@ -58,6 +34,7 @@ fn run_server() {
         network_protocol::server::run::<network_protocol::grpc::ServerImpl, _, _>(
             server_addr(),
             default_config(),
+            Arc::new(actor::Registries::new()),
             network_protocol::server::wait_signal(),
         )
         .await
@@ -0,0 +1,132 @@
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, StatusCode};
+use prost::Message as _;
+use tokio::sync::mpsc;
+
+use super::*;
+
+pub struct ClientImpl {
+    tx: mpsc::UnboundedSender<protobuf::app::ClientMessage>,
+    rx: mpsc::UnboundedReceiver<protobuf::app::ServerMessage>,
+}
+
+// TODO: close, 雑なunwrap
+impl ClientImpl {
+    pub fn new(addr: SocketAddr) -> Self {
+        let (input_tx, mut input_rx) = mpsc::unbounded_channel::<protobuf::app::ClientMessage>();
+        let (output_tx, output_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let client = hyper::Client::new();
+            let base_url = format!("http://{}/app/sessions", addr);
+            let request = Request::post(&base_url).body(Body::empty()).unwrap();
+            let response = client.request(request).await.unwrap();
+            assert_eq!(StatusCode::CREATED, response.status());
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let session_id = body["sessionId"].as_str().unwrap().to_string();
+
+            let request = Request::get(format!("{}/{}/events", base_url, session_id))
+                .body(Body::empty())
+                .unwrap();
+            let response = client.request(request).await.unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+            let mut events = response.into_body();
+            let mut buf = String::new();
+            loop {
+                tokio::select! {
+                    input_msg = input_rx.recv() => {
+                        if let Some(input_msg) = input_msg {
+                            // 順序を保つために、1つずつレスポンスを待ってから次を送る。
+                            let request = Request::builder()
+                                .method(Method::POST)
+                                .uri(format!("{}/{}/messages", base_url, session_id))
+                                .header("content-type", "application/x-protobuf")
+                                .body(Body::from(input_msg.encode_to_vec()))
+                                .unwrap();
+                            let response = client.request(request).await.unwrap();
+                            assert_eq!(StatusCode::ACCEPTED, response.status());
+                        }
+                    }
+                    chunk = events.data() => {
+                        let chunk = match chunk {
+                            Some(chunk) => chunk.unwrap(),
+                            None => return,
+                        };
+                        buf.push_str(std::str::from_utf8(&chunk).unwrap());
+                        while let Some(end) = buf.find("\n\n") {
+                            let event: String = buf.drain(..end + 2).collect();
+                            // keep-aliveのコメントなど、dataの無いイベントは読み飛ばす。
+                            let data: String = event
+                                .lines()
+                                .filter_map(|line| line.strip_prefix("data:"))
+                                .collect();
+                            if data.is_empty() {
+                                continue;
+                            }
+                            let message: protobuf::app::ServerMessage = serde_json::from_str(&data).unwrap();
+                            output_tx.send(message).unwrap();
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            tx: input_tx,
+            rx: output_rx,
+        }
+    }
+}
+
+#[async_trait]
+impl Client for ClientImpl {
+    fn generate() -> Self {
+        Self::new(server_addr())
+    }
+
+    fn send(
+        &self,
+        message: protobuf::app::ClientMessage,
+    ) -> Result<(), mpsc::error::SendError<protobuf::app::ClientMessage>> {
+        self.tx.send(message)
+    }
+
+    async fn recv(&mut self) -> Option<protobuf::app::ServerMessage> {
+        self.rx.recv().await
+    }
+}
+
+fn run_server() {
+    tokio::spawn(async move {
+        network_protocol::server::run::<network_protocol::http_fallback::ServerImpl, _, _>(
+            server_addr(),
+            default_config(),
+            Arc::new(actor::Registries::new()),
+            network_protocol::server::wait_signal(),
+        )
+        .await
+        .unwrap();
+    });
+}
+
+fn server_addr() -> SocketAddr {
+    env::var("GS_TEST_HTTP_FALLBACK_SERVER_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:8007".to_string())
+        .parse::<SocketAddr>()
+        .unwrap()
+}
+
+pub async fn setup() {
+    if boot_server() {
+        run_server();
+        // TODO: 雑にスリープ。実際はReadiness Probe的なチェックが必要かも。
+        sleep(Duration::from_millis(1000)).await;
+    }
+}
+
+pub async fn teardown() {
+    // TODO: shutdown
+}
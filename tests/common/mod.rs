@@ -11,8 +11,13 @@ extern crate mini_realtime_server;
 pub use mini_realtime_server::*;
 
 pub mod grpc;
+pub mod http_fallback;
+pub mod multi_protocol;
+pub mod quic;
 pub mod scenario;
 pub mod tcp;
+pub mod udp;
+pub mod unix;
 pub mod websocket;
 
 #[async_trait]
@@ -32,12 +37,34 @@ pub fn default_config() -> Arc<config::Config> {
         auth: config::Auth {
             enable_bearer: true,
             bearer: "bearer".to_string(),
+            trusted_unix_uids: Vec::new(),
         },
         tls: config::Tls {
             enable: false,
             cert_file_path: "./server.crt".to_string(),
             key_file_path: "./server.key".to_string(),
         },
+        compression: config::Compression {
+            enable: true,
+            threshold: 1024,
+        },
+        compatibility: config::Compatibility {
+            min_protocol_version: 0,
+        },
+        limits: config::Limits {
+            max_frame_size: 1024 * 1024,
+            max_body_size: 64 * 1024,
+            max_reservation_timeout: actor::DEFAULT_RESERVATION_TIMEOUT,
+            max_reservations_per_requester: 8,
+            max_udp_sessions: 1024,
+            max_http_sessions: 1024,
+            max_http_sessions_per_addr: 1024,
+        },
+        outbound: config::Outbound {
+            queue_size: 1024,
+            overflow_policy: config::OverflowPolicy::Disconnect,
+        },
+        admin: config::Admin::default(),
     })
 }
 
@@ -0,0 +1,61 @@
+use std::net::SocketAddr;
+
+use super::*;
+
+// 同じプロセスで起動した異なるプロトコルのサーバに、それぞれのクライアントで接続する。
+
+fn run_server() {
+    tokio::spawn(async move {
+        network_protocol::server::run_all(
+            vec![
+                (
+                    network_protocol::server::Protocol::Websocket,
+                    websocket_server_addr().into(),
+                ),
+                (
+                    network_protocol::server::Protocol::Tcp,
+                    tcp_server_addr().into(),
+                ),
+            ],
+            default_config(),
+            Arc::new(actor::Registries::new()),
+            network_protocol::server::wait_signal(),
+        )
+        .await
+        .unwrap();
+    });
+}
+
+fn websocket_server_addr() -> SocketAddr {
+    env::var("GS_TEST_MULTI_PROTOCOL_WEBSOCKET_SERVER_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:8005".to_string())
+        .parse::<SocketAddr>()
+        .unwrap()
+}
+
+fn tcp_server_addr() -> SocketAddr {
+    env::var("GS_TEST_MULTI_PROTOCOL_TCP_SERVER_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:8006".to_string())
+        .parse::<SocketAddr>()
+        .unwrap()
+}
+
+pub fn generate_websocket_client() -> websocket::ClientImpl {
+    websocket::ClientImpl::new(format!("ws://{}/app", websocket_server_addr()))
+}
+
+pub fn generate_tcp_client() -> tcp::ClientImpl {
+    tcp::ClientImpl::new(tcp_server_addr())
+}
+
+pub async fn setup() {
+    if boot_server() {
+        run_server();
+        // TODO: 雑にスリープ。実際はReadiness Probe的なチェックが必要かも。
+        sleep(Duration::from_millis(1000)).await;
+    }
+}
+
+pub async fn teardown() {
+    // TODO: shutdown
+}
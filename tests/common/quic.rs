@@ -0,0 +1,153 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use futures::SinkExt;
+use prost::Message as _;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls;
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+use super::*;
+
+pub struct ClientImpl {
+    tx: mpsc::UnboundedSender<protobuf::app::ClientMessage>,
+    rx: mpsc::UnboundedReceiver<protobuf::app::ServerMessage>,
+}
+
+// TODO: close, 雑なunwrap
+impl ClientImpl {
+    pub fn new(addr: SocketAddr) -> Self {
+        let (input_tx, mut input_rx) = mpsc::unbounded_channel::<protobuf::app::ClientMessage>();
+        let (output_tx, output_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in network_protocol::tcp::load_certs(cert_file_path()).unwrap() {
+                roots.add(&cert).unwrap();
+            }
+            let mut tls_config = rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            tls_config.alpn_protocols = vec![network_protocol::quic::ALPN.to_vec()];
+            let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+            endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(tls_config)));
+
+            let connection = endpoint.connect(addr, "localhost").unwrap().await.unwrap();
+            let (send, recv) = connection.open_bi().await.unwrap();
+            let mut framed_reader = FramedRead::new(recv, LengthDelimitedCodec::new());
+            let mut framed_writer = FramedWrite::new(send, LengthDelimitedCodec::new());
+            loop {
+                tokio::select! {
+                    input_msg = input_rx.recv() => {
+                        if let Some(input_msg) = input_msg {
+                            match &input_msg.data {
+                                Some(protobuf::app::client_message::Data::SendMessage(msg))
+                                    if msg.delivery == protobuf::app::Delivery::Unreliable as i32 =>
+                                {
+                                    connection.send_datagram(input_msg.encode_to_vec().into()).unwrap();
+                                }
+                                _ => {
+                                    framed_writer.send(input_msg.encode_to_vec().into()).await.unwrap();
+                                }
+                            }
+                        }
+                    }
+                    output_msg = framed_reader.next() => {
+                        match output_msg {
+                            Some(output_msg) => {
+                                let message: Result<protobuf::app::ServerMessage, prost::DecodeError> = prost::Message::decode(output_msg.unwrap());
+                                output_tx.send(message.unwrap()).unwrap();
+                            }
+                            // サーバが切断した。
+                            None => return,
+                        }
+                    }
+                    Ok(datagram) = connection.read_datagram() => {
+                        let message: Result<protobuf::app::ServerMessage, prost::DecodeError> = prost::Message::decode(datagram);
+                        output_tx.send(message.unwrap()).unwrap();
+                    }
+                }
+            }
+        });
+
+        Self {
+            tx: input_tx,
+            rx: output_rx,
+        }
+    }
+}
+
+#[async_trait]
+impl Client for ClientImpl {
+    fn generate() -> Self {
+        Self::new(client_addr())
+    }
+
+    fn send(
+        &self,
+        message: protobuf::app::ClientMessage,
+    ) -> Result<(), mpsc::error::SendError<protobuf::app::ClientMessage>> {
+        self.tx.send(message)
+    }
+
+    async fn recv(&mut self) -> Option<protobuf::app::ServerMessage> {
+        self.rx.recv().await
+    }
+}
+
+fn run_server() {
+    // QUICはTLSが必須なので、テスト用の自己署名証明書を生成しておく。
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    std::fs::write(cert_file_path(), cert.serialize_pem().unwrap()).unwrap();
+    std::fs::write(key_file_path(), cert.serialize_private_key_pem()).unwrap();
+
+    let mut config = (*default_config()).clone();
+    config.tls.cert_file_path = cert_file_path().to_string_lossy().to_string();
+    config.tls.key_file_path = key_file_path().to_string_lossy().to_string();
+    tokio::spawn(async move {
+        network_protocol::server::run::<network_protocol::quic::ServerImpl, _, _>(
+            server_addr(),
+            Arc::new(config),
+            Arc::new(actor::Registries::new()),
+            network_protocol::server::wait_signal(),
+        )
+        .await
+        .unwrap();
+    });
+}
+
+fn server_addr() -> SocketAddr {
+    env::var("GS_TEST_QUIC_SERVER_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:8004".to_string())
+        .parse::<SocketAddr>()
+        .unwrap()
+}
+
+fn client_addr() -> SocketAddr {
+    server_addr()
+}
+
+fn cert_file_path() -> PathBuf {
+    match env::var("GS_TEST_QUIC_CERT_FILE_PATH") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => env::temp_dir().join("mini-realtime-server-quic-test.crt"),
+    }
+}
+
+fn key_file_path() -> PathBuf {
+    env::temp_dir().join("mini-realtime-server-quic-test.key")
+}
+
+pub async fn setup() {
+    if boot_server() {
+        run_server();
+        // TODO: 雑にスリープ。実際はReadiness Probe的なチェックが必要かも。
+        sleep(Duration::from_millis(1000)).await;
+    }
+}
+
+pub async fn teardown() {
+    // TODO: shutdown
+}
@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+
+use prost::Message as _;
 use uuid::Uuid;
 
 extern crate mini_realtime_server;
@@ -17,12 +20,118 @@ where
         &mut C::generate(),
     )
     .await;
+    e2e_join_random_room(&mut C::generate(), &mut C::generate(), &mut C::generate()).await;
+    e2e_reserve_seats(
+        generate_random_id(),
+        &mut C::generate(),
+        &mut C::generate(),
+        &mut C::generate(),
+    )
+    .await;
+    e2e_party(generate_random_id(), &mut C::generate(), &mut C::generate()).await;
+    e2e_presence_and_direct_message(generate_random_id(), &mut C::generate(), &mut C::generate())
+        .await;
+    e2e_channel(generate_random_id(), &mut C::generate(), &mut C::generate()).await;
+    e2e_compression(&mut C::generate(), &mut C::generate()).await;
+}
+
+/// 上限を超える大きさのメッセージを送ると、MessageTooLargeを通知されて切断される。
+/// フレームの長さで判定するトランスポート(TCP、WebSocket)で使う。
+pub async fn run_e2e_too_large_message<C>()
+where
+    C: Client,
+{
+    let mut p1 = C::generate();
+    login(&mut p1, &generate_random_id()).await;
+
+    p1.send(protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::SendMessage(
+            protobuf::app::SendMessage {
+                room_id: generate_random_id(),
+                body: vec![0; default_config().limits.max_frame_size],
+                ..Default::default()
+            },
+        )),
+    })
+    .unwrap();
+
+    let data = p1.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::ErrorNotification(notification) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::MessageTooLarge as i32,
+            notification.error.unwrap().code
+        );
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+    assert!(p1.recv().await.is_none());
+}
+
+/// 異なるプロトコルのクライアント同士で同じRoomに参加する。
+pub async fn run_e2e_mixed(p1: &mut impl Client, p2: &mut impl Client) {
+    e2e_normal(generate_random_id(), p1, p2).await;
 }
 
 fn generate_random_id() -> String {
     Uuid::new_v4().to_string()
 }
 
+/// 複数のプレイヤーが同時に動く場合は通知の順序が決まらないので、条件に一致するメッセージまで読み飛ばす。
+async fn recv_until<F>(p: &mut impl Client, f: F) -> protobuf::app::server_message::Data
+where
+    F: Fn(&protobuf::app::server_message::Data) -> bool,
+{
+    loop {
+        let data = p.recv().await.unwrap().data.unwrap();
+        if f(&data) {
+            return data;
+        }
+    }
+}
+
+async fn login(p: &mut impl Client, player_id: &str) {
+    login_with_compression(p, player_id, protobuf::app::Compression::Uncompressed).await;
+}
+
+async fn login_with_compression(
+    p: &mut impl Client,
+    player_id: &str,
+    compression: protobuf::app::Compression,
+) {
+    p.send(protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::LoginRequest(
+            protobuf::app::LoginRequest {
+                player_id: player_id.to_string(),
+                compression: compression as i32,
+                protocol_version: protobuf::PROTOCOL_VERSION,
+                capabilities: vec![protobuf::app::Capability::UnreliableDelivery as i32],
+                auth_config: Some(protobuf::app::login_request::AuthConfig::Bearer(
+                    protobuf::app::AuthConfigBearer {
+                        token: "bearer".to_string(),
+                    },
+                )),
+            },
+        )),
+    })
+    .unwrap();
+
+    let data = p.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::LoginResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+        assert_eq!(compression as i32, res.compression);
+        assert_eq!(protobuf::PROTOCOL_VERSION, res.protocol_version);
+        assert_eq!(
+            vec![protobuf::app::Capability::UnreliableDelivery as i32],
+            res.capabilities
+        );
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+}
+
 async fn e2e_normal(room_id: String, p1: &mut impl Client, p2: &mut impl Client) {
     let p1_id = generate_random_id();
     let p2_id = generate_random_id();
@@ -30,6 +139,9 @@ async fn e2e_normal(room_id: String, p1: &mut impl Client, p2: &mut impl Client)
         data: Some(protobuf::app::client_message::Data::LoginRequest(
             protobuf::app::LoginRequest {
                 player_id: p1_id.clone(),
+                compression: protobuf::app::Compression::Uncompressed as i32,
+                protocol_version: protobuf::PROTOCOL_VERSION,
+                capabilities: vec![protobuf::app::Capability::UnreliableDelivery as i32],
                 auth_config: Some(protobuf::app::login_request::AuthConfig::Bearer(
                     protobuf::app::AuthConfigBearer {
                         token: "bearer".to_string(),
@@ -57,6 +169,7 @@ async fn e2e_normal(room_id: String, p1: &mut impl Client, p2: &mut impl Client)
                 room_config: Some(protobuf::app::RoomConfig {
                     max_players: 2,
                 }),
+                room_properties: HashMap::new(),
             },
         )),
     })
@@ -77,6 +190,9 @@ async fn e2e_normal(room_id: String, p1: &mut impl Client, p2: &mut impl Client)
         data: Some(protobuf::app::client_message::Data::LoginRequest(
             protobuf::app::LoginRequest {
                 player_id: p2_id.clone(),
+                compression: protobuf::app::Compression::Uncompressed as i32,
+                protocol_version: protobuf::PROTOCOL_VERSION,
+                capabilities: vec![protobuf::app::Capability::UnreliableDelivery as i32],
                 auth_config: Some(protobuf::app::login_request::AuthConfig::Bearer(
                     protobuf::app::AuthConfigBearer {
                         token: "bearer".to_string(),
@@ -104,6 +220,7 @@ async fn e2e_normal(room_id: String, p1: &mut impl Client, p2: &mut impl Client)
                 room_config: Some(protobuf::app::RoomConfig {
                     max_players: 2,
                 }),
+                room_properties: HashMap::new(),
             },
         )),
     })
@@ -135,6 +252,7 @@ async fn e2e_normal(room_id: String, p1: &mut impl Client, p2: &mut impl Client)
                 room_id: room_id.clone(),
                 target_ids: Vec::new(),
                 body: p1_broadcast_msg.clone(),
+                delivery: protobuf::app::Delivery::Reliable as i32,
             },
         )),
     })
@@ -165,6 +283,7 @@ async fn e2e_normal(room_id: String, p1: &mut impl Client, p2: &mut impl Client)
                 room_id: room_id.clone(),
                 target_ids: vec![p1_id.clone()],
                 body: p2_to_p1_msg.clone(),
+                delivery: protobuf::app::Delivery::Unreliable as i32,
             },
         )),
     })
@@ -175,6 +294,10 @@ async fn e2e_normal(room_id: String, p1: &mut impl Client, p2: &mut impl Client)
         assert_eq!(room_id, notification.room_id);
         assert_eq!(p2_id, notification.sender_id);
         assert_eq!(p2_to_p1_msg, notification.body);
+        assert_eq!(
+            protobuf::app::Delivery::Unreliable as i32,
+            notification.delivery
+        );
     } else {
         panic!("Unexpected message. {:?}", data);
     }
@@ -188,6 +311,7 @@ async fn e2e_normal(room_id: String, p1: &mut impl Client, p2: &mut impl Client)
                 room_id: room_id.clone(),
                 target_ids: Vec::new(),
                 body: p2_broadcast_msg.clone(),
+                delivery: protobuf::app::Delivery::Reliable as i32,
             },
         )),
     })
@@ -267,6 +391,9 @@ async fn e2e_double_login(p1: &mut impl Client, p2: &mut impl Client) {
         data: Some(protobuf::app::client_message::Data::LoginRequest(
             protobuf::app::LoginRequest {
                 player_id: p1_id.clone(),
+                compression: protobuf::app::Compression::Uncompressed as i32,
+                protocol_version: protobuf::PROTOCOL_VERSION,
+                capabilities: vec![protobuf::app::Capability::UnreliableDelivery as i32],
                 auth_config: Some(protobuf::app::login_request::AuthConfig::Bearer(
                     protobuf::app::AuthConfigBearer {
                         token: "bearer".to_string(),
@@ -291,6 +418,9 @@ async fn e2e_double_login(p1: &mut impl Client, p2: &mut impl Client) {
         data: Some(protobuf::app::client_message::Data::LoginRequest(
             protobuf::app::LoginRequest {
                 player_id: p2_id.clone(),
+                compression: protobuf::app::Compression::Uncompressed as i32,
+                protocol_version: protobuf::PROTOCOL_VERSION,
+                capabilities: vec![protobuf::app::Capability::UnreliableDelivery as i32],
                 auth_config: Some(protobuf::app::login_request::AuthConfig::Bearer(
                     protobuf::app::AuthConfigBearer {
                         token: "bearer".to_string(),
@@ -324,6 +454,9 @@ async fn e2e_multi_room(
         data: Some(protobuf::app::client_message::Data::LoginRequest(
             protobuf::app::LoginRequest {
                 player_id: p1_id.clone(),
+                compression: protobuf::app::Compression::Uncompressed as i32,
+                protocol_version: protobuf::PROTOCOL_VERSION,
+                capabilities: vec![protobuf::app::Capability::UnreliableDelivery as i32],
                 auth_config: Some(protobuf::app::login_request::AuthConfig::Bearer(
                     protobuf::app::AuthConfigBearer {
                         token: "bearer".to_string(),
@@ -351,6 +484,7 @@ async fn e2e_multi_room(
                 room_config: Some(protobuf::app::RoomConfig {
                     max_players: 2,
                 }),
+                room_properties: HashMap::new(),
             },
         )),
     })
@@ -374,6 +508,7 @@ async fn e2e_multi_room(
                 room_config: Some(protobuf::app::RoomConfig {
                     max_players: 2,
                 }),
+                room_properties: HashMap::new(),
             },
         )),
     })
@@ -394,6 +529,9 @@ async fn e2e_multi_room(
         data: Some(protobuf::app::client_message::Data::LoginRequest(
             protobuf::app::LoginRequest {
                 player_id: p2_id.clone(),
+                compression: protobuf::app::Compression::Uncompressed as i32,
+                protocol_version: protobuf::PROTOCOL_VERSION,
+                capabilities: vec![protobuf::app::Capability::UnreliableDelivery as i32],
                 auth_config: Some(protobuf::app::login_request::AuthConfig::Bearer(
                     protobuf::app::AuthConfigBearer {
                         token: "bearer".to_string(),
@@ -421,6 +559,7 @@ async fn e2e_multi_room(
                 room_config: Some(protobuf::app::RoomConfig {
                     max_players: 2,
                 }),
+                room_properties: HashMap::new(),
             },
         )),
     })
@@ -444,6 +583,7 @@ async fn e2e_multi_room(
                 room_config: Some(protobuf::app::RoomConfig {
                     max_players: 2,
                 }),
+                room_properties: HashMap::new(),
             },
         )),
     })
@@ -484,6 +624,7 @@ async fn e2e_multi_room(
                 room_id: room_id_1.clone(),
                 target_ids: Vec::new(),
                 body: p1_broadcast_msg.clone(),
+                delivery: protobuf::app::Delivery::Reliable as i32,
             },
         )),
     })
@@ -515,6 +656,7 @@ async fn e2e_multi_room(
                 room_id: room_id_2.clone(),
                 target_ids: Vec::new(),
                 body: p1_broadcast_msg.clone(),
+                delivery: protobuf::app::Delivery::Reliable as i32,
             },
         )),
     })
@@ -575,6 +717,7 @@ async fn e2e_multi_room(
                 room_id: room_id_2.clone(),
                 target_ids: Vec::new(),
                 body: p1_broadcast_msg.clone(),
+                delivery: protobuf::app::Delivery::Reliable as i32,
             },
         )),
     })
@@ -667,3 +810,660 @@ async fn e2e_multi_room(
         panic!("Unexpected message. {:?}", data);
     }
 }
+
+async fn e2e_join_random_room(p1: &mut impl Client, p2: &mut impl Client, p3: &mut impl Client) {
+    // 他のテストのRoomと混ざらないように、プロパティでこのテスト専用のRoomに絞り込む。
+    let room_properties = HashMap::from([("match".to_string(), generate_random_id())]);
+    let join_random_room = protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::JoinRandomRoomRequest(
+            protobuf::app::JoinRandomRoomRequest {
+                room_config: Some(protobuf::app::RoomConfig { max_players: 2 }),
+                room_properties: room_properties.clone(),
+            },
+        )),
+    };
+
+    login(p1, &generate_random_id()).await;
+    p1.send(join_random_room.clone()).unwrap();
+    let data = p1.recv().await.unwrap().data.unwrap();
+    let room_id = if let protobuf::app::server_message::Data::JoinResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+        assert_eq!(room_properties, res.room_properties);
+        res.room_id
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    };
+
+    // 空きのある既存のRoomに入る。
+    login(p2, &generate_random_id()).await;
+    p2.send(join_random_room.clone()).unwrap();
+    let data = p2.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::JoinResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+        assert_eq!(room_id, res.room_id);
+        assert_eq!(2, res.current_players.len());
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    // 満員なので新しいRoomが作成される。
+    login(p3, &generate_random_id()).await;
+    p3.send(join_random_room).unwrap();
+    let data = p3.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::JoinResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+        assert_ne!(room_id, res.room_id);
+        assert_eq!(1, res.current_players.len());
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+}
+
+async fn e2e_reserve_seats<C: Client>(room_id: String, p1: &mut C, p2: &mut C, p3: &mut C) {
+    let p1_id = generate_random_id();
+    let p2_id = generate_random_id();
+    let p3_id = generate_random_id();
+    let absent_player_id = generate_random_id();
+    let room_config = protobuf::app::RoomConfig { max_players: 3 };
+    let join_request = protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::JoinRequest(
+            protobuf::app::JoinRequest {
+                room_id: room_id.clone(),
+                room_config: Some(room_config.clone()),
+                room_properties: HashMap::new(),
+            },
+        )),
+    };
+    let reserve_seats_request = |player_id: &str, timeout_ms: u32| protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::ReserveSeatsRequest(
+            protobuf::app::ReserveSeatsRequest {
+                room_id: room_id.clone(),
+                room_config: Some(room_config.clone()),
+                room_properties: HashMap::new(),
+                player_ids: vec![player_id.to_string()],
+                timeout_ms,
+            },
+        )),
+    };
+
+    login(p1, &p1_id).await;
+    login(p2, &p2_id).await;
+    login(p3, &p3_id).await;
+
+    p1.send(reserve_seats_request(&p2_id, 0)).unwrap();
+    let data = p1.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::ReserveSeatsResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+        assert_eq!(room_id, res.room_id);
+        assert_eq!(vec![p2_id.clone()], res.player_ids);
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    p1.send(join_request.clone()).unwrap();
+    let data = p1.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::JoinResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    // 参加しないプレイヤーの席を短い期限で予約して満員にする。
+    p1.send(reserve_seats_request(&absent_player_id, 500)).unwrap();
+    let data = p1.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::ReserveSeatsResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    // 予約されていないプレイヤーは予約済みの席にJoinできない。
+    p3.send(join_request.clone()).unwrap();
+    let data = p3.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::JoinResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::RoomIsFull as i32,
+            res.error.unwrap().code
+        );
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    p2.send(join_request.clone()).unwrap();
+    let data = p2.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::JoinResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    let data = p1.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::JoinNotification(notification) = data {
+        assert_eq!(p2_id, notification.player_id);
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    // 期限切れで解放された予約がRoom内に通知される。
+    for p in [&mut *p1, &mut *p2] {
+        let data = p.recv().await.unwrap().data.unwrap();
+        if let protobuf::app::server_message::Data::ReservationExpiredNotification(notification) =
+            data
+        {
+            assert_eq!(room_id, notification.room_id);
+            assert_eq!(vec![absent_player_id.clone()], notification.player_ids);
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+    }
+
+    p3.send(join_request).unwrap();
+    let data = p3.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::JoinResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+        assert_eq!(3, res.current_players.len());
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+}
+
+async fn e2e_party<C: Client>(room_id: String, p1: &mut C, p2: &mut C) {
+    let p1_id = generate_random_id();
+    let p2_id = generate_random_id();
+    login(p1, &p1_id).await;
+    login(p2, &p2_id).await;
+
+    p1.send(protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::CreatePartyRequest(
+            protobuf::app::CreatePartyRequest {},
+        )),
+    })
+    .unwrap();
+    let data = p1.recv().await.unwrap().data.unwrap();
+    let party_id = if let protobuf::app::server_message::Data::CreatePartyResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+        res.party_id
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    };
+
+    // 招待されていないパーティには参加できない。
+    let join_party_request = protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::JoinPartyRequest(
+            protobuf::app::JoinPartyRequest {
+                party_id: party_id.clone(),
+            },
+        )),
+    };
+    p2.send(join_party_request.clone()).unwrap();
+    let data = p2.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::JoinPartyResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::NotInvitedToTheParty as i32,
+            res.error.unwrap().code
+        );
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    p1.send(protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::InvitePartyRequest(
+            protobuf::app::InvitePartyRequest {
+                player_id: p2_id.clone(),
+            },
+        )),
+    })
+    .unwrap();
+    let data = p1.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::InvitePartyResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+        assert_eq!(p2_id, res.player_id);
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+    let data = p2.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::PartyInviteNotification(notification) = data {
+        assert_eq!(party_id, notification.party_id);
+        assert_eq!(p1_id, notification.inviter_id);
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    p2.send(join_party_request).unwrap();
+    let data = p2.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::JoinPartyResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+        assert_eq!(p1_id, res.leader_id);
+        assert_eq!(vec![p1_id.clone(), p2_id.clone()], res.member_ids);
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+    let data = p1.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::PartyJoinNotification(notification) = data {
+        assert_eq!(p2_id, notification.player_id);
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    let party_msg = "party_msg".as_bytes().to_vec();
+    p2.send(protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::PartyMessageRequest(
+            protobuf::app::PartyMessageRequest {
+                body: party_msg.clone(),
+            },
+        )),
+    })
+    .unwrap();
+    for p in [&mut *p1, &mut *p2] {
+        let data = p.recv().await.unwrap().data.unwrap();
+        if let protobuf::app::server_message::Data::PartyMessageNotification(notification) = data {
+            assert_eq!(p2_id, notification.sender_id);
+            assert_eq!(party_msg, notification.body);
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+    }
+
+    let party_join_room_request = |room_id: &str| protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::PartyJoinRoomRequest(
+            protobuf::app::PartyJoinRoomRequest {
+                room_id: room_id.to_string(),
+                room_config: Some(protobuf::app::RoomConfig { max_players: 2 }),
+                room_properties: HashMap::from([("party".to_string(), party_id.clone())]),
+            },
+        )),
+    };
+
+    // リーダー以外はRoomへの移動を指示できない。
+    p2.send(party_join_room_request(&room_id)).unwrap();
+    let data = p2.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::PartyJoinRoomResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::NotPartyLeader as i32,
+            res.error.unwrap().code
+        );
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    // 全員がJoinできてから、移動を指示したリーダーに結果が届く。
+    p1.send(party_join_room_request(&room_id)).unwrap();
+    for p in [&mut *p1, &mut *p2] {
+        let data = recv_until(p, |data| {
+            matches!(data, protobuf::app::server_message::Data::JoinResponse(_))
+        })
+        .await;
+        if let protobuf::app::server_message::Data::JoinResponse(res) = data {
+            assert_eq!(
+                protobuf::app::ErrorCode::None as i32,
+                res.error.unwrap().code
+            );
+            assert_eq!(room_id, res.room_id);
+        }
+    }
+    let data = recv_until(p1, |data| {
+        matches!(
+            data,
+            protobuf::app::server_message::Data::PartyJoinRoomResponse(_)
+        )
+    })
+    .await;
+    if let protobuf::app::server_message::Data::PartyJoinRoomResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+        assert_eq!(room_id, res.room_id);
+    }
+
+    // 別のRoomへ移動すると、全員がJoinできてから前のRoomを抜ける。
+    p1.send(party_join_room_request("")).unwrap();
+    let mut next_room_id = None;
+    for p in [&mut *p1, &mut *p2] {
+        let data = recv_until(p, |data| {
+            matches!(data, protobuf::app::server_message::Data::JoinResponse(_))
+        })
+        .await;
+        if let protobuf::app::server_message::Data::JoinResponse(res) = data {
+            assert_eq!(
+                protobuf::app::ErrorCode::None as i32,
+                res.error.unwrap().code
+            );
+            assert_ne!(room_id, res.room_id);
+            assert_eq!(
+                &res.room_id,
+                next_room_id.get_or_insert_with(|| res.room_id.clone())
+            );
+        }
+        let data = recv_until(p, |data| {
+            matches!(data, protobuf::app::server_message::Data::LeaveResponse(_))
+        })
+        .await;
+        if let protobuf::app::server_message::Data::LeaveResponse(res) = data {
+            assert_eq!(room_id, res.room_id);
+        }
+    }
+
+    p1.send(protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::LeavePartyRequest(
+            protobuf::app::LeavePartyRequest {},
+        )),
+    })
+    .unwrap();
+    let data = recv_until(p1, |data| {
+        matches!(
+            data,
+            protobuf::app::server_message::Data::LeavePartyResponse(_)
+        )
+    })
+    .await;
+    if let protobuf::app::server_message::Data::LeavePartyResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+    }
+    let data = recv_until(p2, |data| {
+        matches!(
+            data,
+            protobuf::app::server_message::Data::PartyLeaveNotification(_)
+        )
+    })
+    .await;
+    if let protobuf::app::server_message::Data::PartyLeaveNotification(notification) = data {
+        assert_eq!(p1_id, notification.player_id);
+        assert_eq!(p2_id, notification.leader_id);
+    }
+}
+
+async fn e2e_presence_and_direct_message(
+    room_id: String,
+    p1: &mut impl Client,
+    p2: &mut impl Client,
+) {
+    let p1_id = generate_random_id();
+    let p2_id = generate_random_id();
+    login(p1, &p1_id).await;
+
+    p1.send(protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::PresenceSubscribeRequest(
+            protobuf::app::PresenceSubscribeRequest {
+                player_ids: vec![p2_id.clone()],
+            },
+        )),
+    })
+    .unwrap();
+    let data = p1.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::PresenceSubscribeResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+        assert_eq!(p2_id, res.presences[0].player_id);
+        assert_eq!(
+            protobuf::app::PresenceStatus::Offline as i32,
+            res.presences[0].status
+        );
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    login(p2, &p2_id).await;
+    let data = p1.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::PresenceNotification(notification) = data {
+        let presence = notification.presence.unwrap();
+        assert_eq!(p2_id, presence.player_id);
+        assert_eq!(protobuf::app::PresenceStatus::Online as i32, presence.status);
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    let direct_msg = "direct_msg".as_bytes().to_vec();
+    p1.send(protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::DirectMessageRequest(
+            protobuf::app::DirectMessageRequest {
+                target_id: p2_id.clone(),
+                body: direct_msg.clone(),
+            },
+        )),
+    })
+    .unwrap();
+    let data = p1.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::DirectMessageResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+    let data = p2.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::DirectMessageNotification(notification) = data {
+        assert_eq!(p1_id, notification.sender_id);
+        assert_eq!(direct_msg, notification.body);
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    // ログインしていないプレイヤーには送れない。
+    p1.send(protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::DirectMessageRequest(
+            protobuf::app::DirectMessageRequest {
+                target_id: generate_random_id(),
+                body: direct_msg,
+            },
+        )),
+    })
+    .unwrap();
+    let data = p1.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::DirectMessageResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::PlayerNotFound as i32,
+            res.error.unwrap().code
+        );
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    p2.send(protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::JoinRequest(
+            protobuf::app::JoinRequest {
+                room_id: room_id.clone(),
+                room_config: Some(protobuf::app::RoomConfig { max_players: 2 }),
+                room_properties: HashMap::new(),
+            },
+        )),
+    })
+    .unwrap();
+    let data = p1.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::PresenceNotification(notification) = data {
+        let presence = notification.presence.unwrap();
+        assert_eq!(p2_id, presence.player_id);
+        assert_eq!(protobuf::app::PresenceStatus::InRoom as i32, presence.status);
+        assert_eq!(vec![room_id], presence.room_ids);
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+}
+
+async fn e2e_channel<C: Client>(channel: String, p1: &mut C, p2: &mut C) {
+    let p1_id = generate_random_id();
+    let p2_id = generate_random_id();
+    login(p1, &p1_id).await;
+    login(p2, &p2_id).await;
+
+    for p in [&mut *p1, &mut *p2] {
+        p.send(protobuf::app::ClientMessage {
+            data: Some(protobuf::app::client_message::Data::ChannelSubscribeRequest(
+                protobuf::app::ChannelSubscribeRequest {
+                    channel: channel.clone(),
+                },
+            )),
+        })
+        .unwrap();
+        let data = p.recv().await.unwrap().data.unwrap();
+        if let protobuf::app::server_message::Data::ChannelSubscribeResponse(res) = data {
+            assert_eq!(channel, res.channel);
+            assert_eq!(
+                protobuf::app::ErrorCode::None as i32,
+                res.error.unwrap().code
+            );
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+    }
+
+    // 送信者自身にも届く。
+    let msg = "channel_msg".as_bytes().to_vec();
+    let publish = protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::ChannelPublishRequest(
+            protobuf::app::ChannelPublishRequest {
+                channel: channel.clone(),
+                body: msg.clone(),
+            },
+        )),
+    };
+    p1.send(publish.clone()).unwrap();
+    for p in [&mut *p1, &mut *p2] {
+        let data = p.recv().await.unwrap().data.unwrap();
+        if let protobuf::app::server_message::Data::ChannelMessageNotification(notification) = data
+        {
+            assert_eq!(channel, notification.channel);
+            assert_eq!(p1_id, notification.sender_id);
+            assert_eq!(msg, notification.body);
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+    }
+
+    let unsubscribe = protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::ChannelUnsubscribeRequest(
+            protobuf::app::ChannelUnsubscribeRequest {
+                channel: channel.clone(),
+            },
+        )),
+    };
+    for code in [
+        protobuf::app::ErrorCode::None,
+        protobuf::app::ErrorCode::NotSubscribedToTheChannel,
+    ] {
+        p2.send(unsubscribe.clone()).unwrap();
+        let data = p2.recv().await.unwrap().data.unwrap();
+        if let protobuf::app::server_message::Data::ChannelUnsubscribeResponse(res) = data {
+            assert_eq!(code as i32, res.error.unwrap().code);
+        } else {
+            panic!("Unexpected message. {:?}", data);
+        }
+    }
+
+    // 購読していないチャネルには送信できないので、p2の送信は誰にも届かない。
+    p2.send(publish.clone()).unwrap();
+    p1.send(publish).unwrap();
+    let data = p1.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::ChannelMessageNotification(notification) = data {
+        assert_eq!(p1_id, notification.sender_id);
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+}
+
+async fn e2e_compression(p1: &mut impl Client, p2: &mut impl Client) {
+    let p1_id = generate_random_id();
+    let p2_id = generate_random_id();
+    login_with_compression(p1, &p1_id, protobuf::app::Compression::Zstd).await;
+    login_with_compression(p2, &p2_id, protobuf::app::Compression::Deflate).await;
+
+    // クライアントからも圧縮して送れる。
+    let direct_msg = vec![b'a'; 8 * 1024];
+    let request = protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::DirectMessageRequest(
+            protobuf::app::DirectMessageRequest {
+                target_id: p1_id.clone(),
+                body: direct_msg.clone(),
+            },
+        )),
+    };
+    let data = network_protocol::compression::compress(
+        protobuf::app::Compression::Deflate,
+        &request.encode_to_vec(),
+    )
+    .unwrap();
+    p2.send(protobuf::app::ClientMessage {
+        data: Some(protobuf::app::client_message::Data::CompressedMessage(
+            protobuf::app::CompressedMessage {
+                compression: protobuf::app::Compression::Deflate as i32,
+                data,
+            },
+        )),
+    })
+    .unwrap();
+
+    // 閾値未満のレスポンスは圧縮されない。
+    let data = p2.recv().await.unwrap().data.unwrap();
+    if let protobuf::app::server_message::Data::DirectMessageResponse(res) = data {
+        assert_eq!(
+            protobuf::app::ErrorCode::None as i32,
+            res.error.unwrap().code
+        );
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+
+    let data = p1.recv().await.unwrap().data.unwrap();
+    let message = if let protobuf::app::server_message::Data::CompressedMessage(compressed) = data {
+        assert_eq!(
+            protobuf::app::Compression::Zstd as i32,
+            compressed.compression
+        );
+        assert!(compressed.data.len() < direct_msg.len());
+        let data = network_protocol::compression::decompress(
+            protobuf::app::Compression::Zstd,
+            &compressed.data,
+            usize::MAX,
+        )
+        .unwrap();
+        protobuf::app::ServerMessage::decode(data.as_slice()).unwrap()
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    };
+    let data = message.data.unwrap();
+    if let protobuf::app::server_message::Data::DirectMessageNotification(notification) = data {
+        assert_eq!(p2_id, notification.sender_id);
+        assert_eq!(direct_msg, notification.body);
+    } else {
+        panic!("Unexpected message. {:?}", data);
+    }
+}
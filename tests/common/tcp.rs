@@ -1,59 +1,31 @@
 use std::net::SocketAddr;
 
 use async_trait::async_trait;
-use futures::SinkExt;
-use prost::Message as _;
-use tokio::net::TcpStream;
+use futures::Future;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc;
-use tokio_stream::StreamExt;
-use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
 use super::*;
 
-pub struct ClientImpl {
-    tx: mpsc::UnboundedSender<protobuf::app::ClientMessage>,
-    rx: mpsc::UnboundedReceiver<protobuf::app::ServerMessage>,
-}
+pub struct ClientImpl(client::Client);
 
-// TODO: close, 雑なunwrap
 impl ClientImpl {
     pub fn new(addr: SocketAddr) -> Self {
-        let (input_tx, mut input_rx) = mpsc::unbounded_channel::<protobuf::app::ClientMessage>();
-        let (output_tx, output_rx) = mpsc::unbounded_channel();
-        tokio::spawn(async move {
-            let conn = TcpStream::connect(addr).await.unwrap();
-            conn.set_nodelay(true).unwrap();
-            let (reader, writer) = tokio::io::split(conn);
-            let mut framed_reader = FramedRead::new(reader, LengthDelimitedCodec::new());
-            let mut framed_writer = FramedWrite::new(writer, LengthDelimitedCodec::new());
-            loop {
-                tokio::select! {
-                    input_msg = input_rx.recv() => {
-                        if let Some(input_msg) = input_msg {
-                            framed_writer.send(input_msg.encode_to_vec().into()).await.unwrap();
-                        }
-                    }
-                    output_msg = framed_reader.next() => {
-                        if let Some(output_msg) = output_msg {
-                            match output_msg {
-                                Ok(output_msg) => {
-                                    let message: Result<protobuf::app::ServerMessage, prost::DecodeError> = prost::Message::decode(output_msg.as_ref());
-                                    output_tx.send(message.unwrap()).unwrap();
-                                }
-                                Err(err) => {
-                                    panic!("Unexpected error: {:?}", err);
-                                }
-                            };
-                        }
-                    }
-                }
-            }
-        });
-
-        Self {
-            tx: input_tx,
-            rx: output_rx,
-        }
+        Self::with_codec(addr, network_protocol::codec::Codec::Protobuf)
+    }
+
+    /// protobuf以外の場合は、接続直後にコーデック選択フレームを送る。
+    pub fn with_codec(addr: SocketAddr, codec: network_protocol::codec::Codec) -> Self {
+        Self(client::tcp::connect(addr, codec))
+    }
+
+    /// `connect`で接続したストリームで、TCPと同じフレームを使ってやり取りする。
+    pub fn spawn<S, Fut>(connect: Fut, codec: network_protocol::codec::Codec) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+        Fut: Future<Output = anyhow::Result<S>> + Send + 'static,
+    {
+        Self(client::tcp::spawn(connect, codec))
     }
 }
 
@@ -67,11 +39,35 @@ impl Client for ClientImpl {
         &self,
         message: protobuf::app::ClientMessage,
     ) -> Result<(), mpsc::error::SendError<protobuf::app::ClientMessage>> {
-        self.tx.send(message)
+        self.0.send(message)
+    }
+
+    async fn recv(&mut self) -> Option<protobuf::app::ServerMessage> {
+        self.0.recv().await
+    }
+}
+
+/// MessagePackでやり取りするクライアント。
+pub struct MessagePackClientImpl(ClientImpl);
+
+#[async_trait]
+impl Client for MessagePackClientImpl {
+    fn generate() -> Self {
+        Self(ClientImpl::with_codec(
+            client_addr(),
+            network_protocol::codec::Codec::MessagePack,
+        ))
+    }
+
+    fn send(
+        &self,
+        message: protobuf::app::ClientMessage,
+    ) -> Result<(), mpsc::error::SendError<protobuf::app::ClientMessage>> {
+        self.0.send(message)
     }
 
     async fn recv(&mut self) -> Option<protobuf::app::ServerMessage> {
-        self.rx.recv().await
+        self.0.recv().await
     }
 }
 
@@ -80,6 +76,7 @@ fn run_server() {
         network_protocol::server::run::<network_protocol::tcp::ServerImpl, _, _>(
             server_addr(),
             default_config(),
+            Arc::new(actor::Registries::new()),
             network_protocol::server::wait_signal(),
         )
         .await
@@ -0,0 +1,164 @@
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use prost::Message as _;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use super::*;
+use network_protocol::udp::*;
+
+pub struct ClientImpl {
+    tx: mpsc::UnboundedSender<protobuf::app::ClientMessage>,
+    rx: mpsc::UnboundedReceiver<protobuf::app::ServerMessage>,
+}
+
+// TODO: close, 雑なunwrap
+impl ClientImpl {
+    pub fn new(addr: SocketAddr) -> Self {
+        let (input_tx, mut input_rx) = mpsc::unbounded_channel::<protobuf::app::ClientMessage>();
+        let (output_tx, output_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.connect(addr).await.unwrap();
+            let mut lanes = Lanes::new(
+                connect(&socket).await,
+                default_config().limits.max_frame_size,
+            );
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            let mut tick = tokio::time::interval(Duration::from_millis(50));
+            let mut last_sent_at = Instant::now();
+            loop {
+                tokio::select! {
+                    input_msg = input_rx.recv() => {
+                        if let Some(input_msg) = input_msg {
+                            let lane = match &input_msg.data {
+                                Some(protobuf::app::client_message::Data::SendMessage(msg))
+                                    if msg.delivery == protobuf::app::Delivery::Unreliable as i32 =>
+                                {
+                                    Lane::Unreliable
+                                }
+                                _ => Lane::Reliable,
+                            };
+                            let packet = lanes.send(lane, input_msg.encode_to_vec().into(), Instant::now()).unwrap();
+                            socket.send(&packet.encode()).await.unwrap();
+                            last_sent_at = Instant::now();
+                        }
+                    }
+                    len = socket.recv(&mut buf) => {
+                        let packet = Packet::decode(Bytes::copy_from_slice(&buf[..len.unwrap()])).unwrap();
+                        let received = lanes.receive(packet.body);
+                        if let Some(reply) = received.reply {
+                            socket.send(&reply.encode()).await.unwrap();
+                        }
+                        for payload in received.payloads {
+                            let message: Result<protobuf::app::ServerMessage, prost::DecodeError> = prost::Message::decode(payload);
+                            output_tx.send(message.unwrap()).unwrap();
+                        }
+                    }
+                    _ = tick.tick() => {
+                        for packet in lanes.retransmit(Instant::now()).unwrap() {
+                            socket.send(&packet.encode()).await.unwrap();
+                        }
+                        if last_sent_at.elapsed() > SESSION_TIMEOUT / 2 {
+                            let packet = Packet::new(lanes.session_id(), PacketBody::Heartbeat);
+                            socket.send(&packet.encode()).await.unwrap();
+                            last_sent_at = Instant::now();
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            tx: input_tx,
+            rx: output_rx,
+        }
+    }
+}
+
+/// Acceptが返ってくるまでConnectを送り続ける。Challengeが返ってきたらCookieを付けて送り直す。
+async fn connect(socket: &UdpSocket) -> SessionId {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    let mut cookie = Bytes::new();
+    loop {
+        socket
+            .send(
+                &Packet::new(
+                    0,
+                    PacketBody::Connect {
+                        cookie: cookie.clone(),
+                    },
+                )
+                .encode(),
+            )
+            .await
+            .unwrap();
+        let result = tokio::time::timeout(Duration::from_millis(200), socket.recv(&mut buf)).await;
+        if let Ok(len) = result {
+            let packet = Packet::decode(Bytes::copy_from_slice(&buf[..len.unwrap()])).unwrap();
+            match packet.body {
+                PacketBody::Challenge { cookie: challenge } => cookie = challenge,
+                PacketBody::Accept => return packet.session_id,
+                PacketBody::Disconnect => panic!("Connection rejected"),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Client for ClientImpl {
+    fn generate() -> Self {
+        Self::new(client_addr())
+    }
+
+    fn send(
+        &self,
+        message: protobuf::app::ClientMessage,
+    ) -> Result<(), mpsc::error::SendError<protobuf::app::ClientMessage>> {
+        self.tx.send(message)
+    }
+
+    async fn recv(&mut self) -> Option<protobuf::app::ServerMessage> {
+        self.rx.recv().await
+    }
+}
+
+fn run_server() {
+    tokio::spawn(async move {
+        network_protocol::server::run::<network_protocol::udp::ServerImpl, _, _>(
+            server_addr(),
+            default_config(),
+            Arc::new(actor::Registries::new()),
+            network_protocol::server::wait_signal(),
+        )
+        .await
+        .unwrap();
+    });
+}
+
+fn server_addr() -> SocketAddr {
+    env::var("GS_TEST_UDP_SERVER_ADDR")
+        .unwrap_or_else(|_| "127.0.0.1:8003".to_string())
+        .parse::<SocketAddr>()
+        .unwrap()
+}
+
+fn client_addr() -> SocketAddr {
+    server_addr()
+}
+
+pub async fn setup() {
+    if boot_server() {
+        run_server();
+        // TODO: 雑にスリープ。実際はReadiness Probe的なチェックが必要かも。
+        sleep(Duration::from_millis(1000)).await;
+    }
+}
+
+pub async fn teardown() {
+    // TODO: shutdown
+}
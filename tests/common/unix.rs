@@ -0,0 +1,66 @@
+use std::path::PathBuf;
+
+use tokio::net::UnixStream;
+
+use super::*;
+
+// Unix domain socketのクライアントは、TCPのクライアントと同じフレームでやり取りする。
+pub struct ClientImpl(tcp::ClientImpl);
+
+impl ClientImpl {
+    pub fn new(path: PathBuf) -> Self {
+        Self(tcp::ClientImpl::spawn(
+            async move { Ok(UnixStream::connect(path).await?) },
+            network_protocol::codec::Codec::Protobuf,
+        ))
+    }
+}
+
+#[async_trait]
+impl Client for ClientImpl {
+    fn generate() -> Self {
+        Self::new(socket_path())
+    }
+
+    fn send(
+        &self,
+        message: protobuf::app::ClientMessage,
+    ) -> Result<(), mpsc::error::SendError<protobuf::app::ClientMessage>> {
+        self.0.send(message)
+    }
+
+    async fn recv(&mut self) -> Option<protobuf::app::ServerMessage> {
+        self.0.recv().await
+    }
+}
+
+fn run_server() {
+    tokio::spawn(async move {
+        network_protocol::tcp::run_unix(
+            socket_path(),
+            default_config(),
+            Arc::new(actor::Registries::new()),
+            network_protocol::server::wait_signal(),
+        )
+        .await
+        .unwrap();
+    });
+}
+
+fn socket_path() -> PathBuf {
+    env::var("GS_TEST_UNIX_SOCKET_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| env::temp_dir().join("mini-realtime-server-test.sock"))
+}
+
+pub async fn setup() {
+    if boot_server() {
+        run_server();
+        // TODO: 雑にスリープ。実際はReadiness Probe的なチェックが必要かも。
+        sleep(Duration::from_millis(1000)).await;
+    }
+}
+
+pub async fn teardown() {
+    // TODO: shutdown
+}
@@ -1,58 +1,19 @@
 use std::net::SocketAddr;
 
 use async_trait::async_trait;
-use futures::{pin_mut, SinkExt, StreamExt};
-use prost::Message as _;
 use tokio::sync::mpsc;
-use tokio_tungstenite::tungstenite::Message;
 
 use super::*;
 
-pub struct ClientImpl {
-    tx: mpsc::UnboundedSender<protobuf::app::ClientMessage>,
-    rx: mpsc::UnboundedReceiver<protobuf::app::ServerMessage>,
-}
+pub struct ClientImpl(client::Client);
 
-// TODO: close, 雑なunwrap
 impl ClientImpl {
     pub fn new(addr: String) -> Self {
-        let (input_tx, mut input_rx) = mpsc::unbounded_channel::<protobuf::app::ClientMessage>();
-        let (output_tx, output_rx) = mpsc::unbounded_channel();
-        tokio::spawn(async move {
-            let (ws_stream, _) = tokio_tungstenite::connect_async(addr).await.unwrap();
-            let (tx, rx) = ws_stream.split();
-            pin_mut!(tx, rx);
-            loop {
-                tokio::select! {
-                    input_msg = input_rx.recv() => {
-                        if let Some(input_msg) = input_msg {
-                            tx.send(Message::binary(input_msg.encode_to_vec())).await.unwrap();
-                        }
-                    }
-                    output_msg = rx.next() => {
-                        if let Some(output_msg) = output_msg {
-                            match output_msg {
-                                Ok(Message::Binary(output_msg)) => {
-                                    let message: Result<protobuf::app::ServerMessage, prost::DecodeError> = prost::Message::decode(output_msg.as_ref());
-                                    output_tx.send(message.unwrap()).unwrap();
-                                }
-                                Ok(output_msg) => {
-                                    panic!("Unexpected message: {:?}", output_msg);
-                                }
-                                Err(err) => {
-                                    panic!("Unexpected error: {:?}", err);
-                                }
-                            };
-                        }
-                    }
-                }
-            }
-        });
-
-        Self {
-            tx: input_tx,
-            rx: output_rx,
-        }
+        Self::with_subprotocol(addr, network_protocol::websocket::SUBPROTOCOL_PROTOBUF)
+    }
+
+    pub fn with_subprotocol(addr: String, subprotocol: &'static str) -> Self {
+        Self(client::websocket::connect(addr, subprotocol))
     }
 }
 
@@ -66,11 +27,35 @@ impl Client for ClientImpl {
         &self,
         message: protobuf::app::ClientMessage,
     ) -> Result<(), mpsc::error::SendError<protobuf::app::ClientMessage>> {
-        self.tx.send(message)
+        self.0.send(message)
+    }
+
+    async fn recv(&mut self) -> Option<protobuf::app::ServerMessage> {
+        self.0.recv().await
+    }
+}
+
+/// protobufのJSONマッピングをテキストフレームでやり取りするクライアント。
+pub struct JsonClientImpl(ClientImpl);
+
+#[async_trait]
+impl Client for JsonClientImpl {
+    fn generate() -> Self {
+        Self(ClientImpl::with_subprotocol(
+            client_addr(),
+            network_protocol::websocket::SUBPROTOCOL_JSON,
+        ))
+    }
+
+    fn send(
+        &self,
+        message: protobuf::app::ClientMessage,
+    ) -> Result<(), mpsc::error::SendError<protobuf::app::ClientMessage>> {
+        self.0.send(message)
     }
 
     async fn recv(&mut self) -> Option<protobuf::app::ServerMessage> {
-        self.rx.recv().await
+        self.0.recv().await
     }
 }
 
@@ -79,6 +64,7 @@ fn run_server() {
         network_protocol::server::run::<network_protocol::websocket::ServerImpl, _, _>(
             server_addr(),
             default_config(),
+            Arc::new(actor::Registries::new()),
             network_protocol::server::wait_signal(),
         )
         .await
@@ -21,6 +21,8 @@ async fn e2e_websocket() {
     websocket::setup().await;
     let result = std::panic::AssertUnwindSafe(async {
         scenario::run_e2e_all::<websocket::ClientImpl>().await;
+        scenario::run_e2e_all::<websocket::JsonClientImpl>().await;
+        scenario::run_e2e_too_large_message::<websocket::ClientImpl>().await;
     })
     .catch_unwind()
     .await;
@@ -35,6 +37,8 @@ async fn e2e_tcp() {
     tcp::setup().await;
     let result = std::panic::AssertUnwindSafe(async {
         scenario::run_e2e_all::<tcp::ClientImpl>().await;
+        scenario::run_e2e_all::<tcp::MessagePackClientImpl>().await;
+        scenario::run_e2e_too_large_message::<tcp::ClientImpl>().await;
     })
     .catch_unwind()
     .await;
@@ -43,3 +47,77 @@ async fn e2e_tcp() {
         std::panic::resume_unwind(err);
     }
 }
+
+#[tokio::test]
+async fn e2e_unix() {
+    unix::setup().await;
+    let result = std::panic::AssertUnwindSafe(async {
+        scenario::run_e2e_all::<unix::ClientImpl>().await;
+    })
+    .catch_unwind()
+    .await;
+    unix::teardown().await;
+    if let Err(err) = result {
+        std::panic::resume_unwind(err);
+    }
+}
+
+#[tokio::test]
+async fn e2e_udp() {
+    udp::setup().await;
+    let result = std::panic::AssertUnwindSafe(async {
+        scenario::run_e2e_all::<udp::ClientImpl>().await;
+    })
+    .catch_unwind()
+    .await;
+    udp::teardown().await;
+    if let Err(err) = result {
+        std::panic::resume_unwind(err);
+    }
+}
+
+#[tokio::test]
+async fn e2e_quic() {
+    quic::setup().await;
+    let result = std::panic::AssertUnwindSafe(async {
+        scenario::run_e2e_all::<quic::ClientImpl>().await;
+    })
+    .catch_unwind()
+    .await;
+    quic::teardown().await;
+    if let Err(err) = result {
+        std::panic::resume_unwind(err);
+    }
+}
+
+#[tokio::test]
+async fn e2e_http_fallback() {
+    http_fallback::setup().await;
+    let result = std::panic::AssertUnwindSafe(async {
+        scenario::run_e2e_all::<http_fallback::ClientImpl>().await;
+    })
+    .catch_unwind()
+    .await;
+    http_fallback::teardown().await;
+    if let Err(err) = result {
+        std::panic::resume_unwind(err);
+    }
+}
+
+#[tokio::test]
+async fn e2e_multi_protocol() {
+    multi_protocol::setup().await;
+    let result = std::panic::AssertUnwindSafe(async {
+        scenario::run_e2e_mixed(
+            &mut multi_protocol::generate_websocket_client(),
+            &mut multi_protocol::generate_tcp_client(),
+        )
+        .await;
+    })
+    .catch_unwind()
+    .await;
+    multi_protocol::teardown().await;
+    if let Err(err) = result {
+        std::panic::resume_unwind(err);
+    }
+}